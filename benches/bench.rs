@@ -77,6 +77,57 @@ fn dec_c(b: &mut Bencher) {
 }
 
 
+fn mkem_group(n: usize) -> Vec<hila5::PublicKey> {
+    let seed = [7u8; hila5::mkem::GROUP_SEED_LEN];
+    (0..n).map(|_| hila5::mkem::keypair(&seed).unwrap().0).collect()
+}
+
+fn mkem_enc(b: &mut Bencher, n: usize) {
+    let pks = mkem_group(n);
+    let pks: Vec<&hila5::PublicKey> = pks.iter().collect();
+
+    b.iter(|| hila5::mkem::enc(&pks).unwrap())
+}
+
+fn separate_enc(b: &mut Bencher, n: usize) {
+    let pks = mkem_group(n);
+
+    b.iter(|| {
+        for pk in &pks {
+            hila5::kem::enc(pk).unwrap();
+        }
+    })
+}
+
+#[bench]
+fn mkem_enc_32(b: &mut Bencher) {
+    mkem_enc(b, 32)
+}
+
+#[bench]
+fn mkem_enc_300(b: &mut Bencher) {
+    mkem_enc(b, 300)
+}
+
+#[bench]
+fn mkem_enc_1000(b: &mut Bencher) {
+    mkem_enc(b, 1000)
+}
+
+#[bench]
+fn separate_enc_32(b: &mut Bencher) {
+    separate_enc(b, 32)
+}
+
+#[bench]
+fn separate_enc_300(b: &mut Bencher) {
+    separate_enc(b, 300)
+}
+
+#[bench]
+fn separate_enc_1000(b: &mut Bencher) {
+    separate_enc(b, 1000)
+}
 
 mod ffi {
     extern "C" {
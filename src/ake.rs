@@ -0,0 +1,337 @@
+//! Mutually authenticated key exchange from static and ephemeral HILA5 keys.
+//!
+//! This follows the two-message construction of Kyber.AKE (itself an instance
+//! of the FSXY transform): both parties hold a static keypair and know the
+//! static `PublicKey` of their peer.
+//!
+//!  1. The initiator generates an ephemeral keypair, and encapsulates to the
+//!     responder's static key, sending `Msg1 = (pk_e, ct_R)`.
+//!  2. The responder decapsulates `ct_R`, and encapsulates both to the
+//!     initiator's static key and to `pk_e`, sending
+//!     `Msg2 = (ct_I, ct_e, mac_R)`.
+//!  3. The initiator decapsulates both ciphertexts, checks the responder's key
+//!     confirmation MAC and returns `Msg3 = mac_I` to confirm its own key.
+//!
+//! The session key is derived from all three shared secrets and a hash over
+//! the full transcript (including both static public keys), so it is only
+//! known to the holders of the two static private keys.
+//!
+//! ```rust
+//! use hila5::ake::{Initiator, Responder};
+//!
+//! let (pk_alice, sk_alice) = hila5::crypto_kem_keypair().unwrap();
+//! let (pk_bob, sk_bob) = hila5::crypto_kem_keypair().unwrap();
+//!
+//! let (alice, msg1) = Initiator::start(&sk_alice, &pk_bob).unwrap();
+//! let (bob, msg2) = Responder::respond(&sk_bob, &pk_alice, &msg1).unwrap();
+//! let (key_alice, msg3) = alice.finish(&msg2).unwrap();
+//! let key_bob = bob.confirm(&msg3).unwrap();
+//!
+//! assert_eq!(key_alice.0, key_bob.0);
+//! ```
+
+use ring::{constant_time, digest, hmac};
+use sha3::{Digest, Sha3_256};
+
+use std::io::Write;
+
+use super::*;
+use errors::*;
+
+/// Length of the key confirmation MACs.
+pub const MAC_LEN: usize = 32;
+
+const AKE_LABEL: &[u8] = b"HILA5v10-AKE";
+
+/// Key established by a successful key exchange.
+pub struct SessionKey(pub Vec<u8>);
+
+/// First message: sent from initiator to responder.
+pub struct Msg1 {
+    /// Initiator's ephemeral public key.
+    pub ephemeral: PublicKey,
+    /// Encapsulation to the responder's static key.
+    pub ct: Vec<u8>,
+}
+
+/// Second message: sent from responder to initiator.
+pub struct Msg2 {
+    /// Encapsulation to the initiator's static key.
+    pub ct_static: Vec<u8>,
+    /// Encapsulation to the initiator's ephemeral key.
+    pub ct_ephemeral: Vec<u8>,
+    /// Responder's key confirmation.
+    pub mac: Vec<u8>,
+}
+
+/// Third message: the initiator's key confirmation.
+pub struct Msg3 {
+    pub mac: Vec<u8>,
+}
+
+/// Initiator waiting for the responder's `Msg2`.
+pub struct Initiator<'a> {
+    sk: &'a PrivateKey,
+    peer_digest: Vec<u8>,
+    ephemeral: PrivateKey,
+    ss: SharedSecret,
+    msg1: Vec<u8>,
+}
+
+/// Responder waiting for the initiator's `Msg3`.
+pub struct Responder {
+    key: SessionKey,
+    mac_key: hmac::SigningKey,
+    transcript: Vec<u8>,
+}
+
+impl<'a> Initiator<'a> {
+    /// Start a key exchange with the owner of `peer`, authenticating with our
+    /// static key `sk`.
+    pub fn start(sk: &'a PrivateKey, peer: &PublicKey) -> Result<(Self, Msg1)> {
+        let (ephemeral, ephemeral_sk) = crypto_kem_keypair()?;
+        let (ct, ss) = peer.enc()?;
+        let msg1 = Msg1 { ephemeral, ct };
+
+        let mut msg1_bytes = vec![];
+        msg1.write_to(&mut msg1_bytes)?;
+
+        let initiator = Initiator {
+            sk,
+            peer_digest: peer.digest()?,
+            ephemeral: ephemeral_sk,
+            ss,
+            msg1: msg1_bytes,
+        };
+        Ok((initiator, msg1))
+    }
+
+    /// Process the responder's reply, returning the session key and our key
+    /// confirmation message.
+    pub fn finish(self, msg2: &Msg2) -> Result<(SessionKey, Msg3)> {
+        let (keys, transcript) = self.verify(msg2)?;
+        let mac = hmac::sign(&keys.initiator_mac, &transcript).as_ref().to_vec();
+        Ok((keys.session, Msg3 { mac }))
+    }
+
+    /// Check the responder's reply without finishing, so that a caller
+    /// receiving replies over an unreliable transport can skip the bad ones.
+    pub fn check(&self, msg2: &Msg2) -> Result<()> {
+        self.verify(msg2).map(|_| ())
+    }
+
+    fn verify(&self, msg2: &Msg2) -> Result<(KeySchedule, Vec<u8>)> {
+        let ss_static = self.sk.dec(&msg2.ct_static)?;
+        let ss_ephemeral = self.ephemeral.dec(&msg2.ct_ephemeral)?;
+
+        let transcript = transcript_hash(&self.sk.pk_digest, &self.peer_digest, &self.msg1, msg2);
+        let keys = KeySchedule::new(&transcript, &self.ss, &ss_static, &ss_ephemeral);
+
+        let responder_mac = hmac::sign(&keys.responder_mac, &transcript);
+        constant_time::verify_slices_are_equal(responder_mac.as_ref(), &msg2.mac)
+            .map_err(|_| "responder key confirmation failed")?;
+        Ok((keys, transcript))
+    }
+}
+
+impl Responder {
+    /// Respond to a key exchange started by the owner of `peer`,
+    /// authenticating with our static key `sk`.
+    pub fn respond(sk: &PrivateKey, peer: &PublicKey, msg1: &Msg1) -> Result<(Self, Msg2)> {
+        let ss = sk.dec(&msg1.ct)?;
+        let (ct_static, ss_static) = peer.enc()?;
+        let (ct_ephemeral, ss_ephemeral) = msg1.ephemeral.enc()?;
+
+        let mut msg1_bytes = vec![];
+        msg1.write_to(&mut msg1_bytes)?;
+
+        let mut msg2 = Msg2 { ct_static, ct_ephemeral, mac: vec![] };
+        let transcript = transcript_hash(&peer.digest()?, &sk.pk_digest, &msg1_bytes, &msg2);
+        let keys = KeySchedule::new(&transcript, &ss, &ss_static, &ss_ephemeral);
+        msg2.mac = hmac::sign(&keys.responder_mac, &transcript).as_ref().to_vec();
+
+        let responder = Responder {
+            key: keys.session,
+            mac_key: keys.initiator_mac,
+            transcript,
+        };
+        Ok((responder, msg2))
+    }
+
+    /// Check the initiator's key confirmation, returning the session key.
+    pub fn confirm(self, msg3: &Msg3) -> Result<SessionKey> {
+        self.check(msg3)?;
+        Ok(self.key)
+    }
+
+    /// Check the initiator's key confirmation without consuming the
+    /// responder.
+    pub fn check(&self, msg3: &Msg3) -> Result<()> {
+        hmac::verify_with_own_key(&self.mac_key, &self.transcript, &msg3.mac)
+            .map_err(|_| "initiator key confirmation failed".into())
+    }
+}
+
+impl Msg1 {
+    /// Length in bytes of the serialised message.
+    pub const LEN: usize = PUBKEY_LEN + CIPHERTEXT_LEN;
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        self.ephemeral.write_to(writer)?;
+        writer.write_all(&self.ct)?;
+        Ok(())
+    }
+
+    pub fn from_bytes(input: &[u8]) -> Result<Self> {
+        if input.len() != Self::LEN {
+            return Err("invalid length for AKE message 1".into());
+        }
+        let (pk, ct) = input.split_at(PUBKEY_LEN);
+        Ok(Msg1 {
+            ephemeral: PublicKey::from_bytes(pk),
+            ct: ct.to_vec(),
+        })
+    }
+}
+
+impl Msg2 {
+    /// Length in bytes of the serialised message.
+    pub const LEN: usize = 2 * CIPHERTEXT_LEN + MAC_LEN;
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&self.ct_static)?;
+        writer.write_all(&self.ct_ephemeral)?;
+        writer.write_all(&self.mac)?;
+        Ok(())
+    }
+
+    pub fn from_bytes(input: &[u8]) -> Result<Self> {
+        if input.len() != Self::LEN {
+            return Err("invalid length for AKE message 2".into());
+        }
+        let (ct_static, rest) = input.split_at(CIPHERTEXT_LEN);
+        let (ct_ephemeral, mac) = rest.split_at(CIPHERTEXT_LEN);
+        Ok(Msg2 {
+            ct_static: ct_static.to_vec(),
+            ct_ephemeral: ct_ephemeral.to_vec(),
+            mac: mac.to_vec(),
+        })
+    }
+}
+
+impl Msg3 {
+    /// Length in bytes of the serialised message.
+    pub const LEN: usize = MAC_LEN;
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&self.mac)?;
+        Ok(())
+    }
+
+    pub fn from_bytes(input: &[u8]) -> Result<Self> {
+        if input.len() != Self::LEN {
+            return Err("invalid length for AKE message 3".into());
+        }
+        Ok(Msg3 { mac: input.to_vec() })
+    }
+}
+
+/// Hash of both identities and every message up to (and excluding) the
+/// responder's MAC.
+fn transcript_hash(initiator: &[u8], responder: &[u8], msg1: &[u8], msg2: &Msg2) -> Vec<u8> {
+    let mut hasher = Sha3_256::default();
+    hasher.input(AKE_LABEL);
+    hasher.input(initiator);
+    hasher.input(responder);
+    hasher.input(msg1);
+    hasher.input(&msg2.ct_static);
+    hasher.input(&msg2.ct_ephemeral);
+    hasher.result().to_vec()
+}
+
+struct KeySchedule {
+    session: SessionKey,
+    initiator_mac: hmac::SigningKey,
+    responder_mac: hmac::SigningKey,
+}
+
+impl KeySchedule {
+    /// `ss_responder` is the secret encapsulated to the responder's static
+    /// key, the other two are encapsulated to the initiator's keys.
+    fn new(transcript: &[u8], ss_responder: &SharedSecret, ss_static: &SharedSecret,
+           ss_ephemeral: &SharedSecret) -> Self {
+        let mut hasher = Sha3_256::default();
+        hasher.input(AKE_LABEL);
+        hasher.input(transcript);
+        hasher.input(&ss_responder.0);
+        hasher.input(&ss_static.0);
+        hasher.input(&ss_ephemeral.0);
+        let master = hasher.result();
+
+        KeySchedule {
+            session: SessionKey(derive(&master, b"session key")),
+            initiator_mac: hmac::SigningKey::new(&digest::SHA256, &derive(&master, b"initiator confirm")),
+            responder_mac: hmac::SigningKey::new(&digest::SHA256, &derive(&master, b"responder confirm")),
+        }
+    }
+}
+
+fn derive(master: &[u8], label: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha3_256::default();
+    hasher.input(label);
+    hasher.input(master);
+    hasher.result().to_vec()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ake_agrees() {
+        let (pk_a, sk_a) = crypto_kem_keypair().unwrap();
+        let (pk_b, sk_b) = crypto_kem_keypair().unwrap();
+        let mut buf = vec![];
+
+        let (alice, msg1) = Initiator::start(&sk_a, &pk_b).unwrap();
+        msg1.write_to(&mut buf).unwrap();
+        let msg1 = Msg1::from_bytes(&buf).unwrap();
+
+        let (bob, msg2) = Responder::respond(&sk_b, &pk_a, &msg1).unwrap();
+        buf.clear();
+        msg2.write_to(&mut buf).unwrap();
+        let msg2 = Msg2::from_bytes(&buf).unwrap();
+
+        let (key_a, msg3) = alice.finish(&msg2).unwrap();
+        buf.clear();
+        msg3.write_to(&mut buf).unwrap();
+        let msg3 = Msg3::from_bytes(&buf).unwrap();
+
+        let key_b = bob.confirm(&msg3).unwrap();
+        assert_eq!(key_a.0, key_b.0);
+    }
+
+    #[test]
+    fn wrong_identity_fails() {
+        let (pk_a, sk_a) = crypto_kem_keypair().unwrap();
+        let (pk_b, sk_b) = crypto_kem_keypair().unwrap();
+        let (pk_eve, _) = crypto_kem_keypair().unwrap();
+
+        // Bob believes he is talking to Eve, so encapsulates to the wrong key.
+        let (alice, msg1) = Initiator::start(&sk_a, &pk_b).unwrap();
+        let (_, msg2) = Responder::respond(&sk_b, &pk_eve, &msg1).unwrap();
+        assert!(alice.finish(&msg2).is_err());
+
+        // Tampered confirmations are rejected by both sides.
+        let (alice, msg1) = Initiator::start(&sk_a, &pk_b).unwrap();
+        let (bob, mut msg2) = Responder::respond(&sk_b, &pk_a, &msg1).unwrap();
+        msg2.mac[0] ^= 1;
+        assert!(alice.check(&msg2).is_err());
+        msg2.mac[0] ^= 1;
+        assert!(alice.check(&msg2).is_ok());
+        msg2.mac[0] ^= 1;
+        assert!(alice.finish(&msg2).is_err());
+        assert!(bob.check(&Msg3 { mac: vec![0; MAC_LEN] }).is_err());
+        assert!(bob.confirm(&Msg3 { mac: vec![0; MAC_LEN] }).is_err());
+    }
+}
@@ -0,0 +1,303 @@
+//! Bech32m encodings of public keys and key fingerprints.
+//!
+//! Public keys are written as Bech32m strings (BIP 350) with the
+//! human-readable part `hila5pk`, so they start with `hila5pk1`. An encoded
+//! key is about 2900 characters, well past the 90 character limit of BIP 173,
+//! which this encoding does not apply. The checksum still catches any typo
+//! with probability `1 - 2^-30`, though the guarantee that every error in up
+//! to four characters is caught only holds for short strings like
+//! fingerprints.
+//!
+//! A `Fingerprint` is the first 16 bytes of the `sha3` digest of the key, as
+//! also kept in `PrivateKey::pk_digest`. It is written as a short Bech32m
+//! string starting `hila5fp1`, or as grouped hex for reading aloud.
+//!
+//! ```rust
+//! use hila5::bech32::{self, Fingerprint};
+//!
+//! let (pk, sk) = hila5::crypto_kem_keypair().unwrap();
+//! let encoded = bech32::encode_public_key(&pk).unwrap();
+//! assert!(encoded.starts_with("hila5pk1"));
+//! let parsed = bech32::decode_public_key(&encoded).unwrap();
+//! assert_eq!(parsed.digest().unwrap(), pk.digest().unwrap());
+//!
+//! let fingerprint = Fingerprint::from_public_key(&pk).unwrap();
+//! assert_eq!(fingerprint, Fingerprint::from_private_key(&sk));
+//! assert_eq!(Fingerprint::parse(&fingerprint.to_string()).unwrap(), fingerprint);
+//!
+//! let grouped = fingerprint.to_grouped_hex();
+//! assert_eq!(grouped.len(), 39);
+//! let groups = grouped.split(' ').collect::<Vec<_>>();
+//! assert_eq!(groups.len(), 8);
+//! assert!(groups.iter().all(|g| g.len() == 4 && g.bytes().all(|b| b.is_ascii_hexdigit())));
+//! ```
+
+use std::fmt;
+
+use super::*;
+use errors::*;
+
+/// Human-readable part of encoded public keys.
+pub const PUBLIC_KEY_HRP: &str = "hila5pk";
+/// Human-readable part of encoded fingerprints.
+pub const FINGERPRINT_HRP: &str = "hila5fp";
+/// Length of a fingerprint in bytes.
+pub const FINGERPRINT_LEN: usize = 16;
+
+const CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const BECH32M_CONST: u32 = 0x2bc8_30a3;
+const CHECKSUM_LEN: usize = 6;
+
+/// Encode `pk` as a `hila5pk1…` string.
+pub fn encode_public_key(pk: &PublicKey) -> Result<String> {
+    let mut bytes = vec![];
+    pk.write_to(&mut bytes)?;
+    Ok(encode(PUBLIC_KEY_HRP, &bytes))
+}
+
+/// Parse a `hila5pk1…` string, rejecting typos and non-canonical keys.
+pub fn decode_public_key(s: &str) -> Result<PublicKey> {
+    let bytes = decode_with_hrp(s, PUBLIC_KEY_HRP)?;
+    if bytes.len() != PUBKEY_LEN {
+        return Err(format_error(format!("public key is {} bytes, expected {}", bytes.len(), PUBKEY_LEN)));
+    }
+    let unpacked: NttVector = encode::unpack14(&bytes[rand::SEED_LEN..]);
+    if unpacked.get_inner().iter().any(|&x| x >= HILA5_Q) {
+        return Err(format_error("non-canonical key: coefficient not reduced mod q"));
+    }
+    Ok(PublicKey::from_bytes(&bytes))
+}
+
+/// A short fingerprint of a public key, for comparing keys by hand.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Fingerprint(pub [u8; FINGERPRINT_LEN]);
+
+impl Fingerprint {
+    pub fn from_public_key(pk: &PublicKey) -> Result<Self> {
+        Ok(Self::from_digest(&pk.digest()?))
+    }
+
+    /// The fingerprint of the public key, from the digest kept with the
+    /// private key.
+    pub fn from_private_key(sk: &PrivateKey) -> Self {
+        Self::from_digest(&sk.pk_digest)
+    }
+
+    fn from_digest(digest: &[u8]) -> Self {
+        let mut fingerprint = [0u8; FINGERPRINT_LEN];
+        fingerprint.copy_from_slice(&digest[..FINGERPRINT_LEN]);
+        Fingerprint(fingerprint)
+    }
+
+    /// Parse a `hila5fp1…` string.
+    pub fn parse(s: &str) -> Result<Self> {
+        let bytes = decode_with_hrp(s, FINGERPRINT_HRP)?;
+        if bytes.len() != FINGERPRINT_LEN {
+            return Err(format_error("fingerprint has the wrong length"));
+        }
+        Ok(Self::from_digest(&bytes))
+    }
+
+    /// Groups of four hex digits, e.g. `3f2a 91c0 …`, for reading out of
+    /// band.
+    pub fn to_grouped_hex(&self) -> String {
+        self.0.chunks(2)
+            .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    /// The `hila5fp1…` form.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&encode(FINGERPRINT_HRP, &self.0))
+    }
+}
+
+pub(crate) fn decode_with_hrp(s: &str, expected: &str) -> Result<Vec<u8>> {
+    let (hrp, data) = decode(s.trim())?;
+    if hrp != expected {
+        return Err(format_error(format!("expected a `{}` string, found `{}`", expected, hrp)));
+    }
+    from_base32(&data)
+}
+
+/// Bech32m encoding of `data` under `hrp`, in lower case.
+pub(crate) fn encode(hrp: &str, data: &[u8]) -> String {
+    let mut values = to_base32(data);
+    let checksum = create_checksum(hrp, &values);
+    values.extend_from_slice(&checksum);
+    let mut s = String::with_capacity(hrp.len() + 1 + values.len());
+    s.push_str(hrp);
+    s.push('1');
+    s.extend(values.iter().map(|&v| CHARSET[v as usize] as char));
+    s
+}
+
+/// Decode a Bech32m string into its (lower case) human-readable part and
+/// 5-bit values, without the checksum.
+fn decode(s: &str) -> Result<(String, Vec<u8>)> {
+    if s.chars().any(|c| c.is_ascii_lowercase()) && s.chars().any(|c| c.is_ascii_uppercase()) {
+        return Err(format_error("mixed case in Bech32m string"));
+    }
+    if s.bytes().any(|b| !(33..=126).contains(&b)) {
+        return Err(format_error("invalid character in Bech32m string"));
+    }
+    let s = s.to_ascii_lowercase();
+    let sep = s.rfind('1').ok_or_else(|| format_error("missing separator in Bech32m string"))?;
+    let (hrp, rest) = (&s[..sep], &s.as_bytes()[sep + 1..]);
+    if hrp.is_empty() || rest.len() < CHECKSUM_LEN {
+        return Err(format_error("Bech32m string is too short"));
+    }
+    let mut values = Vec::with_capacity(rest.len());
+    for (i, &c) in rest.iter().enumerate() {
+        let value = CHARSET.iter().position(|&x| x == c)
+            .ok_or_else(|| format_error(format!("invalid Bech32m character `{}` at position {}",
+                                                c as char, sep + 1 + i)))?;
+        values.push(value as u8);
+    }
+    if polymod(&[&hrp_expand(hrp)[..], &values[..]].concat()) != BECH32M_CONST {
+        return Err(format_error("Bech32m checksum mismatch, check for typos"));
+    }
+    values.truncate(values.len() - CHECKSUM_LEN);
+    Ok((hrp.to_string(), values))
+}
+
+fn polymod(values: &[u8]) -> u32 {
+    const GEN: [u32; 5] = [0x3b6a_57b2, 0x2650_8e6d, 0x1ea1_19fa, 0x3d42_33dd, 0x2a14_62b3];
+    let mut chk = 1u32;
+    for &v in values {
+        let top = chk >> 25;
+        chk = ((chk & 0x1ff_ffff) << 5) ^ v as u32;
+        for (i, g) in GEN.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                chk ^= g;
+            }
+        }
+    }
+    chk
+}
+
+fn hrp_expand(hrp: &str) -> Vec<u8> {
+    let mut out: Vec<u8> = hrp.bytes().map(|b| b >> 5).collect();
+    out.push(0);
+    out.extend(hrp.bytes().map(|b| b & 31));
+    out
+}
+
+fn create_checksum(hrp: &str, values: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let mut input = hrp_expand(hrp);
+    input.extend_from_slice(values);
+    input.extend_from_slice(&[0; CHECKSUM_LEN]);
+    let pm = polymod(&input) ^ BECH32M_CONST;
+    let mut checksum = [0u8; CHECKSUM_LEN];
+    for (i, c) in checksum.iter_mut().enumerate() {
+        *c = ((pm >> (5 * (5 - i))) & 31) as u8;
+    }
+    checksum
+}
+
+/// Regroup bytes into 5-bit values, padding the last with zero bits.
+fn to_base32(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() * 8 / 5 + 1);
+    let (mut acc, mut bits) = (0u32, 0);
+    for &b in data {
+        acc = (acc << 8) | b as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(((acc >> bits) & 31) as u8);
+        }
+    }
+    if bits > 0 {
+        out.push(((acc << (5 - bits)) & 31) as u8);
+    }
+    out
+}
+
+/// Regroup 5-bit values into bytes, rejecting non-zero or excess padding.
+fn from_base32(values: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(values.len() * 5 / 8);
+    let (mut acc, mut bits) = (0u32, 0);
+    for &v in values {
+        acc = (acc << 5) | v as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    if bits >= 5 || acc & ((1 << bits) - 1) != 0 {
+        return Err(format_error("invalid padding in Bech32m data"));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bip350_vectors() {
+        for s in &["A1LQFN3A", "a1lqfn3a", "abcdef1l7aum6echk45nj3s0wdvt2fg8x9yrzpqzd3ryx",
+                   "split1checkupstagehandshakeupstreamerranterredcaperredlc445v", "?1v759aa"] {
+            let (hrp, values) = decode(s).unwrap();
+            let mut expected = values.clone();
+            expected.extend_from_slice(&create_checksum(&hrp, &values));
+            assert_eq!(encode_values(&hrp, &expected), s.to_ascii_lowercase());
+        }
+        for s in &["A1G7SGD8", "1qzzfhee", "M1VUXWEZ", "abcdef1l7aum6echk45nj3s0wdvt2fg8x9yrzpqzd3ryX",
+                   "16plkw9", "1p2gdwpf", "qyrz8wqd2c9m", "y1b0jsk6g", "lt1igcx5c0", "in1muywd"] {
+            assert!(decode(s).is_err(), "{}", s);
+        }
+    }
+
+    fn encode_values(hrp: &str, values: &[u8]) -> String {
+        format!("{}1{}", hrp, values.iter().map(|&v| CHARSET[v as usize] as char).collect::<String>())
+    }
+
+    #[test]
+    fn public_key_roundtrip() {
+        let (pk, _) = crypto_kem_keypair().unwrap();
+        let encoded = encode_public_key(&pk).unwrap();
+        assert!(encoded.starts_with("hila5pk1"));
+        assert_eq!(encoded.len(), PUBLIC_KEY_HRP.len() + 1 + (PUBKEY_LEN * 8 + 4) / 5 + CHECKSUM_LEN);
+        let parsed = decode_public_key(&format!(" {}\n", encoded.to_ascii_uppercase())).unwrap();
+        assert_eq!(parsed.digest().unwrap(), pk.digest().unwrap());
+
+        // any single substituted or swapped character is caught
+        let bytes = encoded.as_bytes();
+        for &at in &[8, 100, bytes.len() / 2, bytes.len() - 1] {
+            let mut typo = bytes.to_vec();
+            typo[at] = if typo[at] == b'q' { b'p' } else { b'q' };
+            assert!(decode_public_key(std::str::from_utf8(&typo).unwrap()).is_err());
+            let mut swap = bytes.to_vec();
+            swap.swap(at - 1, at);
+            if swap != bytes {
+                assert!(decode_public_key(std::str::from_utf8(&swap).unwrap()).is_err());
+            }
+        }
+        let fingerprint = Fingerprint::from_public_key(&pk).unwrap().to_string();
+        assert!(decode_public_key(&fingerprint).is_err());
+    }
+
+    #[test]
+    fn fingerprints() {
+        let (pk, sk) = crypto_kem_keypair().unwrap();
+        let fingerprint = Fingerprint::from_public_key(&pk).unwrap();
+        assert_eq!(fingerprint, Fingerprint::from_private_key(&sk));
+        assert_eq!(&fingerprint.0[..], &pk.digest().unwrap()[..FINGERPRINT_LEN]);
+
+        let s = fingerprint.to_string();
+        assert!(s.starts_with("hila5fp1"));
+        assert_eq!(s.len(), 8 + 26 + 6);
+        assert_eq!(Fingerprint::parse(&s).unwrap(), fingerprint);
+        let mut typo = s.clone().into_bytes();
+        typo[20] = if typo[20] == b'x' { b'y' } else { b'x' };
+        assert!(Fingerprint::parse(std::str::from_utf8(&typo).unwrap()).is_err());
+
+        let hex = Fingerprint([0xab; FINGERPRINT_LEN]).to_grouped_hex();
+        assert_eq!(hex, "abab abab abab abab abab abab abab abab");
+    }
+}
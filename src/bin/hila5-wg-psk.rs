@@ -0,0 +1,144 @@
+//! Derive and rotate WireGuard preshared keys with HILA5.
+//!
+//! ```text
+//! hila5-wg-psk keygen <name>
+//! hila5-wg-psk respond <listen addr> <key> <peer.pub> <psk file> [options]
+//! hila5-wg-psk initiate <peer addr> <key> <peer.pub> <psk file> [options]
+//!
+//! options:
+//!     --interval <secs>       seconds between key exchanges (default 120)
+//!     --rounds <n>            stop after n exchanges (default: run forever)
+//!     --wg <iface> <peer>     print the `wg set` command for each new PSK
+//! ```
+//!
+//! `keygen` writes the private key to `<name>.key` and the public key to
+//! `<name>.pub`, which is given to the other peer.
+
+extern crate base64;
+extern crate hila5;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+
+use hila5::errors::*;
+use hila5::wireguard::{self, Peers, Psk};
+use hila5::{openssh, PrivateKey, PublicKey, PRIVKEY_LEN};
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
+
+const USAGE: &str = "usage:
+    hila5-wg-psk keygen <name>
+    hila5-wg-psk respond <listen addr> <key> <peer.pub> <psk file> [options]
+    hila5-wg-psk initiate <peer addr> <key> <peer.pub> <psk file> [options]
+
+options:
+    --interval <secs>       seconds between key exchanges (default 120)
+    --rounds <n>            stop after n exchanges (default: run forever)
+    --wg <iface> <peer>     print the `wg set` command for each new PSK";
+
+struct Options {
+    interval: Duration,
+    rounds: Option<u32>,
+    wg: Option<(String, String)>,
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(e) = run(&args) {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(args: &[String]) -> Result<()> {
+    match args.first().map(|s| s.as_str()) {
+        Some("keygen") if args.len() == 2 => keygen(&args[1]),
+        Some(cmd @ "respond") | Some(cmd @ "initiate") if args.len() >= 5 => {
+            let sk = read_private_key(&args[2])?;
+            let remote = read_public_key(&args[3])?;
+            let psk_file = PathBuf::from(&args[4]);
+            let options = parse_options(&args[5..])?;
+            let peers = Peers { sk: &sk, remote: &remote };
+            let on_psk = |epoch: u32, psk: &Psk| install(epoch, psk, &psk_file, &options);
+
+            if cmd == "respond" {
+                let socket = UdpSocket::bind(&args[1])?;
+                eprintln!("listening on {}", socket.local_addr()?);
+                wireguard::run_responder(&socket, &peers, options.interval * 2, options.rounds,
+                                         on_psk)
+            } else {
+                let addr = args[1].to_socket_addrs()?.next()
+                    .ok_or_else(|| format!("could not resolve {}", args[1]))?;
+                let socket = UdpSocket::bind(if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+                wireguard::run_initiator(&socket, addr, &peers, options.interval, options.rounds,
+                                         on_psk)
+            }
+        }
+        _ => Err(USAGE.into()),
+    }
+}
+
+fn parse_options(args: &[String]) -> Result<Options> {
+    let mut options = Options { interval: Duration::from_secs(120), rounds: None, wg: None };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| Error::from(format!("missing value for {}", arg)));
+        match arg.as_str() {
+            "--interval" => {
+                let secs = value()?.parse().map_err(|_| "invalid --interval")?;
+                options.interval = Duration::from_secs(secs);
+            }
+            "--rounds" => {
+                options.rounds = Some(value()?.parse().map_err(|_| "invalid --rounds")?);
+            }
+            "--wg" => {
+                let interface = value()?.clone();
+                options.wg = Some((interface, value()?.clone()));
+            }
+            _ => return Err(format!("unknown option {}\n\n{}", arg, USAGE).into()),
+        }
+    }
+    Ok(options)
+}
+
+fn install(epoch: u32, psk: &Psk, psk_file: &Path, options: &Options) -> Result<()> {
+    psk.write_to_file(psk_file)?;
+    eprintln!("epoch {}: wrote new preshared key to {}", epoch, psk_file.display());
+    if let Some((ref interface, ref peer)) = options.wg {
+        println!("{}", wireguard::wg_set_command(interface, peer, psk_file));
+    }
+    Ok(())
+}
+
+fn keygen(name: &str) -> Result<()> {
+    let (pk, sk) = hila5::crypto_kem_keypair()?;
+    let mut sk_bytes = vec![];
+    sk.write_to(&mut sk_bytes)?;
+    let mut key_file = wireguard::create_private(Path::new(&format!("{}.key", name)))?;
+    writeln!(key_file, "{}", STANDARD.encode(&sk_bytes))?;
+    fs::write(format!("{}.pub", name), format!("{}\n", openssh::encode_line(&pk, name)?))?;
+    eprintln!("wrote {0}.key and {0}.pub", name);
+    Ok(())
+}
+
+fn read_private_key(path: &str) -> Result<PrivateKey> {
+    let contents = fs::read_to_string(path)?;
+    let bytes = STANDARD.decode(contents.trim())
+        .map_err(|e| format!("{}: invalid base64: {}", path, e))?;
+    if bytes.len() != PRIVKEY_LEN {
+        return Err(format!("{}: not a HILA5 private key", path).into());
+    }
+    Ok(PrivateKey::from_bytes(&bytes))
+}
+
+fn read_public_key(path: &str) -> Result<PublicKey> {
+    let contents = fs::read_to_string(path)?;
+    let (pk, _) = openssh::parse_line(&contents)?;
+    Ok(pk)
+}
@@ -0,0 +1,662 @@
+//! Encrypted stream over any `AsyncRead + AsyncWrite`, keyed by a HILA5
+//! handshake.
+//!
+//! The handshake is the unauthenticated exchange from `kex`: the client sends
+//! an ephemeral public key, and the server replies with an encapsulation.
+//! Per-direction ChaCha20-Poly1305 keys are derived from the session key with
+//! HKDF-SHA256.
+//!
+//! To finish the handshake, each side sends a static (for the lifetime of
+//! the channel) rekeying public key and waits for its peer's, so both
+//! directions can rekey whether or not they ever read. After
+//! `Config::rekey_after` bytes, a writer encapsulates to its peer's rekeying
+//! key, sends the ciphertext in a rekey frame, and mixes the fresh shared
+//! secret into its sending key. Since each direction is rekeyed
+//! independently, this requires no round trip.
+//!
+//! Shutting down the stream sends a close frame, so a reader can distinguish
+//! a graceful close from a truncated connection.
+//!
+//! Note that the handshake does *not* authenticate either party.
+//!
+//! ## Wire format
+//!
+//! Every frame is a 4-byte big-endian length followed by that many bytes of
+//! AEAD ciphertext (the length is authenticated as associated data). The
+//! plaintext is a 1-byte frame type followed by the payload. Nonces are a
+//! per-direction frame counter, reset on every rekey.
+
+use byteorder::{BigEndian, ByteOrder};
+use ring::{aead, digest, hkdf, hmac};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use super::*;
+use kex::{AwaitingReply, Initiator, Msg1, Msg2, Responder};
+
+/// Maximum length of the payload of a single data frame.
+pub const MAX_PAYLOAD_LEN: usize = 1 << 14;
+
+const LEN_LEN: usize = 4;
+const KEY_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const MAX_BODY_LEN: usize =
+    if MAX_PAYLOAD_LEN > CIPHERTEXT_LEN { MAX_PAYLOAD_LEN } else { CIPHERTEXT_LEN };
+const MAX_FRAME_LEN: usize = 1 + MAX_BODY_LEN + TAG_LEN;
+const REKEY_KEY_FRAME_LEN: usize = LEN_LEN + 1 + PUBKEY_LEN + TAG_LEN;
+const CHANNEL_LABEL: &[u8] = b"HILA5v10-channel";
+
+/// Channel configuration.
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    /// Number of bytes sent on one key before the writer rekeys.
+    pub rekey_after: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config { rekey_after: 1 << 30 }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FrameType {
+    Data = 0,
+    RekeyKey = 1,
+    Rekey = 2,
+    Close = 3,
+}
+
+impl FrameType {
+    fn from_u8(x: u8) -> io::Result<Self> {
+        match x {
+            0 => Ok(FrameType::Data),
+            1 => Ok(FrameType::RekeyKey),
+            2 => Ok(FrameType::Rekey),
+            3 => Ok(FrameType::Close),
+            _ => Err(invalid_data("unknown frame type")),
+        }
+    }
+}
+
+/// Start the handshake as the client.
+pub fn connect<S>(io: S, config: Config) -> Handshake<S>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    Handshake::new(io, config, HandshakeState::ClientStart)
+}
+
+/// Start the handshake as the server.
+pub fn accept<S>(io: S, config: Config) -> Handshake<S>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    let mut handshake = Handshake::new(io, config, HandshakeState::AwaitInit);
+    handshake.expect(Msg1::LEN);
+    handshake
+}
+
+enum HandshakeState {
+    ClientStart,
+    AwaitReply(Box<Initiator<AwaitingReply>>),
+    AwaitInit,
+    AwaitRekeyKey(Box<Keys>),
+    Finish(Box<Keys>, Box<PublicKey>),
+    Done,
+}
+
+/// Future resolving to a `SecureStream` once the handshake has completed.
+pub struct Handshake<S> {
+    io: Option<S>,
+    config: Config,
+    state: HandshakeState,
+    out: Vec<u8>,
+    written: usize,
+    input: Vec<u8>,
+    filled: usize,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Handshake<S> {
+    fn new(io: S, config: Config, state: HandshakeState) -> Self {
+        Handshake {
+            io: Some(io),
+            config,
+            state,
+            out: vec![],
+            written: 0,
+            input: vec![],
+            filled: 0,
+        }
+    }
+
+    /// Read exactly `self.input.len()` bytes.
+    fn poll_fill(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        let io = self.io.as_mut().expect("polled after completion");
+        while self.filled < self.input.len() {
+            let mut buf = ReadBuf::new(&mut self.input[self.filled..]);
+            ready!(Pin::new(&mut *io).poll_read(cx, &mut buf))?;
+            if buf.filled().is_empty() {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            self.filled += buf.filled().len();
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Write out everything queued in `self.out`.
+    fn poll_drain(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        let io = self.io.as_mut().expect("polled after completion");
+        while self.written < self.out.len() {
+            let n = ready!(Pin::new(&mut *io).poll_write(cx, &self.out[self.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn expect(&mut self, len: usize) {
+        self.input = vec![0; len];
+        self.filled = 0;
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Future for Handshake<S> {
+    type Output = io::Result<SecureStream<S>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        loop {
+            ready!(this.poll_drain(cx))?;
+            match ::std::mem::replace(&mut this.state, HandshakeState::Done) {
+                HandshakeState::ClientStart => {
+                    let (initiator, msg1) = Initiator::start().map_err(other)?;
+                    msg1.write_to(&mut this.out).map_err(other)?;
+                    this.expect(Msg2::LEN);
+                    this.state = HandshakeState::AwaitReply(Box::new(initiator));
+                }
+                HandshakeState::AwaitReply(initiator) => {
+                    if this.poll_fill(cx)?.is_pending() {
+                        this.state = HandshakeState::AwaitReply(initiator);
+                        return Poll::Pending;
+                    }
+                    let msg2 = Msg2::from_bytes(&this.input).map_err(other)?;
+                    let key = initiator.finish(&msg2).map_err(other)?;
+                    let mut keys = Keys::new(&key, true)?;
+                    keys.send_rekey_key(&mut this.out)?;
+                    this.expect(REKEY_KEY_FRAME_LEN);
+                    this.state = HandshakeState::AwaitRekeyKey(Box::new(keys));
+                }
+                HandshakeState::AwaitInit => {
+                    if this.poll_fill(cx)?.is_pending() {
+                        this.state = HandshakeState::AwaitInit;
+                        return Poll::Pending;
+                    }
+                    let msg1 = Msg1::from_bytes(&this.input).map_err(other)?;
+                    let (key, msg2) = Responder::respond(&msg1).map_err(other)?;
+                    msg2.write_to(&mut this.out).map_err(other)?;
+                    let mut keys = Keys::new(&key, false)?;
+                    keys.send_rekey_key(&mut this.out)?;
+                    this.expect(REKEY_KEY_FRAME_LEN);
+                    this.state = HandshakeState::AwaitRekeyKey(Box::new(keys));
+                }
+                HandshakeState::AwaitRekeyKey(mut keys) => {
+                    if this.poll_fill(cx)?.is_pending() {
+                        this.state = HandshakeState::AwaitRekeyKey(keys);
+                        return Poll::Pending;
+                    }
+                    let peer = keys.recv_rekey_key(&mut this.input)?;
+                    this.state = HandshakeState::Finish(keys, Box::new(peer));
+                }
+                HandshakeState::Finish(keys, peer) => {
+                    let io = this.io.as_mut().expect("polled after completion");
+                    if Pin::new(&mut *io).poll_flush(cx)?.is_pending() {
+                        this.state = HandshakeState::Finish(keys, peer);
+                        return Poll::Pending;
+                    }
+                    let keys = *keys;
+                    return Poll::Ready(Ok(SecureStream {
+                        io: this.io.take().unwrap(),
+                        config: this.config,
+                        send: keys.send,
+                        recv: keys.recv,
+                        rekey_sk: keys.rekey_sk,
+                        peer_rekey_pk: *peer,
+                        input: vec![],
+                        plaintext: vec![],
+                        read: 0,
+                        output: vec![],
+                        written: 0,
+                        read_closed: false,
+                        close_sent: false,
+                    }));
+                }
+                HandshakeState::Done => panic!("handshake polled after completion"),
+            }
+        }
+    }
+}
+
+/// Keys established by the handshake.
+struct Keys {
+    send: SendKey,
+    recv: RecvKey,
+    rekey_pk: PublicKey,
+    rekey_sk: PrivateKey,
+}
+
+impl Keys {
+    fn new(key: &SessionKey, client: bool) -> io::Result<Self> {
+        let salt = hmac::SigningKey::new(&digest::SHA256, CHANNEL_LABEL);
+        let mut client_key = [0u8; KEY_LEN];
+        let mut server_key = [0u8; KEY_LEN];
+        hkdf::extract_and_expand(&salt, &key.0, b"client to server", &mut client_key);
+        hkdf::extract_and_expand(&salt, &key.0, b"server to client", &mut server_key);
+        let (send, recv) = if client {
+            (client_key, server_key)
+        } else {
+            (server_key, client_key)
+        };
+        let (rekey_pk, rekey_sk) = crypto_kem_keypair().map_err(other)?;
+        Ok(Keys {
+            send: SendKey::new(&send)?,
+            recv: RecvKey::new(&recv)?,
+            rekey_pk,
+            rekey_sk,
+        })
+    }
+
+    /// Queue the frame announcing our rekeying public key.
+    fn send_rekey_key(&mut self, out: &mut Vec<u8>) -> io::Result<()> {
+        let mut pk = vec![];
+        self.rekey_pk.write_to(&mut pk).map_err(other)?;
+        self.send.seal(FrameType::RekeyKey, &pk, out)
+    }
+
+    /// Open the frame carrying the peer's rekeying public key.
+    fn recv_rekey_key(&mut self, frame: &mut [u8]) -> io::Result<PublicKey> {
+        let (header, body) = frame.split_at_mut(LEN_LEN);
+        if BigEndian::read_u32(header) as usize != body.len() {
+            return Err(invalid_data("invalid rekey key frame"));
+        }
+        match self.recv.open(header, body)? {
+            (FrameType::RekeyKey, pk) if pk.len() == PUBKEY_LEN => Ok(PublicKey::from_bytes(pk)),
+            _ => Err(invalid_data("expected a rekey key frame")),
+        }
+    }
+}
+
+/// Derive the key following `key` after mixing in a fresh shared secret.
+fn next_key(key: &[u8], ss: &SharedSecret) -> [u8; KEY_LEN] {
+    let salt = hmac::SigningKey::new(&digest::SHA256, key);
+    let mut next = [0u8; KEY_LEN];
+    hkdf::extract_and_expand(&salt, &ss.0, b"rekey", &mut next);
+    next
+}
+
+fn nonce(seq: u64) -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+    BigEndian::write_u64(&mut nonce[NONCE_LEN - 8..], seq);
+    nonce
+}
+
+struct SendKey {
+    raw: [u8; KEY_LEN],
+    key: aead::SealingKey,
+    seq: u64,
+    sent: u64,
+    epoch: u64,
+}
+
+impl SendKey {
+    fn new(raw: &[u8; KEY_LEN]) -> io::Result<Self> {
+        Ok(SendKey {
+            raw: *raw,
+            key: aead::SealingKey::new(&aead::CHACHA20_POLY1305, raw).map_err(other)?,
+            seq: 0,
+            sent: 0,
+            epoch: 0,
+        })
+    }
+
+    /// Encrypt a frame and append it to `out`.
+    fn seal(&mut self, ty: FrameType, payload: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        let mut header = [0u8; LEN_LEN];
+        BigEndian::write_u32(&mut header, (1 + payload.len() + TAG_LEN) as u32);
+
+        let mut frame = Vec::with_capacity(1 + payload.len() + TAG_LEN);
+        frame.push(ty as u8);
+        frame.extend_from_slice(payload);
+        frame.extend_from_slice(&[0u8; TAG_LEN]);
+        aead::seal_in_place(&self.key, &nonce(self.seq), &header, &mut frame, TAG_LEN)
+            .map_err(other)?;
+
+        self.seq = self.seq.checked_add(1).ok_or_else(|| invalid_data("nonce overflow"))?;
+        self.sent += frame.len() as u64;
+        out.extend_from_slice(&header);
+        out.extend_from_slice(&frame);
+        Ok(())
+    }
+
+    /// Encapsulate to `peer`, queue the rekey frame, and switch to the new
+    /// key.
+    fn rekey(&mut self, peer: &PublicKey, out: &mut Vec<u8>) -> io::Result<()> {
+        let (ct, ss) = peer.enc().map_err(other)?;
+        self.seal(FrameType::Rekey, &ct, out)?;
+        let epoch = self.epoch + 1;
+        *self = SendKey::new(&next_key(&self.raw, &ss))?;
+        self.epoch = epoch;
+        Ok(())
+    }
+}
+
+struct RecvKey {
+    raw: [u8; KEY_LEN],
+    key: aead::OpeningKey,
+    seq: u64,
+    epoch: u64,
+}
+
+impl RecvKey {
+    fn new(raw: &[u8; KEY_LEN]) -> io::Result<Self> {
+        Ok(RecvKey {
+            raw: *raw,
+            key: aead::OpeningKey::new(&aead::CHACHA20_POLY1305, raw).map_err(other)?,
+            seq: 0,
+            epoch: 0,
+        })
+    }
+
+    /// Decrypt a frame in place, returning its type and payload.
+    fn open<'a>(&mut self, header: &[u8], frame: &'a mut [u8]) -> io::Result<(FrameType, &'a [u8])> {
+        let plaintext = aead::open_in_place(&self.key, &nonce(self.seq), header, 0, frame)
+            .map_err(|_| invalid_data("frame authentication failed"))?;
+        self.seq = self.seq.checked_add(1).ok_or_else(|| invalid_data("nonce overflow"))?;
+        if plaintext.is_empty() {
+            return Err(invalid_data("empty frame"));
+        }
+        let ty = FrameType::from_u8(plaintext[0])?;
+        Ok((ty, &plaintext[1..]))
+    }
+
+    /// Decapsulate the peer's rekey ciphertext and switch to the new key.
+    fn rekey(&mut self, sk: &PrivateKey, ct: &[u8]) -> io::Result<()> {
+        if ct.len() != CIPHERTEXT_LEN {
+            return Err(invalid_data("invalid rekey frame"));
+        }
+        let ss = sk.dec(ct).map_err(other)?;
+        let epoch = self.epoch + 1;
+        *self = RecvKey::new(&next_key(&self.raw, &ss))?;
+        self.epoch = epoch;
+        Ok(())
+    }
+}
+
+/// Encrypted stream returned by a completed `Handshake`.
+pub struct SecureStream<S> {
+    io: S,
+    config: Config,
+    send: SendKey,
+    recv: RecvKey,
+    rekey_sk: PrivateKey,
+    peer_rekey_pk: PublicKey,
+    /// Raw bytes read from `io`, not yet decrypted.
+    input: Vec<u8>,
+    /// Decrypted data not yet returned to the reader.
+    plaintext: Vec<u8>,
+    read: usize,
+    /// Encrypted frames not yet written to `io`.
+    output: Vec<u8>,
+    written: usize,
+    read_closed: bool,
+    close_sent: bool,
+}
+
+impl<S> SecureStream<S> {
+    /// Number of times the sending direction has been rekeyed.
+    pub fn send_epoch(&self) -> u64 {
+        self.send.epoch
+    }
+
+    /// Number of times the receiving direction has been rekeyed.
+    pub fn recv_epoch(&self) -> u64 {
+        self.recv.epoch
+    }
+
+    /// Access the underlying stream.
+    pub fn get_ref(&self) -> &S {
+        &self.io
+    }
+
+    /// Decrypt and process the next complete frame in `self.input`, if any.
+    fn process_frame(&mut self) -> io::Result<bool> {
+        if self.input.len() < LEN_LEN {
+            return Ok(false);
+        }
+        let len = BigEndian::read_u32(&self.input[..LEN_LEN]) as usize;
+        if len > MAX_FRAME_LEN {
+            return Err(invalid_data("frame too long"));
+        }
+        if self.input.len() < LEN_LEN + len {
+            return Ok(false);
+        }
+
+        let mut frame: Vec<u8> = self.input.drain(..LEN_LEN + len).collect();
+        let (header, body) = frame.split_at_mut(LEN_LEN);
+        let (ty, payload) = self.recv.open(header, body)?;
+        match ty {
+            FrameType::Data => {
+                self.plaintext.drain(..self.read);
+                self.read = 0;
+                self.plaintext.extend_from_slice(payload);
+            }
+            FrameType::RekeyKey => return Err(invalid_data("unexpected rekey key frame")),
+            FrameType::Rekey => self.recv.rekey(&self.rekey_sk, payload)?,
+            FrameType::Close => self.read_closed = true,
+        }
+        Ok(true)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> SecureStream<S> {
+    /// Write out everything queued in `self.output`.
+    fn poll_drain(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        while self.written < self.output.len() {
+            let n = ready!(Pin::new(&mut self.io).poll_write(cx, &self.output[self.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        self.output.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for SecureStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut ReadBuf) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if this.read < this.plaintext.len() {
+                let n = ::std::cmp::min(buf.remaining(), this.plaintext.len() - this.read);
+                buf.put_slice(&this.plaintext[this.read..][..n]);
+                this.read += n;
+                return Poll::Ready(Ok(()));
+            }
+            if this.read_closed {
+                return Poll::Ready(Ok(()));
+            }
+            if this.process_frame()? {
+                continue;
+            }
+
+            let mut chunk = [0u8; 4096];
+            let mut chunk_buf = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.io).poll_read(cx, &mut chunk_buf))?;
+            if chunk_buf.filled().is_empty() {
+                // The peer went away without sending a close frame.
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            this.input.extend_from_slice(chunk_buf.filled());
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for SecureStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.close_sent {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        ready!(this.poll_drain(cx))?;
+
+        if this.send.sent >= this.config.rekey_after {
+            this.send.rekey(&this.peer_rekey_pk, &mut this.output)?;
+        }
+
+        let n = ::std::cmp::min(buf.len(), MAX_PAYLOAD_LEN);
+        this.send.seal(FrameType::Data, &buf[..n], &mut this.output)?;
+        // The data is now buffered, so a pending write does not matter here.
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if !this.close_sent {
+            ready!(this.poll_drain(cx))?;
+            this.send.seal(FrameType::Close, &[], &mut this.output)?;
+            this.close_sent = true;
+        }
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.io).poll_shutdown(cx)
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn other<E: ::std::fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    use tokio::runtime::{Builder, Runtime};
+
+    use super::*;
+
+    fn runtime() -> Runtime {
+        Builder::new_current_thread().build().unwrap()
+    }
+
+    #[test]
+    fn channel_roundtrip() {
+        let rt = runtime();
+        let (a, b) = duplex(1 << 16);
+        let config = Config { rekey_after: 4096 };
+
+        let server = rt.spawn(accept(b, config));
+        let mut client = rt.block_on(connect(a, config)).unwrap();
+        let mut server = rt.block_on(server).unwrap().unwrap();
+
+        let msg = [0x5a; 1500];
+        let mut buf = [0u8; 1500];
+        for _ in 0..10 {
+            rt.block_on(client.write_all(&msg)).unwrap();
+            rt.block_on(client.flush()).unwrap();
+            rt.block_on(server.read_exact(&mut buf)).unwrap();
+            assert_eq!(&buf[..], &msg[..]);
+
+            rt.block_on(server.write_all(&msg[..10])).unwrap();
+            rt.block_on(server.flush()).unwrap();
+            rt.block_on(client.read_exact(&mut buf[..10])).unwrap();
+        }
+        // The client sent enough data to rekey, the server did not.
+        assert!(client.send_epoch() > 0);
+        assert_eq!(client.send_epoch(), server.recv_epoch());
+        assert_eq!(server.send_epoch(), 0);
+
+        rt.block_on(client.shutdown()).unwrap();
+        let mut rest = vec![];
+        rt.block_on(server.read_to_end(&mut rest)).unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn large_writes_rekey_without_reading() {
+        let rt = runtime();
+        let (a, b) = duplex(1 << 20);
+        let config = Config { rekey_after: 1 << 15 };
+
+        let server = rt.spawn(accept(b, config));
+        let mut client = rt.block_on(connect(a, config)).unwrap();
+        let mut server = rt.block_on(server).unwrap().unwrap();
+
+        // A single write carries a whole frame of payload.
+        let msg = (0..100_000).map(|i| i as u8).collect::<Vec<_>>();
+        assert_eq!(rt.block_on(client.write(&msg)).unwrap(), MAX_PAYLOAD_LEN);
+        rt.block_on(client.write_all(&msg[MAX_PAYLOAD_LEN..])).unwrap();
+        rt.block_on(client.flush()).unwrap();
+
+        let mut buf = vec![0u8; msg.len()];
+        rt.block_on(server.read_exact(&mut buf)).unwrap();
+        assert_eq!(buf, msg);
+
+        // The client never read from the server, but still rekeyed.
+        assert!(client.send_epoch() > 0);
+        assert_eq!(client.send_epoch(), server.recv_epoch());
+    }
+
+    #[test]
+    fn detects_truncation() {
+        let rt = runtime();
+        let (a, b) = duplex(1 << 16);
+        let server = rt.spawn(accept(b, Config::default()));
+        let client = rt.block_on(connect(a, Config::default())).unwrap();
+        let mut server = rt.block_on(server).unwrap().unwrap();
+
+        // Dropping the connection without a close frame is an error.
+        drop(client);
+        let mut rest = vec![];
+        let err = rt.block_on(server.read_to_end(&mut rest)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn detects_tampering() {
+        let key = [7u8; KEY_LEN];
+        let mut send = SendKey::new(&key).unwrap();
+        let mut recv = RecvKey::new(&key).unwrap();
+
+        let mut out = vec![];
+        send.seal(FrameType::Data, b"hello", &mut out).unwrap();
+        send.seal(FrameType::Data, b"world", &mut out).unwrap();
+        let (first, second) = out.split_at_mut(LEN_LEN + 1 + 5 + TAG_LEN);
+
+        let (header, body) = first.split_at_mut(LEN_LEN);
+        let (ty, payload) = recv.open(header, body).unwrap();
+        assert_eq!(ty, FrameType::Data);
+        assert_eq!(payload, b"hello");
+
+        let (header, body) = second.split_at_mut(LEN_LEN);
+        body[3] ^= 1;
+        assert!(recv.open(header, body).is_err());
+    }
+}
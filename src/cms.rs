@@ -0,0 +1,509 @@
+//! CMS `EnvelopedData` with `KEMRecipientInfo` recipients (RFC 9629).
+//!
+//! Each recipient gets a `KEMRecipientInfo` inside an `OtherRecipientInfo`
+//! of type `id-ori-kem`. The sender runs `kem::enc` to the recipient's key and
+//! derives a key-encryption key from the shared secret with the CMS KDF,
+//! HKDF-SHA256, whose info is the DER encoding of:
+//!
+//! ```text
+//! CMSORIforKEMOtherInfo ::= SEQUENCE {
+//!   wrap       KeyEncryptionAlgorithmIdentifier,   -- id-aes256-wrap
+//!   kekLength  INTEGER (1..65535),                 -- 32
+//!   ukm        [0] EXPLICIT UserKeyingMaterial OPTIONAL }
+//! ```
+//!
+//! The KEK wraps the content-encryption key with AES key wrap, and the
+//! content is encrypted with AES-256-CBC. Recipients are identified by
+//! `subjectKeyIdentifier`, set to the `PublicKey` digest, so that
+//! `decrypt` can find the right one for a `PrivateKey`. Only KEM
+//! recipients are supported.
+//!
+//! HILA5 has no assigned algorithm identifier yet; `KEM_OID` is a UUID-based
+//! OID under `2.25` until there is one.
+//!
+//! ```rust
+//! use hila5::cms::EnvelopedData;
+//!
+//! let (pk_alice, sk_alice) = hila5::crypto_kem_keypair().unwrap();
+//! let (pk_bob, _) = hila5::crypto_kem_keypair().unwrap();
+//!
+//! let enveloped = EnvelopedData::encrypt(b"quarterly report", &[&pk_alice, &pk_bob], None).unwrap();
+//! let der = enveloped.to_der();
+//!
+//! let parsed = EnvelopedData::from_der(&der).unwrap();
+//! assert_eq!(parsed.decrypt(&sk_alice).unwrap(), b"quarterly report");
+//! ```
+
+use aes::Aes256;
+use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+use aes::cipher::generic_array::GenericArray;
+use aes_kw::KekAes256;
+use ring::{digest, hkdf, hmac};
+use ring::rand::SecureRandom;
+
+use super::*;
+use errors::*;
+
+/// Placeholder algorithm identifier for HILA5,
+/// `2.25.147692494484470906861674552801630559046`.
+pub const KEM_OID: &[u8] = &[0x69, 0x81, 0xde, 0x9c, 0xc5, 0x8b, 0xc9, 0xb3, 0xea, 0xb9,
+                             0xb3, 0x9a, 0xbe, 0x8b, 0xab, 0xe8, 0xf8, 0x8c, 0xb6, 0x46];
+/// `id-ori-kem`, 1.2.840.113549.1.9.16.13.3.
+const ORI_KEM_OID: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x10, 0x0d, 0x03];
+/// `id-alg-hkdf-with-sha256`, 1.2.840.113549.1.9.16.3.29.
+const HKDF_SHA256_OID: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x10, 0x03, 0x1d];
+/// `id-aes256-wrap`, 2.16.840.1.101.3.4.1.45.
+const AES256_WRAP_OID: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x01, 0x2d];
+/// `id-aes256-CBC`, 2.16.840.1.101.3.4.1.42.
+const AES256_CBC_OID: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x01, 0x2a];
+/// `id-data`, 1.2.840.113549.1.7.1.
+const DATA_OID: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x01];
+/// `id-envelopedData`, 1.2.840.113549.1.7.3.
+const ENVELOPED_DATA_OID: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x03];
+
+/// `EnvelopedData` is version 3 when it has `OtherRecipientInfo` recipients.
+const ENVELOPED_DATA_VERSION: u32 = 3;
+const KEM_RECIPIENT_VERSION: u32 = 0;
+const KEK_LEN: usize = 32;
+const CEK_LEN: usize = 32;
+const BLOCK_LEN: usize = 16;
+
+const INTEGER: u8 = 0x02;
+const OCTET_STRING: u8 = 0x04;
+const OID: u8 = 0x06;
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const CONTEXT_0: u8 = 0x80;
+const CONTEXT_0_CONSTRUCTED: u8 = 0xa0;
+const CONTEXT_4_CONSTRUCTED: u8 = 0xa4;
+
+/// A `KEMRecipientInfo` using HILA5, HKDF-SHA256 and AES-256 key wrap.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KemRecipientInfo {
+    /// The `subjectKeyIdentifier` of the recipient.
+    pub subject_key_id: Vec<u8>,
+    /// The HILA5 ciphertext, `kemct`.
+    pub kemct: Vec<u8>,
+    /// Optional user keying material, mixed into the KDF.
+    pub ukm: Option<Vec<u8>>,
+    /// The wrapped content-encryption key.
+    pub encrypted_key: Vec<u8>,
+}
+
+impl KemRecipientInfo {
+    fn new(pk: &PublicKey, cek: &[u8], ukm: Option<&[u8]>) -> Result<Self> {
+        let (kemct, ss) = kem::enc(pk)?;
+        let ukm = ukm.map(|ukm| ukm.to_vec());
+        let encrypted_key = kek(&ss, &ukm).wrap_vec(cek)
+            .map_err(|_| Error::from("AES key wrap failed"))?;
+        Ok(KemRecipientInfo { subject_key_id: pk.digest()?, kemct, ukm, encrypted_key })
+    }
+
+    /// The `RecipientInfo`, an `OtherRecipientInfo` of type `id-ori-kem`.
+    fn to_der(&self) -> Vec<u8> {
+        let mut kemri = integer(KEM_RECIPIENT_VERSION);
+        kemri.extend(tlv(CONTEXT_0, &self.subject_key_id));
+        kemri.extend(algorithm(KEM_OID));
+        kemri.extend(tlv(OCTET_STRING, &self.kemct));
+        kemri.extend(algorithm(HKDF_SHA256_OID));
+        kemri.extend(integer(KEK_LEN as u32));
+        if let Some(ref ukm) = self.ukm {
+            kemri.extend(tlv(CONTEXT_0_CONSTRUCTED, &tlv(OCTET_STRING, ukm)));
+        }
+        kemri.extend(algorithm(AES256_WRAP_OID));
+        kemri.extend(tlv(OCTET_STRING, &self.encrypted_key));
+
+        let mut ori = tlv(OID, ORI_KEM_OID);
+        ori.extend(tlv(SEQUENCE, &kemri));
+        tlv(CONTEXT_4_CONSTRUCTED, &ori)
+    }
+
+    fn from_der(der: &mut Der) -> Result<Self> {
+        let mut ori = Der(der.read(CONTEXT_4_CONSTRUCTED)
+                          .chain_err(|| "only KEM recipients are supported")?);
+        if ori.read(OID)? != ORI_KEM_OID {
+            return Err("only KEM recipients are supported".into());
+        }
+        let mut kemri = Der(ori.read(SEQUENCE)?);
+        ori.finish()?;
+
+        if kemri.read_integer()? != KEM_RECIPIENT_VERSION {
+            return Err("unsupported KEMRecipientInfo version".into());
+        }
+        let subject_key_id = kemri.read(CONTEXT_0)
+            .chain_err(|| "recipients must be identified by subject key identifier")?.to_vec();
+        kemri.read_algorithm(KEM_OID, "KEM")?;
+        let kemct = kemri.read(OCTET_STRING)?.to_vec();
+        if kemct.len() != CIPHERTEXT_LEN {
+            return Err("invalid HILA5 ciphertext length".into());
+        }
+        kemri.read_algorithm(HKDF_SHA256_OID, "KDF")?;
+        if kemri.read_integer()? as usize != KEK_LEN {
+            return Err("unsupported KEK length".into());
+        }
+        let ukm = match kemri.peek() {
+            Some(CONTEXT_0_CONSTRUCTED) => {
+                let mut ukm = Der(kemri.read(CONTEXT_0_CONSTRUCTED)?);
+                let value = ukm.read(OCTET_STRING)?.to_vec();
+                ukm.finish()?;
+                Some(value)
+            }
+            _ => None,
+        };
+        kemri.read_algorithm(AES256_WRAP_OID, "key wrap")?;
+        let encrypted_key = kemri.read(OCTET_STRING)?.to_vec();
+        kemri.finish()?;
+        Ok(KemRecipientInfo { subject_key_id, kemct, ukm, encrypted_key })
+    }
+}
+
+/// `EnvelopedData` for one or more HILA5 recipients, with AES-256-CBC
+/// encrypted content of type `id-data`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EnvelopedData {
+    pub recipients: Vec<KemRecipientInfo>,
+    pub iv: [u8; BLOCK_LEN],
+    pub encrypted_content: Vec<u8>,
+}
+
+impl EnvelopedData {
+    /// Encrypt `content` to every key in `recipients`, passing `ukm` to the
+    /// KDF of each if given.
+    pub fn encrypt(content: &[u8], recipients: &[&PublicKey], ukm: Option<&[u8]>) -> Result<Self> {
+        if recipients.is_empty() {
+            return Err("EnvelopedData needs at least one recipient".into());
+        }
+        let rng = get_rng();
+        let mut cek = [0u8; CEK_LEN];
+        let mut iv = [0u8; BLOCK_LEN];
+        rng.fill(&mut cek)?;
+        rng.fill(&mut iv)?;
+
+        let recipients = recipients.iter()
+            .map(|pk| KemRecipientInfo::new(pk, &cek, ukm))
+            .collect::<Result<Vec<_>>>()?;
+        let encrypted_content = cbc_encrypt(&cek, &iv, content);
+        Ok(EnvelopedData { recipients, iv, encrypted_content })
+    }
+
+    /// Decrypt the content with the recipient matching `sk`.
+    pub fn decrypt(&self, sk: &PrivateKey) -> Result<Vec<u8>> {
+        let recipient = self.recipients.iter()
+            .find(|r| r.subject_key_id == sk.pk_digest)
+            .ok_or_else(|| Error::from("no recipient for this key"))?;
+        let ss = sk.dec(&recipient.kemct)?;
+        let cek = kek(&ss, &recipient.ukm).unwrap_vec(&recipient.encrypted_key)
+            .map_err(|_| Error::from("content-encryption key failed to unwrap"))?;
+        if cek.len() != CEK_LEN {
+            return Err("invalid content-encryption key length".into());
+        }
+        cbc_decrypt(&cek, &self.iv, &self.encrypted_content)
+    }
+
+    /// DER encoding of the `ContentInfo` holding this `EnvelopedData`.
+    /// `RecipientInfos` is a SET OF, so its elements are sorted by encoding.
+    pub fn to_der(&self) -> Vec<u8> {
+        let mut infos = self.recipients.iter().map(KemRecipientInfo::to_der).collect::<Vec<_>>();
+        infos.sort();
+
+        let mut content_algorithm = tlv(OID, AES256_CBC_OID);
+        content_algorithm.extend(tlv(OCTET_STRING, &self.iv));
+        let mut content_info = tlv(OID, DATA_OID);
+        content_info.extend(tlv(SEQUENCE, &content_algorithm));
+        content_info.extend(tlv(CONTEXT_0, &self.encrypted_content));
+
+        let mut enveloped = integer(ENVELOPED_DATA_VERSION);
+        enveloped.extend(tlv(SET, &infos.concat()));
+        enveloped.extend(tlv(SEQUENCE, &content_info));
+
+        let mut outer = tlv(OID, ENVELOPED_DATA_OID);
+        outer.extend(tlv(CONTEXT_0_CONSTRUCTED, &tlv(SEQUENCE, &enveloped)));
+        tlv(SEQUENCE, &outer)
+    }
+
+    /// Parse a DER `ContentInfo` holding `EnvelopedData`.
+    pub fn from_der(input: &[u8]) -> Result<Self> {
+        let mut top = Der(input);
+        let mut outer = Der(top.read(SEQUENCE)?);
+        top.finish()?;
+        if outer.read(OID)? != ENVELOPED_DATA_OID {
+            return Err("content is not EnvelopedData".into());
+        }
+        let mut explicit = Der(outer.read(CONTEXT_0_CONSTRUCTED)?);
+        outer.finish()?;
+        let mut enveloped = Der(explicit.read(SEQUENCE)?);
+        explicit.finish()?;
+
+        if enveloped.read_integer()? != ENVELOPED_DATA_VERSION {
+            return Err("unsupported EnvelopedData version".into());
+        }
+        if enveloped.peek() == Some(CONTEXT_0_CONSTRUCTED) {
+            return Err("originatorInfo is not supported".into());
+        }
+        let mut infos = Der(enveloped.read(SET)?);
+        let mut recipients = vec![];
+        while !infos.0.is_empty() {
+            recipients.push(KemRecipientInfo::from_der(&mut infos)?);
+        }
+        if recipients.is_empty() {
+            return Err("EnvelopedData has no recipients".into());
+        }
+
+        let mut content_info = Der(enveloped.read(SEQUENCE)?);
+        enveloped.finish().chain_err(|| "unprotectedAttrs are not supported")?;
+        if content_info.read(OID)? != DATA_OID {
+            return Err("encrypted content type is not id-data".into());
+        }
+        let mut content_algorithm = Der(content_info.read(SEQUENCE)?);
+        if content_algorithm.read(OID)? != AES256_CBC_OID {
+            return Err("unsupported content-encryption algorithm".into());
+        }
+        let iv = content_algorithm.read(OCTET_STRING)?;
+        content_algorithm.finish()?;
+        if iv.len() != BLOCK_LEN {
+            return Err("invalid AES-CBC IV length".into());
+        }
+        let encrypted_content = content_info.read(CONTEXT_0)
+            .chain_err(|| "detached content is not supported")?.to_vec();
+        content_info.finish()?;
+
+        let mut result = EnvelopedData { recipients, iv: [0; BLOCK_LEN], encrypted_content };
+        result.iv.copy_from_slice(iv);
+        Ok(result)
+    }
+}
+
+/// The KEK, from the CMS KDF over the shared secret and
+/// `CMSORIforKEMOtherInfo`.
+fn kek(ss: &SharedSecret, ukm: &Option<Vec<u8>>) -> KekAes256 {
+    let mut other_info = algorithm(AES256_WRAP_OID);
+    other_info.extend(integer(KEK_LEN as u32));
+    if let Some(ref ukm) = *ukm {
+        other_info.extend(tlv(CONTEXT_0_CONSTRUCTED, &tlv(OCTET_STRING, ukm)));
+    }
+    let info = tlv(SEQUENCE, &other_info);
+
+    let salt = hmac::SigningKey::new(&digest::SHA256, &[]);
+    let mut key = [0u8; KEK_LEN];
+    hkdf::extract_and_expand(&salt, &ss.0, &info, &mut key);
+    KekAes256::from(key)
+}
+
+/// AES-256-CBC with PKCS #7 padding.
+fn cbc_encrypt(key: &[u8], iv: &[u8; BLOCK_LEN], plaintext: &[u8]) -> Vec<u8> {
+    let cipher = Aes256::new(GenericArray::from_slice(key));
+    let pad = BLOCK_LEN - plaintext.len() % BLOCK_LEN;
+    let mut data = plaintext.to_vec();
+    data.resize(plaintext.len() + pad, pad as u8);
+
+    let mut chain = *iv;
+    for block in data.chunks_mut(BLOCK_LEN) {
+        for (b, c) in block.iter_mut().zip(chain.iter()) {
+            *b ^= c;
+        }
+        cipher.encrypt_block(GenericArray::from_mut_slice(block));
+        chain.copy_from_slice(block);
+    }
+    data
+}
+
+fn cbc_decrypt(key: &[u8], iv: &[u8; BLOCK_LEN], ciphertext: &[u8]) -> Result<Vec<u8>> {
+    if ciphertext.is_empty() || ciphertext.len() % BLOCK_LEN != 0 {
+        return Err("invalid AES-CBC ciphertext length".into());
+    }
+    let cipher = Aes256::new(GenericArray::from_slice(key));
+    let mut data = ciphertext.to_vec();
+    let mut chain = *iv;
+    for block in data.chunks_mut(BLOCK_LEN) {
+        let mut next = [0u8; BLOCK_LEN];
+        next.copy_from_slice(block);
+        cipher.decrypt_block(GenericArray::from_mut_slice(block));
+        for (b, c) in block.iter_mut().zip(chain.iter()) {
+            *b ^= c;
+        }
+        chain = next;
+    }
+
+    let pad = data[data.len() - 1] as usize;
+    if pad == 0 || pad > BLOCK_LEN || data[data.len() - pad..].iter().any(|&b| b as usize != pad) {
+        return Err("invalid padding in decrypted content".into());
+    }
+    data.truncate(data.len() - pad);
+    Ok(data)
+}
+
+fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes = (len as u64).to_be_bytes();
+        let skip = bytes.iter().take_while(|&&b| b == 0).count();
+        out.push(0x80 | (bytes.len() - skip) as u8);
+        out.extend_from_slice(&bytes[skip..]);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+fn integer(value: u32) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let skip = bytes.iter().take_while(|&&b| b == 0).count().min(3);
+    // a leading zero keeps values with the high bit set positive
+    let mut content = if bytes[skip] & 0x80 != 0 { vec![0] } else { vec![] };
+    content.extend_from_slice(&bytes[skip..]);
+    tlv(INTEGER, &content)
+}
+
+/// An `AlgorithmIdentifier` with absent parameters.
+fn algorithm(oid: &[u8]) -> Vec<u8> {
+    tlv(SEQUENCE, &tlv(OID, oid))
+}
+
+/// A reader over DER-encoded elements, accepting only definite, minimal
+/// lengths.
+struct Der<'a>(&'a [u8]);
+
+impl<'a> Der<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.0.first().cloned()
+    }
+
+    /// Read an element with the given tag, returning its contents.
+    fn read(&mut self, tag: u8) -> Result<&'a [u8]> {
+        let input = self.0;
+        match input.first() {
+            Some(&found) if found == tag => {}
+            Some(&found) => {
+                return Err(format!("expected DER tag {:#04x}, found {:#04x}", tag, found).into())
+            }
+            None => return Err("truncated DER".into()),
+        }
+        let first = *input.get(1).ok_or_else(|| Error::from("truncated DER"))? as usize;
+        let (len, header) = if first < 0x80 {
+            (first, 2)
+        } else {
+            let count = first & 0x7f;
+            if count == 0 || count > 4 || input.len() < 2 + count {
+                return Err("invalid DER length".into());
+            }
+            let len = input[2..2 + count].iter().fold(0usize, |len, &b| (len << 8) | b as usize);
+            if input[2] == 0 || len < 0x80 {
+                return Err("non-minimal DER length".into());
+            }
+            (len, 2 + count)
+        };
+        if input.len() - header < len {
+            return Err("truncated DER".into());
+        }
+        self.0 = &input[header + len..];
+        Ok(&input[header..header + len])
+    }
+
+    /// Read a small non-negative INTEGER.
+    fn read_integer(&mut self) -> Result<u32> {
+        let bytes = self.read(INTEGER)?;
+        let negative = bytes.is_empty() || bytes[0] & 0x80 != 0;
+        let padded = bytes.len() > 1 && bytes[0] == 0 && bytes[1] & 0x80 == 0;
+        if negative || padded || bytes.len() > 5 || (bytes.len() == 5 && bytes[0] != 0) {
+            return Err("invalid or unsupported DER integer".into());
+        }
+        Ok(bytes.iter().fold(0u32, |value, &b| (value << 8) | b as u32))
+    }
+
+    /// Read an `AlgorithmIdentifier` and check that it is `oid` without
+    /// parameters.
+    fn read_algorithm(&mut self, oid: &[u8], what: &str) -> Result<()> {
+        let mut alg = Der(self.read(SEQUENCE)?);
+        if alg.read(OID)? != oid || alg.finish().is_err() {
+            return Err(format!("unsupported {} algorithm", what).into());
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        if !self.0.is_empty() {
+            return Err("trailing data in DER element".into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn multiple_recipients() {
+        let keys = (0..3).map(|_| crypto_kem_keypair().unwrap()).collect::<Vec<_>>();
+        let pks = keys.iter().map(|(pk, _)| pk).collect::<Vec<_>>();
+        let content = vec![0x42u8; 1000];
+        let enveloped = EnvelopedData::encrypt(&content, &pks, Some(b"pipeline-7")).unwrap();
+        assert_eq!(enveloped.encrypted_content.len(), 1008);
+
+        let der = enveloped.to_der();
+        let parsed = EnvelopedData::from_der(&der).unwrap();
+        assert_eq!(parsed.recipients.len(), 3);
+        assert_eq!(parsed.to_der(), der);
+        for (_, sk) in keys {
+            assert_eq!(parsed.decrypt(&sk).unwrap(), content);
+        }
+        let (_, stranger) = crypto_kem_keypair().unwrap();
+        assert!(parsed.decrypt(&stranger).is_err());
+    }
+
+    #[test]
+    fn ukm_is_bound() {
+        let (pk, sk) = crypto_kem_keypair().unwrap();
+        let mut enveloped = EnvelopedData::encrypt(b"", &[&pk], None).unwrap();
+        assert_eq!(enveloped.decrypt(&sk).unwrap(), b"");
+        assert_eq!(EnvelopedData::from_der(&enveloped.to_der()).unwrap(), enveloped);
+
+        enveloped.recipients[0].ukm = Some(b"added later".to_vec());
+        assert!(enveloped.decrypt(&sk).is_err());
+        assert!(EnvelopedData::encrypt(b"", &[], None).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_der() {
+        let (pk, _) = crypto_kem_keypair().unwrap();
+        let der = EnvelopedData::encrypt(b"data", &[&pk], None).unwrap().to_der();
+        assert!(EnvelopedData::from_der(&der[..der.len() - 1]).is_err());
+        assert!(EnvelopedData::from_der(&[&der[..], &[0][..]].concat()).is_err());
+
+        // swap the KEM algorithm OID for another one of the same length
+        let at = der.windows(KEM_OID.len()).position(|w| w == KEM_OID).unwrap();
+        let mut other = der.clone();
+        other[at + KEM_OID.len() - 1] ^= 1;
+        assert!(EnvelopedData::from_der(&other).is_err());
+
+        // a long-form length that fits in short form
+        assert!(Der(&[SEQUENCE, 0x81, 0x05, 0, 0, 0, 0, 0]).read(SEQUENCE).is_err());
+    }
+
+    #[test]
+    fn der_primitives() {
+        assert_eq!(integer(0), [INTEGER, 1, 0]);
+        assert_eq!(integer(32), [INTEGER, 1, 32]);
+        assert_eq!(integer(128), [INTEGER, 2, 0, 128]);
+        assert_eq!(integer(0x0100_0000), [INTEGER, 4, 1, 0, 0, 0]);
+        for &value in &[0u32, 3, 127, 128, 255, 65535, u32::MAX] {
+            assert_eq!(Der(&integer(value)).read_integer().unwrap(), value);
+        }
+        assert_eq!(tlv(OCTET_STRING, &[0; 200])[..3], [OCTET_STRING, 0x81, 200]);
+        assert_eq!(tlv(OCTET_STRING, &[0; 2012])[..4], [OCTET_STRING, 0x82, 0x07, 0xdc]);
+
+        // NIST SP 800-38A F.2.5, first block of CBC-AES256.Encrypt
+        let key = [0x60, 0x3d, 0xeb, 0x10, 0x15, 0xca, 0x71, 0xbe, 0x2b, 0x73, 0xae, 0xf0, 0x85, 0x7d,
+                   0x77, 0x81, 0x1f, 0x35, 0x2c, 0x07, 0x3b, 0x61, 0x08, 0xd7, 0x2d, 0x98, 0x10, 0xa3,
+                   0x09, 0x14, 0xdf, 0xf4];
+        let iv = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+        let plaintext = [0x6b, 0xc1, 0xbe, 0xe2, 0x2e, 0x40, 0x9f, 0x96, 0xe9, 0x3d, 0x7e, 0x11, 0x73,
+                         0x93, 0x17, 0x2a];
+        let ciphertext = cbc_encrypt(&key, &iv, &plaintext);
+        assert_eq!(ciphertext[..16], [0xf5, 0x8c, 0x4c, 0x04, 0xd6, 0xe5, 0xf1, 0xba, 0x77, 0x9e, 0xab,
+                                      0xfb, 0x5f, 0x7b, 0xfb, 0xd6]);
+        assert_eq!(cbc_decrypt(&key, &iv, &ciphertext).unwrap(), plaintext);
+    }
+}
@@ -0,0 +1,493 @@
+//! COSE_Key encoding and COSE_Encrypt key establishment with HILA5.
+//!
+//! HILA5 keys and algorithms have no registered COSE values yet, so values
+//! from the private use ranges stand in:
+//!
+//! ```text
+//! COSE_Key = {
+//!   1 => -65537,        ; kty: KTY_HILA5
+//!   ? 2 => bstr,        ; kid
+//!   -1 => bstr,         ; pub: encoded PublicKey
+//!   ? -2 => bstr,       ; priv: encoded PrivateKey
+//! }
+//! ```
+//!
+//! `COSE_Encrypt` messages (tag 96) use `A256GCM` for the content and one
+//! recipient layer per key with algorithm `ALG_HILA5_A256KW`. That layer
+//! carries the KEM ciphertext in the `ek` header parameter (-4) and the
+//! content key wrapped with `A256KW`, under a KEK derived from the shared
+//! secret by HKDF-SHA256 with the `COSE_KDF_Context` of RFC 9053 section 5.2
+//! as info.
+//!
+//! `CoseKey` converts to and from `jose::Jwk`, so a key can be published in
+//! either format.
+//!
+//! ```rust
+//! use hila5::cose::{self, CoseKey};
+//!
+//! let (pk, sk) = hila5::crypto_kem_keypair().unwrap();
+//! let key = CoseKey::from_keypair(&pk, &sk, Some(b"sensor-3")).unwrap();
+//! let public = CoseKey::from_cbor(&key.to_public().to_cbor()).unwrap();
+//!
+//! let message = cose::encrypt(&public, b"22.5C", b"").unwrap();
+//! assert_eq!(cose::decrypt(&key, &message, b"").unwrap(), b"22.5C");
+//! ```
+
+use aes_kw::KekAes256;
+use ring::{digest, hkdf, hmac};
+use ring::rand::SecureRandom;
+
+use super::*;
+use errors::*;
+use jose::Jwk;
+
+/// Private use key type for HILA5 keys.
+pub const KTY_HILA5: i64 = -65537;
+/// Private use algorithm: HILA5, HKDF-SHA256 and AES-256 key wrap.
+pub const ALG_HILA5_A256KW: i64 = -65538;
+/// Tag of `COSE_Encrypt` messages.
+pub const TAG_ENCRYPT: u64 = 96;
+
+const KEY_KTY: i64 = 1;
+const KEY_KID: i64 = 2;
+const KEY_PUB: i64 = -1;
+const KEY_PRIV: i64 = -2;
+const HEADER_ALG: i64 = 1;
+const HEADER_KID: i64 = 4;
+const HEADER_IV: i64 = 5;
+const HEADER_EK: i64 = -4;
+const ALG_A256GCM: i64 = 3;
+const ALG_A256KW: i64 = -5;
+const KEK_LEN: usize = 32;
+/// Nesting limit when decoding CBOR.
+const MAX_DEPTH: usize = 8;
+
+/// A HILA5 COSE_Key, public or private.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CoseKey {
+    pub kid: Option<Vec<u8>>,
+    public: Vec<u8>,
+    private: Option<Vec<u8>>,
+}
+
+impl CoseKey {
+    pub fn from_public_key(pk: &PublicKey, kid: Option<&[u8]>) -> Result<Self> {
+        Self::from_jwk_with_kid(&Jwk::from_public_key(pk, None)?, kid)
+    }
+
+    pub fn from_keypair(pk: &PublicKey, sk: &PrivateKey, kid: Option<&[u8]>) -> Result<Self> {
+        Self::from_jwk_with_kid(&Jwk::from_keypair(pk, sk, None)?, kid)
+    }
+
+    /// Convert a JWK, using the UTF-8 bytes of its `kid`.
+    pub fn from_jwk(jwk: &Jwk) -> Self {
+        Self::from_jwk_with_kid(jwk, jwk.kid.as_ref().map(|kid| kid.as_bytes()))
+            .expect("a Jwk holds a valid key")
+    }
+
+    fn from_jwk_with_kid(jwk: &Jwk, kid: Option<&[u8]>) -> Result<Self> {
+        Ok(CoseKey {
+            kid: kid.map(|kid| kid.to_vec()),
+            public: jwk.public_bytes().to_vec(),
+            private: jwk.private_bytes().map(|private| private.to_vec()),
+        })
+    }
+
+    /// Convert to a JWK; fails if the `kid` is not UTF-8.
+    pub fn to_jwk(&self) -> Result<Jwk> {
+        let kid = match self.kid {
+            Some(ref kid) => Some(String::from_utf8(kid.clone())
+                                  .map_err(|_| Error::from("kid is not UTF-8"))?),
+            None => None,
+        };
+        Jwk::from_parts(kid, self.public.clone(), self.private.clone())
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey::from_bytes(&self.public)
+    }
+
+    pub fn private_key(&self) -> Option<PrivateKey> {
+        self.private.as_ref().map(|private| PrivateKey::from_bytes(private))
+    }
+
+    /// The same key without its private part.
+    pub fn to_public(&self) -> CoseKey {
+        CoseKey { kid: self.kid.clone(), public: self.public.clone(), private: None }
+    }
+
+    pub fn to_cbor(&self) -> Vec<u8> {
+        let mut map = vec![(Cbor::Int(KEY_KTY), Cbor::Int(KTY_HILA5))];
+        if let Some(ref kid) = self.kid {
+            map.push((Cbor::Int(KEY_KID), Cbor::Bytes(kid.clone())));
+        }
+        map.push((Cbor::Int(KEY_PUB), Cbor::Bytes(self.public.clone())));
+        if let Some(ref private) = self.private {
+            map.push((Cbor::Int(KEY_PRIV), Cbor::Bytes(private.clone())));
+        }
+        Cbor::Map(map).to_bytes()
+    }
+
+    /// Parse a COSE_Key. Other labels are ignored.
+    pub fn from_cbor(input: &[u8]) -> Result<Self> {
+        let map = Cbor::from_bytes(input)?;
+        if map.get(KEY_KTY) != Some(&Cbor::Int(KTY_HILA5)) {
+            return Err("COSE_Key is not a HILA5 key".into());
+        }
+        let kid = map.get(KEY_KID).map(Cbor::as_bytes).transpose()?.map(|kid| kid.to_vec());
+        let public = map.get(KEY_PUB).ok_or_else(|| Error::from("COSE_Key has no public key"))?
+            .as_bytes()?.to_vec();
+        let private = map.get(KEY_PRIV).map(Cbor::as_bytes).transpose()?.map(|private| private.to_vec());
+        // validate through the JWK, which checks lengths and that the parts match
+        Jwk::from_parts(None, public.clone(), private.clone())?;
+        Ok(CoseKey { kid, public, private })
+    }
+}
+
+/// Encrypt `plaintext` to `recipient` as a tagged `COSE_Encrypt` message,
+/// authenticating `external_aad` as well.
+pub fn encrypt(recipient: &CoseKey, plaintext: &[u8], external_aad: &[u8]) -> Result<Vec<u8>> {
+    let (ct, ss) = kem::enc(&recipient.public_key())?;
+    let mut cek = [0u8; jose::CEK_LEN];
+    get_rng().fill(&mut cek)?;
+
+    let recipient_protected = Cbor::Map(vec![(Cbor::Int(HEADER_ALG), Cbor::Int(ALG_HILA5_A256KW))])
+        .to_bytes();
+    let encrypted_key = kek(&ss, &recipient_protected).wrap_vec(&cek)
+        .map_err(|_| Error::from("AES key wrap failed"))?;
+    let mut recipient_unprotected = vec![];
+    if let Some(ref kid) = recipient.kid {
+        recipient_unprotected.push((Cbor::Int(HEADER_KID), Cbor::Bytes(kid.clone())));
+    }
+    recipient_unprotected.push((Cbor::Int(HEADER_EK), Cbor::Bytes(ct)));
+
+    let protected = Cbor::Map(vec![(Cbor::Int(HEADER_ALG), Cbor::Int(ALG_A256GCM))]).to_bytes();
+    let (nonce, ciphertext) = jose::seal(&cek, &enc_structure(&protected, external_aad), plaintext)?;
+
+    let message = Cbor::Array(vec![
+        Cbor::Bytes(protected),
+        Cbor::Map(vec![(Cbor::Int(HEADER_IV), Cbor::Bytes(nonce.to_vec()))]),
+        Cbor::Bytes(ciphertext),
+        Cbor::Array(vec![Cbor::Array(vec![
+            Cbor::Bytes(recipient_protected),
+            Cbor::Map(recipient_unprotected),
+            Cbor::Bytes(encrypted_key),
+        ])]),
+    ]);
+    Ok(Cbor::Tag(TAG_ENCRYPT, Box::new(message)).to_bytes())
+}
+
+/// Decrypt a `COSE_Encrypt` message, tagged or not, with the private key in
+/// `recipient`, using the first recipient layer that matches it.
+pub fn decrypt(recipient: &CoseKey, message: &[u8], external_aad: &[u8]) -> Result<Vec<u8>> {
+    let sk = recipient.private_key().ok_or_else(|| Error::from("COSE_Key has no private key"))?;
+    let message = match Cbor::from_bytes(message)? {
+        Cbor::Tag(TAG_ENCRYPT, message) => *message,
+        Cbor::Tag(..) => return Err("not a COSE_Encrypt message".into()),
+        message => message,
+    };
+    let (protected, unprotected, ciphertext, recipients) = match message {
+        Cbor::Array(ref items) if items.len() == 4 => (&items[0], &items[1], &items[2], &items[3]),
+        _ => return Err("COSE_Encrypt is an array of four items".into()),
+    };
+    let protected = protected.as_bytes()?;
+    if Cbor::from_bytes(protected)?.get(HEADER_ALG) != Some(&Cbor::Int(ALG_A256GCM)) {
+        return Err("unsupported COSE content encryption algorithm".into());
+    }
+    let nonce = unprotected.get(HEADER_IV).ok_or_else(|| Error::from("COSE_Encrypt has no IV"))?
+        .as_bytes()?;
+
+    let layers = match *recipients {
+        Cbor::Array(ref layers) => layers,
+        _ => return Err("COSE_Encrypt recipients must be an array".into()),
+    };
+    for layer in layers {
+        let (layer_protected, layer_unprotected, encrypted_key) = match *layer {
+            Cbor::Array(ref items) if items.len() == 3 => {
+                (items[0].as_bytes()?, &items[1], items[2].as_bytes()?)
+            }
+            _ => return Err("COSE_recipient is an array of three items".into()),
+        };
+        if Cbor::from_bytes(layer_protected)?.get(HEADER_ALG) != Some(&Cbor::Int(ALG_HILA5_A256KW)) {
+            continue;
+        }
+        match (layer_unprotected.get(HEADER_KID), recipient.kid.as_ref()) {
+            (Some(kid), Some(expected)) if kid.as_bytes()? != &expected[..] => continue,
+            _ => {}
+        }
+        let ct = layer_unprotected.get(HEADER_EK)
+            .ok_or_else(|| Error::from("COSE_recipient has no ek"))?.as_bytes()?;
+        if ct.len() != CIPHERTEXT_LEN {
+            return Err("invalid HILA5 ciphertext length".into());
+        }
+        let ss = sk.dec(ct)?;
+        let cek = kek(&ss, layer_protected).unwrap_vec(encrypted_key)
+            .map_err(|_| Error::from("content-encryption key failed to unwrap"))?;
+        let aad = enc_structure(protected, external_aad);
+        return jose::open(&cek, nonce, &aad, ciphertext.as_bytes()?.to_vec());
+    }
+    Err("no COSE_recipient for this key".into())
+}
+
+/// `Enc_structure`, the additional data for the content layer.
+fn enc_structure(protected: &[u8], external_aad: &[u8]) -> Vec<u8> {
+    Cbor::Array(vec![
+        Cbor::Text("Encrypt".to_string()),
+        Cbor::Bytes(protected.to_vec()),
+        Cbor::Bytes(external_aad.to_vec()),
+    ]).to_bytes()
+}
+
+/// The KEK, from HKDF-SHA256 over the shared secret with the
+/// `COSE_KDF_Context` for `A256KW` as info.
+fn kek(ss: &SharedSecret, recipient_protected: &[u8]) -> KekAes256 {
+    let party = || Cbor::Array(vec![Cbor::Null, Cbor::Null, Cbor::Null]);
+    let context = Cbor::Array(vec![
+        Cbor::Int(ALG_A256KW),
+        party(),
+        party(),
+        Cbor::Array(vec![Cbor::Int(KEK_LEN as i64 * 8), Cbor::Bytes(recipient_protected.to_vec())]),
+    ]).to_bytes();
+
+    let salt = hmac::SigningKey::new(&digest::SHA256, &[]);
+    let mut key = [0u8; KEK_LEN];
+    hkdf::extract_and_expand(&salt, &ss.0, &context, &mut key);
+    KekAes256::from(key)
+}
+
+/// The subset of CBOR that COSE structures here need.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Cbor {
+    Int(i64),
+    Bytes(Vec<u8>),
+    Text(String),
+    Array(Vec<Cbor>),
+    Map(Vec<(Cbor, Cbor)>),
+    Tag(u64, Box<Cbor>),
+    Null,
+}
+
+impl Cbor {
+    fn get(&self, label: i64) -> Option<&Cbor> {
+        match *self {
+            Cbor::Map(ref entries) => entries.iter().find(|e| e.0 == Cbor::Int(label)).map(|e| &e.1),
+            _ => None,
+        }
+    }
+
+    fn as_bytes(&self) -> Result<&[u8]> {
+        match *self {
+            Cbor::Bytes(ref bytes) => Ok(bytes),
+            _ => Err("expected a CBOR byte string".into()),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![];
+        self.write(&mut out);
+        out
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        match *self {
+            Cbor::Int(n) if n >= 0 => write_head(out, 0, n as u64),
+            Cbor::Int(n) => write_head(out, 1, !n as u64),
+            Cbor::Bytes(ref bytes) => {
+                write_head(out, 2, bytes.len() as u64);
+                out.extend_from_slice(bytes);
+            }
+            Cbor::Text(ref text) => {
+                write_head(out, 3, text.len() as u64);
+                out.extend_from_slice(text.as_bytes());
+            }
+            Cbor::Array(ref items) => {
+                write_head(out, 4, items.len() as u64);
+                for item in items {
+                    item.write(out);
+                }
+            }
+            Cbor::Map(ref entries) => {
+                write_head(out, 5, entries.len() as u64);
+                for (key, value) in entries {
+                    key.write(out);
+                    value.write(out);
+                }
+            }
+            Cbor::Tag(tag, ref item) => {
+                write_head(out, 6, tag);
+                item.write(out);
+            }
+            Cbor::Null => out.push(0xf6),
+        }
+    }
+
+    /// Decode a single item, which must use definite lengths.
+    fn from_bytes(mut input: &[u8]) -> Result<Cbor> {
+        let item = Self::read(&mut input, 0)?;
+        if !input.is_empty() {
+            return Err("trailing data after CBOR item".into());
+        }
+        Ok(item)
+    }
+
+    fn read(input: &mut &[u8], depth: usize) -> Result<Cbor> {
+        if depth > MAX_DEPTH {
+            return Err("CBOR nested too deeply".into());
+        }
+        let (&initial, rest) = input.split_first().ok_or_else(|| Error::from("truncated CBOR"))?;
+        *input = rest;
+        let (major, info) = (initial >> 5, initial & 0x1f);
+        if initial == 0xf6 {
+            return Ok(Cbor::Null);
+        }
+        let arg = match info {
+            0..=23 => info as u64,
+            24..=27 => {
+                let len = 1 << (info - 24);
+                if input.len() < len {
+                    return Err("truncated CBOR".into());
+                }
+                let arg = input[..len].iter().fold(0u64, |arg, &b| (arg << 8) | b as u64);
+                *input = &input[len..];
+                arg
+            }
+            _ => return Err("indefinite lengths and reserved CBOR values are not supported".into()),
+        };
+        let take = |input: &mut &[u8]| -> Result<Vec<u8>> {
+            if (input.len() as u64) < arg {
+                return Err("truncated CBOR".into());
+            }
+            let (bytes, rest) = input.split_at(arg as usize);
+            *input = rest;
+            Ok(bytes.to_vec())
+        };
+        // every item takes at least a byte, which bounds the preallocation
+        let capacity = arg.min(input.len() as u64) as usize;
+        Ok(match major {
+            0 if arg <= i64::MAX as u64 => Cbor::Int(arg as i64),
+            1 if arg <= i64::MAX as u64 => Cbor::Int(!(arg as i64)),
+            0 | 1 => return Err("CBOR integer out of range".into()),
+            2 => Cbor::Bytes(take(input)?),
+            3 => {
+                let text = String::from_utf8(take(input)?);
+                Cbor::Text(text.map_err(|_| Error::from("invalid UTF-8 in CBOR"))?)
+            }
+            4 => {
+                let mut items = Vec::with_capacity(capacity);
+                for _ in 0..arg {
+                    items.push(Self::read(input, depth + 1)?);
+                }
+                Cbor::Array(items)
+            }
+            5 => {
+                let mut entries = Vec::with_capacity(capacity);
+                for _ in 0..arg {
+                    let key = Self::read(input, depth + 1)?;
+                    if entries.iter().any(|e: &(Cbor, Cbor)| e.0 == key) {
+                        return Err("duplicate key in CBOR map".into());
+                    }
+                    entries.push((key, Self::read(input, depth + 1)?));
+                }
+                Cbor::Map(entries)
+            }
+            6 => Cbor::Tag(arg, Box::new(Self::read(input, depth + 1)?)),
+            _ => return Err("unsupported CBOR simple value".into()),
+        })
+    }
+}
+
+/// Write a CBOR initial byte and argument in its shortest form.
+fn write_head(out: &mut Vec<u8>, major: u8, arg: u64) {
+    let major = major << 5;
+    match arg {
+        0..=23 => out.push(major | arg as u8),
+        24..=0xff => out.extend_from_slice(&[major | 24, arg as u8]),
+        0x100..=0xffff => {
+            out.push(major | 25);
+            out.extend_from_slice(&(arg as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(major | 26);
+            out.extend_from_slice(&(arg as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(major | 27);
+            out.extend_from_slice(&arg.to_be_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cose_key_roundtrip() {
+        let (pk, sk) = crypto_kem_keypair().unwrap();
+        let key = CoseKey::from_keypair(&pk, &sk, Some(&[0xff, 1])).unwrap();
+        let cbor = key.to_cbor();
+        // map of four entries, kty -65537
+        assert_eq!(&cbor[..7], &[0xa4, 0x01, 0x3a, 0x00, 0x01, 0x00, 0x00]);
+        let parsed = CoseKey::from_cbor(&cbor).unwrap();
+        assert_eq!(parsed, key);
+        assert_eq!(parsed.private_key().unwrap().pk_digest, sk.pk_digest);
+        assert!(parsed.to_jwk().is_err());
+
+        let public = CoseKey::from_cbor(&key.to_public().to_cbor()).unwrap();
+        assert!(public.private_key().is_none());
+        assert_eq!(public.public_key().digest().unwrap(), pk.digest().unwrap());
+    }
+
+    #[test]
+    fn cross_format() {
+        let (pk, sk) = crypto_kem_keypair().unwrap();
+        let jwk = Jwk::from_keypair(&pk, &sk, Some("gateway")).unwrap();
+        let key = CoseKey::from_cbor(&CoseKey::from_jwk(&Jwk::from_json(&jwk.to_json()).unwrap()).to_cbor())
+            .unwrap();
+        assert_eq!(key.kid, Some(b"gateway".to_vec()));
+        assert_eq!(Jwk::from_json(&key.to_jwk().unwrap().to_json()).unwrap(), jwk);
+
+        // a key published in one format decrypts messages sent to the other
+        let message = encrypt(&key.to_public(), b"over COSE", b"").unwrap();
+        let back = CoseKey::from_jwk(&key.to_jwk().unwrap());
+        assert_eq!(decrypt(&back, &message, b"").unwrap(), b"over COSE");
+        let token = jose::encrypt(&key.to_public().to_jwk().unwrap(), b"over JOSE").unwrap();
+        assert_eq!(jose::decrypt(&key.to_jwk().unwrap(), &token).unwrap(), b"over JOSE");
+    }
+
+    #[test]
+    fn encrypt_roundtrip() {
+        let (pk, sk) = crypto_kem_keypair().unwrap();
+        let key = CoseKey::from_keypair(&pk, &sk, None).unwrap();
+        let message = encrypt(&key.to_public(), b"reading", b"device-7").unwrap();
+        assert_eq!(&message[..2], &[0xd8, 0x60]);
+        assert_eq!(decrypt(&key, &message, b"device-7").unwrap(), b"reading");
+        assert!(decrypt(&key, &message, b"device-8").is_err());
+        assert!(decrypt(&key.to_public(), &message, b"device-7").is_err());
+
+        let (pk2, sk2) = crypto_kem_keypair().unwrap();
+        let other = CoseKey::from_keypair(&pk2, &sk2, Some(b"other")).unwrap();
+        assert!(decrypt(&other, &message, b"device-7").is_err());
+        let labelled = encrypt(&key.to_public(), b"x", b"").unwrap();
+        let relabelled = CoseKey { kid: Some(b"k".to_vec()), ..key.clone() };
+        assert_eq!(decrypt(&relabelled, &labelled, b"").unwrap(), b"x");
+    }
+
+    #[test]
+    fn cbor_codec() {
+        let item = Cbor::Array(vec![
+            Cbor::Int(0), Cbor::Int(-1), Cbor::Int(24), Cbor::Int(-65537), Cbor::Int(i64::MIN),
+            Cbor::Text("Encrypt".to_string()), Cbor::Bytes(vec![0; 300]), Cbor::Null,
+            Cbor::Map(vec![(Cbor::Int(1), Cbor::Tag(96, Box::new(Cbor::Int(3))))]),
+        ]);
+        let bytes = item.to_bytes();
+        assert_eq!(&bytes[..6], &[0x89, 0x00, 0x20, 0x18, 0x18, 0x3a]);
+        assert_eq!(Cbor::from_bytes(&bytes).unwrap(), item);
+
+        assert!(Cbor::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Cbor::from_bytes(&[0x9f, 0xff]).is_err());
+        assert!(Cbor::from_bytes(&[0xa2, 0x01, 0x01, 0x01, 0x02]).is_err());
+        assert!(Cbor::from_bytes(&[0x9b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]).is_err());
+        assert!(Cbor::from_bytes(&[0x81; 20]).is_err());
+    }
+}
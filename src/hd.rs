@@ -0,0 +1,223 @@
+//! Hierarchical deterministic derivation of keys from a master seed.
+//!
+//! Like BIP 32, every node of the tree has a 32 byte key seed and a 32 byte
+//! chain code, and children are derived with HMAC-SHA512:
+//!
+//! ```text
+//! seed(m) || chain(m)       = HMAC-SHA512("HILA5 seed", master)
+//! seed(c_i) || chain(c_i)   = HMAC-SHA512(chain(p), 0x00 || seed(p) || ser32(i))
+//! ```
+//!
+//! A node's key seed is a `SeedPrivateKey`, which drives deterministic HILA5
+//! key generation. Lattice keys have no public derivation, so every index
+//! derives from the parent's private seed; indices with the high bit set are
+//! written with a `'` as in BIP 32, but are derived the same way.
+//!
+//! ```rust
+//! use hila5::hd::{self, DerivationPath};
+//!
+//! let master = [7u8; 32];
+//! let path: DerivationPath = "m/7'/1/42".parse().unwrap();
+//! let (pk, sk) = hd::derive(&master, &path.0).unwrap();
+//! assert_eq!(sk.pk_digest, pk.digest().unwrap());
+//! assert_eq!(path.to_string(), "m/7'/1/42");
+//! ```
+
+use ring::{digest, hmac};
+
+use std::fmt;
+use std::str::FromStr;
+
+use super::*;
+use errors::*;
+
+/// Offset of the indices written with a `'`.
+pub const HARDENED: u32 = 1 << 31;
+/// Shortest master secret accepted, as in BIP 32.
+pub const MIN_MASTER_LEN: usize = 16;
+/// Longest master secret accepted, as in BIP 32.
+pub const MAX_MASTER_LEN: usize = 64;
+
+const MASTER_KEY: &[u8] = b"HILA5 seed";
+
+/// A node of the derivation tree.
+#[derive(Clone)]
+pub struct ExtendedSeed {
+    seed: [u8; SEED_PRIVKEY_LEN],
+    chain_code: [u8; 32],
+    depth: u8,
+}
+
+impl ExtendedSeed {
+    /// The root of the tree for `master`.
+    pub fn master(master: &[u8]) -> Result<Self> {
+        if master.len() < MIN_MASTER_LEN || master.len() > MAX_MASTER_LEN {
+            return Err("master secret must be 16 to 64 bytes".into());
+        }
+        let key = hmac::SigningKey::new(&digest::SHA512, MASTER_KEY);
+        Ok(Self::from_mac(hmac::sign(&key, master).as_ref(), 0))
+    }
+
+    /// The child at `index`.
+    pub fn child(&self, index: u32) -> Result<Self> {
+        if self.depth == u8::MAX {
+            return Err("derivation path is too deep".into());
+        }
+        let key = hmac::SigningKey::new(&digest::SHA512, &self.chain_code);
+        let mut ctx = hmac::SigningContext::with_key(&key);
+        ctx.update(&[0]);
+        ctx.update(&self.seed);
+        ctx.update(&index.to_be_bytes());
+        Ok(Self::from_mac(ctx.sign().as_ref(), self.depth + 1))
+    }
+
+    /// The descendant at `path`, relative to this node.
+    pub fn derive_path(&self, path: &[u32]) -> Result<Self> {
+        path.iter().try_fold(self.clone(), |node, &index| node.child(index))
+    }
+
+    pub fn depth(&self) -> u8 {
+        self.depth
+    }
+
+    /// The private key of this node, in seed form.
+    pub fn private_key(&self) -> SeedPrivateKey {
+        SeedPrivateKey::from_bytes(&self.seed).expect("node seeds have the seed key length")
+    }
+
+    fn from_mac(mac: &[u8], depth: u8) -> Self {
+        let mut seed = [0u8; SEED_PRIVKEY_LEN];
+        let mut chain_code = [0u8; 32];
+        seed.copy_from_slice(&mac[..32]);
+        chain_code.copy_from_slice(&mac[32..]);
+        ExtendedSeed { seed, chain_code, depth }
+    }
+}
+
+/// Derive the keypair at `path` below `master`.
+pub fn derive(master: &[u8], path: &[u32]) -> Result<(PublicKey, PrivateKey)> {
+    ExtendedSeed::master(master)?.derive_path(path)?.private_key().keypair()
+}
+
+/// A derivation path, written like `m/44'/0/7`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct DerivationPath(pub Vec<u32>);
+
+impl fmt::Display for DerivationPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("m")?;
+        for &index in &self.0 {
+            if index >= HARDENED {
+                write!(f, "/{}'", index - HARDENED)?;
+            } else {
+                write!(f, "/{}", index)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for DerivationPath {
+    type Err = Error;
+
+    /// Parse a path starting with `m`, accepting `'` or `h` for hardened
+    /// indices.
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.split('/');
+        if parts.next() != Some("m") {
+            return Err("derivation path must start with `m`".into());
+        }
+        let indices = parts.map(|part| {
+            let (digits, offset) = match part.strip_suffix(['\'', 'h']) {
+                Some(digits) => (digits, HARDENED),
+                None => (part, 0),
+            };
+            if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit())
+                || (digits.len() > 1 && digits.starts_with('0')) {
+                return Err(format!("invalid path component `{}`", part).into());
+            }
+            match digits.parse::<u32>() {
+                Ok(index) if index < HARDENED => Ok(index + offset),
+                _ => Err(format!("path component `{}` is out of range", part).into()),
+            }
+        }).collect::<Result<Vec<_>>>()?;
+        Ok(DerivationPath(indices))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn node(path: &str) -> ExtendedSeed {
+        let master = (0..32).collect::<Vec<u8>>();
+        let path: DerivationPath = path.parse().unwrap();
+        ExtendedSeed::master(&master).unwrap().derive_path(&path.0).unwrap()
+    }
+
+    #[test]
+    fn derivation_vectors() {
+        // seeds and chain codes computed independently with Python's hmac
+        // module; the public key digests pin the keygen run on each seed
+        let vectors = [
+            ("m",
+             "2ebc3d657b10e7f3d3844d8617f41548bd1c0366238bd0d5d13b54be2c343dcf",
+             "426c5b46c89213c91cb4925a7da7b911dbe8465206ce963cbd6d1d5fe13c02cc",
+             "f7615ecc4508f405a4497c576060b48f777674e2d4d9e22031313db732c03e4f"),
+            ("m/0",
+             "78b2c028a1bd6ba4027b1ac481da213703918b740b9c9f6cc6eb2a3f62e923a2",
+             "6ba775bbcf2fc9c4979b3ead7d19bd6cbccfa427362d2e608f7620be26ce3b18",
+             "a8baadb5880f7554a2977a1d417654a1944a6def39e5033ff3990a446fe98a3f"),
+            ("m/44'/1/7",
+             "536130e85361d7d8be6e9531774750c944f6f34dd3924fdbed509f9aac880667",
+             "30cfb02e4250a15bc8e9dbb7068f6d6b7bb1fea4bb65c169769012fb32ab980e",
+             "d1a515edef9b0347376ed304be6a254665c0c8b09f22b50d79479e6efbcc20e0"),
+            ("m/1/2/3/4/5",
+             "4c731979ba401e8d5ac81b315fee5964d0066ac9e7299b503cc0e82d3df45a6d",
+             "5026f064a47c9ed48fdcd2df57045c083b4cc44878dd484bda1f0ba691ee46f2",
+             "9035165b8fb5c53b34829f67164a0597c678542119f13518b98ee83d27db9431"),
+        ];
+        for &(path, seed, chain_code, pk_digest) in &vectors {
+            let node = node(path);
+            assert_eq!(hex(&node.seed), seed, "{}", path);
+            assert_eq!(hex(&node.chain_code), chain_code, "{}", path);
+            assert_eq!(node.depth() as usize, path.matches('/').count());
+            let (pk, _) = node.private_key().keypair().unwrap();
+            assert_eq!(hex(&pk.digest().unwrap()), pk_digest, "{}", path);
+        }
+    }
+
+    #[test]
+    fn keys_are_deterministic() {
+        let master = [0x42u8; 16];
+        let (pk, sk) = derive(&master, &[HARDENED, 5]).unwrap();
+        let (pk2, _) = derive(&master, &[HARDENED, 5]).unwrap();
+        assert_eq!(pk.digest().unwrap(), pk2.digest().unwrap());
+        assert_eq!(sk.pk_digest, pk.digest().unwrap());
+
+        let (sibling, _) = derive(&master, &[HARDENED, 6]).unwrap();
+        let (unhardened, _) = derive(&master, &[0, 5]).unwrap();
+        assert_ne!(sibling.digest().unwrap(), pk.digest().unwrap());
+        assert_ne!(unhardened.digest().unwrap(), pk.digest().unwrap());
+
+        let (ct, ss) = pk.enc().unwrap();
+        assert_eq!(sk.dec(&ct).unwrap().0, ss.0);
+        assert!(derive(&[0u8; 15], &[]).is_err());
+        assert!(derive(&[0u8; 65], &[]).is_err());
+    }
+
+    #[test]
+    fn path_strings() {
+        let path: DerivationPath = "m/44'/0h/2147483647/1".parse().unwrap();
+        assert_eq!(path.0, vec![HARDENED + 44, HARDENED, HARDENED - 1, 1]);
+        assert_eq!(path.to_string(), "m/44'/0'/2147483647/1");
+        assert_eq!("m".parse::<DerivationPath>().unwrap(), DerivationPath::default());
+        for bad in &["", "n/1", "m/", "m/1//2", "m/2147483648", "m/-1", "m/01", "m/1''", "m/x", "/1"] {
+            assert!(bad.parse::<DerivationPath>().is_err(), "{}", bad);
+        }
+    }
+}
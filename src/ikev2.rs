@@ -0,0 +1,312 @@
+//! HILA5 as an additional key exchange in IKEv2 (RFC 9370).
+//!
+//! RFC 9370 lets peers negotiate up to seven additional key exchanges
+//! (transform types `ADDKE1` to `ADDKE7`), each run in its own
+//! `IKE_INTERMEDIATE` exchange (RFC 9242). For HILA5 the initiator's Key
+//! Exchange payload carries a fresh `PublicKey`, and the responder's carries
+//! the `kem::enc` ciphertext. After each additional key exchange both peers
+//! fold the `SharedSecret` into the keys:
+//!
+//! ```text
+//! SKEYSEED(n) = prf(SK_d(n-1), SK(n) | Ni | Nr)
+//! {SK_d(n) | SK_ai(n) | SK_ar(n) | SK_ei(n) | SK_er(n) | SK_pi(n) | SK_pr(n)}
+//!             = prf+(SKEYSEED(n), Ni | Nr | SPIi | SPIr)
+//! ```
+//!
+//! The `prf` is `PRF_HMAC_SHA2_256`; the lengths of the integrity and
+//! encryption keys depend on the negotiated transforms, and are given as
+//! `KeyLengths`. Encrypting the payloads in the `SK` payload of the
+//! `IKE_INTERMEDIATE` messages is left to the IKE implementation.
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use ring::{digest, hkdf, hmac};
+
+use std::io::{Read, Write};
+
+use super::*;
+use errors::*;
+
+/// Payload type of the Key Exchange payload.
+pub const PAYLOAD_KE: u8 = 34;
+/// Transform type of the first additional key exchange, `ADDKE1`. `ADDKEn`
+/// is `ADDKE1 + n - 1`, up to `ADDKE7`.
+pub const TRANSFORM_TYPE_ADDKE1: u8 = 6;
+/// Key exchange method transform ID for HILA5, from the private use range.
+pub const TRANSFORM_ID_HILA5: u16 = 0x4835;
+/// Length of the keys output by `PRF_HMAC_SHA2_256`.
+pub const PRF_KEY_LEN: usize = 32;
+
+/// Length of the generic payload header and the Key Exchange payload's
+/// method number and reserved field.
+const KE_HEADER_LEN: usize = 8;
+const CRITICAL: u8 = 0x80;
+
+/// A Key Exchange payload, RFC 7296 section 3.4.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KePayload {
+    /// Type of the payload following this one.
+    pub next_payload: u8,
+    pub critical: bool,
+    /// Key exchange method transform ID.
+    pub method: u16,
+    pub data: Vec<u8>,
+}
+
+impl KePayload {
+    /// Length of the encoded payload, including its header.
+    pub fn encoded_len(&self) -> usize {
+        KE_HEADER_LEN + self.data.len()
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        if self.encoded_len() > u16::MAX as usize {
+            return Err("Key Exchange payload is too long".into());
+        }
+        writer.write_u8(self.next_payload)?;
+        writer.write_u8(if self.critical { CRITICAL } else { 0 })?;
+        writer.write_u16::<BigEndian>(self.encoded_len() as u16)?;
+        writer.write_u16::<BigEndian>(self.method)?;
+        writer.write_u16::<BigEndian>(0)?;
+        writer.write_all(&self.data)?;
+        Ok(())
+    }
+
+    /// Parse a payload from the start of `reader`, using the length in its
+    /// header.
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Self> {
+        let next_payload = reader.read_u8()?;
+        let critical = reader.read_u8()? & CRITICAL != 0;
+        let len = reader.read_u16::<BigEndian>()? as usize;
+        if len < KE_HEADER_LEN {
+            return Err("invalid Key Exchange payload length".into());
+        }
+        let method = reader.read_u16::<BigEndian>()?;
+        let _reserved = reader.read_u16::<BigEndian>()?;
+        let mut data = vec![0u8; len - KE_HEADER_LEN];
+        reader.read_exact(&mut data)?;
+        Ok(KePayload { next_payload, critical, method, data })
+    }
+
+    pub fn from_bytes(mut input: &[u8]) -> Result<Self> {
+        let payload = Self::read_from(&mut input)?;
+        if !input.is_empty() {
+            return Err("trailing data after Key Exchange payload".into());
+        }
+        Ok(payload)
+    }
+
+    fn check(&self, len: usize) -> Result<()> {
+        if self.method != TRANSFORM_ID_HILA5 {
+            return Err("Key Exchange payload is not for HILA5".into());
+        }
+        if self.data.len() != len {
+            return Err("invalid HILA5 key exchange data length".into());
+        }
+        Ok(())
+    }
+}
+
+/// Transform type of the `n`th additional key exchange, for `n` from 1 to 7.
+pub fn additional_ke_transform_type(n: u8) -> Result<u8> {
+    match n {
+        1..=7 => Ok(TRANSFORM_TYPE_ADDKE1 + n - 1),
+        _ => Err("there are only seven additional key exchanges".into()),
+    }
+}
+
+/// The initiator's Key Exchange payload, with a fresh HILA5 public key, and
+/// the private key to keep for `initiator_shared_secret`.
+pub fn initiator_payload(next_payload: u8) -> Result<(PrivateKey, KePayload)> {
+    let (pk, sk) = crypto_kem_keypair()?;
+    let mut data = vec![];
+    pk.write_to(&mut data)?;
+    Ok((sk, KePayload { next_payload, critical: false, method: TRANSFORM_ID_HILA5, data }))
+}
+
+/// Encapsulate to the initiator's public key, returning the responder's Key
+/// Exchange payload and the shared secret.
+pub fn responder_payload(initiator: &KePayload, next_payload: u8) -> Result<(SharedSecret, KePayload)> {
+    initiator.check(PUBKEY_LEN)?;
+    let (ct, ss) = kem::enc(&PublicKey::from_bytes(&initiator.data))?;
+    Ok((ss, KePayload { next_payload, critical: false, method: TRANSFORM_ID_HILA5, data: ct }))
+}
+
+/// Decapsulate the responder's ciphertext with the initiator's private key.
+pub fn initiator_shared_secret(sk: &PrivateKey, responder: &KePayload) -> Result<SharedSecret> {
+    responder.check(CIPHERTEXT_LEN)?;
+    kem::dec(&responder.data, sk)
+}
+
+/// Lengths of the keys of the negotiated transforms.
+#[derive(Clone, Copy, Debug)]
+pub struct KeyLengths {
+    /// Key length of the integrity algorithm, for `SK_ai` and `SK_ar`.
+    pub integ: usize,
+    /// Key length of the encryption algorithm, including any salt, for
+    /// `SK_ei` and `SK_er`.
+    pub encr: usize,
+}
+
+/// The keys of an IKE SA.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IkeKeys {
+    pub sk_d: Vec<u8>,
+    pub sk_ai: Vec<u8>,
+    pub sk_ar: Vec<u8>,
+    pub sk_ei: Vec<u8>,
+    pub sk_er: Vec<u8>,
+    pub sk_pi: Vec<u8>,
+    pub sk_pr: Vec<u8>,
+}
+
+/// Nonces and SPIs of the IKE SA, which the keys are bound to.
+#[derive(Clone, Copy, Debug)]
+pub struct SaContext<'a> {
+    pub ni: &'a [u8],
+    pub nr: &'a [u8],
+    pub spi_i: &'a [u8; 8],
+    pub spi_r: &'a [u8; 8],
+}
+
+impl IkeKeys {
+    /// Keys after `IKE_SA_INIT`, from the shared secret `g^ir` of its key
+    /// exchange: `SKEYSEED = prf(Ni | Nr, g^ir)`.
+    pub fn initial(g_ir: &[u8], sa: &SaContext, lengths: &KeyLengths) -> Self {
+        let mut nonces = sa.ni.to_vec();
+        nonces.extend_from_slice(sa.nr);
+        Self::derive(&prf(&nonces, g_ir), sa, lengths)
+    }
+
+    /// Keys after an additional key exchange with the shared secret `ss`:
+    /// `SKEYSEED(n) = prf(SK_d(n-1), SK(n) | Ni | Nr)`.
+    pub fn update(&self, ss: &SharedSecret, sa: &SaContext, lengths: &KeyLengths) -> Self {
+        let mut input = ss.0.clone();
+        input.extend_from_slice(sa.ni);
+        input.extend_from_slice(sa.nr);
+        Self::derive(&prf(&self.sk_d, &input), sa, lengths)
+    }
+
+    /// `prf+(SKEYSEED, Ni | Nr | SPIi | SPIr)`, split into the seven keys.
+    fn derive(skeyseed: &[u8], sa: &SaContext, lengths: &KeyLengths) -> Self {
+        let mut seed = sa.ni.to_vec();
+        seed.extend_from_slice(sa.nr);
+        seed.extend_from_slice(sa.spi_i);
+        seed.extend_from_slice(sa.spi_r);
+
+        // prf+ is the expand step of HKDF with the same PRF
+        let key = hmac::SigningKey::new(&digest::SHA256, skeyseed);
+        let mut out = vec![0u8; 3 * PRF_KEY_LEN + 2 * lengths.integ + 2 * lengths.encr];
+        hkdf::expand(&key, &seed, &mut out);
+
+        let mut rest = &out[..];
+        let mut take = |len: usize| {
+            let (key, tail) = rest.split_at(len);
+            rest = tail;
+            key.to_vec()
+        };
+        IkeKeys {
+            sk_d: take(PRF_KEY_LEN),
+            sk_ai: take(lengths.integ),
+            sk_ar: take(lengths.integ),
+            sk_ei: take(lengths.encr),
+            sk_er: take(lengths.encr),
+            sk_pi: take(PRF_KEY_LEN),
+            sk_pr: take(PRF_KEY_LEN),
+        }
+    }
+}
+
+fn prf(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let key = hmac::SigningKey::new(&digest::SHA256, key);
+    hmac::sign(&key, data).as_ref().to_vec()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// AES-256-GCM with a 16 byte ICV, and no separate integrity algorithm.
+    const LENGTHS: KeyLengths = KeyLengths { integ: 0, encr: 36 };
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn intermediate_exchange() {
+        // IKE_SA_INIT, with X25519 as the first key exchange
+        let (ni, nr) = ([0x11u8; 32], [0x22u8; 32]);
+        let (spi_i, spi_r) = ([1u8; 8], [2u8; 8]);
+        let sa = SaContext { ni: &ni, nr: &nr, spi_i: &spi_i, spi_r: &spi_r };
+        let (x_i, x_r) = (x25519_secret().unwrap(), x25519_secret().unwrap());
+        let public_i = x25519_dalek::PublicKey::from(&x_i);
+        let public_r = x25519_dalek::PublicKey::from(&x_r);
+        let keys_i = IkeKeys::initial(&x25519_agree(&x_i, public_r.as_bytes()).unwrap(), &sa, &LENGTHS);
+        let keys_r = IkeKeys::initial(&x25519_agree(&x_r, public_i.as_bytes()).unwrap(), &sa, &LENGTHS);
+        assert_eq!(keys_i, keys_r);
+
+        // IKE_INTERMEDIATE request and response for ADDKE1
+        let (sk, request) = initiator_payload(0).unwrap();
+        let mut bytes = vec![];
+        request.write_to(&mut bytes).unwrap();
+        assert_eq!(&bytes[..8], &[0, 0, 0x07, 0x28, 0x48, 0x35, 0, 0]);
+        let request = KePayload::from_bytes(&bytes).unwrap();
+
+        let (ss_r, response) = responder_payload(&request, 0).unwrap();
+        bytes.clear();
+        response.write_to(&mut bytes).unwrap();
+        let response = KePayload::from_bytes(&bytes).unwrap();
+        let ss_i = initiator_shared_secret(&sk, &response).unwrap();
+
+        let keys_i = keys_i.update(&ss_i, &sa, &LENGTHS);
+        let keys_r = keys_r.update(&ss_r, &sa, &LENGTHS);
+        assert_eq!(keys_i, keys_r);
+        assert_eq!(keys_i.sk_ei.len(), 36);
+        assert!(keys_i.sk_ai.is_empty());
+    }
+
+    #[test]
+    fn rejects_bad_payloads() {
+        let (sk, request) = initiator_payload(0).unwrap();
+        let mut other = request.clone();
+        other.method = 31;
+        assert!(responder_payload(&other, 0).is_err());
+        other = request.clone();
+        other.data.pop();
+        assert!(responder_payload(&other, 0).is_err());
+        let (_, response) = responder_payload(&request, 0).unwrap();
+        let mut truncated = response.clone();
+        truncated.data.truncate(10);
+        assert!(initiator_shared_secret(&sk, &truncated).is_err());
+
+        let mut bytes = vec![];
+        response.write_to(&mut bytes).unwrap();
+        assert!(KePayload::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        bytes.push(0);
+        assert!(KePayload::from_bytes(&bytes).is_err());
+        assert!(additional_ke_transform_type(0).is_err());
+        assert_eq!(additional_ke_transform_type(7).unwrap(), 12);
+    }
+
+    #[test]
+    fn update_test_vector() {
+        let (ni, nr) = ([3u8; 32], [4u8; 32]);
+        let sa = SaContext { ni: &ni, nr: &nr, spi_i: &[5; 8], spi_r: &[6; 8] };
+        let lengths = KeyLengths { integ: 32, encr: 16 };
+        let previous = IkeKeys {
+            sk_d: vec![1; 32],
+            sk_ai: vec![],
+            sk_ar: vec![],
+            sk_ei: vec![],
+            sk_er: vec![],
+            sk_pi: vec![],
+            sk_pr: vec![],
+        };
+        let keys = previous.update(&SharedSecret(vec![2; 32]), &sa, &lengths);
+        // computed independently with Python's hmac module
+        assert_eq!(hex(&keys.sk_d), "a6945f9559b1cefa11959a973d412d9075302472b72f8935249f591a15c9c0de");
+        assert_eq!(hex(&keys.sk_ai), "cb2197f99215cd7775e1fc95565f761040d822b4c036ac44deb32f3f1479dbdc");
+        assert_eq!(hex(&keys.sk_er), "fd8d5d4dfe64d834f973a9fc6c93961c");
+        assert_eq!(hex(&keys.sk_pr), "34a8e4c7d44723aafeaaf6f7d697bef7c7fc9712d4ab38bebaa1edfddc87aef8");
+    }
+}
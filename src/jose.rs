@@ -0,0 +1,528 @@
+//! JSON Web Keys and JWE key establishment with HILA5.
+//!
+//! A HILA5 JWK has `"kty": "HILA5"`, with the encoded `PublicKey` in `pub`
+//! and, for private keys, the encoded `PrivateKey` in `priv`, both base64url
+//! without padding:
+//!
+//! ```text
+//! {"kty":"HILA5","kid":"2024-07","pub":"…","priv":"…"}
+//! ```
+//!
+//! The JWE algorithm `HILA5+A256KW` encapsulates to the recipient's key, runs
+//! the Concat KDF of RFC 7518 section 4.6.2 over the shared secret with
+//! `AlgorithmID` set to the `alg` value, and wraps the content-encryption key
+//! with the result using AES key wrap. The KEM ciphertext travels in the `ek`
+//! header parameter. Content is encrypted with `A256GCM`, and tokens use the
+//! compact serialization.
+//!
+//! ```rust
+//! use hila5::jose::{self, Jwk};
+//!
+//! let (pk, sk) = hila5::crypto_kem_keypair().unwrap();
+//! let private = Jwk::from_keypair(&pk, &sk, Some("device-12")).unwrap();
+//! let public = Jwk::from_json(&private.to_public().to_json()).unwrap();
+//!
+//! let token = jose::encrypt(&public, b"telemetry batch").unwrap();
+//! assert_eq!(jose::decrypt(&private, &token).unwrap(), b"telemetry batch");
+//! ```
+
+use aes_kw::KekAes256;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use ring::{aead, digest};
+use ring::rand::SecureRandom;
+
+use std::fmt::Write;
+
+use super::*;
+use encode::PACKED14;
+use errors::*;
+
+/// JWK key type for HILA5 keys.
+pub const KTY: &str = "HILA5";
+/// JWE key management algorithm: HILA5, Concat KDF and AES-256 key wrap.
+pub const ALG: &str = "HILA5+A256KW";
+/// JWE content encryption algorithm.
+pub const ENC: &str = "A256GCM";
+
+pub(crate) const CEK_LEN: usize = 32;
+pub(crate) const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// A HILA5 JSON Web Key, public or private.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Jwk {
+    pub kid: Option<String>,
+    public: Vec<u8>,
+    private: Option<Vec<u8>>,
+}
+
+impl Jwk {
+    pub fn from_public_key(pk: &PublicKey, kid: Option<&str>) -> Result<Self> {
+        let mut public = vec![];
+        pk.write_to(&mut public)?;
+        Ok(Jwk { kid: kid.map(str::to_string), public, private: None })
+    }
+
+    pub fn from_keypair(pk: &PublicKey, sk: &PrivateKey, kid: Option<&str>) -> Result<Self> {
+        if sk.pk_digest != pk.digest()? {
+            return Err("private key does not belong to the public key".into());
+        }
+        let mut jwk = Self::from_public_key(pk, kid)?;
+        let mut private = vec![];
+        sk.write_to(&mut private)?;
+        jwk.private = Some(private);
+        Ok(jwk)
+    }
+
+    /// Build a key from its encoded parts, as found in JWK and COSE_Key.
+    pub(crate) fn from_parts(kid: Option<String>, public: Vec<u8>, private: Option<Vec<u8>>)
+                             -> Result<Self> {
+        if public.len() != PUBKEY_LEN {
+            return Err("invalid HILA5 public key length".into());
+        }
+        if let Some(ref private) = private {
+            if private.len() != PRIVKEY_LEN {
+                return Err("invalid HILA5 private key length".into());
+            }
+            if private[PACKED14..] != sha3(&public)[..] {
+                return Err("private key does not belong to the public key".into());
+            }
+        }
+        Ok(Jwk { kid, public, private })
+    }
+
+    pub(crate) fn public_bytes(&self) -> &[u8] {
+        &self.public
+    }
+
+    pub(crate) fn private_bytes(&self) -> Option<&[u8]> {
+        self.private.as_ref().map(|private| &private[..])
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey::from_bytes(&self.public)
+    }
+
+    pub fn private_key(&self) -> Option<PrivateKey> {
+        self.private.as_ref().map(|private| PrivateKey::from_bytes(private))
+    }
+
+    /// The same key without its private part.
+    pub fn to_public(&self) -> Jwk {
+        Jwk { kid: self.kid.clone(), public: self.public.clone(), private: None }
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut members = vec![("kty", KTY.to_string())];
+        if let Some(ref kid) = self.kid {
+            members.push(("kid", kid.clone()));
+        }
+        members.push(("pub", URL_SAFE_NO_PAD.encode(&self.public)));
+        if let Some(ref private) = self.private {
+            members.push(("priv", URL_SAFE_NO_PAD.encode(private)));
+        }
+        write_json(&members)
+    }
+
+    /// Parse a JWK. `kty`, `kid`, `pub` and `priv` must have string values;
+    /// other members, such as `key_ops` or `x5c`, may have any JSON value
+    /// and are ignored.
+    pub fn from_json(json: &str) -> Result<Self> {
+        let members = parse_json(json)?;
+        let get = |name: &str| string_member(&members, name);
+        if get("kty")? != Some(KTY) {
+            return Err("JWK is not a HILA5 key".into());
+        }
+        let public = decode(get("pub")?.ok_or_else(|| Error::from("JWK has no `pub` member"))?)?;
+        let private = match get("priv")? {
+            Some(private) => Some(decode(private)?),
+            None => None,
+        };
+        Self::from_parts(get("kid")?.map(str::to_string), public, private)
+    }
+}
+
+/// Encrypt `plaintext` to `recipient` as a compact JWE.
+pub fn encrypt(recipient: &Jwk, plaintext: &[u8]) -> Result<String> {
+    let (ct, ss) = kem::enc(&recipient.public_key())?;
+    let mut cek = [0u8; CEK_LEN];
+    get_rng().fill(&mut cek)?;
+    let encrypted_key = kek(&ss).wrap_vec(&cek)
+        .map_err(|_| Error::from("AES key wrap failed"))?;
+
+    let mut header = vec![("alg", ALG.to_string()), ("enc", ENC.to_string())];
+    if let Some(ref kid) = recipient.kid {
+        header.push(("kid", kid.clone()));
+    }
+    header.push(("ek", URL_SAFE_NO_PAD.encode(&ct)));
+    let header = URL_SAFE_NO_PAD.encode(write_json(&header));
+
+    let (nonce, sealed) = seal(&cek, header.as_bytes(), plaintext)?;
+    let (ciphertext, tag) = sealed.split_at(sealed.len() - TAG_LEN);
+    Ok([&header[..], &URL_SAFE_NO_PAD.encode(&encrypted_key), &URL_SAFE_NO_PAD.encode(nonce),
+        &URL_SAFE_NO_PAD.encode(ciphertext), &URL_SAFE_NO_PAD.encode(tag)].join("."))
+}
+
+/// Decrypt a compact JWE with the private key in `recipient`.
+pub fn decrypt(recipient: &Jwk, token: &str) -> Result<Vec<u8>> {
+    let sk = recipient.private_key().ok_or_else(|| Error::from("JWK has no private key"))?;
+    let parts = token.split('.').collect::<Vec<_>>();
+    if parts.len() != 5 {
+        return Err("JWE compact serialization has five parts".into());
+    }
+    let header = parse_json(&String::from_utf8(decode(parts[0])?)
+                            .map_err(|_| Error::from("JWE header is not UTF-8"))?)?;
+    let get = |name: &str| string_member(&header, name);
+    if get("alg")? != Some(ALG) || get("enc")? != Some(ENC) {
+        return Err("unsupported JWE algorithms".into());
+    }
+    if let (Some(kid), Some(expected)) = (get("kid")?, recipient.kid.as_ref()) {
+        if kid != expected {
+            return Err("JWE is for a different key".into());
+        }
+    }
+    let ct = decode(get("ek")?.ok_or_else(|| Error::from("JWE header has no `ek`"))?)?;
+    if ct.len() != CIPHERTEXT_LEN {
+        return Err("invalid HILA5 ciphertext length".into());
+    }
+
+    let ss = sk.dec(&ct)?;
+    let cek = kek(&ss).unwrap_vec(&decode(parts[1])?)
+        .map_err(|_| Error::from("content-encryption key failed to unwrap"))?;
+    let nonce = decode(parts[2])?;
+    let mut sealed = decode(parts[3])?;
+    sealed.extend(decode(parts[4])?);
+    open(&cek, &nonce, parts[0].as_bytes(), sealed)
+}
+
+/// The Concat KDF of NIST SP 800-56A, as profiled in RFC 7518 section
+/// 4.6.2, with empty `PartyUInfo` and `PartyVInfo` unless given.
+fn concat_kdf(z: &[u8], algorithm: &str, apu: &[u8], apv: &[u8], key_len: usize) -> Vec<u8> {
+    let mut out = vec![];
+    let mut counter = 1u32;
+    while out.len() < key_len {
+        let mut ctx = digest::Context::new(&digest::SHA256);
+        ctx.update(&counter.to_be_bytes());
+        ctx.update(z);
+        for field in &[algorithm.as_bytes(), apu, apv] {
+            ctx.update(&(field.len() as u32).to_be_bytes());
+            ctx.update(field);
+        }
+        ctx.update(&(key_len as u32 * 8).to_be_bytes());
+        out.extend_from_slice(ctx.finish().as_ref());
+        counter += 1;
+    }
+    out.truncate(key_len);
+    out
+}
+
+fn kek(ss: &SharedSecret) -> KekAes256 {
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&concat_kdf(&ss.0, ALG, &[], &[], 32));
+    KekAes256::from(key)
+}
+
+/// Encrypt with AES-256-GCM under a fresh random nonce, returning the nonce
+/// and the ciphertext with its tag.
+pub(crate) fn seal(cek: &[u8], aad: &[u8], plaintext: &[u8]) -> Result<([u8; NONCE_LEN], Vec<u8>)> {
+    let mut nonce = [0u8; NONCE_LEN];
+    get_rng().fill(&mut nonce)?;
+    let key = aead::SealingKey::new(&aead::AES_256_GCM, cek)?;
+    let mut out = plaintext.to_vec();
+    out.extend_from_slice(&[0u8; TAG_LEN]);
+    aead::seal_in_place(&key, &nonce, aad, &mut out, TAG_LEN)?;
+    Ok((nonce, out))
+}
+
+pub(crate) fn open(cek: &[u8], nonce: &[u8], aad: &[u8], mut sealed: Vec<u8>) -> Result<Vec<u8>> {
+    if cek.len() != CEK_LEN || nonce.len() != NONCE_LEN {
+        return Err("invalid AES-256-GCM key or nonce".into());
+    }
+    let key = aead::OpeningKey::new(&aead::AES_256_GCM, cek)?;
+    let len = aead::open_in_place(&key, nonce, aad, 0, &mut sealed)
+        .map_err(|_| Error::from("content failed to decrypt"))?
+        .len();
+    sealed.truncate(len);
+    Ok(sealed)
+}
+
+fn decode(value: &str) -> Result<Vec<u8>> {
+    URL_SAFE_NO_PAD.decode(value).map_err(|e| format!("invalid base64url: {}", e).into())
+}
+
+/// Write a JSON object whose members all have string values.
+fn write_json(members: &[(&str, String)]) -> String {
+    let mut json = String::from("{");
+    for (i, &(name, ref value)) in members.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        write_string(&mut json, name);
+        json.push(':');
+        write_string(&mut json, value);
+    }
+    json.push('}');
+    json
+}
+
+fn write_string(json: &mut String, s: &str) {
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(json, "\\u{:04x}", c as u32).expect("writing to a String"),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+/// Members of a JSON object, with the values that are not strings skipped.
+type JsonMembers = Vec<(String, Option<String>)>;
+
+/// Nesting allowed inside skipped values.
+const MAX_JSON_DEPTH: usize = 32;
+
+/// Parse a JSON object, rejecting duplicate names. Values other than strings
+/// are checked for well-formedness and skipped.
+fn parse_json(json: &str) -> Result<JsonMembers> {
+    let mut chars = json.chars().peekable();
+    let mut members: JsonMembers = vec![];
+    skip_whitespace(&mut chars);
+    if chars.next() != Some('{') {
+        return Err("expected a JSON object".into());
+    }
+    skip_whitespace(&mut chars);
+    if chars.peek() == Some(&'}') {
+        chars.next();
+    } else {
+        loop {
+            skip_whitespace(&mut chars);
+            let name = parse_string(&mut chars)?;
+            skip_whitespace(&mut chars);
+            if chars.next() != Some(':') {
+                return Err("expected `:` in JSON object".into());
+            }
+            skip_whitespace(&mut chars);
+            let value = if chars.peek() == Some(&'"') {
+                Some(parse_string(&mut chars)?)
+            } else {
+                skip_value(&mut chars, 0)?;
+                None
+            };
+            if members.iter().any(|m| m.0 == name) {
+                return Err(format!("duplicate JSON member `{}`", name).into());
+            }
+            members.push((name, value));
+            skip_whitespace(&mut chars);
+            match chars.next() {
+                Some(',') => continue,
+                Some('}') => break,
+                _ => return Err("expected `,` or `}` in JSON object".into()),
+            }
+        }
+    }
+    skip_whitespace(&mut chars);
+    if chars.next().is_some() {
+        return Err("trailing data after JSON object".into());
+    }
+    Ok(members)
+}
+
+/// The string value of member `name`, if present.
+fn string_member<'a>(members: &'a JsonMembers, name: &str) -> Result<Option<&'a str>> {
+    match members.iter().find(|m| m.0 == name) {
+        Some(&(_, Some(ref value))) => Ok(Some(value)),
+        Some(_) => Err(format!("JSON member `{}` must be a string", name).into()),
+        None => Ok(None),
+    }
+}
+
+type Chars<'a> = std::iter::Peekable<std::str::Chars<'a>>;
+
+fn skip_whitespace(chars: &mut Chars) {
+    while chars.peek().is_some_and(|c| matches!(c, ' ' | '\t' | '\n' | '\r')) {
+        chars.next();
+    }
+}
+
+/// Skip a JSON value of any type.
+fn skip_value(chars: &mut Chars, depth: usize) -> Result<()> {
+    if depth > MAX_JSON_DEPTH {
+        return Err("JSON value is nested too deeply".into());
+    }
+    match chars.peek().cloned() {
+        Some('"') => parse_string(chars).map(|_| ()),
+        Some(open @ '{') | Some(open @ '[') => {
+            let close = if open == '{' { '}' } else { ']' };
+            chars.next();
+            skip_whitespace(chars);
+            if chars.peek() == Some(&close) {
+                chars.next();
+                return Ok(());
+            }
+            loop {
+                skip_whitespace(chars);
+                if open == '{' {
+                    parse_string(chars)?;
+                    skip_whitespace(chars);
+                    if chars.next() != Some(':') {
+                        return Err("expected `:` in JSON object".into());
+                    }
+                    skip_whitespace(chars);
+                }
+                skip_value(chars, depth + 1)?;
+                skip_whitespace(chars);
+                match chars.next() {
+                    Some(',') => continue,
+                    Some(c) if c == close => return Ok(()),
+                    _ => return Err(format!("expected `,` or `{}` in JSON value", close).into()),
+                }
+            }
+        }
+        Some('t') => skip_literal(chars, "true"),
+        Some('f') => skip_literal(chars, "false"),
+        Some('n') => skip_literal(chars, "null"),
+        Some(c) if c == '-' || c.is_ascii_digit() => {
+            let mut number = String::new();
+            while let Some(&c) = chars.peek() {
+                if !matches!(c, '0'..='9' | '-' | '+' | '.' | 'e' | 'E') {
+                    break;
+                }
+                number.push(c);
+                chars.next();
+            }
+            number.parse::<f64>().map(|_| ()).map_err(|_| "invalid JSON number".into())
+        }
+        _ => Err("invalid JSON value".into()),
+    }
+}
+
+fn skip_literal(chars: &mut Chars, literal: &str) -> Result<()> {
+    if !literal.chars().all(|c| chars.next() == Some(c)) {
+        return Err("invalid JSON literal".into());
+    }
+    Ok(())
+}
+
+fn parse_string(chars: &mut Chars) -> Result<String> {
+    if chars.next() != Some('"') {
+        return Err("expected a JSON string".into());
+    }
+    let mut s = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(s),
+            Some('\\') => {
+                let c = match chars.next() {
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('/') => '/',
+                    Some('b') => '\u{8}',
+                    Some('f') => '\u{c}',
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('t') => '\t',
+                    Some('u') => {
+                        let hex = chars.by_ref().take(4).collect::<String>();
+                        u32::from_str_radix(&hex, 16).ok().filter(|_| hex.len() == 4)
+                            .and_then(std::char::from_u32)
+                            .ok_or_else(|| Error::from("unsupported JSON unicode escape"))?
+                    }
+                    _ => return Err("invalid JSON escape".into()),
+                };
+                s.push(c);
+            }
+            Some(c) if (c as u32) < 0x20 => return Err("control character in JSON string".into()),
+            Some(c) => s.push(c),
+            None => return Err("unterminated JSON string".into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn jwk_roundtrip() {
+        let (pk, sk) = crypto_kem_keypair().unwrap();
+        let private = Jwk::from_keypair(&pk, &sk, Some("key \"one\"")).unwrap();
+        let json = private.to_json();
+        assert!(json.starts_with("{\"kty\":\"HILA5\",\"kid\":\"key \\\"one\\\"\",\"pub\":\""));
+        let parsed = Jwk::from_json(&json).unwrap();
+        assert_eq!(parsed, private);
+        assert_eq!(parsed.public_key().digest().unwrap(), pk.digest().unwrap());
+        assert_eq!(parsed.private_key().unwrap().pk_digest, sk.pk_digest);
+
+        let encoded = URL_SAFE_NO_PAD.encode(parsed.public_bytes());
+        let json = format!(" {{ \"use\" : \"enc\",\n \"pub\": \"{}\", \"kty\": \"HILA5\" }} ", encoded);
+        let public = Jwk::from_json(&json).unwrap();
+        let mut expected = private.to_public();
+        expected.kid = None;
+        assert_eq!(public, expected);
+        assert!(public.private_key().is_none());
+
+        // members we do not use may have any JSON value
+        let extended = json.replace("\"use\"", "\"key_ops\": [\"encrypt\"], \"ext\": true, \"x5c\": [], \
+                                                  \"n\": -1.5e3, \"d\": null, \"o\": {\"a\": [{}]}, \"use\"");
+        assert_eq!(Jwk::from_json(&extended).unwrap(), expected);
+    }
+
+    #[test]
+    fn jwe_roundtrip() {
+        let (pk, sk) = crypto_kem_keypair().unwrap();
+        let private = Jwk::from_keypair(&pk, &sk, Some("a")).unwrap();
+        let token = encrypt(&private.to_public(), b"hello").unwrap();
+        assert_eq!(token.split('.').count(), 5);
+        assert_eq!(decrypt(&private, &token).unwrap(), b"hello");
+        assert!(decrypt(&private.to_public(), &token).is_err());
+
+        // tampering with the protected header breaks the tag
+        let header = parse_json(&String::from_utf8(decode(token.split('.').next().unwrap()).unwrap())
+                                .unwrap()).unwrap();
+        let mut members = header.iter().map(|m| (&m.0[..], m.1.clone().unwrap())).collect::<Vec<_>>();
+        members.push(("zip", "none".to_string()));
+        let forged = format!("{}{}", URL_SAFE_NO_PAD.encode(write_json(&members)),
+                             &token[token.find('.').unwrap()..]);
+        assert!(decrypt(&private, &forged).is_err());
+
+        let (pk2, sk2) = crypto_kem_keypair().unwrap();
+        let other = Jwk::from_keypair(&pk2, &sk2, None).unwrap();
+        assert!(decrypt(&other, &token).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_jwks() {
+        let (pk, sk) = crypto_kem_keypair().unwrap();
+        let (pk2, _) = crypto_kem_keypair().unwrap();
+        assert!(Jwk::from_keypair(&pk2, &sk, None).is_err());
+        let json = Jwk::from_keypair(&pk, &sk, None).unwrap().to_json();
+        assert!(Jwk::from_json(&json.replace("HILA5", "OKP")).is_err());
+        assert!(Jwk::from_json(&json.replacen("\"pub\":\"", "\"pub\":\"AAAA", 1)).is_err());
+        assert!(Jwk::from_json(&json.replace("}", ",\"kty\":\"HILA5\"}")).is_err());
+        assert!(Jwk::from_json(&json.replace("\"kty\":\"HILA5\"", "\"kty\":5")).is_err());
+        assert!(Jwk::from_json(&json.replace("}", ",\"kid\":[\"a\"]}")).is_err());
+        assert!(Jwk::from_json(&json.replace("}", ",\"n\":01x}")).is_err());
+        assert!(Jwk::from_json(&json.replace("}", ",\"n\":[1,}")).is_err());
+        assert!(Jwk::from_json(&json.replace("}", ",\"n\":tru}")).is_err());
+        let nested = format!("{}1{}", "[".repeat(64), "]".repeat(64));
+        assert!(Jwk::from_json(&json.replace("}", &format!(",\"n\":{}}}", nested))).is_err());
+        assert!(Jwk::from_json(&json[..json.len() - 1]).is_err());
+        assert!(parse_json("{\"a\":\"\\u00e9\\n\"}").unwrap() == vec![("a".into(), Some("\u{e9}\n".into()))]);
+    }
+
+    #[test]
+    fn concat_kdf_vector() {
+        // RFC 7518 appendix C
+        let z = [158, 86, 217, 29, 129, 113, 53, 211, 114, 131, 66, 131, 191, 132, 38, 156, 251, 49,
+                 110, 163, 218, 128, 106, 72, 246, 218, 167, 121, 140, 254, 144, 196];
+        assert_eq!(URL_SAFE_NO_PAD.encode(concat_kdf(&z, "A128GCM", b"Alice", b"Bob", 16)),
+                   "VqqN6vgjbSBcIijNcacQGg");
+    }
+}
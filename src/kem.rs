@@ -14,6 +14,7 @@ use std::io::Cursor;
 use super::*;
 use encode::PACKED14;
 use errors::*;
+use recon::Payload;
 
 const MAX_ITER: usize = 1000;
 
@@ -22,27 +23,11 @@ pub struct SharedSecret(pub Vec<u8>);
 
 /// Type-friendly version of `crypto_kem_enc`
 pub fn enc(pk: &keygen::PublicKey) -> Result<(Vec<u8>, SharedSecret)> {
-    let mut ct = vec![0u8; PACKED14];
-
-    let a = &pk.key;
-
-    #[allow(unused_assignments)]
-    let mut b = NttVector([0; HILA5_N]);
-
     let mut ctr = 0;
-    let (payload, info) = loop {
-        b = arith::ntt(rand::psi16());
-        let e = a * &b;
-        // Need to clear 3^6 factor; 12171 = 3^-6
-        let mut t = arith::intt(e, 12_171);
-
-        #[cfg(feature="opt")]
-        arith::two_reduce12289 (&mut t);
-
-        t.norm();
-
-        if let Ok(x) = recon::safebits(&t) {
-            break x;
+    let (b, recipient, payload) = loop {
+        let b = arith::ntt(rand::psi16());
+        if let Some((recipient, payload)) = recipient_part(&pk.key, &b)? {
+            break (b, recipient, payload);
         }
 
         ctr += 1;
@@ -51,6 +36,37 @@ pub fn enc(pk: &keygen::PublicKey) -> Result<(Vec<u8>, SharedSecret)> {
         }
     };
 
+    let mut ct = ephemeral_part(&pk.gen, &b)?;
+    ct.extend_from_slice(&recipient);
+    let ss = shared_secret(pk, &ct, &payload)?;
+    Ok((ct, ss))
+}
+
+/// Length of the part of a ciphertext specific to the public key `A`: the
+/// reconciliation information and the one-time pad encrypted ECC.
+pub(crate) const RECIPIENT_LEN: usize = CIPHERTEXT_LEN - PACKED14;
+
+/// Compute the reconciliation information and ECC tail of a ciphertext for
+/// public key `a`, from the ephemeral secret `b`.
+///
+/// Returns `None` if `a * b` does not contain enough safe bits, in which case
+/// a new `b` must be sampled.
+pub(crate) fn recipient_part(a: &NttVector, b: &NttVector) -> Result<Option<(Vec<u8>, Payload)>> {
+    let e = a * b;
+    // Need to clear 3^6 factor; 12171 = 3^-6
+    let mut t = arith::intt(e, 12_171);
+
+    #[cfg(feature="opt")]
+    arith::two_reduce12289 (&mut t);
+
+    t.norm();
+
+    let (payload, info) = match recon::safebits(&t) {
+        Ok(x) => x,
+        Err(_) => return Ok(None),
+    };
+
+    let mut ct = Vec::with_capacity(RECIPIENT_LEN + 2);
     info.write_to(&mut ct)?;
 
     // split the payload into data z and OTP data r
@@ -66,40 +82,59 @@ pub fn enc(pk: &keygen::PublicKey) -> Result<(Vec<u8>, SharedSecret)> {
     let _ = ct.pop();
     let _ = ct.pop();
 
+    Ok(Some((ct, payload)))
+}
 
-    // recover the seed/generator
-    // let g: NttVector = rand::from_seed(&pk.seed);
-    let g: &NttVector = &pk.gen;
+/// Compute the packed ephemeral public value `g*b + e`, which starts the
+/// ciphertext.
+pub(crate) fn ephemeral_part(g: &NttVector, b: &NttVector) -> Result<Vec<u8>> {
     // generate some random noise
     let t: Vector = rand::psi16();
     let e = arith::ntt(t);
     // secret key is a = g*b + e
-    let mut a = arith::mul_add(g, &b, &e);
+    let mut a = arith::mul_add(g, b, &e);
     a.norm();
 
-    encode::pack14(&a, &mut &mut ct[..PACKED14])?;
+    let mut ct = vec![0u8; PACKED14];
+    encode::pack14(&a, &mut &mut ct[..])?;
+    Ok(ct)
+}
 
+/// Derive the shared secret from the full ciphertext and the payload.
+pub(crate) fn shared_secret(pk: &keygen::PublicKey, ct: &[u8], payload: &Payload) -> Result<SharedSecret> {
     let mut pk_bytes = vec![];
     pk.write_to(&mut pk_bytes)?;
 
     let mut hasher = Sha3_256::default();
     hasher.input(b"HILA5v10");
     hasher.input(&sha3(&pk_bytes));
-    hasher.input(&sha3(&ct));
+    hasher.input(&sha3(ct));
     hasher.input(&payload.0[..32]);
-    let ss = hasher.result().to_vec();
-
-    Ok((ct, SharedSecret(ss)))
+    Ok(SharedSecret(hasher.result().to_vec()))
 }
 
 pub fn dec(ct: &[u8], sk: &keygen::PrivateKey) -> Result<SharedSecret> {
+    check_ciphertext(ct)?;
     let b = encode::unpack14(&ct[..encode::PACKED14]);
     let x = sk.get_shared_secret(&b);
+    dec_with_product(ct, &x, &sk.pk_digest)
+}
 
+pub(crate) fn check_ciphertext(ct: &[u8]) -> Result<()> {
+    if ct.len() != CIPHERTEXT_LEN {
+        return Err("invalid ciphertext length".into());
+    }
+    Ok(())
+}
+
+/// Finish decapsulating `ct` from `x = a * b`: reconciliation, error
+/// correction and the final hash.
+pub(crate) fn dec_with_product(ct: &[u8], x: &Vector, pk_digest: &[u8]) -> Result<SharedSecret> {
+    check_ciphertext(ct)?;
     // recover the reconciliation info from the ciphertext
     let info = recon::Info::from_bytes(&ct[encode::PACKED14..]);
     // recovers the payload from b ~= v
-    let payload = recon::select(&info, &x)?;
+    let payload = recon::select(&info, x)?;
     // split the payload into data z and one-time pad encrypted redundancy r
     let (ref mut z, mut r) = payload.parse()?;
 
@@ -123,7 +158,7 @@ pub fn dec(ct: &[u8], sk: &keygen::PrivateKey) -> Result<SharedSecret> {
 
     let mut hasher = Sha3_256::default();
     hasher.input(b"HILA5v10");
-    hasher.input(&sk.pk_digest);
+    hasher.input(pk_digest);
     hasher.input(&sha3(ct));
     hasher.input(&z_bytes);
     let ss = hasher.result().to_vec();
@@ -131,3 +166,19 @@ pub fn dec(ct: &[u8], sk: &keygen::PrivateKey) -> Result<SharedSecret> {
     Ok(SharedSecret(ss))
 } 
 
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn dec_rejects_wrong_length() {
+        let (pk, sk) = crypto_kem_keypair().unwrap();
+        let (ct, ss) = enc(&pk).unwrap();
+        assert_eq!(dec(&ct, &sk).unwrap().0, ss.0);
+        assert!(dec(&ct[..PACKED14], &sk).is_err());
+        assert!(dec(&ct[..CIPHERTEXT_LEN - 1], &sk).is_err());
+        assert!(dec(&[ct.clone(), vec![0]].concat(), &sk).is_err());
+        assert!(dec(&[], &sk).is_err());
+    }
+}
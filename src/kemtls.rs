@@ -0,0 +1,590 @@
+//! KEMTLS-style handshake, authenticating the server with a HILA5 key instead
+//! of a signature.
+//!
+//! This follows the message flow and key schedule of KEMTLS
+//! ([Schwabe, Stebila, Wiggers 2020](https://eprint.iacr.org/2020/534)), with
+//! HILA5 used for both the ephemeral and the server's long-term key:
+//!
+//! ```text
+//!  Client                                            Server
+//!  ClientHello (pk_e, random)      -------->
+//!                                  <--------  ServerHello (ct_e, random)
+//!                                  <--------  {Certificate (name, pk_S)}
+//!  {ClientKemCiphertext (ct_S)}    -------->
+//!  {Finished}                      -------->
+//!  [Application data]              <------->  [Application data]
+//!                                  <--------  {Finished}
+//! ```
+//!
+//! `{}` are messages protected with the handshake traffic keys, derived from
+//! `ss_e`, and `[]` are protected with the application traffic keys, which are
+//! additionally derived from `ss_S`. Only the holder of the private key for
+//! `pk_S` can decapsulate `ct_S`, so the server's `Finished` MAC implicitly
+//! authenticates it to the client.
+//!
+//! The key schedule uses HKDF-SHA256 with the TLS 1.3 labels. Records are
+//! protected with ChaCha20-Poly1305, using per-direction keys and IVs in the
+//! same way as TLS 1.3.
+//!
+//! The handshake runs over any blocking `Read + Write` transport.
+
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+use ring::{aead, constant_time, digest, hkdf, hmac};
+use ring::rand::SecureRandom;
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+use super::*;
+use errors::*;
+
+/// Length of the random values in the hello messages.
+pub const RANDOM_LEN: usize = 32;
+/// Maximum length of a record payload.
+pub const MAX_RECORD_LEN: usize = 1 << 14;
+
+const HASH_LEN: usize = 32;
+const KEY_LEN: usize = 32;
+const IV_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+const RECORD_HANDSHAKE: u8 = 22;
+const RECORD_APPLICATION: u8 = 23;
+const RECORD_PROTECTED: u8 = 24;
+
+const CLIENT_HELLO: u8 = 1;
+const SERVER_HELLO: u8 = 2;
+const CERTIFICATE: u8 = 11;
+const CLIENT_KEM_CIPHERTEXT: u8 = 16;
+const FINISHED: u8 = 20;
+
+/// Server certificate: a name bound to a HILA5 public key.
+pub struct Certificate {
+    pub name: String,
+    pub key: PublicKey,
+}
+
+impl Certificate {
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_u16::<BigEndian>(self.name.len() as u16)?;
+        writer.write_all(self.name.as_bytes())?;
+        self.key.write_to(writer)
+    }
+
+    pub fn from_bytes(input: &[u8]) -> Result<Self> {
+        if input.len() < 2 {
+            return Err("truncated certificate".into());
+        }
+        let name_len = BigEndian::read_u16(input) as usize;
+        if input.len() != 2 + name_len + PUBKEY_LEN {
+            return Err("invalid certificate length".into());
+        }
+        let name = String::from_utf8(input[2..2 + name_len].to_vec())
+            .map_err(|_| "certificate name is not valid UTF-8")?;
+        let key = PublicKey::from_bytes(&input[2 + name_len..]);
+        Ok(Certificate { name, key })
+    }
+}
+
+/// Decides whether the client trusts a server certificate.
+pub trait CertVerifier {
+    fn verify(&self, server_name: &str, cert: &Certificate) -> Result<()>;
+}
+
+/// Trusts a fixed set of public keys for each server name.
+#[derive(Default)]
+pub struct PinnedKeys {
+    keys: HashMap<String, Vec<Vec<u8>>>,
+}
+
+impl PinnedKeys {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trust `key` for the server `name`.
+    pub fn add(&mut self, name: &str, key: &PublicKey) -> Result<()> {
+        let digest = key.digest()?;
+        self.keys.entry(name.to_string()).or_default().push(digest);
+        Ok(())
+    }
+}
+
+impl CertVerifier for PinnedKeys {
+    fn verify(&self, server_name: &str, cert: &Certificate) -> Result<()> {
+        if cert.name != server_name {
+            return Err("certificate name does not match server name".into());
+        }
+        let digest = cert.key.digest()?;
+        match self.keys.get(server_name) {
+            Some(keys) if keys.contains(&digest) => Ok(()),
+            _ => Err("untrusted server key".into()),
+        }
+    }
+}
+
+/// An established connection, carrying application data.
+pub struct Connection<T> {
+    transport: T,
+    send: RecordKey<aead::SealingKey>,
+    recv: RecordKey<aead::OpeningKey>,
+    exporter: Vec<u8>,
+}
+
+impl<T: Read + Write> Connection<T> {
+    /// Send a message of application data.
+    pub fn send(&mut self, data: &[u8]) -> Result<()> {
+        for chunk in data.chunks(MAX_RECORD_LEN) {
+            write_protected(&mut self.transport, &mut self.send, RECORD_APPLICATION, chunk)?;
+        }
+        self.transport.flush()?;
+        Ok(())
+    }
+
+    /// Receive the next record of application data.
+    pub fn recv(&mut self) -> Result<Vec<u8>> {
+        read_protected(&mut self.transport, &mut self.recv, RECORD_APPLICATION)
+    }
+
+    /// Secret for deriving further keys bound to this connection.
+    pub fn exporter_secret(&self) -> &[u8] {
+        &self.exporter
+    }
+
+    /// Return the underlying transport.
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+}
+
+/// Run the client side of the handshake with `server_name`, trusting server
+/// certificates accepted by `verifier`.
+pub fn connect<T: Read + Write, V: CertVerifier>(mut transport: T, server_name: &str, verifier: &V)
+    -> Result<Connection<T>>
+{
+    let mut transcript = digest::Context::new(&digest::SHA256);
+
+    // ClientHello
+    let (pk_e, sk_e) = crypto_kem_keypair()?;
+    let mut hello = random()?.to_vec();
+    pk_e.write_to(&mut hello)?;
+    write_handshake(&mut transport, &mut transcript, CLIENT_HELLO, &hello)?;
+    transport.flush()?;
+
+    // ServerHello
+    let server_hello = read_handshake(&mut transport, &mut transcript, SERVER_HELLO)?;
+    if server_hello.len() != RANDOM_LEN + CIPHERTEXT_LEN {
+        return Err("invalid ServerHello".into());
+    }
+    let ss_e = sk_e.dec(&server_hello[RANDOM_LEN..])?;
+    let mut schedule = KeySchedule::new(&ss_e, &transcript);
+    let mut send = RecordKey::sealing(&schedule.client_hs)?;
+    let mut recv = RecordKey::opening(&schedule.server_hs)?;
+
+    // {Certificate}
+    let cert = read_protected_handshake(&mut transport, &mut recv, &mut transcript, CERTIFICATE)?;
+    let cert = Certificate::from_bytes(&cert)?;
+    verifier.verify(server_name, &cert)?;
+
+    // {ClientKemCiphertext}
+    let (ct_s, ss_s) = cert.key.enc()?;
+    write_protected_handshake(&mut transport, &mut send, &mut transcript, CLIENT_KEM_CIPHERTEXT, &ct_s)?;
+    schedule.authenticate(&ss_s, &transcript);
+    let mut send = RecordKey::sealing(&schedule.client_ahs)?;
+    let mut recv_ahs = RecordKey::opening(&schedule.server_ahs)?;
+
+    // {Finished}
+    let finished = schedule.finished(b"c finished", &transcript);
+    write_protected_handshake(&mut transport, &mut send, &mut transcript, FINISHED, &finished)?;
+    transport.flush()?;
+    let client_ap = schedule.derive(b"c ap traffic", &transcript);
+
+    // {Finished} from the server
+    let expected = schedule.finished(b"s finished", &transcript);
+    let server_finished = read_protected_handshake(&mut transport, &mut recv_ahs, &mut transcript, FINISHED)?;
+    constant_time::verify_slices_are_equal(&expected, &server_finished)
+        .map_err(|_| "server Finished verification failed")?;
+    let server_ap = schedule.derive(b"s ap traffic", &transcript);
+    let exporter = schedule.derive(b"exp master", &transcript);
+
+    Ok(Connection {
+        transport,
+        send: RecordKey::sealing(&client_ap)?,
+        recv: RecordKey::opening(&server_ap)?,
+        exporter,
+    })
+}
+
+/// Run the server side of the handshake, authenticating with `cert` and the
+/// matching private key `sk`.
+pub fn accept<T: Read + Write>(mut transport: T, cert: &Certificate, sk: &PrivateKey)
+    -> Result<Connection<T>>
+{
+    let mut transcript = digest::Context::new(&digest::SHA256);
+
+    // ClientHello
+    let client_hello = read_handshake(&mut transport, &mut transcript, CLIENT_HELLO)?;
+    if client_hello.len() != RANDOM_LEN + PUBKEY_LEN {
+        return Err("invalid ClientHello".into());
+    }
+    let pk_e = PublicKey::from_bytes(&client_hello[RANDOM_LEN..]);
+
+    // ServerHello
+    let (ct_e, ss_e) = pk_e.enc()?;
+    let mut hello = random()?.to_vec();
+    hello.extend_from_slice(&ct_e);
+    write_handshake(&mut transport, &mut transcript, SERVER_HELLO, &hello)?;
+    let mut schedule = KeySchedule::new(&ss_e, &transcript);
+    let mut send = RecordKey::sealing(&schedule.server_hs)?;
+    let mut recv = RecordKey::opening(&schedule.client_hs)?;
+
+    // {Certificate}
+    let mut cert_bytes = vec![];
+    cert.write_to(&mut cert_bytes)?;
+    write_protected_handshake(&mut transport, &mut send, &mut transcript, CERTIFICATE, &cert_bytes)?;
+    transport.flush()?;
+
+    // {ClientKemCiphertext}
+    let ct_s = read_protected_handshake(&mut transport, &mut recv, &mut transcript, CLIENT_KEM_CIPHERTEXT)?;
+    if ct_s.len() != CIPHERTEXT_LEN {
+        return Err("invalid ClientKemCiphertext".into());
+    }
+    let ss_s = sk.dec(&ct_s)?;
+    schedule.authenticate(&ss_s, &transcript);
+    let mut send = RecordKey::sealing(&schedule.server_ahs)?;
+    let mut recv = RecordKey::opening(&schedule.client_ahs)?;
+
+    // {Finished} from the client
+    let expected = schedule.finished(b"c finished", &transcript);
+    let client_finished = read_protected_handshake(&mut transport, &mut recv, &mut transcript, FINISHED)?;
+    constant_time::verify_slices_are_equal(&expected, &client_finished)
+        .map_err(|_| "client Finished verification failed")?;
+    let client_ap = schedule.derive(b"c ap traffic", &transcript);
+
+    // {Finished}
+    let finished = schedule.finished(b"s finished", &transcript);
+    write_protected_handshake(&mut transport, &mut send, &mut transcript, FINISHED, &finished)?;
+    transport.flush()?;
+    let server_ap = schedule.derive(b"s ap traffic", &transcript);
+    let exporter = schedule.derive(b"exp master", &transcript);
+
+    Ok(Connection {
+        transport,
+        send: RecordKey::sealing(&server_ap)?,
+        recv: RecordKey::opening(&client_ap)?,
+        exporter,
+    })
+}
+
+/// KEMTLS key schedule.
+struct KeySchedule {
+    secret: Vec<u8>,
+    client_hs: Vec<u8>,
+    server_hs: Vec<u8>,
+    client_ahs: Vec<u8>,
+    server_ahs: Vec<u8>,
+}
+
+impl KeySchedule {
+    /// Derive the handshake secret from the ephemeral shared secret.
+    fn new(ss_e: &SharedSecret, transcript: &digest::Context) -> Self {
+        let early = extract(&[], &[0u8; HASH_LEN]);
+        let derived = derive_secret(&early, b"derived", &empty_hash());
+        let handshake = extract(&derived, &ss_e.0);
+        let hash = transcript.clone().finish();
+        KeySchedule {
+            client_hs: derive_secret(&handshake, b"c hs traffic", hash.as_ref()),
+            server_hs: derive_secret(&handshake, b"s hs traffic", hash.as_ref()),
+            secret: handshake,
+            client_ahs: vec![],
+            server_ahs: vec![],
+        }
+    }
+
+    /// Mix in the shared secret encapsulated to the server's long-term key,
+    /// producing the authenticated handshake secret and then the master
+    /// secret.
+    fn authenticate(&mut self, ss_s: &SharedSecret, transcript: &digest::Context) {
+        let derived = derive_secret(&self.secret, b"derived", &empty_hash());
+        let authenticated = extract(&derived, &ss_s.0);
+        let hash = transcript.clone().finish();
+        self.client_ahs = derive_secret(&authenticated, b"c ahs traffic", hash.as_ref());
+        self.server_ahs = derive_secret(&authenticated, b"s ahs traffic", hash.as_ref());
+
+        let derived = derive_secret(&authenticated, b"derived", &empty_hash());
+        self.secret = extract(&derived, &[0u8; HASH_LEN]);
+    }
+
+    /// Derive a secret from the master secret.
+    fn derive(&self, label: &[u8], transcript: &digest::Context) -> Vec<u8> {
+        derive_secret(&self.secret, label, transcript.clone().finish().as_ref())
+    }
+
+    /// Compute a `Finished` MAC over the current transcript.
+    fn finished(&self, label: &[u8], transcript: &digest::Context) -> Vec<u8> {
+        let key = expand_label(&self.secret, label, &[], HASH_LEN);
+        let key = hmac::SigningKey::new(&digest::SHA256, &key);
+        hmac::sign(&key, transcript.clone().finish().as_ref()).as_ref().to_vec()
+    }
+}
+
+fn extract(salt: &[u8], ikm: &[u8]) -> Vec<u8> {
+    let salt = hmac::SigningKey::new(&digest::SHA256, salt);
+    hmac::sign(&salt, ikm).as_ref().to_vec()
+}
+
+fn expand_label(secret: &[u8], label: &[u8], context: &[u8], len: usize) -> Vec<u8> {
+    let mut info = vec![];
+    info.write_u16::<BigEndian>(len as u16).unwrap();
+    info.push((6 + label.len()) as u8);
+    info.extend_from_slice(b"tls13 ");
+    info.extend_from_slice(label);
+    info.push(context.len() as u8);
+    info.extend_from_slice(context);
+
+    let prk = hmac::SigningKey::new(&digest::SHA256, secret);
+    let mut out = vec![0u8; len];
+    hkdf::expand(&prk, &info, &mut out);
+    out
+}
+
+fn derive_secret(secret: &[u8], label: &[u8], hash: &[u8]) -> Vec<u8> {
+    expand_label(secret, label, hash, HASH_LEN)
+}
+
+fn empty_hash() -> Vec<u8> {
+    digest::digest(&digest::SHA256, &[]).as_ref().to_vec()
+}
+
+fn random() -> Result<[u8; RANDOM_LEN]> {
+    let mut random = [0u8; RANDOM_LEN];
+    get_rng().fill(&mut random)?;
+    Ok(random)
+}
+
+/// Record protection state for one direction.
+struct RecordKey<K> {
+    key: K,
+    iv: Vec<u8>,
+    seq: u64,
+}
+
+impl RecordKey<aead::SealingKey> {
+    fn sealing(secret: &[u8]) -> Result<Self> {
+        let key = expand_label(secret, b"key", &[], KEY_LEN);
+        Ok(RecordKey {
+            key: aead::SealingKey::new(&aead::CHACHA20_POLY1305, &key)?,
+            iv: expand_label(secret, b"iv", &[], IV_LEN),
+            seq: 0,
+        })
+    }
+}
+
+impl RecordKey<aead::OpeningKey> {
+    fn opening(secret: &[u8]) -> Result<Self> {
+        let key = expand_label(secret, b"key", &[], KEY_LEN);
+        Ok(RecordKey {
+            key: aead::OpeningKey::new(&aead::CHACHA20_POLY1305, &key)?,
+            iv: expand_label(secret, b"iv", &[], IV_LEN),
+            seq: 0,
+        })
+    }
+}
+
+impl<K> RecordKey<K> {
+    /// Per-record nonce: the IV XORed with the sequence number.
+    fn next_nonce(&mut self) -> Vec<u8> {
+        let mut nonce = self.iv.clone();
+        let mut seq = [0u8; 8];
+        BigEndian::write_u64(&mut seq, self.seq);
+        for (n, s) in nonce[IV_LEN - 8..].iter_mut().zip(seq.iter()) {
+            *n ^= s;
+        }
+        self.seq += 1;
+        nonce
+    }
+}
+
+fn write_record<W: Write>(writer: &mut W, record_type: u8, payload: &[u8]) -> Result<()> {
+    writer.write_u8(record_type)?;
+    writer.write_u16::<BigEndian>(payload.len() as u16)?;
+    writer.write_all(payload)?;
+    Ok(())
+}
+
+fn read_record<R: Read>(reader: &mut R, record_type: u8) -> Result<Vec<u8>> {
+    let ty = reader.read_u8()?;
+    if ty != record_type {
+        return Err(format!("unexpected record type {}", ty).into());
+    }
+    let len = reader.read_u16::<BigEndian>()? as usize;
+    if len > MAX_RECORD_LEN + 1 + TAG_LEN {
+        return Err("record too long".into());
+    }
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+/// Write an encrypted record, with the inner content type appended to the
+/// plaintext as in TLS 1.3.
+fn write_protected<W: Write>(writer: &mut W, key: &mut RecordKey<aead::SealingKey>, inner_type: u8,
+                             payload: &[u8]) -> Result<()> {
+    let len = payload.len() + 1 + TAG_LEN;
+    let mut header = [RECORD_PROTECTED, 0, 0];
+    BigEndian::write_u16(&mut header[1..], len as u16);
+
+    let mut record = Vec::with_capacity(len);
+    record.extend_from_slice(payload);
+    record.push(inner_type);
+    record.extend_from_slice(&[0u8; TAG_LEN]);
+    let nonce = key.next_nonce();
+    aead::seal_in_place(&key.key, &nonce, &header, &mut record, TAG_LEN)?;
+    writer.write_all(&header)?;
+    writer.write_all(&record)?;
+    Ok(())
+}
+
+fn read_protected<R: Read>(reader: &mut R, key: &mut RecordKey<aead::OpeningKey>, inner_type: u8)
+    -> Result<Vec<u8>>
+{
+    let mut record = read_record(reader, RECORD_PROTECTED)?;
+    let mut header = [RECORD_PROTECTED, 0, 0];
+    BigEndian::write_u16(&mut header[1..], record.len() as u16);
+    let nonce = key.next_nonce();
+    let plaintext = aead::open_in_place(&key.key, &nonce, &header, 0, &mut record)
+        .map_err(|_| "record authentication failed")?;
+    match plaintext.split_last() {
+        Some((&ty, payload)) if ty == inner_type => Ok(payload.to_vec()),
+        _ => Err("unexpected record content type".into()),
+    }
+}
+
+fn handshake_message(msg_type: u8, body: &[u8]) -> Vec<u8> {
+    let mut msg = vec![msg_type, 0, 0, 0];
+    BigEndian::write_u24(&mut msg[1..], body.len() as u32);
+    msg.extend_from_slice(body);
+    msg
+}
+
+fn parse_handshake(msg: &[u8], msg_type: u8) -> Result<Vec<u8>> {
+    if msg.len() < 4 || msg[0] != msg_type {
+        return Err(format!("expected handshake message {}", msg_type).into());
+    }
+    if BigEndian::read_u24(&msg[1..]) as usize != msg.len() - 4 {
+        return Err("invalid handshake message length".into());
+    }
+    Ok(msg[4..].to_vec())
+}
+
+fn write_handshake<W: Write>(writer: &mut W, transcript: &mut digest::Context, msg_type: u8,
+                             body: &[u8]) -> Result<()> {
+    let msg = handshake_message(msg_type, body);
+    transcript.update(&msg);
+    write_record(writer, RECORD_HANDSHAKE, &msg)
+}
+
+fn read_handshake<R: Read>(reader: &mut R, transcript: &mut digest::Context, msg_type: u8)
+    -> Result<Vec<u8>>
+{
+    let msg = read_record(reader, RECORD_HANDSHAKE)?;
+    let body = parse_handshake(&msg, msg_type)?;
+    transcript.update(&msg);
+    Ok(body)
+}
+
+fn write_protected_handshake<W: Write>(writer: &mut W, key: &mut RecordKey<aead::SealingKey>,
+                                       transcript: &mut digest::Context, msg_type: u8,
+                                       body: &[u8]) -> Result<()> {
+    let msg = handshake_message(msg_type, body);
+    transcript.update(&msg);
+    write_protected(writer, key, RECORD_HANDSHAKE, &msg)
+}
+
+fn read_protected_handshake<R: Read>(reader: &mut R, key: &mut RecordKey<aead::OpeningKey>,
+                                     transcript: &mut digest::Context, msg_type: u8)
+    -> Result<Vec<u8>>
+{
+    let msg = read_protected(reader, key, RECORD_HANDSHAKE)?;
+    let body = parse_handshake(&msg, msg_type)?;
+    transcript.update(&msg);
+    Ok(body)
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    use super::*;
+
+    fn server_identity() -> (Certificate, PrivateKey) {
+        let (pk, sk) = crypto_kem_keypair().unwrap();
+        (Certificate { name: "server.test".to_string(), key: pk }, sk)
+    }
+
+    #[test]
+    fn loopback_handshake() {
+        let (cert, sk) = server_identity();
+        let mut pinned = PinnedKeys::new();
+        pinned.add("server.test", &cert.key).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut conn = accept(stream, &cert, &sk).unwrap();
+            let msg = conn.recv().unwrap();
+            conn.send(&msg).unwrap();
+            conn.exporter_secret().to_vec()
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut conn = connect(stream, "server.test", &pinned).unwrap();
+        conn.send(b"ping").unwrap();
+        assert_eq!(conn.recv().unwrap(), b"ping");
+
+        let server_exporter = server.join().unwrap();
+        assert_eq!(conn.exporter_secret(), &server_exporter[..]);
+    }
+
+    #[test]
+    fn rejects_untrusted_server() {
+        let (cert, sk) = server_identity();
+        let (other, _) = crypto_kem_keypair().unwrap();
+        let mut pinned = PinnedKeys::new();
+        pinned.add("server.test", &other).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            accept(stream, &cert, &sk).is_err()
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        assert!(connect(stream, "server.test", &pinned).is_err());
+        // The client hangs up, so the server fails too.
+        assert!(server.join().unwrap());
+    }
+
+    #[test]
+    fn wrong_server_key_fails_finished() {
+        // A server presenting someone else's certificate cannot decapsulate.
+        let (cert, _) = server_identity();
+        let (_, wrong_sk) = crypto_kem_keypair().unwrap();
+        let mut pinned = PinnedKeys::new();
+        pinned.add("server.test", &cert.key).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            accept(stream, &cert, &wrong_sk).is_err()
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        assert!(connect(stream, "server.test", &pinned).is_err());
+        assert!(server.join().unwrap());
+    }
+}
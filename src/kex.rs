@@ -0,0 +1,292 @@
+//! Typestate API for using the KEM as an (unauthenticated) two-party key
+//! exchange.
+//!
+//! The initiator's ephemeral private key lives inside an
+//! `Initiator<AwaitingReply>`, which is consumed by `finish`, so the compiler
+//! rejects reusing the ephemeral key or finishing the exchange twice.
+//!
+//! Each message starts with a versioned header and ends with the running
+//! transcript hash, which the receiver recomputes and checks.
+//!
+//! ```rust
+//! use hila5::kex::{Initiator, Responder};
+//!
+//! let (alice, msg1) = Initiator::start().unwrap();
+//! let (key_bob, msg2) = Responder::respond(&msg1).unwrap();
+//! let key_alice = alice.finish(&msg2).unwrap();
+//!
+//! assert_eq!(key_alice.0, key_bob.0);
+//! ```
+
+use ring::constant_time;
+use sha3::{Digest, Sha3_256};
+
+use std::io::Write;
+
+use super::*;
+use errors::*;
+
+/// Current version of the message format.
+pub const VERSION: u8 = 1;
+/// Length of the message header.
+pub const HEADER_LEN: usize = 4;
+/// Length of the transcript hash carried by each message.
+pub const TRANSCRIPT_LEN: usize = 32;
+
+const MAGIC: &[u8; 2] = b"H5";
+const KEX_LABEL: &[u8] = b"HILA5v10-KEX";
+
+/// Message types
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MsgType {
+    Init = 1,
+    Reply = 2,
+}
+
+/// Versioned message header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub version: u8,
+    pub msg_type: MsgType,
+}
+
+impl Header {
+    fn new(msg_type: MsgType) -> Self {
+        Header { version: VERSION, msg_type }
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[self.version, self.msg_type as u8])?;
+        Ok(())
+    }
+
+    /// Parse a header, checking it has the expected message type.
+    pub fn from_bytes(input: &[u8], expected: MsgType) -> Result<Self> {
+        if input.len() < HEADER_LEN || &input[..2] != MAGIC {
+            return Err("invalid key exchange header".into());
+        }
+        if input[2] != VERSION {
+            return Err(format!("unsupported key exchange version {}", input[2]).into());
+        }
+        if input[3] != expected as u8 {
+            return Err("unexpected key exchange message type".into());
+        }
+        Ok(Header::new(expected))
+    }
+}
+
+/// Initiator's message, carrying the ephemeral public key.
+pub struct Msg1 {
+    pub header: Header,
+    pub pk: PublicKey,
+    pub transcript: Vec<u8>,
+}
+
+/// Responder's message, carrying the encapsulated secret.
+pub struct Msg2 {
+    pub header: Header,
+    pub ct: Vec<u8>,
+    pub transcript: Vec<u8>,
+}
+
+impl Msg1 {
+    /// Length in bytes of the serialised message.
+    pub const LEN: usize = HEADER_LEN + PUBKEY_LEN + TRANSCRIPT_LEN;
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        self.header.write_to(writer)?;
+        self.pk.write_to(writer)?;
+        writer.write_all(&self.transcript)?;
+        Ok(())
+    }
+
+    /// Parse the message and check its transcript hash.
+    pub fn from_bytes(input: &[u8]) -> Result<Self> {
+        if input.len() != Self::LEN {
+            return Err("invalid length for key exchange message 1".into());
+        }
+        let header = Header::from_bytes(input, MsgType::Init)?;
+        let (body, transcript) = input.split_at(Self::LEN - TRANSCRIPT_LEN);
+        check_transcript(&[], body, transcript)?;
+        Ok(Msg1 {
+            header,
+            pk: PublicKey::from_bytes(&body[HEADER_LEN..]),
+            transcript: transcript.to_vec(),
+        })
+    }
+
+    fn body(&self) -> Result<Vec<u8>> {
+        let mut body = vec![];
+        self.header.write_to(&mut body)?;
+        self.pk.write_to(&mut body)?;
+        Ok(body)
+    }
+}
+
+impl Msg2 {
+    /// Length in bytes of the serialised message.
+    pub const LEN: usize = HEADER_LEN + CIPHERTEXT_LEN + TRANSCRIPT_LEN;
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        self.header.write_to(writer)?;
+        writer.write_all(&self.ct)?;
+        writer.write_all(&self.transcript)?;
+        Ok(())
+    }
+
+    /// Parse the message. The transcript hash is checked by the initiator on
+    /// `finish`, since it also covers `Msg1`.
+    pub fn from_bytes(input: &[u8]) -> Result<Self> {
+        if input.len() != Self::LEN {
+            return Err("invalid length for key exchange message 2".into());
+        }
+        let header = Header::from_bytes(input, MsgType::Reply)?;
+        let (body, transcript) = input.split_at(Self::LEN - TRANSCRIPT_LEN);
+        Ok(Msg2 {
+            header,
+            ct: body[HEADER_LEN..].to_vec(),
+            transcript: transcript.to_vec(),
+        })
+    }
+
+    fn body(&self) -> Result<Vec<u8>> {
+        let mut body = vec![];
+        self.header.write_to(&mut body)?;
+        body.write_all(&self.ct)?;
+        Ok(body)
+    }
+}
+
+mod private {
+    pub trait Sealed {}
+}
+
+/// States of the initiator.
+pub trait State: private::Sealed {}
+
+/// The initiator has sent `Msg1` and is waiting for the reply.
+pub struct AwaitingReply {
+    sk: PrivateKey,
+    transcript: Vec<u8>,
+}
+
+impl private::Sealed for AwaitingReply {}
+impl State for AwaitingReply {}
+
+/// Initiator of the key exchange, parameterised by its current state.
+pub struct Initiator<S: State> {
+    state: S,
+}
+
+impl Initiator<AwaitingReply> {
+    /// Generate an ephemeral keypair, and the first message to send to the
+    /// responder.
+    pub fn start() -> Result<(Self, Msg1)> {
+        let (pk, sk) = crypto_kem_keypair()?;
+        let mut msg1 = Msg1 {
+            header: Header::new(MsgType::Init),
+            pk,
+            transcript: vec![],
+        };
+        msg1.transcript = transcript_hash(&[], &msg1.body()?);
+        let initiator = Initiator {
+            state: AwaitingReply { sk, transcript: msg1.transcript.clone() },
+        };
+        Ok((initiator, msg1))
+    }
+
+    /// Consume the responder's reply, producing the session key.
+    pub fn finish(self, msg2: &Msg2) -> Result<SessionKey> {
+        let AwaitingReply { sk, transcript } = self.state;
+        check_transcript(&transcript, &msg2.body()?, &msg2.transcript)?;
+        let ss = sk.dec(&msg2.ct)?;
+        Ok(session_key(&msg2.transcript, &ss))
+    }
+}
+
+/// Responder of the key exchange.
+pub struct Responder;
+
+impl Responder {
+    /// Encapsulate to the initiator's ephemeral key, producing the session
+    /// key and the reply.
+    pub fn respond(msg1: &Msg1) -> Result<(SessionKey, Msg2)> {
+        check_transcript(&[], &msg1.body()?, &msg1.transcript)?;
+
+        let (ct, ss) = msg1.pk.enc()?;
+        let mut msg2 = Msg2 {
+            header: Header::new(MsgType::Reply),
+            ct,
+            transcript: vec![],
+        };
+        msg2.transcript = transcript_hash(&msg1.transcript, &msg2.body()?);
+        Ok((session_key(&msg2.transcript, &ss), msg2))
+    }
+}
+
+/// Extend the transcript hash `prev` with a message body.
+fn transcript_hash(prev: &[u8], body: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha3_256::default();
+    hasher.input(KEX_LABEL);
+    hasher.input(prev);
+    hasher.input(body);
+    hasher.result().to_vec()
+}
+
+fn check_transcript(prev: &[u8], body: &[u8], transcript: &[u8]) -> Result<()> {
+    constant_time::verify_slices_are_equal(&transcript_hash(prev, body), transcript)
+        .map_err(|_| "key exchange transcript mismatch".into())
+}
+
+fn session_key(transcript: &[u8], ss: &SharedSecret) -> SessionKey {
+    let mut hasher = Sha3_256::default();
+    hasher.input(KEX_LABEL);
+    hasher.input(transcript);
+    hasher.input(&ss.0);
+    SessionKey(hasher.result().to_vec())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn kex_agrees() {
+        let (alice, msg1) = Initiator::start().unwrap();
+        let mut buf = vec![];
+        msg1.write_to(&mut buf).unwrap();
+        let msg1 = Msg1::from_bytes(&buf).unwrap();
+
+        let (key_b, msg2) = Responder::respond(&msg1).unwrap();
+        buf.clear();
+        msg2.write_to(&mut buf).unwrap();
+        let msg2 = Msg2::from_bytes(&buf).unwrap();
+
+        let key_a = alice.finish(&msg2).unwrap();
+        assert_eq!(key_a.0, key_b.0);
+    }
+
+    #[test]
+    fn rejects_bad_messages() {
+        let (alice, msg1) = Initiator::start().unwrap();
+        let mut buf = vec![];
+        msg1.write_to(&mut buf).unwrap();
+
+        // Wrong version and wrong message type
+        let mut bad = buf.clone();
+        bad[2] = VERSION + 1;
+        assert!(Msg1::from_bytes(&bad).is_err());
+        assert!(Msg2::from_bytes(&buf).is_err());
+
+        // Tampered public key
+        let mut bad = buf.clone();
+        bad[HEADER_LEN + 100] ^= 1;
+        assert!(Msg1::from_bytes(&bad).is_err());
+
+        // Reply to a different initiator
+        let (_, other) = Initiator::start().unwrap();
+        let (_, msg2) = Responder::respond(&other).unwrap();
+        assert!(alice.finish(&msg2).is_err());
+    }
+}
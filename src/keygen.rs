@@ -5,7 +5,9 @@
 // 2017-09-09  Markku-Juhani O. Saarinen <mjos@iki.fi>
 
 
+use digest::{Input, ExtendableOutput, XofReader};
 use ring::rand::SecureRandom;
+use sha3::Shake256;
 
 use std::io::Write;
 
@@ -18,6 +20,7 @@ use errors::*;
 ///
 /// Contains the seed to generate the generator, the generator itself (`g`), and the 
 /// public key (`A`).
+#[derive(Clone)]
 pub struct PublicKey {
     seed: [u8; rand::SEED_LEN],
     pub gen: NttVector,
@@ -29,9 +32,25 @@ pub struct PublicKey {
 /// Contains the `NttVector` key, and the hash of the
 /// public key (needed for API compatability, `crypto_kem_dec` does not take PK
 /// as input).
+#[derive(Clone)]
 pub struct PrivateKey {
     key: NttVector,
     pub pk_digest: Vec<u8>,
+    /// The public key, when it is known (keys regenerated from a seed).
+    public: Option<PublicKey>,
+}
+
+/// Compact Hila5 private key: a 32 byte seed from which the whole keypair is
+/// regenerated, and optionally a secret for implicit rejection.
+///
+/// The expanded `PrivateKey` cannot be turned back into a seed, so keep the
+/// seed form if it may be needed later. Regenerating the keypair costs about
+/// as much as key generation, so callers that decapsulate often should keep
+/// the expanded key from `keypair` around.
+#[derive(Clone)]
+pub struct SeedPrivateKey {
+    seed: [u8; SEED_PRIVKEY_LEN],
+    rejection: Option<[u8; SEED_PRIVKEY_LEN]>,
 }
 
 impl PublicKey {
@@ -51,6 +70,18 @@ impl PublicKey {
         encode::pack14(&self.key, writer)
     }
 
+    /// Seed from which the generator `g` is derived.
+    pub fn generator_seed(&self) -> &[u8] {
+        &self.seed
+    }
+
+    /// SHA3 digest of the serialised public key.
+    pub fn digest(&self) -> Result<Vec<u8>> {
+        let mut pk_bytes = vec![];
+        self.write_to(&mut pk_bytes)?;
+        Ok(sha3(&pk_bytes))
+    }
+
     pub fn enc(&self) -> Result<(Vec<u8>, SharedSecret)> {
         kem::enc(&self)
     }
@@ -63,7 +94,13 @@ impl PrivateKey {
         let key = encode::unpack14(&input[..PACKED14]);
         let mut pk_digest = vec![];
         pk_digest.extend_from_slice(&input[PACKED14..]);
-        Self { key, pk_digest }
+        Self { key, pk_digest, public: None }
+    }
+
+    /// The public key, if this key was regenerated from a `SeedPrivateKey`.
+    /// Keys loaded from the expanded layout only keep its digest.
+    pub fn public_key(&self) -> Option<&PublicKey> {
+        self.public.as_ref()
     }
 
     /// Write the serialised private key to the `writer`.
@@ -74,24 +111,125 @@ impl PrivateKey {
     }
 
     pub fn get_shared_secret(&self, b: &NttVector) -> Vector {
-        let a = &self.key * b;
-        let mut ss = if cfg!(feature = "opt") {
-            // We get an extra 3^2 factor from these methods, so need to
-            // clear9545 = 3^-8
-            let mut a = arith::intt(a, 9545);
-            #[cfg(feature = "opt")]
-            arith::two_reduce12289(&mut a);
-            a
+        ring_product(&self.key, b)
+    }
+
+    /// The secret `a`, in the NTT domain.
+    pub(crate) fn secret(&self) -> &NttVector {
+        &self.key
+    }
+
+    pub fn dec(&self, ct: &[u8]) -> Result<SharedSecret> {
+        kem::dec(&ct, self)
+    }
+}
+
+/// The product `key * b`, out of the NTT domain and normalised.
+pub(crate) fn ring_product(key: &NttVector, b: &NttVector) -> Vector {
+    let a = key * b;
+    let mut ss = if cfg!(feature = "opt") {
+        // We get an extra 3^2 factor from these methods, so need to
+        // clear9545 = 3^-8
+        let mut a = arith::intt(a, 9545);
+        #[cfg(feature = "opt")]
+        arith::two_reduce12289(&mut a);
+        a
+    } else {
+        // Need to clear 3^6 factor; 12171 = 3^-6
+        arith::intt(a, 12_171)
+    };
+    ss.norm();
+    ss
+}
+
+impl SeedPrivateKey {
+    /// Generate a fresh seed, with a rejection secret if
+    /// `implicit_rejection` is set.
+    pub fn generate(implicit_rejection: bool) -> Result<Self> {
+        let rng = get_rng();
+        let mut seed = [0u8; SEED_PRIVKEY_LEN];
+        rng.fill(&mut seed)?;
+        let rejection = if implicit_rejection {
+            let mut rejection = [0u8; SEED_PRIVKEY_LEN];
+            rng.fill(&mut rejection)?;
+            Some(rejection)
         } else {
-            // Need to clear 3^6 factor; 12171 = 3^-6
-            arith::intt(a, 12_171)
+            None
+        };
+        Ok(Self { seed, rejection })
+    }
+
+    /// Parse the 32 byte seed, optionally followed by the 32 byte rejection
+    /// secret.
+    pub fn from_bytes(input: &[u8]) -> Result<Self> {
+        let mut seed = [0u8; SEED_PRIVKEY_LEN];
+        let rejection = match input.len() {
+            SEED_PRIVKEY_LEN => None,
+            len if len == 2 * SEED_PRIVKEY_LEN => {
+                let mut rejection = [0u8; SEED_PRIVKEY_LEN];
+                rejection.copy_from_slice(&input[SEED_PRIVKEY_LEN..]);
+                Some(rejection)
+            }
+            _ => return Err("seed private key must be 32 or 64 bytes".into()),
         };
-        ss.norm();
-        ss
+        seed.copy_from_slice(&input[..SEED_PRIVKEY_LEN]);
+        Ok(Self { seed, rejection })
+    }
+
+    /// Write the seed, followed by the rejection secret if there is one.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&self.seed)?;
+        if let Some(ref rejection) = self.rejection {
+            writer.write_all(rejection)?;
+        }
+        Ok(())
+    }
+
+    pub fn has_rejection_secret(&self) -> bool {
+        self.rejection.is_some()
+    }
+
+    /// Regenerate the keypair. The returned `PrivateKey` knows its public
+    /// key.
+    pub fn keypair(&self) -> Result<(PublicKey, PrivateKey)> {
+        keypair_from_seed(&self.seed)
     }
 
+    pub fn public_key(&self) -> Result<PublicKey> {
+        Ok(self.keypair()?.0)
+    }
+
+    /// The private key in the expanded NIST layout, as written by
+    /// `PrivateKey::write_to` and read by `crypto_kem_dec`.
+    pub fn to_expanded(&self) -> Result<Vec<u8>> {
+        let mut expanded = Vec::with_capacity(PRIVKEY_LEN);
+        self.keypair()?.1.write_to(&mut expanded)?;
+        Ok(expanded)
+    }
+
+    /// Check that `expanded`, in the NIST layout, is the key regenerated
+    /// from this seed.
+    pub fn matches_expanded(&self, expanded: &[u8]) -> Result<bool> {
+        Ok(expanded.len() == PRIVKEY_LEN && self.to_expanded()? == expanded)
+    }
+
+    /// Decapsulate `ct`. With a rejection secret, a ciphertext that fails to
+    /// decapsulate yields a pseudorandom shared secret derived from the
+    /// secret and `ct`, rather than an error. The failure path is not
+    /// constant time.
     pub fn dec(&self, ct: &[u8]) -> Result<SharedSecret> {
-        kem::dec(&ct, self)
+        if ct.len() != CIPHERTEXT_LEN {
+            return Err("invalid ciphertext length".into());
+        }
+        match (self.keypair()?.1.dec(ct), self.rejection) {
+            (Err(_), Some(rejection)) => {
+                let mut input = b"HILA5 reject".to_vec();
+                input.extend_from_slice(&rejection);
+                input.extend_from_slice(ct);
+                Ok(SharedSecret(sha3(&input)))
+            }
+            (result, _) => result,
+        }
     }
 }
 
@@ -100,11 +238,51 @@ impl PrivateKey {
 pub fn crypto_kem_keypair() -> Result<(PublicKey, PrivateKey)> {
     let rng = get_rng();
 
-    let mut a = arith::ntt(rand::psi16());
-    let e = arith::ntt(rand::psi16());
+    let a = rand::psi16();
+    let e = rand::psi16();
     let mut seed = [0u8; rand::SEED_LEN];
     rng.fill(&mut seed)?;
 
+    keypair_from_parts(a, e, seed)
+}
+
+/// Generate a keypair with its private key in seed form.
+pub fn crypto_kem_seed_keypair(implicit_rejection: bool) -> Result<(PublicKey, SeedPrivateKey)> {
+    let sk = SeedPrivateKey::generate(implicit_rejection)?;
+    Ok((sk.public_key()?, sk))
+}
+
+/// Generate a keypair whose generator is derived from `seed`, rather than a
+/// fresh random seed.
+pub(crate) fn keypair_with_generator(seed: [u8; rand::SEED_LEN]) -> Result<(PublicKey, PrivateKey)> {
+    keypair_from_parts(rand::psi16(), rand::psi16(), seed)
+}
+
+/// Deterministically derive a keypair from `seed`, which should be at least
+/// 32 bytes of uniformly random data.
+///
+/// The generator seed, `a` and `e` are read in that order from
+/// `SHAKE256("HILA5 keypair" || seed)`.
+pub(crate) fn keypair_from_seed(seed: &[u8]) -> Result<(PublicKey, PrivateKey)> {
+    let mut hasher = Shake256::default();
+    hasher.process(b"HILA5 keypair");
+    hasher.process(seed);
+    let mut xof = hasher.xof_result();
+
+    let mut gen_seed = [0u8; rand::SEED_LEN];
+    xof.read(&mut gen_seed);
+    let a = rand::psi16_from_xof(&mut xof);
+    let e = rand::psi16_from_xof(&mut xof);
+    let (pk, mut sk) = keypair_from_parts(a, e, gen_seed)?;
+    sk.public = Some(pk.clone());
+    Ok((pk, sk))
+}
+
+/// Build a keypair from the secret `a`, noise `e` and the generator seed.
+fn keypair_from_parts(a: Vector, e: Vector, seed: [u8; rand::SEED_LEN]) -> Result<(PublicKey, PrivateKey)> {
+    let mut a = arith::ntt(a);
+    let e = arith::ntt(e);
+
     let g: NttVector = rand::from_seed(&seed);
     // t = g * a + e
     let mut t = arith::mul_add(&g, &a, &e);
@@ -130,6 +308,75 @@ pub fn crypto_kem_keypair() -> Result<(PublicKey, PrivateKey)> {
         PrivateKey {
             key: a,
             pk_digest: pk_digest,
+            public: None,
         }
     ))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn seed_keys_regenerate() {
+        let (pk, seed) = crypto_kem_seed_keypair(false).unwrap();
+        let mut bytes = vec![];
+        seed.write_to(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), SEED_PRIVKEY_LEN);
+
+        let loaded = SeedPrivateKey::from_bytes(&bytes).unwrap();
+        let (pk2, sk) = loaded.keypair().unwrap();
+        assert_eq!(pk2.digest().unwrap(), pk.digest().unwrap());
+        assert_eq!(sk.public_key().unwrap().digest().unwrap(), sk.pk_digest);
+
+        let (ct, ss) = pk.enc().unwrap();
+        assert_eq!(loaded.dec(&ct).unwrap().0, ss.0);
+        assert_eq!(sk.dec(&ct).unwrap().0, ss.0);
+        assert!(SeedPrivateKey::from_bytes(&bytes[..31]).is_err());
+    }
+
+    #[test]
+    fn expanded_layout() {
+        let seed = SeedPrivateKey::generate(true).unwrap();
+        let expanded = seed.to_expanded().unwrap();
+        assert_eq!(expanded.len(), PRIVKEY_LEN);
+        assert!(seed.matches_expanded(&expanded).unwrap());
+        assert!(!SeedPrivateKey::generate(false).unwrap().matches_expanded(&expanded).unwrap());
+
+        // the expanded key works with the NIST API, but no longer knows its
+        // public key
+        let pk = seed.public_key().unwrap();
+        let mut pk_bytes = vec![];
+        pk.write_to(&mut pk_bytes).unwrap();
+        let (ct, ss) = ::crypto_kem_enc(&pk_bytes).unwrap();
+        assert_eq!(::crypto_kem_dec(&expanded, &ct).unwrap(), ss);
+        assert!(PrivateKey::from_bytes(&expanded).public_key().is_none());
+    }
+
+    #[test]
+    fn implicit_rejection() {
+        let (pk, seed) = crypto_kem_seed_keypair(true).unwrap();
+        let mut bytes = vec![];
+        seed.write_to(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), 2 * SEED_PRIVKEY_LEN);
+        assert!(SeedPrivateKey::from_bytes(&bytes).unwrap().has_rejection_secret());
+
+        // scramble the reconciliation data until decapsulation fails
+        let (_, sk) = seed.keypair().unwrap();
+        let (valid, _) = pk.enc().unwrap();
+        let ct = (1..=255u8).map(|mask| {
+            let mut ct = valid.clone();
+            for b in ct[PACKED14..].iter_mut() {
+                *b ^= mask;
+            }
+            ct
+        }).find(|ct| sk.dec(ct).is_err()).expect("a scrambled ciphertext fails");
+
+        let rejected = seed.dec(&ct).unwrap();
+        assert_eq!(rejected.0, seed.dec(&ct).unwrap().0);
+        let (_, other) = crypto_kem_seed_keypair(true).unwrap();
+        assert_ne!(other.dec(&ct).unwrap().0, rejected.0);
+        assert!(SeedPrivateKey::from_bytes(&bytes[..SEED_PRIVKEY_LEN]).unwrap().dec(&ct).is_err());
+        assert!(seed.dec(&ct[1..]).is_err());
+    }
+}
@@ -39,34 +39,102 @@
 //! specify the optimised NTT methods based on
 //! Microsoft's [LatticeCrypto](https://www.microsoft.com/en-us/research/project/lattice-cryptography-library/)
 //!
+//! The `tokio` feature enables the `channel` module, an encrypted stream over
+//! any `tokio` `AsyncRead + AsyncWrite`.
+//!
+//! The `rustls` feature enables the `tls` module, providing pure and hybrid
+//! HILA5 key exchange groups for TLS 1.3.
+//!
 //! The `kat` feature is used to run the KAT tests, and uses a seeded RNG for
 //! predictable outputs. Do not use this feature other than for testing.
 
+extern crate aes;
+extern crate aes_kw;
+extern crate base64;
 extern crate byteorder;
 extern crate digest;
+extern crate ed25519_dalek;
 #[macro_use]
 extern crate error_chain;
-#[cfg(not(feature = "opt"))]
 #[macro_use]
 extern crate lazy_static;
 extern crate sha3;
 extern crate ring;
+extern crate x25519_dalek;
+#[cfg(feature = "rustls")]
+extern crate rustls;
+#[cfg(feature = "tokio")]
+extern crate tokio;
+#[cfg(all(test, feature = "rustls"))]
+extern crate rcgen;
 
 use sha3::{Digest, Sha3_256};
 
+/// Authenticated key exchange from static and ephemeral keys.
+pub mod ake;
 #[cfg(not(feature = "opt"))]
 mod arith;
+/// Bech32m encodings of public keys and key fingerprints.
+pub mod bech32;
+/// Encrypted streams over `tokio` I/O, keyed by a HILA5 handshake.
+#[cfg(feature = "tokio")]
+pub mod channel;
+/// CMS `EnvelopedData` with `KEMRecipientInfo` recipients (RFC 9629).
+pub mod cms;
+/// COSE_Key encoding and COSE_Encrypt key establishment with HILA5.
+pub mod cose;
 mod ecc;
 mod encode;
+/// Hierarchical deterministic derivation of keys from a master seed.
+pub mod hd;
+/// HILA5 as an additional key exchange in IKEv2 (RFC 9370).
+pub mod ikev2;
+/// JSON Web Keys and JWE key establishment with HILA5.
+pub mod jose;
 /// Key encapsulation/decapsulation methods.
 pub mod kem;
+/// KEMTLS-style handshake, authenticating the server with a HILA5 key.
+pub mod kemtls;
+/// Typestate API for an unauthenticated two-party key exchange.
+pub mod kex;
 mod keygen;
+/// Multi-recipient KEM sharing the ephemeral part between recipients.
+pub mod mkem;
+/// Mnemonic word-list backups of root key seeds.
+pub mod mnemonic;
+/// Hybrid ntor circuit-extension handshake with X25519 and HILA5.
+pub mod ntor;
+/// OpenPGP encryption subkeys and session key packets for HILA5.
+pub mod openpgp;
+/// OpenSSH-style single-line encoding of public keys.
+pub mod openssh;
 #[cfg(feature = "opt")]
 mod opt;
 #[cfg(feature = "opt")]
 use opt::arith;
+/// 1-out-of-2 oblivious transfer from HILA5 public keys.
+pub mod ot;
+/// Password-authenticated key exchange on the HILA5 ring.
+pub mod pake;
+/// PQXDH-style asynchronous initial key agreement with HILA5 prekeys.
+pub mod pqxdh;
 mod rand;
+/// Post-quantum ratchet for long-lived sessions.
+pub mod ratchet;
 mod recon;
+/// Shamir secret sharing of private keys for m-of-n recovery.
+pub mod shamir;
+/// SSH key exchange methods using HILA5, alone or hybridised with X25519.
+pub mod ssh;
+/// Threshold decapsulation with the ring secret shared among parties.
+pub mod threshold;
+/// HILA5 key exchange groups for `rustls`.
+#[cfg(feature = "rustls")]
+pub mod tls;
+/// TreeKEM-style group key agreement on HILA5.
+pub mod treekem;
+/// Post-quantum preshared keys for WireGuard.
+pub mod wireguard;
 
 
 /// Error handling and conversion
@@ -79,15 +147,30 @@ pub mod errors {
             Io(io::Error);
             Ring(ring::error::Unspecified) #[doc = "Errors originating from `ring`"];
         }
+
+        errors {
+            /// A serialised key is malformed.
+            KeyFormat(reason: String) {
+                description("invalid key encoding")
+                display("invalid key encoding: {}", reason)
+            }
+        }
+    }
+
+    /// A `KeyFormat` error for `reason`.
+    pub(crate) fn format_error<S: Into<String>>(reason: S) -> Error {
+        ErrorKind::KeyFormat(reason.into()).into()
     }
 }
 
 use errors::*;
 
 #[doc(inline)]
-pub use keygen::{crypto_kem_keypair, PrivateKey, PublicKey};
+pub use keygen::{crypto_kem_keypair, crypto_kem_seed_keypair, PrivateKey, PublicKey, SeedPrivateKey};
 #[doc(inline)]
 pub use kem::SharedSecret;
+#[doc(inline)]
+pub use ake::SessionKey;
 
 /// Key encapsulation
 pub fn crypto_kem_enc(pk: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
@@ -110,6 +193,9 @@ pub const HILA5_Q: i32 = 12_289;
 pub const PUBKEY_LEN: usize = rand::SEED_LEN + encode::PACKED14;
 /// Length in bytes of `PrivateKey` on calling `write_to`.
 pub const PRIVKEY_LEN: usize = encode::PACKED14 + 32;
+/// Length in bytes of the seed of a `SeedPrivateKey`, which is followed by a
+/// rejection secret of the same length if it has one.
+pub const SEED_PRIVKEY_LEN: usize = 32;
 /// Output ciphertext len from `kem::dec`
 pub const CIPHERTEXT_LEN: usize = encode::PACKED14 + (HILA5_N / 8) + recon::PAYLOAD_LEN + recon::ECC_LEN;
 
@@ -117,6 +203,7 @@ pub type Scalar = i32;
 /// Standard vector type
 pub struct Vector([Scalar; HILA5_N]);
 /// Vector mapped under the NTT transform.
+#[derive(Clone)]
 pub struct NttVector([Scalar; HILA5_N]);
 
 use std::fmt;
@@ -189,6 +276,28 @@ fn sha3(input: &[u8]) -> Vec<u8> {
     hasher.result().to_vec()
 }
 
+/// Length of X25519 keys and shared secrets.
+const X25519_LEN: usize = 32;
+
+/// Generate an X25519 secret from the crate's random number generator.
+fn x25519_secret() -> Result<x25519_dalek::StaticSecret> {
+    use ring::rand::SecureRandom;
+    let mut bytes = [0u8; X25519_LEN];
+    get_rng().fill(&mut bytes)?;
+    Ok(x25519_dalek::StaticSecret::from(bytes))
+}
+
+/// X25519 with the peer's public key `peer`, rejecting low order points.
+fn x25519_agree(secret: &x25519_dalek::StaticSecret, peer: &[u8]) -> Result<Vec<u8>> {
+    let mut bytes = [0u8; X25519_LEN];
+    bytes.copy_from_slice(peer);
+    let shared = secret.diffie_hellman(&x25519_dalek::PublicKey::from(bytes));
+    if !shared.was_contributory() {
+        return Err("X25519 produced an all-zero shared secret".into());
+    }
+    Ok(shared.as_bytes().to_vec())
+}
+
 #[cfg(not(all(feature = "kat", test)))]
 fn get_rng() -> ring::rand::SystemRandom {
     ring::rand::SystemRandom::new()
@@ -0,0 +1,323 @@
+//! Multi-recipient KEM, sharing the ephemeral part of the ciphertext between
+//! many recipients.
+//!
+//! A HILA5 ciphertext for the public key `A = g*a + e_A` is the packed
+//! ephemeral value `B = g*b + e` followed by the reconciliation information
+//! and ECC tail computed from `A*b`. When all recipients use the same
+//! generator `g`, the sender can sample `b` and `e` once, compute `B` once,
+//! and only compute `A_i*b`, the reconciliation information and the ECC tail
+//! for each recipient `i`. This saves a noise sample, a forward NTT, a
+//! multiply-add and the packing of `B` per recipient.
+//!
+//! A `BatchCiphertext` is encoded as
+//!
+//! ```text
+//! u32 count (big endian) || B || entry 1 || ... || entry count
+//! entry = 0x00 || recipient part  |  0x01 || standalone ciphertext
+//! ```
+//!
+//! For a recipient part, `B || recipient part i` is an ordinary HILA5
+//! ciphertext for recipient `i`. A standalone entry is a whole HILA5
+//! ciphertext from `kem::enc`, used when the shared `b` fails the safe-bit
+//! selection for that recipient. Either way `ciphertext_for` gives a
+//! ciphertext which can be passed to `kem::dec` directly. Recipients use keys
+//! from `keypair`, which derives the generator from a seed shared by the group.
+//!
+//! # Security
+//!
+//! Reusing encryption randomness across recipients is the setting of
+//! Bellare, Boldyreva and Staddon, "Randomness Re-use in Multi-recipient
+//! Encryption Schemes" (PKC 2003), and of Katsumata, Kwiatkowski, Pintore and
+//! Prest, "Scalable Ciphertext Compression Techniques for Post-Quantum KEMs
+//! and their Applications" (ASIACRYPT 2020), whose argument for lattice KEMs
+//! applies here:
+//!
+//! * Each recipient sees exactly the distribution of a single-recipient
+//!   HILA5 ciphertext, so a single ciphertext is as secure as HILA5 itself.
+//! * For the whole batch, first replace each public key `A_i` by a uniform
+//!   ring element. This is one Ring-LWE instance per recipient, all with the
+//!   shared `g`, costing a factor `N` in the advantage. The batch is then a
+//!   function of `(g, A_1, ..., A_N, g*b + e, A_1*b, ..., A_N*b)` with uniform
+//!   `g` and `A_i`: Ring-LWE with the single secret `b` and `N + 1` samples,
+//!   rather than the two samples of single-recipient HILA5. The per-recipient
+//!   reconciliation is applied to each `A_i*b` independently, as in HILA5.
+//! * The shared generator is essential. Recipients with different
+//!   generators cannot share `B`, and `enc` refuses them. It is not a
+//!   weakness: single-recipient HILA5 already works with any fixed `g`, and
+//!   the group seed plays the role of a system parameter.
+//! * The shared `b` is sampled once and never resampled. A recipient for
+//!   whom `A_i*b` fails the safe-bit selection gets a standalone `kem::enc`
+//!   ciphertext with its own fresh `b` instead. Resampling `b` until it
+//!   suits every recipient would condition it on `N` events at once, and the
+//!   chance of needing a retry, and with it the bias, grows with `N`. With
+//!   the fallback, a recipient part is conditioned only on that recipient's
+//!   own success, exactly as in `kem::enc`.
+//! * Which entries are standalone is public, and each one reveals that
+//!   `A_i*b` failed the selection for that recipient: one bit about `b` per
+//!   fallback. Failures are rare, so in a large batch this is a few bits, but
+//!   it is not zero, and the argument above holds only up to this leakage.
+//!
+//! As with `kem`, this is only passively (IND-CPA) secure: there is no
+//! Fujisaki-Okamoto transform, so secret keys must not be used to decapsulate
+//! ciphertexts chosen by an active attacker. A fresh `b` is sampled on every
+//! call, and never reused across batches. Each shared secret is the HILA5
+//! shared secret for that recipient's own ciphertext, so it does not bind
+//! the parts sent to other recipients, and a recipient learns nothing about
+//! whether others received the same key.
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use std::io::{Read, Write};
+
+use super::*;
+use encode::PACKED14;
+use errors::*;
+use kem::RECIPIENT_LEN;
+
+/// Length of the seed shared by a group of recipients.
+pub const GROUP_SEED_LEN: usize = rand::SEED_LEN;
+
+/// Generate a keypair for the group whose generator is derived from `group`.
+///
+/// The keypair is an ordinary HILA5 keypair, usable with `kem` as well.
+pub fn keypair(group: &[u8; GROUP_SEED_LEN]) -> Result<(PublicKey, PrivateKey)> {
+    keygen::keypair_with_generator(*group)
+}
+
+const SHARED: u8 = 0;
+const STANDALONE: u8 = 1;
+
+/// The entry of a single recipient in a `BatchCiphertext`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Entry {
+    /// Recipient part to append to the shared `B`.
+    Shared(Vec<u8>),
+    /// Whole ciphertext, for when the shared `b` did not suit the recipient.
+    Standalone(Vec<u8>),
+}
+
+impl Entry {
+    fn encoded_len(&self) -> usize {
+        match *self {
+            Entry::Shared(_) => 1 + RECIPIENT_LEN,
+            Entry::Standalone(_) => 1 + CIPHERTEXT_LEN,
+        }
+    }
+}
+
+/// Ciphertext encapsulating a shared secret for each of several recipients.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BatchCiphertext {
+    ephemeral: Vec<u8>,
+    recipients: Vec<Entry>,
+}
+
+impl BatchCiphertext {
+    /// Number of recipients.
+    pub fn len(&self) -> usize {
+        self.recipients.len()
+    }
+
+    /// Whether there are no recipients.
+    pub fn is_empty(&self) -> bool {
+        self.recipients.is_empty()
+    }
+
+    /// The single-recipient HILA5 ciphertext for recipient `index`.
+    pub fn ciphertext_for(&self, index: usize) -> Option<Vec<u8>> {
+        self.recipients.get(index).map(|entry| match *entry {
+            Entry::Shared(ref part) => {
+                let mut ct = Vec::with_capacity(CIPHERTEXT_LEN);
+                ct.extend_from_slice(&self.ephemeral);
+                ct.extend_from_slice(part);
+                ct
+            }
+            Entry::Standalone(ref ct) => ct.clone(),
+        })
+    }
+
+    /// Length of the encoded batch ciphertext.
+    pub fn encoded_len(&self) -> usize {
+        4 + PACKED14 + self.recipients.iter().map(Entry::encoded_len).sum::<usize>()
+    }
+
+    /// Write the encoded batch ciphertext to the `writer`.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_u32::<BigEndian>(self.recipients.len() as u32)?;
+        writer.write_all(&self.ephemeral)?;
+        for entry in &self.recipients {
+            match *entry {
+                Entry::Shared(ref part) => {
+                    writer.write_u8(SHARED)?;
+                    writer.write_all(part)?;
+                }
+                Entry::Standalone(ref ct) => {
+                    writer.write_u8(STANDALONE)?;
+                    writer.write_all(ct)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Parse an encoded batch ciphertext, which must make up all of `input`.
+    pub fn from_bytes(mut input: &[u8]) -> Result<Self> {
+        let count = input.read_u32::<BigEndian>()? as usize;
+        // every entry takes at least a tag and a recipient part
+        if count == 0 || input.len() < PACKED14 + count * (1 + RECIPIENT_LEN) {
+            return Err("invalid batch ciphertext length".into());
+        }
+        let mut ephemeral = vec![0u8; PACKED14];
+        input.read_exact(&mut ephemeral)?;
+
+        let mut recipients = Vec::with_capacity(count);
+        for _ in 0..count {
+            let (len, standalone) = match input.read_u8()? {
+                SHARED => (RECIPIENT_LEN, false),
+                STANDALONE => (CIPHERTEXT_LEN, true),
+                _ => return Err("invalid batch ciphertext entry".into()),
+            };
+            if input.len() < len {
+                return Err("invalid batch ciphertext length".into());
+            }
+            let (bytes, rest) = input.split_at(len);
+            input = rest;
+            recipients.push(if standalone {
+                Entry::Standalone(bytes.to_vec())
+            } else {
+                Entry::Shared(bytes.to_vec())
+            });
+        }
+        if !input.is_empty() {
+            return Err("invalid batch ciphertext length".into());
+        }
+        Ok(Self { ephemeral, recipients })
+    }
+}
+
+/// Encapsulate a shared secret for each of the public keys `pks`, which must
+/// all have the same generator.
+///
+/// The shared secrets are returned in the same order as `pks`.
+pub fn enc(pks: &[&PublicKey]) -> Result<(BatchCiphertext, Vec<SharedSecret>)> {
+    let first = match pks.first() {
+        Some(pk) => pk,
+        None => return Err("no recipients".into()),
+    };
+    if pks.iter().any(|pk| pk.generator_seed() != first.generator_seed()) {
+        return Err("recipients do not share a generator".into());
+    }
+
+    let b = arith::ntt(rand::psi16());
+    let ephemeral = kem::ephemeral_part(&first.gen, &b)?;
+    let mut recipients = Vec::with_capacity(pks.len());
+    let mut secrets = Vec::with_capacity(pks.len());
+    let mut ct = ephemeral.clone();
+    for pk in pks {
+        match kem::recipient_part(&pk.key, &b)? {
+            Some((part, payload)) => {
+                ct.truncate(PACKED14);
+                ct.extend_from_slice(&part);
+                secrets.push(kem::shared_secret(pk, &ct, &payload)?);
+                recipients.push(Entry::Shared(part));
+            }
+            None => {
+                // never resample the shared b, see the module docs
+                let (standalone, secret) = kem::enc(pk)?;
+                secrets.push(secret);
+                recipients.push(Entry::Standalone(standalone));
+            }
+        }
+    }
+
+    Ok((BatchCiphertext { ephemeral, recipients }, secrets))
+}
+
+/// Decapsulate the shared secret of recipient `index` from `batch`.
+pub fn dec(batch: &BatchCiphertext, index: usize, sk: &PrivateKey) -> Result<SharedSecret> {
+    let ct = batch.ciphertext_for(index).ok_or("no such recipient")?;
+    kem::dec(&ct, sk)
+}
+
+#[cfg(test)]
+mod test {
+    use ring::rand::SecureRandom;
+
+    use super::*;
+
+    fn group(n: usize) -> Vec<(PublicKey, PrivateKey)> {
+        let mut seed = [0u8; GROUP_SEED_LEN];
+        get_rng().fill(&mut seed).unwrap();
+        (0..n).map(|_| keypair(&seed).unwrap()).collect()
+    }
+
+    #[test]
+    fn roundtrip() {
+        let keys = group(5);
+        let pks: Vec<&PublicKey> = keys.iter().map(|(pk, _)| pk).collect();
+        let (batch, secrets) = enc(&pks).unwrap();
+        assert_eq!(batch.len(), 5);
+
+        for (i, (_, sk)) in keys.iter().enumerate() {
+            assert_eq!(dec(&batch, i, sk).unwrap().0, secrets[i].0);
+            // each recipient's ciphertext is a plain HILA5 ciphertext
+            let ct = batch.ciphertext_for(i).unwrap();
+            assert_eq!(ct.len(), CIPHERTEXT_LEN);
+            assert_eq!(kem::dec(&ct, sk).unwrap().0, secrets[i].0);
+        }
+        assert!(secrets[0].0 != secrets[1].0);
+        assert!(batch.ciphertext_for(5).is_none());
+    }
+
+    #[test]
+    fn encoding() {
+        let keys = group(3);
+        let pks: Vec<&PublicKey> = keys.iter().map(|(pk, _)| pk).collect();
+        let (batch, secrets) = enc(&pks).unwrap();
+
+        let mut bytes = vec![];
+        batch.write_to(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), batch.encoded_len());
+        let parsed = BatchCiphertext::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed, batch);
+        assert_eq!(dec(&parsed, 2, &keys[2].1).unwrap().0, secrets[2].0);
+
+        assert!(BatchCiphertext::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        bytes.push(0);
+        assert!(BatchCiphertext::from_bytes(&bytes).is_err());
+        assert!(BatchCiphertext::from_bytes(&[0, 0, 0, 0]).is_err());
+        bytes.pop();
+        bytes[4 + PACKED14] = 2;
+        assert!(BatchCiphertext::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn standalone_fallback() {
+        let keys = group(3);
+        let pks: Vec<&PublicKey> = keys.iter().map(|(pk, _)| pk).collect();
+        let (mut batch, mut secrets) = enc(&pks).unwrap();
+        // stand in for a recipient whose A*b failed the safe-bit selection
+        let (ct, secret) = kem::enc(&keys[1].0).unwrap();
+        batch.recipients[1] = Entry::Standalone(ct.clone());
+        secrets[1] = secret;
+
+        let mut bytes = vec![];
+        batch.write_to(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), batch.encoded_len());
+        assert_eq!(bytes.len(), 4 + PACKED14 + 3 + 2 * RECIPIENT_LEN + CIPHERTEXT_LEN);
+        let parsed = BatchCiphertext::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed, batch);
+        assert_eq!(parsed.ciphertext_for(1).unwrap(), ct);
+        for (i, (_, sk)) in keys.iter().enumerate() {
+            assert_eq!(dec(&parsed, i, sk).unwrap().0, secrets[i].0);
+        }
+    }
+
+    #[test]
+    fn requires_shared_generator() {
+        let keys = group(2);
+        let (other, _) = crypto_kem_keypair().unwrap();
+        assert!(enc(&[&keys[0].0, &other, &keys[1].0]).is_err());
+        assert!(enc(&[]).is_err());
+    }
+}
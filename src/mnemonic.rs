@@ -0,0 +1,224 @@
+//! Mnemonic backups of HILA5 root keys.
+//!
+//! A `Mnemonic` writes 32 bytes of entropy as 24 words from the BIP 39
+//! English wordlist, the last of which carries an 8 bit SHA-256 checksum.
+//! Restoring stretches the phrase and an optional passphrase into a 64 byte
+//! seed exactly as BIP 39 does (PBKDF2-HMAC-SHA512, 2048 iterations, salt
+//! `"mnemonic" || passphrase`), and that seed is the master secret of an
+//! `hd` derivation tree whose root drives deterministic HILA5 keygen.
+//!
+//! Phrases and passphrases are used as given; callers handling non-ASCII
+//! passphrases must apply NFKD normalization themselves.
+//!
+//! ```rust
+//! use hila5::mnemonic::Mnemonic;
+//!
+//! let backup = Mnemonic::generate().unwrap();
+//! let (pk, _) = backup.keypair("correct horse").unwrap();
+//!
+//! let restored: Mnemonic = backup.to_string().parse().unwrap();
+//! let (pk2, _) = restored.keypair("correct horse").unwrap();
+//! assert_eq!(pk.digest().unwrap(), pk2.digest().unwrap());
+//! ```
+
+use ring::rand::SecureRandom;
+use ring::{digest, pbkdf2};
+
+use std::fmt;
+use std::str::FromStr;
+
+use super::*;
+use errors::*;
+use hd::ExtendedSeed;
+
+/// Number of words in a phrase.
+pub const WORDS: usize = 24;
+/// PBKDF2 iterations used to stretch a phrase, as in BIP 39.
+pub const PBKDF2_ITERATIONS: u32 = 2048;
+/// Length of the stretched seed.
+pub const SEED_LEN: usize = 64;
+
+const ENTROPY_LEN: usize = SEED_PRIVKEY_LEN;
+const BITS_PER_WORD: usize = 11;
+const ENGLISH: &str = include_str!("mnemonic/english.txt");
+
+lazy_static! {
+    /// The BIP 39 English wordlist, in its sorted order.
+    static ref WORDLIST: Vec<&'static str> = ENGLISH.lines().collect();
+}
+
+/// 32 bytes of entropy that can be written down as 24 words.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Mnemonic {
+    entropy: [u8; ENTROPY_LEN],
+}
+
+impl Mnemonic {
+    /// Draw fresh entropy.
+    pub fn generate() -> Result<Self> {
+        let mut entropy = [0u8; ENTROPY_LEN];
+        get_rng().fill(&mut entropy)?;
+        Ok(Mnemonic { entropy })
+    }
+
+    pub fn from_entropy(entropy: &[u8]) -> Result<Self> {
+        if entropy.len() != ENTROPY_LEN {
+            return Err(format_error("mnemonic entropy must be 32 bytes"));
+        }
+        let mut mnemonic = Mnemonic { entropy: [0u8; ENTROPY_LEN] };
+        mnemonic.entropy.copy_from_slice(entropy);
+        Ok(mnemonic)
+    }
+
+    pub fn entropy(&self) -> &[u8] {
+        &self.entropy
+    }
+
+    /// The words of the phrase, checksum word last.
+    pub fn words(&self) -> Vec<&'static str> {
+        let mut bits = self.entropy.to_vec();
+        bits.push(checksum(&self.entropy));
+        (0..WORDS).map(|i| WORDLIST[read_bits(&bits, i * BITS_PER_WORD)]).collect()
+    }
+
+    /// The BIP 39 seed for `passphrase`.
+    pub fn to_seed(&self, passphrase: &str) -> [u8; SEED_LEN] {
+        let salt = format!("mnemonic{}", passphrase);
+        let mut seed = [0u8; SEED_LEN];
+        pbkdf2::derive(&digest::SHA512, PBKDF2_ITERATIONS, salt.as_bytes(),
+                       self.to_string().as_bytes(), &mut seed);
+        seed
+    }
+
+    /// The root of the derivation tree for `passphrase`.
+    pub fn master(&self, passphrase: &str) -> ExtendedSeed {
+        ExtendedSeed::master(&self.to_seed(passphrase))
+            .expect("BIP 39 seeds are a valid master length")
+    }
+
+    /// Restore the root keypair for `passphrase`.
+    pub fn keypair(&self, passphrase: &str) -> Result<(PublicKey, PrivateKey)> {
+        self.master(passphrase).private_key().keypair()
+    }
+}
+
+impl fmt::Display for Mnemonic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.words().join(" "))
+    }
+}
+
+impl fmt::Debug for Mnemonic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Mnemonic(..)")
+    }
+}
+
+impl FromStr for Mnemonic {
+    type Err = Error;
+
+    /// Parse a phrase separated by any whitespace, ignoring case.
+    fn from_str(s: &str) -> Result<Self> {
+        let words = s.split_whitespace().collect::<Vec<_>>();
+        if words.len() != WORDS {
+            let reason = format!("mnemonic has {} words, expected {}", words.len(), WORDS);
+            return Err(format_error(reason));
+        }
+        let mut bits = [0u8; ENTROPY_LEN + 1];
+        for (i, word) in words.iter().enumerate() {
+            let index = WORDLIST.binary_search(&&*word.to_lowercase())
+                .map_err(|_| format_error(format!("`{}` is not in the wordlist", word)))?;
+            write_bits(&mut bits, i * BITS_PER_WORD, index);
+        }
+        let mnemonic = Mnemonic::from_entropy(&bits[..ENTROPY_LEN])?;
+        if checksum(&mnemonic.entropy) != bits[ENTROPY_LEN] {
+            return Err(format_error("mnemonic checksum mismatch"));
+        }
+        Ok(mnemonic)
+    }
+}
+
+fn checksum(entropy: &[u8]) -> u8 {
+    digest::digest(&digest::SHA256, entropy).as_ref()[0]
+}
+
+fn read_bits(bytes: &[u8], offset: usize) -> usize {
+    (offset..offset + BITS_PER_WORD).fold(0, |acc, bit| {
+        acc << 1 | (bytes[bit / 8] >> (7 - bit % 8) & 1) as usize
+    })
+}
+
+fn write_bits(bytes: &mut [u8], offset: usize, value: usize) {
+    for i in 0..BITS_PER_WORD {
+        let bit = offset + i;
+        bytes[bit / 8] |= ((value >> (BITS_PER_WORD - 1 - i) & 1) as u8) << (7 - bit % 8);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn bip39_vectors() {
+        // 256 bit vectors from the BIP 39 reference, passphrase "TREZOR"
+        let vectors = [
+            (0x00,
+             "abandon abandon abandon abandon abandon abandon abandon abandon \
+              abandon abandon abandon abandon abandon abandon abandon abandon \
+              abandon abandon abandon abandon abandon abandon abandon art",
+             "bda85446c68413707090a52022edd26a1c9462295029f2e60cd7c4f2bbd30971\
+              70af7a4d73245cafa9c3cca8d561a7c3de6f5d4a10be8ed2a5e608d68f92fcc8"),
+            (0x7f,
+             "legal winner thank year wave sausage worth useful legal winner thank year \
+              wave sausage worth useful legal winner thank year wave sausage worth title",
+             "bc09fca1804f7e69da93c2f2028eb238c227f2e9dda30cd63699232578480a40\
+              21b146ad717fbb7e451ce9eb835f43620bf5c514db0f8add49f5d121449d3e87"),
+            (0xff,
+             "zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo \
+              zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo vote",
+             "dd48c104698c30cfe2b6142103248622fb7bb0ff692eebb00089b32d22484e16\
+              13912f0a5b694407be899ffd31ed3992c456cdf60f5d4564b8ba3f05a69890ad"),
+        ];
+        for &(byte, phrase, seed) in &vectors {
+            let mnemonic = Mnemonic::from_entropy(&[byte; 32]).unwrap();
+            assert_eq!(mnemonic.to_string(), phrase);
+            assert_eq!(hex(&mnemonic.to_seed("TREZOR")), seed);
+            assert!(phrase.parse::<Mnemonic>().unwrap() == mnemonic);
+        }
+    }
+
+    #[test]
+    fn checksum_failure() {
+        let phrase = Mnemonic::from_entropy(&[0x7f; 32]).unwrap().to_string();
+        let mut words = phrase.split(' ').collect::<Vec<_>>();
+        words[23] = "abandon";
+        assert!(words.join(" ").parse::<Mnemonic>().is_err());
+        words[23] = "title";
+        words[0] = "legend";
+        assert!(words.join(" ").parse::<Mnemonic>().is_err());
+
+        assert!(phrase.replace("wave", "waves").parse::<Mnemonic>().is_err());
+        assert!(phrase.split_once(' ').unwrap().1.parse::<Mnemonic>().is_err());
+        let shouted = phrase.to_uppercase().replace(' ', "\n  ");
+        assert!(shouted.parse::<Mnemonic>().unwrap() == Mnemonic::from_entropy(&[0x7f; 32]).unwrap());
+    }
+
+    #[test]
+    fn restore_keypair() {
+        let mnemonic = Mnemonic::from_entropy(&[0x7f; 32]).unwrap();
+        let (pk, sk) = mnemonic.keypair("").unwrap();
+        let (pk2, _) = hd::derive(&mnemonic.to_seed(""), &[]).unwrap();
+        assert_eq!(pk.digest().unwrap(), pk2.digest().unwrap());
+
+        let (ct, ss) = pk.enc().unwrap();
+        assert_eq!(sk.dec(&ct).unwrap().0, ss.0);
+
+        let (other, _) = mnemonic.keypair("TREZOR").unwrap();
+        assert_ne!(other.digest().unwrap(), pk.digest().unwrap());
+    }
+}
@@ -0,0 +1,328 @@
+//! Hybrid ntor circuit-extension handshake, adding HILA5 to Tor's
+//! `ntor-curve25519-sha256-1`.
+//!
+//! The client knows the relay's node ID and its X25519 onion key `B`. It sends
+//! an ephemeral X25519 key `X` together with an ephemeral HILA5 public key;
+//! the relay replies with its ephemeral `Y`, the ntor `AUTH` MAC and a
+//! `kem::enc` ciphertext to the HILA5 key. As in the tor specification
+//! (section 5.1.4), with `H(x, t) = HMAC-SHA256(key = t, x)`:
+//!
+//! ```text
+//! CLIENT_HANDSHAKE = ID | B | X | PK
+//! SERVER_HANDSHAKE = Y | AUTH | CT
+//!
+//! secret_input = EXP(X,y) | EXP(X,b) | SS | ID | B | X | Y | H_PK | H_CT | PROTOID
+//! KEY_SEED     = H(secret_input, t_key)
+//! verify       = H(secret_input, t_verify)
+//! auth_input   = verify | ID | B | Y | X | H_PK | H_CT | PROTOID | "Server"
+//! AUTH         = H(auth_input, t_mac)
+//! ```
+//!
+//! where `SS` is the HILA5 shared secret and `H_PK`, `H_CT` are the SHA-256
+//! digests of the HILA5 public key and ciphertext. Circuit keys are expanded
+//! from `KEY_SEED` with HKDF-SHA256 and the info `m_expand`, as for ntor.
+//!
+//! The session keys depend on both the X25519 and the HILA5 shared secrets,
+//! so they stay secret unless both are broken. As in ntor, the relay is
+//! authenticated by `EXP(X,b)`: only the holder of the onion key can compute
+//! `AUTH`.
+//!
+//! ```rust
+//! use hila5::ntor::{Client, Relay};
+//!
+//! let relay = Relay::generate([7u8; 20]).unwrap();
+//! let (client, onionskin) = Client::start(&[7u8; 20], &relay.onion_key()).unwrap();
+//! let (reply, relay_keys) = relay.respond(&onionskin).unwrap();
+//! let client_keys = client.finish(&reply).unwrap();
+//! assert_eq!(client_keys.forward_key, relay_keys.forward_key);
+//! ```
+
+use ring::{constant_time, digest, hkdf, hmac};
+use x25519_dalek;
+
+use super::*;
+use errors::*;
+
+/// Length of relay node IDs.
+pub const NODE_ID_LEN: usize = 20;
+/// Length of the client's handshake message.
+pub const CLIENT_HANDSHAKE_LEN: usize = NODE_ID_LEN + 2 * X25519_LEN + PUBKEY_LEN;
+/// Length of the relay's handshake message.
+pub const SERVER_HANDSHAKE_LEN: usize = X25519_LEN + AUTH_LEN + CIPHERTEXT_LEN;
+/// Length of the `AUTH` MAC.
+pub const AUTH_LEN: usize = 32;
+/// Length of the digest seeds of relay cells.
+pub const DIGEST_LEN: usize = 20;
+/// Length of the relay cell cipher keys.
+pub const CIPHER_KEY_LEN: usize = 16;
+
+const PROTOID: &[u8] = b"ntor-hila5-curve25519-sha256-1";
+const T_MAC: &[u8] = b"ntor-hila5-curve25519-sha256-1:mac";
+const T_KEY: &[u8] = b"ntor-hila5-curve25519-sha256-1:key_extract";
+const T_VERIFY: &[u8] = b"ntor-hila5-curve25519-sha256-1:verify";
+const M_EXPAND: &[u8] = b"ntor-hila5-curve25519-sha256-1:key_expand";
+
+/// Keys for relay cells on a circuit hop, as expanded from `KEY_SEED`.
+pub struct CircuitKeys {
+    /// Seed of the running digest of cells sent towards the relay.
+    pub forward_digest: Vec<u8>,
+    /// Seed of the running digest of cells sent towards the client.
+    pub backward_digest: Vec<u8>,
+    /// Key for cells sent towards the relay.
+    pub forward_key: Vec<u8>,
+    /// Key for cells sent towards the client.
+    pub backward_key: Vec<u8>,
+    /// Nonce `KH`, e.g. for onion service rendezvous.
+    pub nonce: Vec<u8>,
+}
+
+impl CircuitKeys {
+    fn expand(key_seed: &[u8]) -> Self {
+        let prk = hmac::SigningKey::new(&digest::SHA256, key_seed);
+        let mut out = [0u8; 3 * DIGEST_LEN + 2 * CIPHER_KEY_LEN];
+        hkdf::expand(&prk, M_EXPAND, &mut out);
+        let (forward_digest, rest) = out.split_at(DIGEST_LEN);
+        let (backward_digest, rest) = rest.split_at(DIGEST_LEN);
+        let (forward_key, rest) = rest.split_at(CIPHER_KEY_LEN);
+        let (backward_key, nonce) = rest.split_at(CIPHER_KEY_LEN);
+        CircuitKeys {
+            forward_digest: forward_digest.to_vec(),
+            backward_digest: backward_digest.to_vec(),
+            forward_key: forward_key.to_vec(),
+            backward_key: backward_key.to_vec(),
+            nonce: nonce.to_vec(),
+        }
+    }
+}
+
+/// A relay's node ID and onion key.
+pub struct Relay {
+    id: [u8; NODE_ID_LEN],
+    onion_key: x25519_dalek::StaticSecret,
+}
+
+impl Relay {
+    /// Relay with node ID `id` and a fresh onion key.
+    pub fn generate(id: [u8; NODE_ID_LEN]) -> Result<Self> {
+        Ok(Relay { id, onion_key: x25519_secret()? })
+    }
+
+    /// Relay with node ID `id` and the private onion key `onion_key`.
+    pub fn from_secret(id: [u8; NODE_ID_LEN], onion_key: [u8; X25519_LEN]) -> Self {
+        Relay { id, onion_key: x25519_dalek::StaticSecret::from(onion_key) }
+    }
+
+    /// The public onion key `B`, published in the relay's descriptor.
+    pub fn onion_key(&self) -> [u8; X25519_LEN] {
+        x25519_dalek::PublicKey::from(&self.onion_key).to_bytes()
+    }
+
+    /// Answer a client's `CLIENT_HANDSHAKE`, returning `SERVER_HANDSHAKE` and
+    /// the circuit keys.
+    pub fn respond(&self, client_handshake: &[u8]) -> Result<(Vec<u8>, CircuitKeys)> {
+        if client_handshake.len() != CLIENT_HANDSHAKE_LEN {
+            return Err("invalid ntor client handshake length".into());
+        }
+        let (id, rest) = client_handshake.split_at(NODE_ID_LEN);
+        let (onion_key, rest) = rest.split_at(X25519_LEN);
+        let (x, pk) = rest.split_at(X25519_LEN);
+        if id != self.id || onion_key != self.onion_key() {
+            return Err("ntor handshake is for another relay".into());
+        }
+
+        let y = x25519_secret()?;
+        let y_public = x25519_dalek::PublicKey::from(&y).to_bytes();
+        let exp_xy = x25519_agree(&y, x)?;
+        let exp_xb = x25519_agree(&self.onion_key, x)?;
+        let (ct, ss) = kem::enc(&PublicKey::from_bytes(pk))?;
+
+        let input = SecretInput {
+            exp_xy,
+            exp_xb,
+            ss: ss.0,
+            id,
+            b: onion_key,
+            x,
+            y: &y_public,
+            pk,
+            ct: &ct,
+        };
+        let (key_seed, auth) = input.derive();
+
+        let mut reply = y_public.to_vec();
+        reply.extend_from_slice(&auth);
+        reply.extend_from_slice(&ct);
+        Ok((reply, CircuitKeys::expand(&key_seed)))
+    }
+}
+
+/// Client waiting for the relay's `SERVER_HANDSHAKE`.
+pub struct Client {
+    id: [u8; NODE_ID_LEN],
+    onion_key: [u8; X25519_LEN],
+    x: x25519_dalek::StaticSecret,
+    pk: Vec<u8>,
+    sk: PrivateKey,
+}
+
+impl Client {
+    /// Start a handshake with the relay with node ID `id` and onion key
+    /// `onion_key`, returning `CLIENT_HANDSHAKE`.
+    pub fn start(id: &[u8; NODE_ID_LEN], onion_key: &[u8; X25519_LEN]) -> Result<(Self, Vec<u8>)> {
+        let x = x25519_secret()?;
+        let (pk, sk) = crypto_kem_keypair()?;
+        let mut pk_bytes = vec![];
+        pk.write_to(&mut pk_bytes)?;
+
+        let mut msg = id.to_vec();
+        msg.extend_from_slice(onion_key);
+        msg.extend_from_slice(x25519_dalek::PublicKey::from(&x).as_bytes());
+        msg.extend_from_slice(&pk_bytes);
+
+        let client = Client { id: *id, onion_key: *onion_key, x, pk: pk_bytes, sk };
+        Ok((client, msg))
+    }
+
+    /// Process the relay's `SERVER_HANDSHAKE`, checking `AUTH` and returning
+    /// the circuit keys.
+    pub fn finish(self, server_handshake: &[u8]) -> Result<CircuitKeys> {
+        if server_handshake.len() != SERVER_HANDSHAKE_LEN {
+            return Err("invalid ntor server handshake length".into());
+        }
+        let (y, rest) = server_handshake.split_at(X25519_LEN);
+        let (auth, ct) = rest.split_at(AUTH_LEN);
+
+        let x_public = x25519_dalek::PublicKey::from(&self.x).to_bytes();
+        let exp_xy = x25519_agree(&self.x, y)?;
+        let exp_xb = x25519_agree(&self.x, &self.onion_key)?;
+        let ss = self.sk.dec(ct)?;
+
+        let input = SecretInput {
+            exp_xy,
+            exp_xb,
+            ss: ss.0,
+            id: &self.id,
+            b: &self.onion_key,
+            x: &x_public,
+            y,
+            pk: &self.pk,
+            ct,
+        };
+        let (key_seed, expected) = input.derive();
+        constant_time::verify_slices_are_equal(&expected, auth)
+            .map_err(|_| "ntor AUTH check failed")?;
+        Ok(CircuitKeys::expand(&key_seed))
+    }
+}
+
+struct SecretInput<'a> {
+    exp_xy: Vec<u8>,
+    exp_xb: Vec<u8>,
+    ss: Vec<u8>,
+    id: &'a [u8],
+    b: &'a [u8],
+    x: &'a [u8],
+    y: &'a [u8],
+    pk: &'a [u8],
+    ct: &'a [u8],
+}
+
+impl<'a> SecretInput<'a> {
+    /// Returns `KEY_SEED` and `AUTH`.
+    fn derive(&self) -> (Vec<u8>, Vec<u8>) {
+        let h_pk = digest::digest(&digest::SHA256, self.pk);
+        let h_ct = digest::digest(&digest::SHA256, self.ct);
+
+        let mut secret_input = vec![];
+        for part in &[&self.exp_xy[..], &self.exp_xb, &self.ss, self.id, self.b, self.x, self.y,
+                      h_pk.as_ref(), h_ct.as_ref(), PROTOID] {
+            secret_input.extend_from_slice(part);
+        }
+        let key_seed = h(&secret_input, T_KEY);
+        let verify = h(&secret_input, T_VERIFY);
+
+        let mut auth_input = verify;
+        for part in &[self.id, self.b, self.y, self.x, h_pk.as_ref(), h_ct.as_ref(), PROTOID, b"Server"] {
+            auth_input.extend_from_slice(part);
+        }
+        (key_seed, h(&auth_input, T_MAC))
+    }
+}
+
+/// `H(x, t)`: HMAC-SHA256 of `x` keyed with the tweak `t`.
+fn h(x: &[u8], t: &[u8]) -> Vec<u8> {
+    let key = hmac::SigningKey::new(&digest::SHA256, t);
+    hmac::sign(&key, x).as_ref().to_vec()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn handshake_agrees() {
+        let relay = Relay::generate([0x42; NODE_ID_LEN]).unwrap();
+        let (client, onionskin) = Client::start(&[0x42; NODE_ID_LEN], &relay.onion_key()).unwrap();
+        assert_eq!(onionskin.len(), CLIENT_HANDSHAKE_LEN);
+        let (reply, relay_keys) = relay.respond(&onionskin).unwrap();
+        assert_eq!(reply.len(), SERVER_HANDSHAKE_LEN);
+        let client_keys = client.finish(&reply).unwrap();
+
+        assert_eq!(client_keys.forward_digest, relay_keys.forward_digest);
+        assert_eq!(client_keys.backward_digest, relay_keys.backward_digest);
+        assert_eq!(client_keys.forward_key, relay_keys.forward_key);
+        assert_eq!(client_keys.backward_key, relay_keys.backward_key);
+        assert_eq!(client_keys.nonce, relay_keys.nonce);
+        assert!(client_keys.forward_key != client_keys.backward_key);
+    }
+
+    #[test]
+    fn rejects_wrong_relay_and_bad_auth() {
+        let relay = Relay::generate([1; NODE_ID_LEN]).unwrap();
+        let other = Relay::generate([1; NODE_ID_LEN]).unwrap();
+
+        // handshakes for another node ID or onion key are refused
+        let (_, onionskin) = Client::start(&[2; NODE_ID_LEN], &relay.onion_key()).unwrap();
+        assert!(relay.respond(&onionskin).is_err());
+        let (_, onionskin) = Client::start(&[1; NODE_ID_LEN], &other.onion_key()).unwrap();
+        assert!(relay.respond(&onionskin).is_err());
+
+        // a relay without the onion key cannot produce AUTH
+        let (client, onionskin) = Client::start(&[1; NODE_ID_LEN], &relay.onion_key()).unwrap();
+        let impostor = Relay::from_secret([1; NODE_ID_LEN], [9; X25519_LEN]);
+        let mut forged = onionskin.clone();
+        forged[NODE_ID_LEN..NODE_ID_LEN + X25519_LEN].copy_from_slice(&impostor.onion_key());
+        let (reply, _) = impostor.respond(&forged).unwrap();
+        assert!(client.finish(&reply).is_err());
+
+        // tampering with the ciphertext changes the keys
+        let (client, onionskin) = Client::start(&[1; NODE_ID_LEN], &relay.onion_key()).unwrap();
+        let (mut reply, _) = relay.respond(&onionskin).unwrap();
+        reply[SERVER_HANDSHAKE_LEN - 1] ^= 1;
+        assert!(client.finish(&reply).is_err());
+    }
+
+    #[test]
+    fn key_schedule_test_vector() {
+        let fill = |n: usize, byte: u8| vec![byte; n];
+        let (exp_xy, exp_xb, ss) = (fill(32, 1), fill(32, 2), fill(32, 3));
+        let (id, b, x, y) = (fill(NODE_ID_LEN, 4), fill(32, 5), fill(32, 6), fill(32, 7));
+        let (pk, ct) = (fill(PUBKEY_LEN, 8), fill(CIPHERTEXT_LEN, 9));
+        let input = SecretInput { exp_xy, exp_xb, ss, id: &id, b: &b, x: &x, y: &y, pk: &pk, ct: &ct };
+        let (key_seed, auth) = input.derive();
+        // computed independently with Python's hmac module
+        assert_eq!(hex(&key_seed), "c05db562c33b90029423a8022d97e9014f5efca28db76ab04f1f368c9c572f15");
+        assert_eq!(hex(&auth), "8f7e853b028016d4a861e4527c176e0a516728cc37940c333a644da586159adc");
+
+        let keys = CircuitKeys::expand(&key_seed);
+        assert_eq!(hex(&keys.forward_digest), "f3b50deffaeb55a1ce2394c8cf335eecff65b07b");
+        assert_eq!(hex(&keys.backward_digest), "5098519fd8cf0e83f769733d54c04a4a396846e2");
+        assert_eq!(hex(&keys.forward_key), "a1e870aeaa803a989efe1793d1b2cea5");
+        assert_eq!(hex(&keys.backward_key), "f205c3510ad120f32805ec1a0e45bd3c");
+        assert_eq!(hex(&keys.nonce), "c0d1995ade4e9f2866e87d7099e3c816e6eec38f");
+    }
+}
@@ -0,0 +1,483 @@
+//! OpenPGP encryption subkeys and session key packets for HILA5.
+//!
+//! A HILA5 key is carried in a version 4 Public-Key or Public-Subkey packet
+//! (RFC 9580 section 5.5.2) under the experimental algorithm ID
+//! `ALGORITHM_ID`, with the encoded `PublicKey` as its key material. Session
+//! keys are sent in version 3 Public-Key Encrypted Session Key packets
+//! (section 5.1), laid out like those for X25519:
+//!
+//! ```text
+//! HILA5 ciphertext                 (2012 bytes)
+//! length of the following fields   (1 byte)
+//! symmetric algorithm ID           (1 byte)
+//! AES-256 key wrapped session key  (RFC 3394)
+//! ```
+//!
+//! The key wrapping key is `HKDF-SHA256(ciphertext || public key || shared
+//! secret, "OpenPGP HILA5")`. Packets use the OpenPGP (new) packet format,
+//! and `armor` / `dearmor` convert them to and from ASCII armor.
+//!
+//! ```rust
+//! use hila5::openpgp::{self, ArmorKind, KeyPacket, Pkesk, SymmetricAlgorithm};
+//!
+//! let (pk, sk) = hila5::crypto_kem_keypair().unwrap();
+//! let subkey = KeyPacket::new(&pk, 1_500_000_000, true).unwrap();
+//!
+//! let session_key = [7u8; 32];
+//! let pkesk = openpgp::encrypt_session_key(&subkey, SymmetricAlgorithm::Aes256, &session_key)
+//!     .unwrap();
+//! let message = openpgp::armor(ArmorKind::Message, &pkesk.to_bytes().unwrap());
+//!
+//! let (_, packet) = openpgp::dearmor(&message).unwrap();
+//! let pkesk = Pkesk::from_bytes(&packet).unwrap();
+//! let (algorithm, key) = openpgp::decrypt_session_key(&pkesk, &subkey, &sk).unwrap();
+//! assert_eq!(algorithm, SymmetricAlgorithm::Aes256);
+//! assert_eq!(key, session_key);
+//! ```
+
+use aes_kw::KekAes256;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use ring::{digest, hkdf, hmac};
+
+use std::io::Write;
+
+use super::*;
+use errors::*;
+
+/// Public-key algorithm ID for HILA5, from the experimental range.
+pub const ALGORITHM_ID: u8 = 100;
+/// Packet tag of Public-Key Encrypted Session Key packets.
+pub const TAG_PKESK: u8 = 1;
+/// Packet tag of Public-Key packets.
+pub const TAG_PUBLIC_KEY: u8 = 6;
+/// Packet tag of Public-Subkey packets.
+pub const TAG_PUBLIC_SUBKEY: u8 = 14;
+
+const KEY_VERSION: u8 = 4;
+const PKESK_VERSION: u8 = 3;
+const KDF_INFO: &[u8] = b"OpenPGP HILA5";
+const KEK_LEN: usize = 32;
+/// Lines of base64 in ASCII armor are at most 76 characters; 64 is common.
+const ARMOR_LINE_LEN: usize = 64;
+
+/// Symmetric algorithms that a session key can be for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SymmetricAlgorithm {
+    Aes128 = 7,
+    Aes256 = 9,
+}
+
+impl SymmetricAlgorithm {
+    pub fn from_id(id: u8) -> Result<Self> {
+        match id {
+            7 => Ok(SymmetricAlgorithm::Aes128),
+            9 => Ok(SymmetricAlgorithm::Aes256),
+            _ => Err(format!("unsupported symmetric algorithm {}", id).into()),
+        }
+    }
+
+    pub fn key_len(self) -> usize {
+        match self {
+            SymmetricAlgorithm::Aes128 => 16,
+            SymmetricAlgorithm::Aes256 => 32,
+        }
+    }
+}
+
+/// A version 4 Public-Key or Public-Subkey packet holding a HILA5 key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyPacket {
+    /// Whether this is a Public-Subkey packet rather than a primary key.
+    pub subkey: bool,
+    /// Creation time, in seconds since the Unix epoch.
+    pub created: u32,
+    key: Vec<u8>,
+}
+
+impl KeyPacket {
+    pub fn new(pk: &PublicKey, created: u32, subkey: bool) -> Result<Self> {
+        let mut key = vec![];
+        pk.write_to(&mut key)?;
+        Ok(KeyPacket { subkey, created, key })
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey::from_bytes(&self.key)
+    }
+
+    /// The packet body, without the packet header.
+    pub fn body(&self) -> Vec<u8> {
+        let mut body = vec![KEY_VERSION, 0, 0, 0, 0, ALGORITHM_ID];
+        BigEndian::write_u32(&mut body[1..5], self.created);
+        body.extend_from_slice(&self.key);
+        body
+    }
+
+    /// The version 4 fingerprint, SHA-1 over the body prefixed by `0x99` and
+    /// its two byte length.
+    pub fn fingerprint(&self) -> [u8; 20] {
+        let body = self.body();
+        let mut ctx = digest::Context::new(&digest::SHA1);
+        ctx.update(&[0x99, (body.len() >> 8) as u8, body.len() as u8]);
+        ctx.update(&body);
+        let mut fingerprint = [0u8; 20];
+        fingerprint.copy_from_slice(ctx.finish().as_ref());
+        fingerprint
+    }
+
+    /// The key ID, the low 64 bits of the fingerprint.
+    pub fn key_id(&self) -> [u8; 8] {
+        let mut key_id = [0u8; 8];
+        key_id.copy_from_slice(&self.fingerprint()[12..]);
+        key_id
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let tag = if self.subkey { TAG_PUBLIC_SUBKEY } else { TAG_PUBLIC_KEY };
+        write_packet(writer, tag, &self.body())
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = vec![];
+        self.write_to(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Parse a single Public-Key or Public-Subkey packet.
+    pub fn from_bytes(mut input: &[u8]) -> Result<Self> {
+        let (tag, body) = read_packet(&mut input)?;
+        if !input.is_empty() {
+            return Err("trailing data after key packet".into());
+        }
+        let subkey = match tag {
+            TAG_PUBLIC_KEY => false,
+            TAG_PUBLIC_SUBKEY => true,
+            _ => return Err(format!("expected a key packet, found tag {}", tag).into()),
+        };
+        if body.len() < 6 || body[0] != KEY_VERSION {
+            return Err("unsupported key packet version".into());
+        }
+        if body[5] != ALGORITHM_ID {
+            return Err(format!("key packet has algorithm {}, expected {}", body[5], ALGORITHM_ID).into());
+        }
+        if body.len() != 6 + PUBKEY_LEN {
+            return Err("invalid HILA5 key length".into());
+        }
+        let created = BigEndian::read_u32(&body[1..5]);
+        Ok(KeyPacket { subkey, created, key: body[6..].to_vec() })
+    }
+}
+
+/// A version 3 Public-Key Encrypted Session Key packet for a HILA5 key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pkesk {
+    /// Key ID of the recipient key, or all zeros for an anonymous recipient.
+    pub key_id: [u8; 8],
+    pub ciphertext: Vec<u8>,
+    pub symmetric: SymmetricAlgorithm,
+    pub wrapped_key: Vec<u8>,
+}
+
+impl Pkesk {
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        if self.ciphertext.len() != CIPHERTEXT_LEN || self.wrapped_key.len() >= 255 {
+            return Err("invalid HILA5 session key fields".into());
+        }
+        let mut body = vec![PKESK_VERSION];
+        body.extend_from_slice(&self.key_id);
+        body.push(ALGORITHM_ID);
+        body.extend_from_slice(&self.ciphertext);
+        body.push(1 + self.wrapped_key.len() as u8);
+        body.push(self.symmetric as u8);
+        body.extend_from_slice(&self.wrapped_key);
+        write_packet(writer, TAG_PKESK, &body)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = vec![];
+        self.write_to(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Parse a single Public-Key Encrypted Session Key packet.
+    pub fn from_bytes(mut input: &[u8]) -> Result<Self> {
+        let (tag, body) = read_packet(&mut input)?;
+        if !input.is_empty() {
+            return Err("trailing data after session key packet".into());
+        }
+        if tag != TAG_PKESK {
+            return Err(format!("expected a session key packet, found tag {}", tag).into());
+        }
+        if body.len() < 10 || body[0] != PKESK_VERSION {
+            return Err("unsupported session key packet version".into());
+        }
+        if body[9] != ALGORITHM_ID {
+            return Err(format!("session key packet has algorithm {}, expected {}", body[9], ALGORITHM_ID)
+                .into());
+        }
+        let rest = &body[10..];
+        if rest.len() < CIPHERTEXT_LEN + 2
+            || rest[CIPHERTEXT_LEN] as usize != rest.len() - CIPHERTEXT_LEN - 1 {
+            return Err("invalid HILA5 session key fields".into());
+        }
+        let mut key_id = [0u8; 8];
+        key_id.copy_from_slice(&body[1..9]);
+        Ok(Pkesk {
+            key_id,
+            ciphertext: rest[..CIPHERTEXT_LEN].to_vec(),
+            symmetric: SymmetricAlgorithm::from_id(rest[CIPHERTEXT_LEN + 1])?,
+            wrapped_key: rest[CIPHERTEXT_LEN + 2..].to_vec(),
+        })
+    }
+}
+
+/// Encrypt `session_key` for `symmetric` to the HILA5 key in `recipient`.
+pub fn encrypt_session_key(recipient: &KeyPacket, symmetric: SymmetricAlgorithm, session_key: &[u8])
+                           -> Result<Pkesk> {
+    if session_key.len() != symmetric.key_len() {
+        return Err("session key length does not match the symmetric algorithm".into());
+    }
+    let (ciphertext, ss) = kem::enc(&recipient.public_key())?;
+    let wrapped_key = kek(&ciphertext, recipient, &ss).wrap_vec(session_key)
+        .map_err(|_| Error::from("AES key wrap failed"))?;
+    Ok(Pkesk { key_id: recipient.key_id(), ciphertext, symmetric, wrapped_key })
+}
+
+/// Decrypt the session key in `pkesk` with the private key for `recipient`.
+pub fn decrypt_session_key(pkesk: &Pkesk, recipient: &KeyPacket, sk: &PrivateKey)
+                           -> Result<(SymmetricAlgorithm, Vec<u8>)> {
+    if pkesk.key_id != [0; 8] && pkesk.key_id != recipient.key_id() {
+        return Err("session key packet is for a different key".into());
+    }
+    let ss = kem::dec(&pkesk.ciphertext, sk)?;
+    let session_key = kek(&pkesk.ciphertext, recipient, &ss).unwrap_vec(&pkesk.wrapped_key)
+        .map_err(|_| Error::from("session key failed to unwrap"))?;
+    if session_key.len() != pkesk.symmetric.key_len() {
+        return Err("session key length does not match the symmetric algorithm".into());
+    }
+    Ok((pkesk.symmetric, session_key))
+}
+
+fn kek(ciphertext: &[u8], recipient: &KeyPacket, ss: &SharedSecret) -> KekAes256 {
+    let mut ikm = ciphertext.to_vec();
+    ikm.extend_from_slice(&recipient.key);
+    ikm.extend_from_slice(&ss.0);
+    let salt = hmac::SigningKey::new(&digest::SHA256, &[]);
+    let mut key = [0u8; KEK_LEN];
+    hkdf::extract_and_expand(&salt, &ikm, KDF_INFO, &mut key);
+    KekAes256::from(key)
+}
+
+fn write_packet<W: Write>(writer: &mut W, tag: u8, body: &[u8]) -> Result<()> {
+    writer.write_u8(0xc0 | tag)?;
+    match body.len() {
+        len if len < 192 => writer.write_u8(len as u8)?,
+        len if len < 8384 => {
+            let len = len - 192;
+            writer.write_u8((len >> 8) as u8 + 192)?;
+            writer.write_u8(len as u8)?;
+        }
+        len if len <= u32::MAX as usize => {
+            writer.write_u8(255)?;
+            writer.write_u32::<BigEndian>(len as u32)?;
+        }
+        _ => return Err("packet is too long".into()),
+    }
+    writer.write_all(body)?;
+    Ok(())
+}
+
+/// Read one packet from the front of `input`, returning its tag and body.
+/// Only the OpenPGP packet format with definite lengths is accepted.
+fn read_packet<'a>(input: &mut &'a [u8]) -> Result<(u8, &'a [u8])> {
+    let truncated = || Error::from("truncated packet");
+    let (&header, rest) = input.split_first().ok_or_else(truncated)?;
+    if header & 0xc0 != 0xc0 {
+        return Err("only the OpenPGP packet format is supported".into());
+    }
+    let (len, rest) = match rest.first() {
+        Some(&len) if len < 192 => (len as usize, &rest[1..]),
+        Some(&len) if len < 224 => {
+            let second = *rest.get(1).ok_or_else(truncated)? as usize;
+            (((len as usize - 192) << 8) + second + 192, &rest[2..])
+        }
+        Some(&255) if rest.len() >= 5 => (BigEndian::read_u32(&rest[1..5]) as usize, &rest[5..]),
+        Some(&255) | None => return Err(truncated()),
+        Some(_) => return Err("partial body lengths are not supported".into()),
+    };
+    if rest.len() < len {
+        return Err(truncated());
+    }
+    *input = &rest[len..];
+    Ok((header & 0x3f, &rest[..len]))
+}
+
+/// The kinds of ASCII armor used here.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArmorKind {
+    PublicKey,
+    Message,
+}
+
+impl ArmorKind {
+    fn label(self) -> &'static str {
+        match self {
+            ArmorKind::PublicKey => "PGP PUBLIC KEY BLOCK",
+            ArmorKind::Message => "PGP MESSAGE",
+        }
+    }
+}
+
+/// Wrap `data` in ASCII armor, with a CRC-24 checksum line.
+pub fn armor(kind: ArmorKind, data: &[u8]) -> String {
+    let mut text = format!("-----BEGIN {}-----\n\n", kind.label());
+    let encoded = STANDARD.encode(data);
+    for line in encoded.as_bytes().chunks(ARMOR_LINE_LEN) {
+        text.push_str(std::str::from_utf8(line).expect("base64 is ASCII"));
+        text.push('\n');
+    }
+    let crc = crc24(data);
+    text.push('=');
+    text.push_str(&STANDARD.encode([(crc >> 16) as u8, (crc >> 8) as u8, crc as u8]));
+    text.push_str(&format!("\n-----END {}-----\n", kind.label()));
+    text
+}
+
+/// Remove ASCII armor, checking the CRC-24 if there is one. Armor headers
+/// are skipped.
+pub fn dearmor(text: &str) -> Result<(ArmorKind, Vec<u8>)> {
+    let mut lines = text.lines().map(str::trim_end).skip_while(|line| line.is_empty());
+    let begin = lines.next().unwrap_or("");
+    let kind = [ArmorKind::PublicKey, ArmorKind::Message].iter().cloned()
+        .find(|kind| begin == format!("-----BEGIN {}-----", kind.label()))
+        .ok_or_else(|| Error::from("missing or unsupported armor header line"))?;
+    let end = format!("-----END {}-----", kind.label());
+
+    // armor headers end at the first blank line
+    let mut lines = lines.skip_while(|line| !line.is_empty()).skip(1);
+    let mut encoded = String::new();
+    let mut checksum = None;
+    loop {
+        match lines.next() {
+            Some(line) if line == end => break,
+            Some(line) if line.starts_with('=') => checksum = Some(&line[1..]),
+            Some(line) => encoded.push_str(line),
+            None => return Err("missing armor tail line".into()),
+        }
+    }
+    let data = STANDARD.decode(&encoded).map_err(|e| Error::from(format!("invalid base64: {}", e)))?;
+    if let Some(checksum) = checksum {
+        let crc = crc24(&data);
+        let expected = [(crc >> 16) as u8, (crc >> 8) as u8, crc as u8];
+        if STANDARD.decode(checksum).ok().as_ref().map(|c| &c[..]) != Some(&expected[..]) {
+            return Err("armor checksum mismatch".into());
+        }
+    }
+    Ok((kind, data))
+}
+
+fn crc24(data: &[u8]) -> u32 {
+    let mut crc = 0xb7_04ce_u32;
+    for &byte in data {
+        crc ^= (byte as u32) << 16;
+        for _ in 0..8 {
+            crc <<= 1;
+            if crc & 0x100_0000 != 0 {
+                crc ^= 0x186_4cfb;
+            }
+        }
+    }
+    crc & 0xff_ffff
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn key_packet_roundtrip() {
+        let (pk, _) = crypto_kem_keypair().unwrap();
+        let subkey = KeyPacket::new(&pk, 1_700_000_000, true).unwrap();
+        let bytes = subkey.to_bytes().unwrap();
+        assert_eq!(&bytes[..3], &[0xc0 | TAG_PUBLIC_SUBKEY, 0xc6, 0x66]);
+        assert_eq!(bytes.len(), 3 + 6 + PUBKEY_LEN);
+
+        let text = armor(ArmorKind::PublicKey, &bytes);
+        assert!(text.starts_with("-----BEGIN PGP PUBLIC KEY BLOCK-----\n\n"));
+        assert!(text.lines().all(|line| line.len() <= ARMOR_LINE_LEN));
+        let (kind, data) = dearmor(&text).unwrap();
+        assert_eq!(kind, ArmorKind::PublicKey);
+        let parsed = KeyPacket::from_bytes(&data).unwrap();
+        assert_eq!(parsed, subkey);
+        assert_eq!(parsed.public_key().digest().unwrap(), pk.digest().unwrap());
+        assert_eq!(&parsed.fingerprint()[12..], &parsed.key_id());
+
+        let primary = KeyPacket::new(&pk, 1_700_000_000, false).unwrap();
+        assert_eq!(primary.to_bytes().unwrap()[0], 0xc0 | TAG_PUBLIC_KEY);
+        assert_eq!(primary.fingerprint(), subkey.fingerprint());
+        assert!(Pkesk::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn session_key_roundtrip() {
+        let (pk, sk) = crypto_kem_keypair().unwrap();
+        let subkey = KeyPacket::new(&pk, 0, true).unwrap();
+        let session_key = [0x5au8; 16];
+        let pkesk = encrypt_session_key(&subkey, SymmetricAlgorithm::Aes128, &session_key).unwrap();
+        assert_eq!(pkesk.wrapped_key.len(), 24);
+
+        let text = armor(ArmorKind::Message, &pkesk.to_bytes().unwrap());
+        let (kind, data) = dearmor(&format!("\n{}", text.replace("\n\n", "\nVersion: test\n\n"))).unwrap();
+        assert_eq!(kind, ArmorKind::Message);
+        let parsed = Pkesk::from_bytes(&data).unwrap();
+        assert_eq!(parsed, pkesk);
+        let (algorithm, key) = decrypt_session_key(&parsed, &subkey, &sk).unwrap();
+        assert_eq!(algorithm, SymmetricAlgorithm::Aes128);
+        assert_eq!(key, session_key);
+
+        let anonymous = Pkesk { key_id: [0; 8], ..parsed.clone() };
+        assert!(decrypt_session_key(&anonymous, &subkey, &sk).is_ok());
+        let (_, other_sk) = crypto_kem_keypair().unwrap();
+        assert!(decrypt_session_key(&parsed, &subkey, &other_sk).is_err());
+        let mut truncated = parsed.clone();
+        truncated.ciphertext.truncate(encode::PACKED14);
+        assert!(decrypt_session_key(&truncated, &subkey, &sk).is_err());
+        assert!(encrypt_session_key(&subkey, SymmetricAlgorithm::Aes256, &session_key).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        let (pk, _) = crypto_kem_keypair().unwrap();
+        let bytes = KeyPacket::new(&pk, 0, true).unwrap().to_bytes().unwrap();
+        assert!(KeyPacket::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        let mut other = bytes.clone();
+        other[8] = 18;
+        assert!(KeyPacket::from_bytes(&other).is_err());
+        other = bytes.clone();
+        other[0] = 0x80 | (TAG_PUBLIC_SUBKEY << 2) | 1;
+        assert!(KeyPacket::from_bytes(&other).is_err());
+
+        let text = armor(ArmorKind::PublicKey, &bytes);
+        let flipped = text.replacen("\n\n", "\n\nA", 1);
+        assert!(dearmor(&flipped).is_err());
+        assert!(dearmor(&text.replace("-----END PGP PUBLIC KEY BLOCK-----", "")).is_err());
+        assert!(dearmor("-----BEGIN PGP SIGNATURE-----\n\n-----END PGP SIGNATURE-----\n").is_err());
+    }
+
+    #[test]
+    fn packet_lengths_and_crc() {
+        for &len in &[0usize, 191, 192, 8383, 8384, 70000] {
+            let body = vec![1u8; len];
+            let mut bytes = vec![];
+            write_packet(&mut bytes, TAG_PKESK, &body).unwrap();
+            let mut input = &bytes[..];
+            assert_eq!(read_packet(&mut input).unwrap(), (TAG_PKESK, &body[..]));
+            assert!(input.is_empty());
+        }
+        // the CRC of no data is the initial value, then the CRC-24/OPENPGP check value
+        assert_eq!(crc24(&[]), 0xb7_04ce);
+        assert_eq!(crc24(b"123456789"), 0x21_cf02);
+    }
+}
@@ -0,0 +1,191 @@
+//! OpenSSH-style single-line encoding of public keys.
+//!
+//! A key is written as `hila5 <base64 blob> [comment]`, like the lines of an
+//! `authorized_keys` file. The blob follows the SSH wire format, as a sequence
+//! of length-prefixed strings:
+//!
+//! ```text
+//! string  "hila5"
+//! string  seed         (32 bytes)
+//! string  packed key   (1792 bytes)
+//! ```
+//!
+//! ```rust
+//! use hila5::openssh;
+//!
+//! let (pk, _) = hila5::crypto_kem_keypair().unwrap();
+//! let line = openssh::encode_line(&pk, "alice@example").unwrap();
+//! assert!(line.starts_with("hila5 "));
+//!
+//! let (parsed, comment) = openssh::parse_line(&line).unwrap();
+//! assert_eq!(parsed.digest().unwrap(), pk.digest().unwrap());
+//! assert_eq!(comment, "alice@example");
+//! ```
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+
+use std::io::Write;
+
+use super::*;
+use encode::PACKED14;
+use errors::*;
+
+/// Algorithm name, used both as the first field of the line and inside the
+/// blob.
+pub const ALGORITHM: &str = "hila5";
+
+/// Encode `pk` as a single line, followed by `comment` if it is non-empty.
+pub fn encode_line(pk: &PublicKey, comment: &str) -> Result<String> {
+    if comment.contains('\n') || comment.contains('\r') {
+        return Err(format_error("comment contains a line break"));
+    }
+    let mut line = format!("{} {}", ALGORITHM, STANDARD.encode(&encode_blob(pk)?));
+    if !comment.is_empty() {
+        line.push(' ');
+        line.push_str(comment);
+    }
+    Ok(line)
+}
+
+/// Parse a line produced by `encode_line`, returning the key and the comment
+/// (empty if there is none).
+pub fn parse_line(line: &str) -> Result<(PublicKey, String)> {
+    let line = line.trim();
+    let mut fields = line.splitn(3, [' ', '\t']);
+    let algorithm = fields.next().unwrap_or("");
+    if algorithm != ALGORITHM {
+        return Err(format_error(format!("unsupported key type `{}`", algorithm)));
+    }
+    let blob = match fields.next() {
+        Some(blob) if !blob.is_empty() => blob,
+        _ => return Err(format_error("missing key data")),
+    };
+    let blob = STANDARD.decode(blob)
+        .map_err(|e| format_error(format!("invalid base64: {}", e)))?;
+    let pk = decode_blob(&blob)?;
+    let comment = fields.next().unwrap_or("").trim().to_string();
+    Ok((pk, comment))
+}
+
+/// Encode `pk` as an SSH wire-format key blob.
+pub fn encode_blob(pk: &PublicKey) -> Result<Vec<u8>> {
+    let mut key = vec![];
+    pk.write_to(&mut key)?;
+    let (seed, packed) = key.split_at(rand::SEED_LEN);
+
+    let mut blob = vec![];
+    for field in &[ALGORITHM.as_bytes(), seed, packed] {
+        blob.write_u32::<BigEndian>(field.len() as u32)?;
+        blob.write_all(field)?;
+    }
+    Ok(blob)
+}
+
+/// Decode an SSH wire-format key blob, rejecting truncated, oversized and
+/// non-canonical keys.
+pub fn decode_blob(blob: &[u8]) -> Result<PublicKey> {
+    let mut input = blob;
+    let algorithm = read_string(&mut input, "algorithm name")?;
+    if algorithm != ALGORITHM.as_bytes() {
+        return Err(format_error(format!("key blob has algorithm `{}`, expected `{}`",
+                                        String::from_utf8_lossy(algorithm), ALGORITHM)));
+    }
+    let seed = read_string(&mut input, "seed")?;
+    if seed.len() != rand::SEED_LEN {
+        return Err(format_error(format!("seed is {} bytes, expected {}",
+                                        seed.len(), rand::SEED_LEN)));
+    }
+    let packed = read_string(&mut input, "packed key")?;
+    if packed.len() != PACKED14 {
+        return Err(format_error(format!("packed key is {} bytes, expected {}",
+                                        packed.len(), PACKED14)));
+    }
+    if !input.is_empty() {
+        return Err(format_error(format!("{} bytes of trailing data after key", input.len())));
+    }
+
+    let unpacked: NttVector = encode::unpack14(packed);
+    if unpacked.get_inner().iter().any(|&x| x >= HILA5_Q) {
+        return Err(format_error("non-canonical key: coefficient not reduced mod q"));
+    }
+
+    let mut key = seed.to_vec();
+    key.extend_from_slice(packed);
+    Ok(PublicKey::from_bytes(&key))
+}
+
+fn read_string<'a>(input: &mut &'a [u8], field: &str) -> Result<&'a [u8]> {
+    if input.len() < 4 {
+        return Err(format_error(format!("key blob truncated before {}", field)));
+    }
+    let len = BigEndian::read_u32(input) as usize;
+    if input.len() - 4 < len {
+        return Err(format_error(format!("key blob truncated in {}", field)));
+    }
+    let (out, rest) = input[4..].split_at(len);
+    *input = rest;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn reason(parsed: Result<(PublicKey, String)>) -> String {
+        match parsed {
+            Err(Error(ErrorKind::KeyFormat(reason), _)) => reason,
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("parsed an invalid line"),
+        }
+    }
+
+    #[test]
+    fn line_roundtrip() {
+        let (pk, _) = crypto_kem_keypair().unwrap();
+        let line = encode_line(&pk, "deploy key for build-01").unwrap();
+        let (parsed, comment) = parse_line(&line).unwrap();
+        assert_eq!(parsed.digest().unwrap(), pk.digest().unwrap());
+        assert_eq!(comment, "deploy key for build-01");
+        assert_eq!(encode_line(&parsed, &comment).unwrap(), line);
+
+        let line = encode_line(&pk, "").unwrap();
+        assert_eq!(line.split(' ').count(), 2);
+        assert_eq!(parse_line(&format!("  {}\n", line)).unwrap().1, "");
+    }
+
+    #[test]
+    fn rejects_bad_lines() {
+        let (pk, _) = crypto_kem_keypair().unwrap();
+        let blob = encode_blob(&pk).unwrap();
+        let line = |blob: &[u8]| format!("hila5 {} c", STANDARD.encode(blob));
+
+        assert!(reason(parse_line("ssh-ed25519 AAAA c")).contains("unsupported key type"));
+        assert!(reason(parse_line("hila5")).contains("missing key data"));
+        assert!(reason(parse_line("hila5 !!!!")).contains("invalid base64"));
+
+        // Truncated
+        assert!(reason(parse_line(&line(&blob[..blob.len() - 1]))).contains("truncated in packed key"));
+        assert!(reason(parse_line(&line(&blob[..2]))).contains("truncated before algorithm"));
+
+        // Trailing data
+        let mut long = blob.clone();
+        long.push(0);
+        assert!(reason(parse_line(&line(&long))).contains("trailing data"));
+
+        // Algorithm name inside the blob
+        let mut wrong = blob.clone();
+        wrong[4] = b'H';
+        assert!(reason(parse_line(&line(&wrong))).contains("key blob has algorithm"));
+
+        // Coefficient >= q
+        let mut wrong = blob.clone();
+        let packed = 4 + ALGORITHM.len() + 4 + rand::SEED_LEN + 4;
+        wrong[packed] = 0xff;
+        wrong[packed + 1] |= 0x3f;
+        assert!(reason(parse_line(&line(&wrong))).contains("non-canonical"));
+
+        assert!(encode_line(&pk, "two\nlines").is_err());
+    }
+}
@@ -0,0 +1,405 @@
+//! 1-out-of-2 oblivious transfer from HILA5 public keys.
+//!
+//! This is the KEM-based base OT of Masny and Rindal ("Endemic Oblivious
+//! Transfer", CCS 2019), which applies to HILA5 since its public keys are
+//! indistinguishable from uniform ring elements under Ring-LWE. For each
+//! choice bit `c` the receiver
+//!
+//!  1. generates a real keypair `(pk_c, sk)`, and a random ring element
+//!     `r_{1-c}` with `rand::from_seed`;
+//!  2. sets `r_c = pk_c - H(r_{1-c})`, and sends `(r_0, r_1)`.
+//!
+//! The sender recovers the two public keys as `pk_0 = r_0 + H(r_1)` and
+//! `pk_1 = r_1 + H(r_0)`, where `H` hashes a ring element to a ring element,
+//! and encapsulates to both. `pk_c` is the receiver's real key, while
+//! `pk_{1-c}` is the sum of a uniform element and a hash output, whose
+//! private key the receiver cannot know; and since `pk_c` looks uniform,
+//! `(r_0, r_1)` does not reveal `c`.
+//!
+//! The sender gets two keys per transfer, and the receiver the chosen one.
+//! `send` uses them to encrypt a pair of messages, and `send_random` returns
+//! them directly for random OT, e.g. as base OTs for OT extension. Like
+//! `kem`, this is secure against semi-honest parties only.
+//!
+//! ```rust
+//! use hila5::ot::{self, Receiver};
+//!
+//! let (receiver, request) = Receiver::new(&[false, true]).unwrap();
+//! let response = ot::send(&request, &[(b"zero", b"one!"), (b"left", b"rite")]).unwrap();
+//! let messages = receiver.receive(&response).unwrap();
+//! assert_eq!(messages, vec![b"zero".to_vec(), b"rite".to_vec()]);
+//! ```
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use ring::aead;
+use ring::rand::SecureRandom;
+use sha3::{Digest, Sha3_256};
+
+use std::io::{Read, Write};
+
+use super::*;
+use encode::PACKED14;
+use errors::*;
+
+/// Length of the keys of random OT.
+pub const KEY_LEN: usize = 32;
+/// Maximum number of transfers in a batch.
+pub const MAX_BATCH: usize = 1 << 16;
+
+const TAG_LEN: usize = 16;
+const OT_LABEL: &[u8] = b"HILA5v10-OT";
+
+/// The sender's keys of a random OT, for choices 0 and 1.
+pub type SenderKeys = (Vec<u8>, Vec<u8>);
+
+/// Receiver's first message: the generator seed, and a pair of ring elements
+/// per choice bit.
+pub struct Request {
+    seed: [u8; rand::SEED_LEN],
+    pairs: Vec<(NttVector, NttVector)>,
+}
+
+/// Sender's reply: the encapsulations to both keys of each transfer, and the
+/// encrypted messages if any.
+pub struct Response {
+    transfers: Vec<Transfer>,
+}
+
+struct Transfer {
+    cts: [Vec<u8>; 2],
+    messages: Option<[Vec<u8>; 2]>,
+}
+
+/// Receiver waiting for the sender's `Response`.
+pub struct Receiver {
+    seed: [u8; rand::SEED_LEN],
+    keys: Vec<(bool, PrivateKey)>,
+}
+
+impl Receiver {
+    /// Start a batch of transfers, one for each of the `choices`.
+    pub fn new(choices: &[bool]) -> Result<(Self, Request)> {
+        if choices.is_empty() || choices.len() > MAX_BATCH {
+            return Err("invalid number of transfers".into());
+        }
+        let rng = get_rng();
+        let mut seed = [0u8; rand::SEED_LEN];
+        rng.fill(&mut seed)?;
+
+        let mut keys = vec![];
+        let mut pairs = vec![];
+        for (i, &choice) in choices.iter().enumerate() {
+            let (pk, sk) = keygen::keypair_with_generator(seed)?;
+            let mut other_seed = [0u8; rand::SEED_LEN];
+            rng.fill(&mut other_seed)?;
+            let other = reduce(rand::from_seed(&other_seed));
+            let h = hash_to_ring(&seed, i as u32, &other)?;
+            let real = sub(&pk.key, &h);
+            pairs.push(if choice { (other, real) } else { (real, other) });
+            keys.push((choice, sk));
+        }
+        Ok((Receiver { seed, keys }, Request { seed, pairs }))
+    }
+
+    /// Decrypt the chosen message of each transfer.
+    pub fn receive(self, response: &Response) -> Result<Vec<Vec<u8>>> {
+        let keys = self.receive_random(response)?;
+        response.transfers.iter().zip(&self.keys).zip(keys).map(|((transfer, &(choice, _)), key)| {
+            let messages = transfer.messages.as_ref().ok_or("response has no messages")?;
+            open(&key, &messages[choice as usize])
+        }).collect()
+    }
+
+    /// The chosen key of each transfer.
+    pub fn receive_random(&self, response: &Response) -> Result<Vec<Vec<u8>>> {
+        if response.transfers.len() != self.keys.len() {
+            return Err("response has the wrong number of transfers".into());
+        }
+        response.transfers.iter().zip(&self.keys).enumerate().map(|(i, (transfer, &(choice, ref sk)))| {
+            let ss = sk.dec(&transfer.cts[choice as usize])?;
+            Ok(derive_key(&self.seed, i as u32, choice, &ss))
+        }).collect()
+    }
+}
+
+/// Transfer one of each pair of `messages`, which must have equal lengths.
+pub fn send(request: &Request, messages: &[(&[u8], &[u8])]) -> Result<Response> {
+    if messages.len() != request.pairs.len() {
+        return Err("wrong number of message pairs".into());
+    }
+    if messages.iter().any(|&(m0, m1)| m0.len() != m1.len()) {
+        return Err("messages in a pair must have the same length".into());
+    }
+    let (mut response, keys) = send_random(request)?;
+    for ((transfer, (k0, k1)), &(m0, m1)) in response.transfers.iter_mut().zip(keys).zip(messages) {
+        transfer.messages = Some([seal(&k0, m0)?, seal(&k1, m1)?]);
+    }
+    Ok(response)
+}
+
+/// Random OT: return a pair of keys for each transfer, of which the receiver
+/// learns the chosen one.
+pub fn send_random(request: &Request) -> Result<(Response, Vec<SenderKeys>)> {
+    let mut transfers = vec![];
+    let mut keys = vec![];
+    for (i, (r0, r1)) in request.pairs.iter().enumerate() {
+        let i = i as u32;
+        let pk0 = add(r0, &hash_to_ring(&request.seed, i, r1)?);
+        let pk1 = add(r1, &hash_to_ring(&request.seed, i, r0)?);
+        let (ct0, ss0) = kem::enc(&public_key(&request.seed, &pk0)?)?;
+        let (ct1, ss1) = kem::enc(&public_key(&request.seed, &pk1)?)?;
+        keys.push((derive_key(&request.seed, i, false, &ss0), derive_key(&request.seed, i, true, &ss1)));
+        transfers.push(Transfer { cts: [ct0, ct1], messages: None });
+    }
+    Ok((Response { transfers }, keys))
+}
+
+impl Request {
+    /// Number of transfers.
+    pub fn len(&self) -> usize {
+        self.pairs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&self.seed)?;
+        writer.write_u32::<BigEndian>(self.pairs.len() as u32)?;
+        for (r0, r1) in &self.pairs {
+            encode::pack14(r0, writer)?;
+            encode::pack14(r1, writer)?;
+        }
+        Ok(())
+    }
+
+    pub fn from_bytes(mut input: &[u8]) -> Result<Self> {
+        let reader = &mut input;
+        let mut seed = [0u8; rand::SEED_LEN];
+        reader.read_exact(&mut seed)?;
+        let count = reader.read_u32::<BigEndian>()? as usize;
+        if count == 0 || count > MAX_BATCH || reader.len() != 2 * count * PACKED14 {
+            return Err("invalid length for OT request".into());
+        }
+        let mut pairs = vec![];
+        for pair in reader.chunks(2 * PACKED14) {
+            pairs.push((unpack_element(&pair[..PACKED14])?, unpack_element(&pair[PACKED14..])?));
+        }
+        Ok(Request { seed, pairs })
+    }
+}
+
+impl Response {
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_u32::<BigEndian>(self.transfers.len() as u32)?;
+        for transfer in &self.transfers {
+            writer.write_all(&transfer.cts[0])?;
+            writer.write_all(&transfer.cts[1])?;
+            match transfer.messages {
+                Some(ref messages) => {
+                    writer.write_u32::<BigEndian>(messages[0].len() as u32)?;
+                    writer.write_all(&messages[0])?;
+                    writer.write_all(&messages[1])?;
+                }
+                None => writer.write_u32::<BigEndian>(0)?,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn from_bytes(mut input: &[u8]) -> Result<Self> {
+        let reader = &mut input;
+        let count = reader.read_u32::<BigEndian>()? as usize;
+        if count > MAX_BATCH {
+            return Err("invalid length for OT response".into());
+        }
+        let mut transfers = vec![];
+        for _ in 0..count {
+            let cts = [read_vec(reader, CIPHERTEXT_LEN)?, read_vec(reader, CIPHERTEXT_LEN)?];
+            let messages = match reader.read_u32::<BigEndian>()? as usize {
+                0 => None,
+                len if len < TAG_LEN => return Err("invalid OT message length".into()),
+                // the length is untrusted, check it before allocating
+                len if len > reader.len() / 2 => return Err("invalid OT message length".into()),
+                len => Some([read_vec(reader, len)?, read_vec(reader, len)?]),
+            };
+            transfers.push(Transfer { cts, messages });
+        }
+        if !reader.is_empty() {
+            return Err("trailing data after OT response".into());
+        }
+        Ok(Response { transfers })
+    }
+}
+
+/// Hash the ring element `r` of transfer `index` to a uniform ring element.
+fn hash_to_ring(seed: &[u8], index: u32, r: &NttVector) -> Result<NttVector> {
+    let mut input = OT_LABEL.to_vec();
+    input.extend_from_slice(seed);
+    input.write_u32::<BigEndian>(index)?;
+    encode::pack14(r, &mut input)?;
+    Ok(reduce(rand::from_seed(&sha3(&input))))
+}
+
+fn reduce(mut v: NttVector) -> NttVector {
+    for vi in v.get_inner_mut().iter_mut() {
+        *vi %= HILA5_Q;
+    }
+    v
+}
+
+fn add(a: &NttVector, b: &NttVector) -> NttVector {
+    let mut out = a.clone();
+    for (x, y) in out.get_inner_mut().iter_mut().zip(b.get_inner().iter()) {
+        *x = (*x + y) % HILA5_Q;
+    }
+    out
+}
+
+fn sub(a: &NttVector, b: &NttVector) -> NttVector {
+    let mut out = a.clone();
+    for (x, y) in out.get_inner_mut().iter_mut().zip(b.get_inner().iter()) {
+        *x = (*x - y + HILA5_Q) % HILA5_Q;
+    }
+    out
+}
+
+fn public_key(seed: &[u8], key: &NttVector) -> Result<PublicKey> {
+    let mut bytes = seed.to_vec();
+    encode::pack14(key, &mut bytes)?;
+    Ok(PublicKey::from_bytes(&bytes))
+}
+
+fn unpack_element(input: &[u8]) -> Result<NttVector> {
+    let v: NttVector = encode::unpack14(input);
+    if v.get_inner().iter().any(|&x| x >= HILA5_Q) {
+        return Err("invalid ring element in OT request".into());
+    }
+    Ok(v)
+}
+
+fn derive_key(seed: &[u8], index: u32, choice: bool, ss: &SharedSecret) -> Vec<u8> {
+    let mut hasher = Sha3_256::default();
+    hasher.input(OT_LABEL);
+    hasher.input(seed);
+    let mut info = Vec::with_capacity(5);
+    info.write_u32::<BigEndian>(index).unwrap();
+    info.push(choice as u8);
+    hasher.input(&info);
+    hasher.input(&ss.0);
+    hasher.result().to_vec()
+}
+
+fn seal(key: &[u8], message: &[u8]) -> Result<Vec<u8>> {
+    let key = aead::SealingKey::new(&aead::CHACHA20_POLY1305, key)?;
+    let mut out = message.to_vec();
+    out.extend_from_slice(&[0u8; TAG_LEN]);
+    aead::seal_in_place(&key, &[0u8; 12], &[], &mut out, TAG_LEN)?;
+    Ok(out)
+}
+
+fn open(key: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+    let key = aead::OpeningKey::new(&aead::CHACHA20_POLY1305, key)?;
+    let mut body = sealed.to_vec();
+    let len = aead::open_in_place(&key, &[0u8; 12], &[], 0, &mut body)
+        .map_err(|_| "could not decrypt OT message")?
+        .len();
+    body.truncate(len);
+    Ok(body)
+}
+
+fn read_vec<R: Read>(reader: &mut R, len: usize) -> Result<Vec<u8>> {
+    let mut out = vec![0u8; len];
+    reader.read_exact(&mut out)?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn roundtrip(request: &Request, response: &Response) -> (Request, Response) {
+        let mut bytes = vec![];
+        request.write_to(&mut bytes).unwrap();
+        let request = Request::from_bytes(&bytes).unwrap();
+        bytes.clear();
+        response.write_to(&mut bytes).unwrap();
+        (request, Response::from_bytes(&bytes).unwrap())
+    }
+
+    #[test]
+    fn batched_transfer() {
+        let choices = [true, false, false, true, true, false, true, false];
+        let messages: Vec<(Vec<u8>, Vec<u8>)> = (0..choices.len())
+            .map(|i| (format!("zero {}", i).into_bytes(), format!("one  {}", i).into_bytes()))
+            .collect();
+        let pairs: Vec<(&[u8], &[u8])> = messages.iter().map(|(m0, m1)| (&m0[..], &m1[..])).collect();
+
+        let (receiver, request) = Receiver::new(&choices).unwrap();
+        let response = send(&request, &pairs).unwrap();
+        let (_, response) = roundtrip(&request, &response);
+        let received = receiver.receive(&response).unwrap();
+        for (i, &choice) in choices.iter().enumerate() {
+            let expected = if choice { &messages[i].1 } else { &messages[i].0 };
+            assert_eq!(&received[i], expected);
+        }
+    }
+
+    #[test]
+    fn receiver_only_learns_chosen_message() {
+        let (receiver, request) = Receiver::new(&[false, true]).unwrap();
+        let (response, keys) = send_random(&request).unwrap();
+        let chosen = receiver.receive_random(&response).unwrap();
+        assert_eq!(chosen, vec![keys[0].0.clone(), keys[1].1.clone()]);
+
+        // decapsulating the other ciphertext with the receiver's key, as the
+        // other choice, does not give the other key
+        for (i, &(choice, ref sk)) in receiver.keys.iter().enumerate() {
+            let other = !choice;
+            let ss = sk.dec(&response.transfers[i].cts[other as usize]).unwrap();
+            let key = derive_key(&receiver.seed, i as u32, other, &ss);
+            let expected = if other { &keys[i].1 } else { &keys[i].0 };
+            assert!(&key != expected);
+        }
+
+        // and neither key opens the other message
+        let response = send(&request, &[(b"zero", b"one!"), (b"left", b"rite")]).unwrap();
+        let transfer = &response.transfers[0];
+        let messages = transfer.messages.as_ref().unwrap();
+        let ss = receiver.keys[0].1.dec(&transfer.cts[1]).unwrap();
+        assert!(open(&derive_key(&receiver.seed, 0, true, &ss), &messages[1]).is_err());
+    }
+
+    #[test]
+    fn request_round_trip_and_mismatched_messages() {
+        // a request survives serialization, the sender's keys for the two
+        // choices differ, and message pairs must match the request
+        let (receiver, request) = Receiver::new(&[true, false]).unwrap();
+        let mut bytes = vec![];
+        request.write_to(&mut bytes).unwrap();
+        let request = Request::from_bytes(&bytes).unwrap();
+        let (response, keys) = send_random(&request).unwrap();
+        assert!(keys.iter().all(|(k0, k1)| k0 != k1));
+        assert_eq!(receiver.receive_random(&response).unwrap()[0], keys[0].1);
+
+        assert!(send(&request, &[(b"a", b"b")]).is_err());
+        assert!(send(&request, &[(b"a", b"b"), (b"a", b"bc")]).is_err());
+        assert!(Request::from_bytes(&[0u8; rand::SEED_LEN + 4]).is_err());
+    }
+
+    #[test]
+    fn rejects_oversized_message_length() {
+        let mut bytes = vec![0, 0, 0, 1];
+        bytes.extend_from_slice(&[0u8; 2 * CIPHERTEXT_LEN]);
+        let mut huge = bytes.clone();
+        huge.write_u32::<BigEndian>(u32::MAX).unwrap();
+        assert!(Response::from_bytes(&huge).is_err());
+
+        bytes.write_u32::<BigEndian>(TAG_LEN as u32 + 1).unwrap();
+        bytes.extend_from_slice(&[0u8; 2 * TAG_LEN + 1]);
+        assert!(Response::from_bytes(&bytes).is_err());
+        bytes.push(0);
+        assert_eq!(Response::from_bytes(&bytes).unwrap().transfers.len(), 1);
+    }
+}
@@ -0,0 +1,338 @@
+//! Password-authenticated key exchange on the HILA5 ring, for short pairing
+//! codes.
+//!
+//! This is the PAK construction over Ring-LWE of Ding, Alsayigh, Lancrenon,
+//! RV and Snook ("Provably Secure Password Authenticated Key Exchange Based
+//! on RLWE for the Post-Quantum World", CT-RSA 2017), with HILA5's
+//! reconciliation and error correction in place of Ding's. Both parties know
+//! the password and agree on a public generator `g`.
+//!
+//!  1. The initiator samples `s_A, e_A` and sends the blinded value
+//!     `m = (g*s_A + e_A) + γ`, where `γ = H1(password)` is a ring element
+//!     derived from the password and both identities.
+//!  2. The responder unblinds `α = m - γ` and encapsulates to it as a HILA5
+//!     public key: it samples `s_B`, sends `μ = g*s_B + e_B` and the
+//!     reconciliation information for `α*s_B`, and a key confirmation MAC.
+//!  3. The initiator reconciles `μ*s_A` with its `s_A`, checks the responder's
+//!     MAC and returns its own.
+//!
+//! Both keys are derived from the reconciled secret, the transcript and a
+//! hash of the password. With a wrong password the responder unblinds an
+//! unrelated `α`, so the secrets disagree and the MAC check fails. `m` is
+//! indistinguishable from uniform under Ring-LWE, so a transcript does not
+//! allow offline guessing: each run tests at most one password, and
+//! responders should limit the number of failed runs.
+//!
+//! ```rust
+//! use hila5::pake::{Initiator, Responder};
+//!
+//! let (alice, msg1) = Initiator::start(b"4711", b"phone", b"laptop").unwrap();
+//! let (bob, msg2) = Responder::respond(b"4711", b"phone", b"laptop", &msg1).unwrap();
+//! let (key_alice, msg3) = alice.finish(&msg2).unwrap();
+//! let key_bob = bob.confirm(&msg3).unwrap();
+//!
+//! assert_eq!(key_alice.0, key_bob.0);
+//! ```
+
+use byteorder::{BigEndian, WriteBytesExt};
+use ring::{constant_time, digest, hmac};
+use sha3::{Digest, Sha3_256};
+
+use std::io::Write;
+
+use super::*;
+use encode::PACKED14;
+use errors::*;
+
+/// Length of the key confirmation MACs.
+pub const MAC_LEN: usize = 32;
+
+const PAKE_LABEL: &[u8] = b"HILA5v10-PAKE";
+
+/// First message: the initiator's blinded ephemeral key.
+pub struct Msg1 {
+    pub blinded: Vec<u8>,
+}
+
+/// Second message: the responder's encapsulation to the unblinded key.
+pub struct Msg2 {
+    pub ct: Vec<u8>,
+    /// Responder's key confirmation.
+    pub mac: Vec<u8>,
+}
+
+/// Third message: the initiator's key confirmation.
+pub struct Msg3 {
+    pub mac: Vec<u8>,
+}
+
+/// Initiator waiting for the responder's `Msg2`.
+pub struct Initiator {
+    ephemeral: PrivateKey,
+    password_hash: Vec<u8>,
+    msg1: Vec<u8>,
+}
+
+/// Responder waiting for the initiator's `Msg3`.
+pub struct Responder {
+    key: SessionKey,
+    mac_key: hmac::SigningKey,
+    transcript: Vec<u8>,
+}
+
+impl Initiator {
+    /// Start a key exchange with `password`, between the parties named
+    /// `initiator` and `responder`.
+    pub fn start(password: &[u8], initiator: &[u8], responder: &[u8]) -> Result<(Self, Msg1)> {
+        let (ephemeral_pk, ephemeral) = keygen::keypair_with_generator(generator_seed())?;
+        let password_hash = hash_password(password, initiator, responder)?;
+        let gamma = password_element(&password_hash);
+
+        let mut blinded = ephemeral_pk.key.clone();
+        for (mi, gi) in blinded.get_inner_mut().iter_mut().zip(gamma.get_inner().iter()) {
+            *mi = (*mi + gi) % HILA5_Q;
+        }
+        let mut msg1 = Msg1 { blinded: vec![0u8; PACKED14] };
+        encode::pack14(&blinded, &mut &mut msg1.blinded[..])?;
+
+        let initiator = Initiator {
+            ephemeral,
+            password_hash,
+            msg1: msg1.blinded.clone(),
+        };
+        Ok((initiator, msg1))
+    }
+
+    /// Process the responder's reply, returning the session key and our key
+    /// confirmation message.
+    pub fn finish(self, msg2: &Msg2) -> Result<(SessionKey, Msg3)> {
+        let ss = self.ephemeral.dec(&msg2.ct)?;
+
+        let transcript = transcript_hash(&self.password_hash, &self.msg1, &msg2.ct);
+        let keys = KeySchedule::new(&transcript, &ss, &self.password_hash);
+
+        let responder_mac = hmac::sign(&keys.responder_mac, &transcript);
+        constant_time::verify_slices_are_equal(responder_mac.as_ref(), &msg2.mac)
+            .map_err(|_| "responder key confirmation failed")?;
+
+        let mac = hmac::sign(&keys.initiator_mac, &transcript).as_ref().to_vec();
+        Ok((keys.session, Msg3 { mac }))
+    }
+}
+
+impl Responder {
+    /// Respond to a key exchange with `password`, between the parties named
+    /// `initiator` and `responder`.
+    pub fn respond(password: &[u8], initiator: &[u8], responder: &[u8], msg1: &Msg1)
+        -> Result<(Self, Msg2)>
+    {
+        let password_hash = hash_password(password, initiator, responder)?;
+        let gamma = password_element(&password_hash);
+
+        if msg1.blinded.len() != Msg1::LEN {
+            return Err("invalid length for PAKE message 1".into());
+        }
+        let mut alpha: NttVector = encode::unpack14(&msg1.blinded);
+        if alpha.get_inner().iter().any(|&x| x >= HILA5_Q) {
+            return Err("invalid PAKE message 1".into());
+        }
+        for (ai, gi) in alpha.get_inner_mut().iter_mut().zip(gamma.get_inner().iter()) {
+            *ai = (*ai - gi + HILA5_Q) % HILA5_Q;
+        }
+        let mut pk_bytes = generator_seed().to_vec();
+        encode::pack14(&alpha, &mut pk_bytes)?;
+        let (ct, ss) = kem::enc(&PublicKey::from_bytes(&pk_bytes))?;
+
+        let transcript = transcript_hash(&password_hash, &msg1.blinded, &ct);
+        let keys = KeySchedule::new(&transcript, &ss, &password_hash);
+        let mac = hmac::sign(&keys.responder_mac, &transcript).as_ref().to_vec();
+
+        let responder = Responder {
+            key: keys.session,
+            mac_key: keys.initiator_mac,
+            transcript,
+        };
+        Ok((responder, Msg2 { ct, mac }))
+    }
+
+    /// Check the initiator's key confirmation, returning the session key.
+    pub fn confirm(self, msg3: &Msg3) -> Result<SessionKey> {
+        hmac::verify_with_own_key(&self.mac_key, &self.transcript, &msg3.mac)
+            .map_err(|_| "initiator key confirmation failed")?;
+        Ok(self.key)
+    }
+}
+
+impl Msg1 {
+    /// Length in bytes of the serialised message.
+    pub const LEN: usize = PACKED14;
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&self.blinded)?;
+        Ok(())
+    }
+
+    pub fn from_bytes(input: &[u8]) -> Result<Self> {
+        if input.len() != Self::LEN {
+            return Err("invalid length for PAKE message 1".into());
+        }
+        Ok(Msg1 { blinded: input.to_vec() })
+    }
+}
+
+impl Msg2 {
+    /// Length in bytes of the serialised message.
+    pub const LEN: usize = CIPHERTEXT_LEN + MAC_LEN;
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&self.ct)?;
+        writer.write_all(&self.mac)?;
+        Ok(())
+    }
+
+    pub fn from_bytes(input: &[u8]) -> Result<Self> {
+        if input.len() != Self::LEN {
+            return Err("invalid length for PAKE message 2".into());
+        }
+        let (ct, mac) = input.split_at(CIPHERTEXT_LEN);
+        Ok(Msg2 {
+            ct: ct.to_vec(),
+            mac: mac.to_vec(),
+        })
+    }
+}
+
+impl Msg3 {
+    /// Length in bytes of the serialised message.
+    pub const LEN: usize = MAC_LEN;
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&self.mac)?;
+        Ok(())
+    }
+
+    pub fn from_bytes(input: &[u8]) -> Result<Self> {
+        if input.len() != Self::LEN {
+            return Err("invalid length for PAKE message 3".into());
+        }
+        Ok(Msg3 { mac: input.to_vec() })
+    }
+}
+
+/// Seed of the public generator shared by all PAKE runs.
+fn generator_seed() -> [u8; rand::SEED_LEN] {
+    let mut seed = [0u8; rand::SEED_LEN];
+    seed.copy_from_slice(&sha3(b"HILA5v10-PAKE generator"));
+    seed
+}
+
+/// Hash of the password and both identities, each prefixed by its length.
+fn hash_password(password: &[u8], initiator: &[u8], responder: &[u8]) -> Result<Vec<u8>> {
+    let mut input = PAKE_LABEL.to_vec();
+    for part in &[initiator, responder, password] {
+        input.write_u32::<BigEndian>(part.len() as u32)?;
+        input.extend_from_slice(part);
+    }
+    Ok(sha3(&input))
+}
+
+/// The blinding ring element `γ`, uniform modulo `q`.
+fn password_element(password_hash: &[u8]) -> NttVector {
+    let mut gamma: NttVector = rand::from_seed(password_hash);
+    for gi in gamma.get_inner_mut().iter_mut() {
+        *gi %= HILA5_Q;
+    }
+    gamma
+}
+
+fn transcript_hash(password_hash: &[u8], msg1: &[u8], ct: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha3_256::default();
+    hasher.input(PAKE_LABEL);
+    hasher.input(&sha3(password_hash));
+    hasher.input(msg1);
+    hasher.input(ct);
+    hasher.result().to_vec()
+}
+
+struct KeySchedule {
+    session: SessionKey,
+    initiator_mac: hmac::SigningKey,
+    responder_mac: hmac::SigningKey,
+}
+
+impl KeySchedule {
+    fn new(transcript: &[u8], ss: &SharedSecret, password_hash: &[u8]) -> Self {
+        let mut hasher = Sha3_256::default();
+        hasher.input(PAKE_LABEL);
+        hasher.input(transcript);
+        hasher.input(&ss.0);
+        hasher.input(password_hash);
+        let master = hasher.result();
+
+        KeySchedule {
+            session: SessionKey(derive(&master, b"session key")),
+            initiator_mac: hmac::SigningKey::new(&digest::SHA256, &derive(&master, b"initiator confirm")),
+            responder_mac: hmac::SigningKey::new(&digest::SHA256, &derive(&master, b"responder confirm")),
+        }
+    }
+}
+
+fn derive(master: &[u8], label: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha3_256::default();
+    hasher.input(label);
+    hasher.input(master);
+    hasher.result().to_vec()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pake_agrees() {
+        let mut buf = vec![];
+
+        let (alice, msg1) = Initiator::start(b"123456", b"alice", b"bob").unwrap();
+        msg1.write_to(&mut buf).unwrap();
+        let msg1 = Msg1::from_bytes(&buf).unwrap();
+
+        let (bob, msg2) = Responder::respond(b"123456", b"alice", b"bob", &msg1).unwrap();
+        buf.clear();
+        msg2.write_to(&mut buf).unwrap();
+        let msg2 = Msg2::from_bytes(&buf).unwrap();
+
+        let (key_a, msg3) = alice.finish(&msg2).unwrap();
+        buf.clear();
+        msg3.write_to(&mut buf).unwrap();
+        let msg3 = Msg3::from_bytes(&buf).unwrap();
+
+        let key_b = bob.confirm(&msg3).unwrap();
+        assert_eq!(key_a.0, key_b.0);
+    }
+
+    #[test]
+    fn wrong_password_fails() {
+        let (alice, msg1) = Initiator::start(b"123456", b"alice", b"bob").unwrap();
+        let (bob, msg2) = Responder::respond(b"123457", b"alice", b"bob", &msg1).unwrap();
+        assert!(alice.finish(&msg2).is_err());
+        assert!(bob.confirm(&Msg3 { mac: vec![0; MAC_LEN] }).is_err());
+
+        // the identities are bound to the password too
+        let (alice, msg1) = Initiator::start(b"123456", b"alice", b"bob").unwrap();
+        let (_, msg2) = Responder::respond(b"123456", b"mallory", b"bob", &msg1).unwrap();
+        assert!(alice.finish(&msg2).is_err());
+
+        // tampered confirmations are rejected by both sides
+        let (alice, msg1) = Initiator::start(b"123456", b"alice", b"bob").unwrap();
+        let (bob, mut msg2) = Responder::respond(b"123456", b"alice", b"bob", &msg1).unwrap();
+        msg2.mac[0] ^= 1;
+        assert!(alice.finish(&msg2).is_err());
+        assert!(bob.confirm(&Msg3 { mac: vec![0; MAC_LEN] }).is_err());
+    }
+
+    #[test]
+    fn rejects_non_canonical_message() {
+        let msg1 = Msg1 { blinded: vec![0xff; Msg1::LEN] };
+        assert!(Responder::respond(b"123456", b"alice", b"bob", &msg1).is_err());
+    }
+}
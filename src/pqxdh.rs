@@ -0,0 +1,551 @@
+//! PQXDH-style asynchronous initial key agreement, with HILA5 prekeys.
+//!
+//! This follows Signal's [PQXDH](https://signal.org/docs/specifications/pqxdh/)
+//! with HILA5 in place of Kyber. Bob publishes a `PrekeyBundle` containing
+//! his identity key, a signed X25519 prekey `SPK_B`, a signed HILA5 prekey
+//! `PQPK_B` (a one-time key if any are left, otherwise the last-resort key)
+//! and optionally a one-time X25519 prekey `OPK_B`. Alice, who may be offline
+//! from Bob's point of view, computes
+//!
+//! ```text
+//! DH1 = DH(IK_A, SPK_B)   DH2 = DH(EK_A, IK_B)   DH3 = DH(EK_A, SPK_B)
+//! DH4 = DH(EK_A, OPK_B)   (CT, SS) = enc(PQPK_B)
+//! SK  = KDF(DH1 || DH2 || DH3 || [DH4] || SS)
+//! ```
+//!
+//! and sends an `InitialMessage` carrying `IK_A`, `EK_A`, `CT`, the prekey
+//! identifiers and a first ciphertext. Bob runs `kem::dec` and the same DH
+//! computations on receipt, deleting the one-time prekeys used.
+//!
+//! Identity keys are an Ed25519 key, for signing prekeys, together with an
+//! X25519 key for the DH computations (Signal instead uses a single key with
+//! XEdDSA). The KDF is HKDF-SHA256 with 32 `0xFF` bytes prepended to the input
+//! key material, a zero salt and the info string `PQXDH_CURVE25519_SHA-256_HILA5`.
+//! The first ciphertext is encrypted with ChaCha20-Poly1305, under a key
+//! derived alongside `SK`, with `Encode(IK_A) || Encode(IK_B)` as associated
+//! data.
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use ed25519_dalek::{self, Signer};
+use ring::{aead, digest, hkdf, hmac};
+use ring::rand::SecureRandom;
+use x25519_dalek;
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+use super::*;
+use errors::*;
+
+/// Length of the shared key `SK`.
+pub const SK_LEN: usize = 32;
+/// Length of Ed25519 signatures over prekeys.
+pub const SIGNATURE_LEN: usize = 64;
+
+const KDF_INFO: &[u8] = b"PQXDH_CURVE25519_SHA-256_HILA5";
+const CURVE_TYPE: u8 = 0x05;
+const ED25519_TYPE: u8 = 0x06;
+const HILA5_TYPE: u8 = 0x48;
+const TAG_LEN: usize = 16;
+
+/// Private identity key.
+pub struct IdentityKey {
+    signing: ed25519_dalek::SigningKey,
+    dh: x25519_dalek::StaticSecret,
+}
+
+/// Public identity key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PublicIdentity {
+    /// Ed25519 key which signs the prekeys.
+    pub signing: [u8; 32],
+    /// X25519 key used in the DH computations.
+    pub dh: [u8; 32],
+}
+
+impl IdentityKey {
+    pub fn generate() -> Result<Self> {
+        let mut seed = [0u8; 32];
+        get_rng().fill(&mut seed)?;
+        Ok(IdentityKey {
+            signing: ed25519_dalek::SigningKey::from_bytes(&seed),
+            dh: x25519_secret()?,
+        })
+    }
+
+    pub fn public(&self) -> PublicIdentity {
+        PublicIdentity {
+            signing: self.signing.verifying_key().to_bytes(),
+            dh: x25519_dalek::PublicKey::from(&self.dh).to_bytes(),
+        }
+    }
+
+    fn sign(&self, msg: &[u8]) -> Vec<u8> {
+        self.signing.sign(msg).to_bytes().to_vec()
+    }
+}
+
+impl PublicIdentity {
+    /// `Encode(IK)`: both keys, each prefixed by its type.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![ED25519_TYPE];
+        out.extend_from_slice(&self.signing);
+        out.push(CURVE_TYPE);
+        out.extend_from_slice(&self.dh);
+        out
+    }
+
+    fn verify(&self, msg: &[u8], signature: &[u8]) -> Result<()> {
+        let key = ed25519_dalek::VerifyingKey::from_bytes(&self.signing)
+            .map_err(|_| "invalid identity signing key")?;
+        if signature.len() != SIGNATURE_LEN {
+            return Err("invalid prekey signature length".into());
+        }
+        let mut sig = [0u8; SIGNATURE_LEN];
+        sig.copy_from_slice(signature);
+        key.verify_strict(msg, &ed25519_dalek::Signature::from_bytes(&sig))
+            .map_err(|_| "invalid prekey signature".into())
+    }
+}
+
+/// Signed X25519 prekey `SPK_B`.
+#[derive(Clone)]
+pub struct SignedPrekey {
+    pub id: u32,
+    pub key: [u8; 32],
+    pub signature: Vec<u8>,
+}
+
+/// Signed HILA5 prekey `PQPK_B`, either one-time or last-resort.
+#[derive(Clone)]
+pub struct PqPrekey {
+    pub id: u32,
+    pub key: PublicKey,
+    pub signature: Vec<u8>,
+}
+
+/// One-time X25519 prekey `OPK_B`.
+#[derive(Clone)]
+pub struct OneTimePrekey {
+    pub id: u32,
+    pub key: [u8; 32],
+}
+
+/// Prekeys published by the responder, fetched by the initiator.
+#[derive(Clone)]
+pub struct PrekeyBundle {
+    pub identity: PublicIdentity,
+    pub signed_prekey: SignedPrekey,
+    pub pq_prekey: PqPrekey,
+    pub one_time_prekey: Option<OneTimePrekey>,
+}
+
+/// First message from the initiator.
+pub struct InitialMessage {
+    pub identity: PublicIdentity,
+    pub ephemeral: [u8; 32],
+    pub signed_prekey_id: u32,
+    pub pq_prekey_id: u32,
+    pub one_time_prekey_id: Option<u32>,
+    /// Encapsulation to `PQPK_B`.
+    pub ct: Vec<u8>,
+    /// The initiator's first message, encrypted.
+    pub ciphertext: Vec<u8>,
+}
+
+fn encode_curve(key: &[u8; 32]) -> Vec<u8> {
+    let mut out = vec![CURVE_TYPE];
+    out.extend_from_slice(key);
+    out
+}
+
+fn encode_hila5(key: &PublicKey) -> Result<Vec<u8>> {
+    let mut out = vec![HILA5_TYPE];
+    key.write_to(&mut out)?;
+    Ok(out)
+}
+
+impl PrekeyBundle {
+    /// Check the signatures on the prekeys.
+    pub fn verify(&self) -> Result<()> {
+        self.identity.verify(&encode_curve(&self.signed_prekey.key), &self.signed_prekey.signature)?;
+        self.identity.verify(&encode_hila5(&self.pq_prekey.key)?, &self.pq_prekey.signature)
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        write_identity(writer, &self.identity)?;
+        writer.write_u32::<BigEndian>(self.signed_prekey.id)?;
+        writer.write_all(&self.signed_prekey.key)?;
+        writer.write_all(&self.signed_prekey.signature)?;
+        writer.write_u32::<BigEndian>(self.pq_prekey.id)?;
+        self.pq_prekey.key.write_to(writer)?;
+        writer.write_all(&self.pq_prekey.signature)?;
+        match self.one_time_prekey {
+            Some(ref opk) => {
+                writer.write_u8(1)?;
+                writer.write_u32::<BigEndian>(opk.id)?;
+                writer.write_all(&opk.key)?;
+            }
+            None => writer.write_u8(0)?,
+        }
+        Ok(())
+    }
+
+    pub fn from_bytes(mut input: &[u8]) -> Result<Self> {
+        let reader = &mut input;
+        let identity = read_identity(reader)?;
+        let signed_prekey = SignedPrekey {
+            id: reader.read_u32::<BigEndian>()?,
+            key: read_array(reader)?,
+            signature: read_vec(reader, SIGNATURE_LEN)?,
+        };
+        let pq_prekey = PqPrekey {
+            id: reader.read_u32::<BigEndian>()?,
+            key: PublicKey::from_bytes(&read_vec(reader, PUBKEY_LEN)?),
+            signature: read_vec(reader, SIGNATURE_LEN)?,
+        };
+        let one_time_prekey = match reader.read_u8()? {
+            0 => None,
+            1 => Some(OneTimePrekey { id: reader.read_u32::<BigEndian>()?, key: read_array(reader)? }),
+            _ => return Err("invalid prekey bundle".into()),
+        };
+        if !reader.is_empty() {
+            return Err("trailing data after prekey bundle".into());
+        }
+        Ok(PrekeyBundle { identity, signed_prekey, pq_prekey, one_time_prekey })
+    }
+}
+
+impl InitialMessage {
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        write_identity(writer, &self.identity)?;
+        writer.write_all(&self.ephemeral)?;
+        writer.write_u32::<BigEndian>(self.signed_prekey_id)?;
+        writer.write_u32::<BigEndian>(self.pq_prekey_id)?;
+        match self.one_time_prekey_id {
+            Some(id) => {
+                writer.write_u8(1)?;
+                writer.write_u32::<BigEndian>(id)?;
+            }
+            None => writer.write_u8(0)?,
+        }
+        writer.write_all(&self.ct)?;
+        writer.write_all(&self.ciphertext)?;
+        Ok(())
+    }
+
+    pub fn from_bytes(mut input: &[u8]) -> Result<Self> {
+        let reader = &mut input;
+        let identity = read_identity(reader)?;
+        let ephemeral = read_array(reader)?;
+        let signed_prekey_id = reader.read_u32::<BigEndian>()?;
+        let pq_prekey_id = reader.read_u32::<BigEndian>()?;
+        let one_time_prekey_id = match reader.read_u8()? {
+            0 => None,
+            1 => Some(reader.read_u32::<BigEndian>()?),
+            _ => return Err("invalid initial message".into()),
+        };
+        let ct = read_vec(reader, CIPHERTEXT_LEN)?;
+        if reader.len() < TAG_LEN {
+            return Err("truncated initial message".into());
+        }
+        Ok(InitialMessage {
+            identity, ephemeral, signed_prekey_id, pq_prekey_id, one_time_prekey_id, ct,
+            ciphertext: reader.to_vec(),
+        })
+    }
+}
+
+fn write_identity<W: Write>(writer: &mut W, identity: &PublicIdentity) -> Result<()> {
+    writer.write_all(&identity.signing)?;
+    writer.write_all(&identity.dh)?;
+    Ok(())
+}
+
+fn read_identity(reader: &mut &[u8]) -> Result<PublicIdentity> {
+    Ok(PublicIdentity { signing: read_array(reader)?, dh: read_array(reader)? })
+}
+
+fn read_array(reader: &mut &[u8]) -> Result<[u8; 32]> {
+    let mut out = [0u8; 32];
+    reader.read_exact(&mut out)?;
+    Ok(out)
+}
+
+fn read_vec(reader: &mut &[u8], len: usize) -> Result<Vec<u8>> {
+    let mut out = vec![0u8; len];
+    reader.read_exact(&mut out)?;
+    Ok(out)
+}
+
+/// Key material from the KDF: the shared key `SK`, and the key for the first
+/// message.
+struct Keys {
+    sk: SessionKey,
+    message_key: Vec<u8>,
+}
+
+/// `KDF(KM)`, returning `SK` and the key for the first message.
+fn kdf(km: &[u8]) -> Keys {
+    let mut ikm = vec![0xffu8; 32];
+    ikm.extend_from_slice(km);
+    let salt = hmac::SigningKey::new(&digest::SHA256, &[0u8; 32]);
+    let mut out = [0u8; 2 * SK_LEN];
+    hkdf::extract_and_expand(&salt, &ikm, KDF_INFO, &mut out);
+    Keys { sk: SessionKey(out[..SK_LEN].to_vec()), message_key: out[SK_LEN..].to_vec() }
+}
+
+fn associated_data(initiator: &PublicIdentity, responder: &PublicIdentity) -> Vec<u8> {
+    let mut ad = initiator.encode();
+    ad.extend_from_slice(&responder.encode());
+    ad
+}
+
+/// Run the initiator side against `bundle`, returning `SK` and the initial
+/// message carrying `plaintext`.
+pub fn initiate(identity: &IdentityKey, bundle: &PrekeyBundle, plaintext: &[u8])
+    -> Result<(SessionKey, InitialMessage)>
+{
+    bundle.verify()?;
+
+    let ephemeral = x25519_secret()?;
+    let mut km = x25519_agree(&identity.dh, &bundle.signed_prekey.key)?;
+    km.extend(x25519_agree(&ephemeral, &bundle.identity.dh)?);
+    km.extend(x25519_agree(&ephemeral, &bundle.signed_prekey.key)?);
+    if let Some(ref opk) = bundle.one_time_prekey {
+        km.extend(x25519_agree(&ephemeral, &opk.key)?);
+    }
+    let (ct, ss) = bundle.pq_prekey.key.enc()?;
+    km.extend_from_slice(&ss.0);
+    let keys = kdf(&km);
+
+    let public = identity.public();
+    let mut ciphertext = plaintext.to_vec();
+    ciphertext.extend_from_slice(&[0u8; TAG_LEN]);
+    let key = aead::SealingKey::new(&aead::CHACHA20_POLY1305, &keys.message_key)?;
+    aead::seal_in_place(&key, &[0u8; 12], &associated_data(&public, &bundle.identity),
+                        &mut ciphertext, TAG_LEN)?;
+
+    let msg = InitialMessage {
+        identity: public,
+        ephemeral: x25519_dalek::PublicKey::from(&ephemeral).to_bytes(),
+        signed_prekey_id: bundle.signed_prekey.id,
+        pq_prekey_id: bundle.pq_prekey.id,
+        one_time_prekey_id: bundle.one_time_prekey.as_ref().map(|opk| opk.id),
+        ct,
+        ciphertext,
+    };
+    Ok((keys.sk, msg))
+}
+
+/// The responder's identity and prekeys, including the private keys.
+pub struct PrekeyStore {
+    identity: IdentityKey,
+    signed_prekey: (x25519_dalek::StaticSecret, SignedPrekey),
+    last_resort: (PrivateKey, PqPrekey),
+    pq_one_time: HashMap<u32, (PrivateKey, PqPrekey)>,
+    one_time: HashMap<u32, (x25519_dalek::StaticSecret, OneTimePrekey)>,
+    next_id: u32,
+}
+
+impl PrekeyStore {
+    /// Create a store with a fresh signed prekey and last-resort HILA5
+    /// prekey, and no one-time prekeys.
+    pub fn new(identity: IdentityKey) -> Result<Self> {
+        let spk = x25519_secret()?;
+        let key = x25519_dalek::PublicKey::from(&spk).to_bytes();
+        let signed_prekey = SignedPrekey { id: 0, key, signature: identity.sign(&encode_curve(&key)) };
+        let last_resort = Self::pq_prekey(&identity, 1)?;
+        Ok(PrekeyStore {
+            identity,
+            signed_prekey: (spk, signed_prekey),
+            last_resort,
+            pq_one_time: HashMap::new(),
+            one_time: HashMap::new(),
+            next_id: 2,
+        })
+    }
+
+    fn pq_prekey(identity: &IdentityKey, id: u32) -> Result<(PrivateKey, PqPrekey)> {
+        let (pk, sk) = crypto_kem_keypair()?;
+        let signature = identity.sign(&encode_hila5(&pk)?);
+        Ok((sk, PqPrekey { id, key: pk, signature }))
+    }
+
+    pub fn identity(&self) -> PublicIdentity {
+        self.identity.public()
+    }
+
+    /// Generate `n` one-time X25519 prekeys and `n` one-time HILA5 prekeys.
+    pub fn generate_one_time_prekeys(&mut self, n: usize) -> Result<()> {
+        for _ in 0..n {
+            let pq = Self::pq_prekey(&self.identity, self.next_id)?;
+            self.pq_one_time.insert(self.next_id, pq);
+            let opk = x25519_secret()?;
+            let public = OneTimePrekey {
+                id: self.next_id + 1,
+                key: x25519_dalek::PublicKey::from(&opk).to_bytes(),
+            };
+            self.one_time.insert(self.next_id + 1, (opk, public));
+            self.next_id += 2;
+        }
+        Ok(())
+    }
+
+    /// Number of unused one-time HILA5 prekeys.
+    pub fn one_time_prekeys_left(&self) -> usize {
+        self.pq_one_time.len()
+    }
+
+    /// Build a bundle for an initiator, using one-time prekeys if any are
+    /// left, and the last-resort HILA5 prekey otherwise.
+    ///
+    /// The one-time prekeys stay in the store until used by `receive`, but a
+    /// server would only hand each one out once.
+    pub fn bundle(&self) -> PrekeyBundle {
+        let pq_prekey = self.pq_one_time.values().min_by_key(|k| k.1.id)
+            .map_or(&self.last_resort.1, |k| &k.1)
+            .clone();
+        let one_time_prekey = self.one_time.values().min_by_key(|k| k.1.id).map(|k| k.1.clone());
+        PrekeyBundle {
+            identity: self.identity.public(),
+            signed_prekey: self.signed_prekey.1.clone(),
+            pq_prekey,
+            one_time_prekey,
+        }
+    }
+
+    /// Process an initial message, returning `SK` and the decrypted first
+    /// message. One-time prekeys are deleted, so a second message using the
+    /// same prekeys is rejected.
+    pub fn receive(&mut self, msg: &InitialMessage) -> Result<(SessionKey, Vec<u8>)> {
+        if msg.signed_prekey_id != self.signed_prekey.1.id {
+            return Err("unknown signed prekey".into());
+        }
+        if msg.pq_prekey_id != self.last_resort.1.id && !self.pq_one_time.contains_key(&msg.pq_prekey_id) {
+            return Err("unknown or already used HILA5 prekey".into());
+        }
+        if let Some(id) = msg.one_time_prekey_id {
+            if !self.one_time.contains_key(&id) {
+                return Err("unknown or already used one-time prekey".into());
+            }
+        }
+
+        let mut km = x25519_agree(&self.signed_prekey.0, &msg.identity.dh)?;
+        km.extend(x25519_agree(&self.identity.dh, &msg.ephemeral)?);
+        km.extend(x25519_agree(&self.signed_prekey.0, &msg.ephemeral)?);
+        if let Some(id) = msg.one_time_prekey_id {
+            km.extend(x25519_agree(&self.one_time[&id].0, &msg.ephemeral)?);
+        }
+        let pq_sk = match self.pq_one_time.get(&msg.pq_prekey_id) {
+            Some(k) => &k.0,
+            None => &self.last_resort.0,
+        };
+        km.extend_from_slice(&pq_sk.dec(&msg.ct)?.0);
+        let keys = kdf(&km);
+
+        let mut plaintext = msg.ciphertext.clone();
+        let key = aead::OpeningKey::new(&aead::CHACHA20_POLY1305, &keys.message_key)?;
+        let len = aead::open_in_place(&key, &[0u8; 12], &associated_data(&msg.identity, &self.identity.public()),
+                                      0, &mut plaintext)
+            .map_err(|_| "initial message decryption failed")?
+            .len();
+        plaintext.truncate(len);
+
+        // Only delete the one-time keys once the message is authenticated.
+        self.pq_one_time.remove(&msg.pq_prekey_id);
+        if let Some(id) = msg.one_time_prekey_id {
+            self.one_time.remove(&id);
+        }
+        Ok((keys.sk, plaintext))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn kdf_test_vector() {
+        let km: Vec<u8> = (0..4 * 32 + 32).map(|i| i as u8).collect();
+        let keys = kdf(&km);
+        assert_eq!(hex(&keys.sk.0),
+                   "a5aad70c5c25970aef1a7f78420daa8bca33c5a538b2a07d2dcee27ea3b09965");
+        assert_eq!(hex(&keys.message_key),
+                   "bc3b143b59f80be986f1fec95245f60dc779f589abf8effae4e5da5f06523945");
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn roundtrip(msg: &InitialMessage) -> InitialMessage {
+        let mut buf = vec![];
+        msg.write_to(&mut buf).unwrap();
+        InitialMessage::from_bytes(&buf).unwrap()
+    }
+
+    #[test]
+    fn pqxdh_agrees() {
+        let alice = IdentityKey::generate().unwrap();
+        let mut bob = PrekeyStore::new(IdentityKey::generate().unwrap()).unwrap();
+        bob.generate_one_time_prekeys(2).unwrap();
+
+        let mut buf = vec![];
+        bob.bundle().write_to(&mut buf).unwrap();
+        let bundle = PrekeyBundle::from_bytes(&buf).unwrap();
+        assert!(bundle.one_time_prekey.is_some());
+
+        let (sk_alice, msg) = initiate(&alice, &bundle, b"hello bob").unwrap();
+        let msg = roundtrip(&msg);
+        let (sk_bob, plaintext) = bob.receive(&msg).unwrap();
+        assert_eq!(sk_alice.0, sk_bob.0);
+        assert_eq!(plaintext, b"hello bob");
+        assert_eq!(bob.one_time_prekeys_left(), 1);
+
+        // One-time prekeys are consumed
+        assert!(bob.receive(&msg).is_err());
+    }
+
+    #[test]
+    fn last_resort_prekey() {
+        let alice = IdentityKey::generate().unwrap();
+        let mut bob = PrekeyStore::new(IdentityKey::generate().unwrap()).unwrap();
+        let bundle = bob.bundle();
+        assert!(bundle.one_time_prekey.is_none());
+
+        // The last-resort key can be used more than once.
+        for _ in 0..2 {
+            let (sk_alice, msg) = initiate(&alice, &bundle, b"").unwrap();
+            let (sk_bob, plaintext) = bob.receive(&msg).unwrap();
+            assert_eq!(sk_alice.0, sk_bob.0);
+            assert!(plaintext.is_empty());
+        }
+    }
+
+    #[test]
+    fn rejects_bad_bundles_and_messages() {
+        let alice = IdentityKey::generate().unwrap();
+        let mut bob = PrekeyStore::new(IdentityKey::generate().unwrap()).unwrap();
+        bob.generate_one_time_prekeys(1).unwrap();
+
+        // Prekey signed by someone else
+        let mut bundle = bob.bundle();
+        bundle.identity.signing = alice.public().signing;
+        assert!(initiate(&alice, &bundle, b"hi").is_err());
+
+        // Substituted HILA5 prekey
+        let mut bundle = bob.bundle();
+        bundle.pq_prekey.key = crypto_kem_keypair().unwrap().0;
+        assert!(initiate(&alice, &bundle, b"hi").is_err());
+
+        // Tampered ciphertext does not consume the one-time prekeys
+        let (_, mut msg) = initiate(&alice, &bob.bundle(), b"hi").unwrap();
+        msg.ciphertext[0] ^= 1;
+        assert!(bob.receive(&msg).is_err());
+        assert_eq!(bob.one_time_prekeys_left(), 1);
+        msg.ciphertext[0] ^= 1;
+        assert!(bob.receive(&msg).is_ok());
+        assert_eq!(bob.one_time_prekeys_left(), 0);
+    }
+}
@@ -38,11 +38,27 @@ pub fn psi16<V: Hila5Vector>() -> V {
     for vi in v.iter_mut() {
         let mut rand_bytes = [0u8; 4];
         rng.fill(&mut rand_bytes).unwrap();
-        *vi = (rand_bytes.iter().map(|x| x.count_ones() as i32).sum::<i32>() +  HILA5_Q - 16) % HILA5_Q;
+        *vi = psi16_sample(&rand_bytes);
     }
     V::from(v)
 }
 
+/// sample a vector of values from the psi16 distribution, reading the
+/// randomness from `xof`
+pub fn psi16_from_xof<V: Hila5Vector, R: XofReader>(xof: &mut R) -> V {
+    let mut v = [0; HILA5_N];
+    for vi in v.iter_mut() {
+        let mut rand_bytes = [0u8; 4];
+        xof.read(&mut rand_bytes);
+        *vi = psi16_sample(&rand_bytes);
+    }
+    V::from(v)
+}
+
+fn psi16_sample(rand_bytes: &[u8; 4]) -> Scalar {
+    (rand_bytes.iter().map(|x| x.count_ones() as i32).sum::<i32>() +  HILA5_Q - 16) % HILA5_Q
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -0,0 +1,581 @@
+//! Post-quantum ratchet for long-lived sessions, healing with KEM steps.
+//!
+//! A `Session` encrypts messages with keys from a symmetric chain, as in the
+//! Double Ratchet, but the asymmetric ratchet is built from HILA5 instead of
+//! Diffie-Hellman:
+//!
+//!  - each party advertises a fresh HILA5 public key in the header of its
+//!    messages, until the peer answers it;
+//!  - when sending, a party holding an unanswered key from its peer
+//!    encapsulates to it, mixes the `SharedSecret` into its sending root
+//!    chain, and starts a new sending chain (a new *epoch*). The ciphertext is
+//!    carried in every message of that epoch;
+//!  - on receiving the first message of the new epoch, the peer decapsulates
+//!    with its private key, derives the same receiving chain, and then
+//!    advertises another fresh public key.
+//!
+//! Each direction has its own root chain, which only the sender advances, so
+//! both parties may ratchet at the same time. Message keys of skipped messages
+//! are stored, so messages may arrive out of order or not at all. At most
+//! `MAX_STORED` keys are kept; beyond that the oldest are evicted, and their
+//! messages can no longer be decrypted.
+//!
+//! ```rust
+//! use hila5::ratchet::Session;
+//! use hila5::SessionKey;
+//!
+//! // A shared key from an initial key agreement, e.g. `pqxdh`.
+//! let sk = SessionKey(vec![7u8; 32]);
+//! let mut alice = Session::initiator(&sk);
+//! let mut bob = Session::responder(&sk);
+//!
+//! let msg = alice.encrypt(b"hello", b"").unwrap();
+//! assert_eq!(bob.decrypt(&msg, b"").unwrap(), b"hello");
+//! let reply = bob.encrypt(b"hi", b"").unwrap();
+//! assert_eq!(alice.decrypt(&reply, b"").unwrap(), b"hi");
+//! ```
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use ring::{aead, digest, hkdf, hmac};
+
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+
+use super::*;
+use errors::*;
+
+/// Maximum number of message keys skipped within a single chain.
+pub const MAX_SKIP: u32 = 1000;
+/// Maximum number of skipped message keys stored. Older keys are evicted to
+/// make room for new ones.
+pub const MAX_STORED: usize = 2000;
+
+const KEY_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+const STATE_VERSION: u8 = 1;
+const HAS_PK: u8 = 1;
+const HAS_CT: u8 = 2;
+
+const ROOT_INFO: &[u8] = b"HILA5v10-ratchet root";
+
+/// Message header.
+pub struct Header {
+    /// Sender's epoch, i.e. the number of KEM steps on its sending chain.
+    pub epoch: u32,
+    /// Index of the message in its chain.
+    pub n: u32,
+    /// Length of the sender's previous chain.
+    pub pn: u32,
+    /// The sender's current public key, and its identifier.
+    pub pk: Option<(u32, PublicKey)>,
+    /// Encapsulation to the receiver's public key with the given identifier,
+    /// which started this epoch.
+    pub ct: Option<(u32, Vec<u8>)>,
+}
+
+impl Header {
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_u32::<BigEndian>(self.epoch)?;
+        writer.write_u32::<BigEndian>(self.n)?;
+        writer.write_u32::<BigEndian>(self.pn)?;
+        let flags = if self.pk.is_some() { HAS_PK } else { 0 }
+            | if self.ct.is_some() { HAS_CT } else { 0 };
+        writer.write_u8(flags)?;
+        if let Some((id, ref pk)) = self.pk {
+            writer.write_u32::<BigEndian>(id)?;
+            pk.write_to(writer)?;
+        }
+        if let Some((id, ref ct)) = self.ct {
+            writer.write_u32::<BigEndian>(id)?;
+            writer.write_all(ct)?;
+        }
+        Ok(())
+    }
+
+    /// Parse a header from the start of `reader`.
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Self> {
+        let epoch = reader.read_u32::<BigEndian>()?;
+        let n = reader.read_u32::<BigEndian>()?;
+        let pn = reader.read_u32::<BigEndian>()?;
+        let flags = reader.read_u8()?;
+        if flags & !(HAS_PK | HAS_CT) != 0 {
+            return Err("invalid ratchet header".into());
+        }
+        let pk = if flags & HAS_PK != 0 {
+            let id = reader.read_u32::<BigEndian>()?;
+            Some((id, PublicKey::from_bytes(&read_vec(reader, PUBKEY_LEN)?)))
+        } else {
+            None
+        };
+        let ct = if flags & HAS_CT != 0 {
+            let id = reader.read_u32::<BigEndian>()?;
+            Some((id, read_vec(reader, CIPHERTEXT_LEN)?))
+        } else {
+            None
+        };
+        Ok(Header { epoch, n, pn, pk, ct })
+    }
+}
+
+/// State of one party in a ratcheted session.
+#[derive(Clone)]
+pub struct Session {
+    root_send: Vec<u8>,
+    root_recv: Vec<u8>,
+
+    send_chain: Vec<u8>,
+    send_epoch: u32,
+    send_n: u32,
+    prev_send_n: u32,
+    /// Encapsulation which started the current sending epoch.
+    send_ct: Option<(u32, Vec<u8>)>,
+
+    recv_chain: Vec<u8>,
+    recv_epoch: u32,
+    recv_n: u32,
+
+    /// Our advertised keypair, waiting for an answer.
+    own_kem: Option<(u32, PrivateKey, PublicKey)>,
+    next_own_id: u32,
+    /// The peer's latest unanswered public key.
+    peer_kem: Option<(u32, PublicKey)>,
+    peer_kem_seen: u32,
+
+    skipped: BTreeMap<(u32, u32), Vec<u8>>,
+}
+
+impl Session {
+    /// Start a session as the party who initiated the key agreement which
+    /// produced `sk`.
+    pub fn initiator(sk: &SessionKey) -> Self {
+        Self::new(sk, b"initiator", b"responder")
+    }
+
+    /// Start a session as the responder of the key agreement which produced
+    /// `sk`.
+    pub fn responder(sk: &SessionKey) -> Self {
+        Self::new(sk, b"responder", b"initiator")
+    }
+
+    fn new(sk: &SessionKey, ours: &[u8], theirs: &[u8]) -> Self {
+        let (root_send, send_chain) = kdf_rk(&[], &sk.0, ours);
+        let (root_recv, recv_chain) = kdf_rk(&[], &sk.0, theirs);
+        Session {
+            root_send,
+            root_recv,
+            send_chain,
+            send_epoch: 0,
+            send_n: 0,
+            prev_send_n: 0,
+            send_ct: None,
+            recv_chain,
+            recv_epoch: 0,
+            recv_n: 0,
+            own_kem: None,
+            next_own_id: 1,
+            peer_kem: None,
+            peer_kem_seen: 0,
+            skipped: BTreeMap::new(),
+        }
+    }
+
+    /// Number of KEM ratchet steps on our sending chain.
+    pub fn send_epoch(&self) -> u32 {
+        self.send_epoch
+    }
+
+    /// Number of KEM ratchet steps on our receiving chain.
+    pub fn recv_epoch(&self) -> u32 {
+        self.recv_epoch
+    }
+
+    /// Encrypt `plaintext`, authenticating `ad` as well.
+    pub fn encrypt(&mut self, plaintext: &[u8], ad: &[u8]) -> Result<Vec<u8>> {
+        if let Some((id, pk)) = self.peer_kem.take() {
+            let (ct, ss) = pk.enc()?;
+            let (root, chain) = kdf_rk(&self.root_send, &ss.0, ROOT_INFO);
+            self.root_send = root;
+            self.send_chain = chain;
+            self.send_epoch += 1;
+            self.prev_send_n = self.send_n;
+            self.send_n = 0;
+            self.send_ct = Some((id, ct));
+        }
+        if self.own_kem.is_none() {
+            let (pk, sk) = crypto_kem_keypair()?;
+            self.own_kem = Some((self.next_own_id, sk, pk));
+            self.next_own_id += 1;
+        }
+
+        let header = Header {
+            epoch: self.send_epoch,
+            n: self.send_n,
+            pn: self.prev_send_n,
+            pk: self.own_kem.as_ref().map(|&(id, _, ref pk)| (id, pk.clone())),
+            ct: self.send_ct.clone(),
+        };
+        let mut msg = vec![];
+        header.write_to(&mut msg)?;
+
+        let (mk, chain) = kdf_ck(&self.send_chain);
+        self.send_chain = chain;
+        self.send_n += 1;
+
+        let header_len = msg.len();
+        msg.extend_from_slice(plaintext);
+        msg.extend_from_slice(&[0u8; TAG_LEN]);
+        let (header, body) = msg.split_at_mut(header_len);
+        let key = aead::SealingKey::new(&aead::CHACHA20_POLY1305, &mk)?;
+        aead::seal_in_place(&key, &[0u8; 12], &associated_data(ad, header), body, TAG_LEN)?;
+        Ok(msg)
+    }
+
+    /// Decrypt a message produced by the peer's `encrypt`. The session is
+    /// unchanged if the message is rejected.
+    pub fn decrypt(&mut self, msg: &[u8], ad: &[u8]) -> Result<Vec<u8>> {
+        let mut reader = msg;
+        let header = Header::read_from(&mut reader)?;
+        let header_bytes = &msg[..msg.len() - reader.len()];
+        if reader.len() < TAG_LEN {
+            return Err("truncated ratchet message".into());
+        }
+
+        let mut state = self.clone();
+        let mk = match state.skipped.remove(&(header.epoch, header.n)) {
+            Some(mk) => mk,
+            None => state.next_recv_key(&header)?,
+        };
+
+        let mut body = reader.to_vec();
+        let key = aead::OpeningKey::new(&aead::CHACHA20_POLY1305, &mk)?;
+        let len = aead::open_in_place(&key, &[0u8; 12], &associated_data(ad, header_bytes), 0, &mut body)
+            .map_err(|_| "ratchet message authentication failed")?
+            .len();
+        body.truncate(len);
+
+        if let Some((id, pk)) = header.pk {
+            if id > state.peer_kem_seen {
+                state.peer_kem = Some((id, pk));
+                state.peer_kem_seen = id;
+            }
+        }
+        *self = state;
+        Ok(body)
+    }
+
+    /// Advance the receiving chains to the message with `header`, storing any
+    /// skipped message keys, and return its message key.
+    fn next_recv_key(&mut self, header: &Header) -> Result<Vec<u8>> {
+        if header.epoch == self.recv_epoch.wrapping_add(1) {
+            let ss = {
+                let (ct_id, ref ct) = *header.ct.as_ref().ok_or("missing ratchet ciphertext")?;
+                match self.own_kem {
+                    Some((id, ref sk, _)) if id == ct_id => sk.dec(ct)?,
+                    _ => return Err("ratchet ciphertext for unknown key".into()),
+                }
+            };
+            self.skip_keys(header.pn)?;
+            let (root, chain) = kdf_rk(&self.root_recv, &ss.0, ROOT_INFO);
+            self.root_recv = root;
+            self.recv_chain = chain;
+            self.recv_epoch += 1;
+            self.recv_n = 0;
+            self.own_kem = None;
+        } else if header.epoch != self.recv_epoch {
+            return Err("ratchet message from an unknown epoch".into());
+        }
+
+        self.skip_keys(header.n)?;
+        let (mk, chain) = kdf_ck(&self.recv_chain);
+        self.recv_chain = chain;
+        self.recv_n += 1;
+        Ok(mk)
+    }
+
+    /// Store message keys of the current receiving chain, up to `until`,
+    /// evicting the oldest stored keys beyond `MAX_STORED`.
+    fn skip_keys(&mut self, until: u32) -> Result<()> {
+        if until <= self.recv_n {
+            return Ok(());
+        }
+        if until - self.recv_n > MAX_SKIP {
+            return Err("too many skipped ratchet messages".into());
+        }
+        while self.recv_n < until {
+            let (mk, chain) = kdf_ck(&self.recv_chain);
+            self.recv_chain = chain;
+            self.skipped.insert((self.recv_epoch, self.recv_n), mk);
+            self.recv_n += 1;
+        }
+        // Keys are ordered by epoch and then message number, so the first
+        // are the oldest.
+        while self.skipped.len() > MAX_STORED {
+            self.skipped.pop_first();
+        }
+        Ok(())
+    }
+
+    /// Serialise the session state, including private keys.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_u8(STATE_VERSION)?;
+        for key in &[&self.root_send, &self.root_recv, &self.send_chain, &self.recv_chain] {
+            writer.write_all(key)?;
+        }
+        for &n in &[self.send_epoch, self.send_n, self.prev_send_n, self.recv_epoch, self.recv_n,
+                    self.next_own_id, self.peer_kem_seen] {
+            writer.write_u32::<BigEndian>(n)?;
+        }
+        match self.send_ct {
+            Some((id, ref ct)) => {
+                writer.write_u8(1)?;
+                writer.write_u32::<BigEndian>(id)?;
+                writer.write_all(ct)?;
+            }
+            None => writer.write_u8(0)?,
+        }
+        match self.own_kem {
+            Some((id, ref sk, ref pk)) => {
+                writer.write_u8(1)?;
+                writer.write_u32::<BigEndian>(id)?;
+                sk.write_to(writer)?;
+                pk.write_to(writer)?;
+            }
+            None => writer.write_u8(0)?,
+        }
+        match self.peer_kem {
+            Some((id, ref pk)) => {
+                writer.write_u8(1)?;
+                writer.write_u32::<BigEndian>(id)?;
+                pk.write_to(writer)?;
+            }
+            None => writer.write_u8(0)?,
+        }
+        writer.write_u32::<BigEndian>(self.skipped.len() as u32)?;
+        for (&(epoch, n), mk) in &self.skipped {
+            writer.write_u32::<BigEndian>(epoch)?;
+            writer.write_u32::<BigEndian>(n)?;
+            writer.write_all(mk)?;
+        }
+        Ok(())
+    }
+
+    /// Restore a session serialised with `write_to`.
+    pub fn from_bytes(mut input: &[u8]) -> Result<Self> {
+        let reader = &mut input;
+        if reader.read_u8()? != STATE_VERSION {
+            return Err("unsupported ratchet state version".into());
+        }
+        let root_send = read_vec(reader, KEY_LEN)?;
+        let root_recv = read_vec(reader, KEY_LEN)?;
+        let send_chain = read_vec(reader, KEY_LEN)?;
+        let recv_chain = read_vec(reader, KEY_LEN)?;
+        let mut counters = [0u32; 7];
+        for n in &mut counters {
+            *n = reader.read_u32::<BigEndian>()?;
+        }
+        let [send_epoch, send_n, prev_send_n, recv_epoch, recv_n, next_own_id, peer_kem_seen] = counters;
+        let send_ct = if read_flag(reader)? {
+            Some((reader.read_u32::<BigEndian>()?, read_vec(reader, CIPHERTEXT_LEN)?))
+        } else {
+            None
+        };
+        let own_kem = if read_flag(reader)? {
+            let id = reader.read_u32::<BigEndian>()?;
+            let sk = PrivateKey::from_bytes(&read_vec(reader, PRIVKEY_LEN)?);
+            let pk = PublicKey::from_bytes(&read_vec(reader, PUBKEY_LEN)?);
+            Some((id, sk, pk))
+        } else {
+            None
+        };
+        let peer_kem = if read_flag(reader)? {
+            let id = reader.read_u32::<BigEndian>()?;
+            Some((id, PublicKey::from_bytes(&read_vec(reader, PUBKEY_LEN)?)))
+        } else {
+            None
+        };
+        let count = reader.read_u32::<BigEndian>()? as usize;
+        if count > MAX_STORED {
+            return Err("too many skipped message keys in ratchet state".into());
+        }
+        let mut skipped = BTreeMap::new();
+        for _ in 0..count {
+            let epoch = reader.read_u32::<BigEndian>()?;
+            let n = reader.read_u32::<BigEndian>()?;
+            skipped.insert((epoch, n), read_vec(reader, KEY_LEN)?);
+        }
+        if !reader.is_empty() {
+            return Err("trailing data after ratchet state".into());
+        }
+        Ok(Session {
+            root_send, root_recv, send_chain, send_epoch, send_n, prev_send_n, send_ct,
+            recv_chain, recv_epoch, recv_n, own_kem, next_own_id, peer_kem, peer_kem_seen,
+            skipped,
+        })
+    }
+}
+
+/// Root chain step: mix `secret` into `root`, returning the new root key and
+/// a new chain key.
+fn kdf_rk(root: &[u8], secret: &[u8], info: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let salt = hmac::SigningKey::new(&digest::SHA256, root);
+    let mut out = [0u8; 2 * KEY_LEN];
+    hkdf::extract_and_expand(&salt, secret, info, &mut out);
+    (out[..KEY_LEN].to_vec(), out[KEY_LEN..].to_vec())
+}
+
+/// Symmetric chain step, returning the message key and the next chain key.
+fn kdf_ck(chain: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let key = hmac::SigningKey::new(&digest::SHA256, chain);
+    let mk = hmac::sign(&key, &[1]).as_ref().to_vec();
+    let next = hmac::sign(&key, &[2]).as_ref().to_vec();
+    (mk, next)
+}
+
+fn associated_data(ad: &[u8], header: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(8 + ad.len() + header.len());
+    out.write_u64::<BigEndian>(ad.len() as u64).unwrap();
+    out.extend_from_slice(ad);
+    out.extend_from_slice(header);
+    out
+}
+
+fn read_flag<R: Read>(reader: &mut R) -> Result<bool> {
+    match reader.read_u8()? {
+        0 => Ok(false),
+        1 => Ok(true),
+        _ => Err("invalid ratchet state".into()),
+    }
+}
+
+fn read_vec<R: Read>(reader: &mut R, len: usize) -> Result<Vec<u8>> {
+    let mut out = vec![0u8; len];
+    reader.read_exact(&mut out)?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pair() -> (Session, Session) {
+        let sk = SessionKey(vec![42u8; 32]);
+        (Session::initiator(&sk), Session::responder(&sk))
+    }
+
+    #[test]
+    fn ratchets_on_each_round_trip() {
+        let (mut alice, mut bob) = pair();
+        for round in 0..3 {
+            let msg = alice.encrypt(b"ping", b"ad").unwrap();
+            assert_eq!(bob.decrypt(&msg, b"ad").unwrap(), b"ping");
+            let msg = bob.encrypt(b"pong", b"ad").unwrap();
+            assert_eq!(alice.decrypt(&msg, b"ad").unwrap(), b"pong");
+            assert_eq!(bob.send_epoch(), round + 1);
+            assert_eq!(alice.recv_epoch(), round + 1);
+        }
+        assert_eq!(alice.send_epoch(), 2);
+        assert_eq!(bob.recv_epoch(), 2);
+    }
+
+    #[test]
+    fn out_of_order_and_concurrent_steps() {
+        let (mut alice, mut bob) = pair();
+        let a0 = alice.encrypt(b"a0", b"").unwrap();
+        let b0 = bob.encrypt(b"b0", b"").unwrap();
+        // Both learn the other's key and ratchet at the same time.
+        alice.decrypt(&b0, b"").unwrap();
+        bob.decrypt(&a0, b"").unwrap();
+        let a1 = alice.encrypt(b"a1", b"").unwrap();
+        let a2 = alice.encrypt(b"a2", b"").unwrap();
+        let b1 = bob.encrypt(b"b1", b"").unwrap();
+        assert_eq!(alice.send_epoch(), 1);
+        assert_eq!(bob.send_epoch(), 1);
+
+        assert_eq!(bob.decrypt(&a2, b"").unwrap(), b"a2");
+        assert_eq!(alice.decrypt(&b1, b"").unwrap(), b"b1");
+
+        // Bob advertises a new key, so Alice ratchets again, and the skipped
+        // a1 still decrypts afterwards.
+        let b2 = bob.encrypt(b"b2", b"").unwrap();
+        assert_eq!(alice.decrypt(&b2, b"").unwrap(), b"b2");
+        let a3 = alice.encrypt(b"a3", b"").unwrap();
+        assert_eq!(alice.send_epoch(), 2);
+        assert_eq!(bob.decrypt(&a3, b"").unwrap(), b"a3");
+        assert_eq!(bob.decrypt(&a1, b"").unwrap(), b"a1");
+
+        // Replays are rejected.
+        assert!(bob.decrypt(&a1, b"").is_err());
+        assert!(bob.decrypt(&a3, b"").is_err());
+    }
+
+    #[test]
+    fn rejects_tampering_without_changing_state() {
+        let (mut alice, mut bob) = pair();
+        let msg = alice.encrypt(b"secret", b"ad").unwrap();
+        assert!(bob.decrypt(&msg, b"other ad").is_err());
+        let mut bad = msg.clone();
+        *bad.last_mut().unwrap() ^= 1;
+        assert!(bob.decrypt(&bad, b"ad").is_err());
+        assert_eq!(bob.decrypt(&msg, b"ad").unwrap(), b"secret");
+
+        // Too many skipped messages
+        for _ in 0..MAX_SKIP + 1 {
+            alice.encrypt(b"", b"").unwrap();
+        }
+        let far = alice.encrypt(b"", b"").unwrap();
+        assert!(bob.decrypt(&far, b"").is_err());
+    }
+
+    #[test]
+    fn evicts_oldest_skipped_keys() {
+        let (mut alice, mut bob) = pair();
+        let per_epoch = MAX_SKIP as usize - 1;
+        let mut first_lost = None;
+        let mut last_lost = None;
+        for epoch in 0..4 {
+            // Bob only receives the last message of each of Alice's epochs.
+            let mut msgs = (0..per_epoch + 1)
+                .map(|_| alice.encrypt(b"lost", b"").unwrap())
+                .collect::<Vec<_>>();
+            let last = msgs.pop().unwrap();
+            first_lost = first_lost.or_else(|| Some(msgs[0].clone()));
+            last_lost = msgs.pop();
+            assert_eq!(bob.decrypt(&last, b"").unwrap(), b"lost");
+            assert_eq!(alice.send_epoch(), epoch);
+
+            let reply = bob.encrypt(b"ack", b"").unwrap();
+            assert_eq!(alice.decrypt(&reply, b"").unwrap(), b"ack");
+        }
+        assert!(4 * per_epoch > MAX_STORED);
+        assert_eq!(bob.skipped.len(), MAX_STORED);
+
+        // The newest skipped keys are kept, the oldest were evicted.
+        assert_eq!(bob.decrypt(&last_lost.unwrap(), b"").unwrap(), b"lost");
+        assert!(bob.decrypt(&first_lost.unwrap(), b"").is_err());
+        let msg = alice.encrypt(b"still alive", b"").unwrap();
+        assert_eq!(bob.decrypt(&msg, b"").unwrap(), b"still alive");
+    }
+
+    #[test]
+    fn serialize_session() {
+        let (mut alice, mut bob) = pair();
+        let a0 = alice.encrypt(b"a0", b"").unwrap();
+        let a1 = alice.encrypt(b"a1", b"").unwrap();
+        bob.decrypt(&a1, b"").unwrap();
+        let b0 = bob.encrypt(b"b0", b"").unwrap();
+
+        let mut state = vec![];
+        bob.write_to(&mut state).unwrap();
+        let mut restored = Session::from_bytes(&state).unwrap();
+        let mut again = vec![];
+        restored.write_to(&mut again).unwrap();
+        assert_eq!(state, again);
+
+        assert_eq!(restored.decrypt(&a0, b"").unwrap(), b"a0");
+        alice.decrypt(&b0, b"").unwrap();
+        let b1 = restored.encrypt(b"b1", b"").unwrap();
+        assert_eq!(alice.decrypt(&b1, b"").unwrap(), b"b1");
+
+        assert!(Session::from_bytes(&state[..state.len() - 1]).is_err());
+    }
+}
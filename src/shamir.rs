@@ -0,0 +1,288 @@
+//! Shamir secret sharing of private keys for m-of-n recovery.
+//!
+//! The encoding of a `SeedPrivateKey` is split byte by byte over GF(256)
+//! (the AES field), so any `threshold` of the shares recover it and fewer
+//! reveal nothing. Seed keys are shared rather than expanded `PrivateKey`s
+//! because only a seed can regenerate its public key, which is how
+//! `combine` checks that recovery worked.
+//!
+//! Each share records its index, the threshold, the fingerprint of the
+//! public key and a 16 byte tag, HMAC-SHA256 under the shared secret of all
+//! other fields. Tags can only be checked once the secret is recovered, and
+//! then identify any share that was corrupted, including shares beyond the
+//! threshold that took no part in recovery.
+//! Shares are printed as Bech32m strings starting `hila5share1`, whose
+//! checksum catches transcription errors before recovery is attempted.
+//!
+//! ```rust
+//! use hila5::shamir::{self, Share};
+//! use hila5::SeedPrivateKey;
+//!
+//! let sk = SeedPrivateKey::generate(false).unwrap();
+//! let shares = shamir::split(&sk, 3, 5).unwrap();
+//! let printed = shares.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+//!
+//! let parsed = [&printed[4], &printed[0], &printed[2]].iter()
+//!     .map(|s| Share::parse(s).unwrap())
+//!     .collect::<Vec<_>>();
+//! let recovered = shamir::combine(&parsed).unwrap();
+//! assert_eq!(recovered.public_key().unwrap().digest().unwrap(),
+//!            sk.public_key().unwrap().digest().unwrap());
+//! ```
+
+use ring::rand::SecureRandom;
+use ring::{constant_time, digest, hmac};
+
+use std::fmt;
+
+use super::*;
+use bech32::{self, Fingerprint, FINGERPRINT_LEN};
+use errors::*;
+
+/// Human-readable part of printed shares.
+pub const SHARE_HRP: &str = "hila5share";
+/// Length of the integrity tag on each share.
+pub const TAG_LEN: usize = 16;
+
+const TAG_LABEL: &[u8] = b"HILA5 share";
+
+/// One share of a split private key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Share {
+    /// The x coordinate of the share, from 1.
+    pub index: u8,
+    /// How many shares are needed to recover the key.
+    pub threshold: u8,
+    /// The fingerprint of the public key of the split key.
+    pub fingerprint: Fingerprint,
+    value: Vec<u8>,
+    tag: [u8; TAG_LEN],
+}
+
+impl Share {
+    pub fn from_bytes(input: &[u8]) -> Result<Self> {
+        let header = 2 + FINGERPRINT_LEN;
+        let value_len = input.len().saturating_sub(header + TAG_LEN);
+        if value_len != SEED_PRIVKEY_LEN && value_len != 2 * SEED_PRIVKEY_LEN {
+            return Err(format_error("share has the wrong length"));
+        }
+        if input[0] == 0 || input[1] == 0 {
+            return Err(format_error("share index and threshold must be non-zero"));
+        }
+        let mut fingerprint = [0u8; FINGERPRINT_LEN];
+        fingerprint.copy_from_slice(&input[2..header]);
+        let mut tag = [0u8; TAG_LEN];
+        tag.copy_from_slice(&input[header + value_len..]);
+        Ok(Share {
+            index: input[0],
+            threshold: input[1],
+            fingerprint: Fingerprint(fingerprint),
+            value: input[header..header + value_len].to_vec(),
+            tag,
+        })
+    }
+
+    /// Index, threshold, fingerprint, share value and tag.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = self.tagged_bytes();
+        out.extend_from_slice(&self.tag);
+        out
+    }
+
+    /// Parse a `hila5share1…` string.
+    pub fn parse(s: &str) -> Result<Self> {
+        Self::from_bytes(&bech32::decode_with_hrp(s, SHARE_HRP)?)
+    }
+
+    fn tagged_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(2 + FINGERPRINT_LEN + self.value.len() + TAG_LEN);
+        out.push(self.index);
+        out.push(self.threshold);
+        out.extend_from_slice(&self.fingerprint.0);
+        out.extend_from_slice(&self.value);
+        out
+    }
+
+    fn compute_tag(&self, secret: &[u8]) -> [u8; TAG_LEN] {
+        let key = hmac::SigningKey::new(&digest::SHA256, secret);
+        let mut ctx = hmac::SigningContext::with_key(&key);
+        ctx.update(TAG_LABEL);
+        ctx.update(&self.tagged_bytes());
+        let mut tag = [0u8; TAG_LEN];
+        tag.copy_from_slice(&ctx.sign().as_ref()[..TAG_LEN]);
+        tag
+    }
+}
+
+impl fmt::Display for Share {
+    /// The `hila5share1…` form.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&bech32::encode(SHARE_HRP, &self.to_bytes()))
+    }
+}
+
+/// Split `sk` into `count` shares, any `threshold` of which recover it.
+pub fn split(sk: &SeedPrivateKey, threshold: u8, count: u8) -> Result<Vec<Share>> {
+    if threshold == 0 || threshold > count {
+        return Err("threshold must be between 1 and the number of shares".into());
+    }
+    let fingerprint = Fingerprint::from_public_key(&sk.public_key()?)?;
+    let mut secret = Vec::with_capacity(2 * SEED_PRIVKEY_LEN);
+    sk.write_to(&mut secret)?;
+
+    // coefficients[j * len + b] is the x^(j + 1) coefficient for byte b
+    let mut coefficients = vec![0u8; (threshold as usize - 1) * secret.len()];
+    get_rng().fill(&mut coefficients)?;
+
+    let shares = (1..=count).map(|index| {
+        let value = secret.iter().enumerate().map(|(b, &constant)| {
+            let higher = coefficients.chunks(secret.len()).rev()
+                .fold(0, |acc, coefficient| gf_mul(acc, index) ^ coefficient[b]);
+            gf_mul(higher, index) ^ constant
+        }).collect();
+        let mut share = Share { index, threshold, fingerprint, value, tag: [0u8; TAG_LEN] };
+        share.tag = share.compute_tag(&secret);
+        share
+    }).collect();
+    Ok(shares)
+}
+
+/// Recover the key from at least `threshold` shares, checking it against
+/// the fingerprint and the tag of every share given.
+pub fn combine(shares: &[Share]) -> Result<SeedPrivateKey> {
+    let first = shares.first().ok_or("no shares given")?;
+    if shares.iter().any(|s| {
+        s.threshold != first.threshold || s.fingerprint != first.fingerprint
+            || s.value.len() != first.value.len()
+    }) {
+        return Err("shares come from different splits".into());
+    }
+    for (i, share) in shares.iter().enumerate() {
+        if shares[..i].iter().any(|s| s.index == share.index) {
+            return Err(format!("share {} was given twice", share.index).into());
+        }
+    }
+    let threshold = first.threshold as usize;
+    if shares.len() < threshold {
+        return Err(format!("{} shares are needed, only {} given", threshold, shares.len()).into());
+    }
+
+    let used = &shares[..threshold];
+    let weights = used.iter().map(|share| {
+        // Lagrange basis polynomial for this share, evaluated at zero
+        let (num, den) = used.iter().filter(|s| s.index != share.index)
+            .fold((1, 1), |(num, den), s| (gf_mul(num, s.index), gf_mul(den, s.index ^ share.index)));
+        gf_mul(num, gf_inv(den))
+    }).collect::<Vec<_>>();
+    let secret = (0..first.value.len()).map(|b| {
+        used.iter().zip(&weights).fold(0, |acc, (share, &weight)| acc ^ gf_mul(weight, share.value[b]))
+    }).collect::<Vec<_>>();
+
+    let sk = SeedPrivateKey::from_bytes(&secret)?;
+    if Fingerprint::from_public_key(&sk.public_key()?)? != first.fingerprint {
+        return Err("recovered key does not match the fingerprint on the shares".into());
+    }
+    for share in shares {
+        if constant_time::verify_slices_are_equal(&share.compute_tag(&secret), &share.tag).is_err() {
+            return Err(format!("share {} failed its integrity check", share.index).into());
+        }
+    }
+    Ok(sk)
+}
+
+/// Multiplication in GF(2^8) modulo x^8 + x^4 + x^3 + x + 1, without
+/// secret-dependent branches.
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    for _ in 0..8 {
+        product ^= a & (b & 1).wrapping_neg();
+        let carry = (a >> 7).wrapping_neg();
+        a = (a << 1) ^ (carry & 0x1b);
+        b >>= 1;
+    }
+    product
+}
+
+/// Inverse in GF(2^8) as `a^254`.
+fn gf_inv(a: u8) -> u8 {
+    let a2 = gf_mul(a, a);
+    let a4 = gf_mul(a2, a2);
+    let a8 = gf_mul(a4, a4);
+    let a16 = gf_mul(a8, a8);
+    let a32 = gf_mul(a16, a16);
+    let a64 = gf_mul(a32, a32);
+    let a128 = gf_mul(a64, a64);
+    [a2, a4, a8, a16, a32, a64].iter().fold(a128, |acc, &x| gf_mul(acc, x))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn field_arithmetic() {
+        // FIPS 197, section 4.2
+        assert_eq!(gf_mul(0x57, 0x83), 0xc1);
+        assert_eq!(gf_mul(0x57, 0x13), 0xfe);
+        assert_eq!(gf_inv(0x53), 0xca);
+        for a in 1..=255 {
+            assert_eq!(gf_mul(a, gf_inv(a)), 1);
+        }
+    }
+
+    #[test]
+    fn any_threshold_subset_recovers() {
+        let sk = SeedPrivateKey::generate(true).unwrap();
+        let digest = sk.public_key().unwrap().digest().unwrap();
+        let shares = split(&sk, 3, 5).unwrap();
+        for subset in &[[0, 1, 2], [4, 2, 0], [1, 3, 4]] {
+            let chosen = subset.iter().map(|&i| shares[i].clone()).collect::<Vec<_>>();
+            let recovered = combine(&chosen).unwrap();
+            assert!(recovered.has_rejection_secret());
+            assert_eq!(recovered.public_key().unwrap().digest().unwrap(), digest);
+        }
+        assert!(combine(&shares).is_ok());
+        assert!(combine(&shares[..2]).is_err());
+        assert!(combine(&[shares[0].clone(), shares[0].clone(), shares[1].clone()]).is_err());
+        assert!(split(&sk, 0, 5).is_err());
+        assert!(split(&sk, 6, 5).is_err());
+    }
+
+    #[test]
+    fn corrupted_shares_are_rejected() {
+        let sk = SeedPrivateKey::generate(false).unwrap();
+        let shares = split(&sk, 2, 3).unwrap();
+
+        // a corrupted value recovers the wrong key
+        let mut bad = shares[1].clone();
+        bad.value[0] ^= 1;
+        assert!(combine(&[shares[0].clone(), bad]).is_err());
+
+        // a share beyond the threshold is still checked against its tag
+        let mut extra = shares[2].clone();
+        extra.value[0] ^= 1;
+        let err = combine(&[shares[0].clone(), shares[1].clone(), extra]).err().unwrap();
+        assert_eq!(err.to_string(), "share 3 failed its integrity check");
+
+        // shares of a different split of the same key do not mix
+        let other = split(&sk, 2, 3).unwrap();
+        let err = combine(&[shares[0].clone(), other[1].clone()]).err().unwrap();
+        assert_eq!(err.to_string(), "recovered key does not match the fingerprint on the shares");
+    }
+
+    #[test]
+    fn printed_shares() {
+        let sk = SeedPrivateKey::generate(false).unwrap();
+        let share = split(&sk, 2, 2).unwrap().remove(1);
+        let printed = share.to_string();
+        assert!(printed.starts_with("hila5share1"));
+        assert_eq!(Share::parse(&printed).unwrap(), share);
+        assert_eq!(Share::parse(&printed.to_uppercase()).unwrap(), share);
+
+        let mut typo = printed.into_bytes();
+        let last = typo.len() - 1;
+        typo[last] = if typo[last] == b'q' { b'p' } else { b'q' };
+        assert!(Share::parse(&String::from_utf8(typo).unwrap()).is_err());
+        assert!(Share::from_bytes(&share.to_bytes()[1..]).is_err());
+    }
+}
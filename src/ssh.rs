@@ -0,0 +1,396 @@
+//! SSH key exchange methods using HILA5, alone or hybridised with X25519.
+//!
+//! The methods follow `sntrup761x25519-sha512`, with HILA5 in place of
+//! Streamlined NTRU Prime and SHA3-256 as the hash:
+//!
+//!  - the client sends `SSH_MSG_KEX_HYBRID_INIT` carrying `Q_C`, its
+//!    ephemeral HILA5 public key followed (for the hybrid) by its X25519
+//!    public key;
+//!  - the server replies with `SSH_MSG_KEX_HYBRID_REPLY` carrying its host key
+//!    `K_S`, `Q_S` (the HILA5 ciphertext followed by the server's X25519 public
+//!    key) and the signature of the exchange hash;
+//!  - the shared secret is `K = SHA3-256(hila5_ss || x25519_ss)`, and is
+//!    encoded as a `string` (not an `mpint`) when hashed;
+//!  - the exchange hash is
+//!    `H = SHA3-256(V_C || V_S || I_C || I_S || K_S || Q_C || Q_S || K)`, with
+//!    every field encoded as an SSH `string`.
+//!
+//! Host key signatures are outside the scope of this module: the server signs
+//! `H` through a callback, and the client must verify the signature in the
+//! reply against `K_S` and the returned exchange hash.
+
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use sha3::{Digest, Sha3_256};
+use x25519_dalek;
+
+use std::io::Write;
+
+use super::*;
+use errors::*;
+
+/// Message number of the client's key exchange message.
+pub const SSH_MSG_KEX_HYBRID_INIT: u8 = 30;
+/// Message number of the server's key exchange message.
+pub const SSH_MSG_KEX_HYBRID_REPLY: u8 = 31;
+
+/// Supported key exchange methods.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Method {
+    /// `hila5-sha3-256`
+    Hila5Sha3_256,
+    /// `hila5x25519-sha3-256`
+    Hila5X25519Sha3_256,
+}
+
+impl Method {
+    /// Name of the method, as used in `SSH_MSG_KEXINIT`.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Method::Hila5Sha3_256 => "hila5-sha3-256",
+            Method::Hila5X25519Sha3_256 => "hila5x25519-sha3-256",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "hila5-sha3-256" => Some(Method::Hila5Sha3_256),
+            "hila5x25519-sha3-256" => Some(Method::Hila5X25519Sha3_256),
+            _ => None,
+        }
+    }
+
+    fn is_hybrid(&self) -> bool {
+        *self == Method::Hila5X25519Sha3_256
+    }
+
+    fn client_share_len(&self) -> usize {
+        if self.is_hybrid() { PUBKEY_LEN + X25519_LEN } else { PUBKEY_LEN }
+    }
+
+    fn server_share_len(&self) -> usize {
+        if self.is_hybrid() { CIPHERTEXT_LEN + X25519_LEN } else { CIPHERTEXT_LEN }
+    }
+}
+
+/// Values from the rest of the connection which are covered by the exchange
+/// hash.
+pub struct Context<'a> {
+    /// `V_C`, the client's identification string without CR LF.
+    pub client_version: &'a [u8],
+    /// `V_S`, the server's identification string without CR LF.
+    pub server_version: &'a [u8],
+    /// `I_C`, the payload of the client's `SSH_MSG_KEXINIT`.
+    pub client_kexinit: &'a [u8],
+    /// `I_S`, the payload of the server's `SSH_MSG_KEXINIT`.
+    pub server_kexinit: &'a [u8],
+}
+
+/// `SSH_MSG_KEX_HYBRID_INIT`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KexInit {
+    /// `Q_C`, the client's key shares.
+    pub client_share: Vec<u8>,
+}
+
+/// `SSH_MSG_KEX_HYBRID_REPLY`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KexReply {
+    /// `K_S`, the server's public host key blob.
+    pub host_key: Vec<u8>,
+    /// `Q_S`, the server's key shares.
+    pub server_share: Vec<u8>,
+    /// Signature of the exchange hash by the host key.
+    pub signature: Vec<u8>,
+}
+
+impl KexInit {
+    /// Encode the message payload, starting with the message number.
+    pub fn to_payload(&self) -> Vec<u8> {
+        let mut payload = vec![SSH_MSG_KEX_HYBRID_INIT];
+        write_string(&mut payload, &self.client_share);
+        payload
+    }
+
+    pub fn from_payload(payload: &[u8]) -> Result<Self> {
+        let mut reader = StringReader::new(payload, SSH_MSG_KEX_HYBRID_INIT)?;
+        let client_share = reader.string()?;
+        reader.finish()?;
+        Ok(KexInit { client_share })
+    }
+}
+
+impl KexReply {
+    /// Encode the message payload, starting with the message number.
+    pub fn to_payload(&self) -> Vec<u8> {
+        let mut payload = vec![SSH_MSG_KEX_HYBRID_REPLY];
+        write_string(&mut payload, &self.host_key);
+        write_string(&mut payload, &self.server_share);
+        write_string(&mut payload, &self.signature);
+        payload
+    }
+
+    pub fn from_payload(payload: &[u8]) -> Result<Self> {
+        let mut reader = StringReader::new(payload, SSH_MSG_KEX_HYBRID_REPLY)?;
+        let host_key = reader.string()?;
+        let server_share = reader.string()?;
+        let signature = reader.string()?;
+        reader.finish()?;
+        Ok(KexReply { host_key, server_share, signature })
+    }
+}
+
+/// Result of the key exchange.
+pub struct KexOutput {
+    /// The shared secret `K`.
+    pub shared_secret: Vec<u8>,
+    /// The exchange hash `H`, which is also the session identifier for the
+    /// first key exchange.
+    pub exchange_hash: Vec<u8>,
+}
+
+impl KexOutput {
+    /// `K` encoded as an SSH `string`, as used when deriving the session keys.
+    pub fn encoded_shared_secret(&self) -> Vec<u8> {
+        let mut out = vec![];
+        write_string(&mut out, &self.shared_secret);
+        out
+    }
+}
+
+/// Client side of the key exchange, holding the ephemeral private keys.
+pub struct ClientKex {
+    method: Method,
+    sk: PrivateKey,
+    x25519: Option<x25519_dalek::StaticSecret>,
+    client_share: Vec<u8>,
+}
+
+impl ClientKex {
+    /// Generate ephemeral keys and the `SSH_MSG_KEX_HYBRID_INIT` to send.
+    pub fn start(method: Method) -> Result<(Self, KexInit)> {
+        let (pk, sk) = crypto_kem_keypair()?;
+        let mut client_share = vec![];
+        pk.write_to(&mut client_share)?;
+        let x25519 = if method.is_hybrid() {
+            let secret = x25519_secret()?;
+            client_share.extend_from_slice(x25519_dalek::PublicKey::from(&secret).as_bytes());
+            Some(secret)
+        } else {
+            None
+        };
+        let init = KexInit { client_share: client_share.clone() };
+        Ok((ClientKex { method, sk, x25519, client_share }, init))
+    }
+
+    /// Process the server's reply. The caller must then verify
+    /// `reply.signature` over the returned exchange hash with `reply.host_key`.
+    pub fn finish(self, ctx: &Context, reply: &KexReply) -> Result<KexOutput> {
+        if reply.server_share.len() != self.method.server_share_len() {
+            return Err("invalid server key share length".into());
+        }
+        let (ct, x25519_share) = reply.server_share.split_at(CIPHERTEXT_LEN);
+        let x25519_ss = match self.x25519 {
+            Some(secret) => Some(x25519_agree(&secret, x25519_share)?),
+            None => None,
+        };
+        let shared_secret = shared_secret(&self.sk.dec(ct)?.0, x25519_ss.as_ref().map(|ss| &ss[..]));
+        let exchange_hash = exchange_hash(ctx, &reply.host_key, &self.client_share,
+                                          &reply.server_share, &shared_secret);
+        Ok(KexOutput { shared_secret, exchange_hash })
+    }
+}
+
+/// Server side of the key exchange: process the client's message, signing the
+/// exchange hash with `sign`, and produce the reply.
+pub fn server_reply<F>(method: Method, ctx: &Context, init: &KexInit, host_key: &[u8], sign: F)
+    -> Result<(KexOutput, KexReply)>
+    where F: FnOnce(&[u8]) -> Result<Vec<u8>>
+{
+    if init.client_share.len() != method.client_share_len() {
+        return Err("invalid client key share length".into());
+    }
+    let (pk, x25519_share) = init.client_share.split_at(PUBKEY_LEN);
+    let (mut server_share, ss) = PublicKey::from_bytes(pk).enc()?;
+    let x25519_ss = if method.is_hybrid() {
+        let secret = x25519_secret()?;
+        server_share.extend_from_slice(x25519_dalek::PublicKey::from(&secret).as_bytes());
+        Some(x25519_agree(&secret, x25519_share)?)
+    } else {
+        None
+    };
+    let shared_secret = shared_secret(&ss.0, x25519_ss.as_ref().map(|ss| &ss[..]));
+    let exchange_hash = exchange_hash(ctx, host_key, &init.client_share, &server_share,
+                                      &shared_secret);
+    let reply = KexReply {
+        host_key: host_key.to_vec(),
+        server_share,
+        signature: sign(&exchange_hash)?,
+    };
+    Ok((KexOutput { shared_secret, exchange_hash }, reply))
+}
+
+/// `K = SHA3-256(hila5_ss || x25519_ss)`.
+fn shared_secret(hila5_ss: &[u8], x25519_ss: Option<&[u8]>) -> Vec<u8> {
+    let mut hasher = Sha3_256::default();
+    hasher.input(hila5_ss);
+    if let Some(x25519_ss) = x25519_ss {
+        hasher.input(x25519_ss);
+    }
+    hasher.result().to_vec()
+}
+
+fn exchange_hash(ctx: &Context, host_key: &[u8], client_share: &[u8], server_share: &[u8],
+                 shared_secret: &[u8]) -> Vec<u8> {
+    let mut buf = vec![];
+    for field in &[ctx.client_version, ctx.server_version, ctx.client_kexinit,
+                   ctx.server_kexinit, host_key, client_share, server_share, shared_secret] {
+        write_string(&mut buf, field);
+    }
+    let mut hasher = Sha3_256::default();
+    hasher.input(&buf);
+    hasher.result().to_vec()
+}
+
+fn write_string(out: &mut Vec<u8>, data: &[u8]) {
+    out.write_u32::<BigEndian>(data.len() as u32).unwrap();
+    out.write_all(data).unwrap();
+}
+
+/// Reads SSH `string` fields from a message payload.
+struct StringReader<'a> {
+    input: &'a [u8],
+}
+
+impl<'a> StringReader<'a> {
+    fn new(payload: &'a [u8], msg_type: u8) -> Result<Self> {
+        match payload.split_first() {
+            Some((&ty, rest)) if ty == msg_type => Ok(StringReader { input: rest }),
+            _ => Err(format!("expected SSH message {}", msg_type).into()),
+        }
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>> {
+        if self.input.len() < 4 {
+            return Err("truncated SSH string".into());
+        }
+        let len = BigEndian::read_u32(self.input) as usize;
+        if self.input.len() - 4 < len {
+            return Err("truncated SSH string".into());
+        }
+        let out = self.input[4..4 + len].to_vec();
+        self.input = &self.input[4 + len..];
+        Ok(out)
+    }
+
+    fn finish(self) -> Result<()> {
+        if !self.input.is_empty() {
+            return Err("trailing data in SSH message".into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ring::{digest, hmac};
+
+    use super::*;
+
+    const HOST_KEY: &[u8] = b"\x00\x00\x00\x0bssh-ed25519\x00\x00\x00\x20test host key...................";
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn sign(h: &[u8]) -> Result<Vec<u8>> {
+        let key = hmac::SigningKey::new(&digest::SHA256, HOST_KEY);
+        Ok(hmac::sign(&key, h).as_ref().to_vec())
+    }
+
+    fn scripted_exchange(method: Method) {
+        let client_kexinit = [&[20u8][..], &[0u8; 16], method.name().as_bytes()].concat();
+        let server_kexinit = [&[20u8][..], &[1u8; 16], method.name().as_bytes()].concat();
+        let ctx = Context {
+            client_version: b"SSH-2.0-hila5_client",
+            server_version: b"SSH-2.0-hila5_server",
+            client_kexinit: &client_kexinit,
+            server_kexinit: &server_kexinit,
+        };
+
+        // Client -> server
+        let (client, init) = ClientKex::start(method).unwrap();
+        let init = KexInit::from_payload(&init.to_payload()).unwrap();
+        assert_eq!(init.client_share.len(), method.client_share_len());
+
+        // Server -> client
+        let (server_out, reply) = server_reply(method, &ctx, &init, HOST_KEY, sign).unwrap();
+        let reply = KexReply::from_payload(&reply.to_payload()).unwrap();
+        assert_eq!(reply.server_share.len(), method.server_share_len());
+
+        let client_out = client.finish(&ctx, &reply).unwrap();
+        assert_eq!(reply.signature, sign(&client_out.exchange_hash).unwrap());
+        assert_eq!(client_out.shared_secret, server_out.shared_secret);
+        assert_eq!(client_out.exchange_hash, server_out.exchange_hash);
+
+        // H is recomputable from the transcript
+        let expected = exchange_hash(&ctx, HOST_KEY, &init.client_share, &reply.server_share,
+                                     &client_out.shared_secret);
+        assert_eq!(client_out.exchange_hash, expected);
+        assert_eq!(&client_out.encoded_shared_secret()[..4], &[0, 0, 0, 32]);
+    }
+
+    #[test]
+    fn hila5_sha3_256() {
+        scripted_exchange(Method::Hila5Sha3_256);
+    }
+
+    #[test]
+    fn hila5x25519_sha3_256() {
+        scripted_exchange(Method::Hila5X25519Sha3_256);
+    }
+
+    #[test]
+    fn exchange_hash_test_vector() {
+        let ctx = Context {
+            client_version: b"SSH-2.0-hila5_client",
+            server_version: b"SSH-2.0-hila5_server",
+            client_kexinit: &[20, 0, 1, 2],
+            server_kexinit: &[20, 3, 4, 5],
+        };
+        let client_share = vec![6u8; PUBKEY_LEN + X25519_LEN];
+        let server_share = vec![7u8; CIPHERTEXT_LEN + X25519_LEN];
+        let k = shared_secret(&[1; 32], Some(&[2; 32]));
+        let h = exchange_hash(&ctx, HOST_KEY, &client_share, &server_share, &k);
+        let pure = shared_secret(&[1; 32], None);
+        // computed independently with Python's hashlib
+        assert_eq!(hex(&k), "adcb9583b7caa53aff2b8f4ed8aaf407399051b1b4f5a39aa2c97d5069b50657");
+        assert_eq!(hex(&h), "1b0998f6468c25f98c9666ab96c97f04c289432a4794d33fb8d58549ecc783f1");
+        assert_eq!(hex(&pure), "0bcdd0df6a2e10784e1241541e91ccb897541af713e327f9555231d141c99644");
+    }
+
+    #[test]
+    fn rejects_malformed_messages() {
+        assert_eq!(Method::from_name("hila5-sha3-256"), Some(Method::Hila5Sha3_256));
+        assert_eq!(Method::from_name("sntrup761x25519-sha512"), None);
+
+        let (client, init) = ClientKex::start(Method::Hila5X25519Sha3_256).unwrap();
+        let payload = init.to_payload();
+        assert!(KexInit::from_payload(&payload[..payload.len() - 1]).is_err());
+        assert!(KexReply::from_payload(&payload).is_err());
+
+        // All-zero X25519 share
+        let ctx = Context {
+            client_version: b"", server_version: b"", client_kexinit: b"", server_kexinit: b"",
+        };
+        let mut bad = init.clone();
+        for b in &mut bad.client_share[PUBKEY_LEN..] {
+            *b = 0;
+        }
+        assert!(server_reply(Method::Hila5X25519Sha3_256, &ctx, &bad, HOST_KEY, sign).is_err());
+
+        // Wrong method
+        assert!(server_reply(Method::Hila5Sha3_256, &ctx, &init, HOST_KEY, sign).is_err());
+        let reply = KexReply { host_key: vec![], server_share: vec![0; 10], signature: vec![] };
+        assert!(client.finish(&ctx, &reply).is_err());
+    }
+}
@@ -0,0 +1,341 @@
+//! Threshold decapsulation with the ring secret shared among parties.
+//!
+//! Decapsulation only uses the secret `a` through the product `a * b`, which
+//! is linear in `a`. `split` shares `a` among `parties` key holders so that
+//! no single host holds the key:
+//!
+//!  * with `threshold == parties`, `a` is split into uniformly random
+//!    additive shares modulo q, and every party must take part;
+//!  * with a smaller threshold, `a` is Shamir shared over Z_q, coefficient by
+//!    coefficient, and any `threshold` parties can decapsulate.
+//!
+//! Each party in a quorum computes a `PartialDecapsulation` from its share
+//! alone. For Shamir shares the party applies its own Lagrange coefficient,
+//! so the quorum has to be fixed before partial decapsulation starts. The
+//! combiner sums the partials and runs the rest of decapsulation:
+//! `recon::select`, the error correction and the final hash.
+//!
+//! The combiner, and whoever supplies the ciphertexts, must be trusted with
+//! the key. Partials are exact and carry no noise, for additive and Shamir
+//! shares alike: there is no noise flooding. Each partial is the party's
+//! weighted share times `b`, and the combiner learns `a * b` for every
+//! ciphertext it has decapsulated. A malicious combiner that sends out a
+//! ciphertext whose packed part is a constant `k` gets back `k` times each
+//! share, and so the key, in one round. Parties cannot tell such a ciphertext
+//! from an honest one. Splitting the key only keeps it off any single host
+//! at rest; it is not a defence against the party that runs decapsulation.
+//!
+//! ```rust
+//! use hila5::threshold;
+//!
+//! let (pk, sk) = hila5::crypto_kem_keypair().unwrap();
+//! let shares = threshold::split(&sk, 2, 3).unwrap();
+//! let (ct, ss) = pk.enc().unwrap();
+//!
+//! let quorum = [3, 1];
+//! let partials = [&shares[2], &shares[0]].iter()
+//!     .map(|share| share.partial_dec(&ct, &quorum).unwrap())
+//!     .collect::<Vec<_>>();
+//! let combined = threshold::combine(&ct, &partials, &shares[0].pk_digest).unwrap();
+//! assert_eq!(combined.0, ss.0);
+//! ```
+
+use ring::rand::SecureRandom;
+
+use std::io::Write;
+
+use super::*;
+use encode::PACKED14;
+use errors::*;
+use kem::SharedSecret;
+
+const DIGEST_LEN: usize = 32;
+
+/// One party's share of a private key.
+#[derive(Clone)]
+pub struct KeyShare {
+    /// The index of this party, from 1.
+    pub index: u8,
+    /// How many parties are needed to decapsulate.
+    pub threshold: u8,
+    /// How many parties hold shares.
+    pub parties: u8,
+    key: NttVector,
+    /// Digest of the public key, needed to combine partials.
+    pub pk_digest: Vec<u8>,
+}
+
+/// One party's contribution to decapsulating a ciphertext.
+pub struct PartialDecapsulation {
+    /// The index of the party that computed it.
+    pub index: u8,
+    /// How many parties are needed to decapsulate.
+    pub threshold: u8,
+    value: Vector,
+}
+
+/// Split `sk` among `parties` key holders, any `threshold` of which can
+/// decapsulate. Shares are additive when `threshold == parties`.
+pub fn split(sk: &PrivateKey, threshold: u8, parties: u8) -> Result<Vec<KeyShare>> {
+    if threshold < 2 || threshold > parties {
+        return Err("threshold must be between 2 and the number of parties".into());
+    }
+    let keys = if threshold == parties {
+        additive_shares(sk.secret(), parties)?
+    } else {
+        shamir_shares(sk.secret(), threshold, parties)?
+    };
+    Ok(keys.into_iter().zip(1..=parties).map(|(key, index)| {
+        KeyShare { index, threshold, parties, key, pk_digest: sk.pk_digest.clone() }
+    }).collect())
+}
+
+fn random_vector() -> Result<NttVector> {
+    let mut seed = [0u8; rand::SEED_LEN];
+    get_rng().fill(&mut seed)?;
+    // `from_seed` leaves values below 5q, too wide to pack
+    Ok(reduce(&rand::from_seed::<NttVector>(&seed)))
+}
+
+fn additive_shares(a: &NttVector, parties: u8) -> Result<Vec<NttVector>> {
+    let mut shares = (1..parties).map(|_| random_vector()).collect::<Result<Vec<_>>>()?;
+    let sum = shares.iter().fold(zero(), |acc, share| mul_add_mod_q(&acc, 1, share));
+    shares.push(mul_add_mod_q(&sum, HILA5_Q - 1, a));
+    Ok(shares)
+}
+
+fn shamir_shares(a: &NttVector, threshold: u8, parties: u8) -> Result<Vec<NttVector>> {
+    // coefficients of x, x^2, ... x^(threshold - 1)
+    let coefficients = (1..threshold).map(|_| random_vector()).collect::<Result<Vec<_>>>()?;
+    Ok((1..=parties).map(|index| {
+        let x = Scalar::from(index);
+        let higher = coefficients.iter().rev()
+            .fold(zero(), |acc, coefficient| mul_add_mod_q(&acc, x, coefficient));
+        mul_add_mod_q(&higher, x, a)
+    }).collect())
+}
+
+impl KeyShare {
+    /// Read a share written by `write_to`.
+    pub fn from_bytes(input: &[u8]) -> Result<Self> {
+        if input.len() != 3 + PACKED14 + DIGEST_LEN {
+            return Err("key share has the wrong length".into());
+        }
+        let (index, threshold, parties) = (input[0], input[1], input[2]);
+        if threshold < 2 || threshold > parties || index == 0 || index > parties {
+            return Err("key share has an invalid index or threshold".into());
+        }
+        Ok(KeyShare {
+            index,
+            threshold,
+            parties,
+            key: reduce(&encode::unpack14::<NttVector>(&input[3..3 + PACKED14])),
+            pk_digest: input[3 + PACKED14..].to_vec(),
+        })
+    }
+
+    /// Write the index, threshold and number of parties, then the share and
+    /// the public key digest.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&[self.index, self.threshold, self.parties])?;
+        encode::pack14(&self.key, writer)?;
+        writer.write_all(&self.pk_digest)?;
+        Ok(())
+    }
+
+    /// Compute this party's partial decapsulation of `ct`, as a member of
+    /// `quorum`, the indices of the `threshold` parties taking part.
+    ///
+    /// Only answer a combiner trusted with the key: the partial is exact,
+    /// and a chosen `ct` lets the combiner read the share off it.
+    pub fn partial_dec(&self, ct: &[u8], quorum: &[u8]) -> Result<PartialDecapsulation> {
+        kem::check_ciphertext(ct)?;
+        let weight = self.weight(quorum)?;
+        let b = encode::unpack14(&ct[..PACKED14]);
+        let product = keygen::ring_product(&self.key, &b);
+        let value = mul_add_mod_q(&product, weight, &zero());
+        Ok(PartialDecapsulation { index: self.index, threshold: self.threshold, value })
+    }
+
+    /// The factor this party's product is scaled by in `quorum`: one for
+    /// additive shares, the Lagrange coefficient at zero for Shamir shares.
+    fn weight(&self, quorum: &[u8]) -> Result<Scalar> {
+        if quorum.len() != self.threshold as usize {
+            return Err(format!("quorum must have exactly {} parties", self.threshold).into());
+        }
+        if !quorum.contains(&self.index) {
+            return Err("quorum does not include this party".into());
+        }
+        for (i, &index) in quorum.iter().enumerate() {
+            if index == 0 || index > self.parties || quorum[..i].contains(&index) {
+                return Err(format!("invalid quorum member {}", index).into());
+            }
+        }
+        if self.threshold == self.parties {
+            return Ok(1);
+        }
+        let own = Scalar::from(self.index);
+        let (num, den) = quorum.iter().map(|&m| Scalar::from(m)).filter(|&m| m != own)
+            .fold((1, 1), |(num, den), m| {
+                (num * m % HILA5_Q, den * (m - own + HILA5_Q) % HILA5_Q)
+            });
+        Ok(num * inverse_mod_q(den) % HILA5_Q)
+    }
+}
+
+impl PartialDecapsulation {
+    pub fn from_bytes(input: &[u8]) -> Result<Self> {
+        if input.len() != 2 + PACKED14 {
+            return Err("partial decapsulation has the wrong length".into());
+        }
+        let (index, threshold) = (input[0], input[1]);
+        if index == 0 || threshold < 2 {
+            return Err("partial decapsulation has an invalid index or threshold".into());
+        }
+        let value = reduce(&encode::unpack14::<Vector>(&input[2..]));
+        Ok(PartialDecapsulation { index, threshold, value })
+    }
+
+    /// The party index and threshold, then the packed partial value.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&[self.index, self.threshold])?;
+        encode::pack14(&self.value, writer)
+    }
+}
+
+/// Combine the partials of a whole quorum into the shared secret, given
+/// the digest of the public key.
+pub fn combine(ct: &[u8], partials: &[PartialDecapsulation], pk_digest: &[u8]) -> Result<SharedSecret> {
+    let threshold = match partials.first() {
+        Some(partial) => partial.threshold,
+        None => return Err("no partial decapsulations given".into()),
+    };
+    if partials.iter().any(|p| p.threshold != threshold) || partials.len() != threshold as usize {
+        return Err(format!("quorum must have exactly {} partials", threshold).into());
+    }
+    for (i, partial) in partials.iter().enumerate() {
+        if partials[..i].iter().any(|p| p.index == partial.index) {
+            return Err(format!("party {} contributed twice", partial.index).into());
+        }
+    }
+    let x = partials.iter().fold(zero(), |acc, p| mul_add_mod_q(&acc, 1, &p.value));
+    kem::dec_with_product(ct, &x, pk_digest)
+}
+
+// Share arithmetic is done coefficient-wise here, reduced into [0, q), as the
+// vector operators of the `opt` backend leave their results partly reduced.
+
+fn zero<V: Hila5Vector>() -> V {
+    V::from([0; HILA5_N])
+}
+
+/// `x * c + y` modulo q.
+fn mul_add_mod_q<V: Hila5Vector>(x: &V, c: Scalar, y: &V) -> V {
+    let q = i64::from(HILA5_Q);
+    let mut out = [0; HILA5_N];
+    for (out, (&xi, &yi)) in out.iter_mut().zip(x.get_inner().iter().zip(y.get_inner().iter())) {
+        *out = (i64::from(xi) * i64::from(c) + i64::from(yi)).rem_euclid(q) as Scalar;
+    }
+    V::from(out)
+}
+
+fn reduce<V: Hila5Vector>(x: &V) -> V {
+    mul_add_mod_q(x, 1, &zero())
+}
+
+/// `x^(q - 2)`, the inverse of `x` modulo q.
+fn inverse_mod_q(x: Scalar) -> Scalar {
+    let (mut base, mut exp, mut result) = (x, HILA5_Q - 2, 1);
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = result * base % HILA5_Q;
+        }
+        base = base * base % HILA5_Q;
+        exp >>= 1;
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Simulate each party of `quorum` decapsulating on its own share,
+    /// sending its partial over the wire, and the combiner finishing.
+    fn run_parties(shares: &[KeyShare], quorum: &[u8], ct: &[u8]) -> Result<SharedSecret> {
+        let mut partials = vec![];
+        for &index in quorum {
+            let mut stored = vec![];
+            shares[index as usize - 1].write_to(&mut stored)?;
+            let share = KeyShare::from_bytes(&stored)?;
+
+            let mut sent = vec![];
+            share.partial_dec(ct, quorum)?.write_to(&mut sent)?;
+            partials.push(PartialDecapsulation::from_bytes(&sent)?);
+        }
+        combine(ct, &partials, &shares[0].pk_digest)
+    }
+
+    #[test]
+    fn n_of_n() {
+        let (pk, sk) = crypto_kem_keypair().unwrap();
+        let shares = split(&sk, 3, 3).unwrap();
+        for _ in 0..3 {
+            let (ct, ss) = pk.enc().unwrap();
+            assert_eq!(run_parties(&shares, &[1, 2, 3], &ct).unwrap().0, ss.0);
+            assert_eq!(run_parties(&shares, &[3, 1, 2], &ct).unwrap().0, ss.0);
+        }
+
+        // without every party, the partials are refused
+        let (ct, _) = pk.enc().unwrap();
+        let partials = shares[..2].iter()
+            .map(|share| share.partial_dec(&ct, &[1, 2, 3]).unwrap())
+            .collect::<Vec<_>>();
+        assert!(combine(&ct, &partials, &sk.pk_digest).is_err());
+    }
+
+    #[test]
+    fn t_of_n() {
+        let (pk, sk) = crypto_kem_keypair().unwrap();
+        let shares = split(&sk, 3, 5).unwrap();
+        let (ct, ss) = pk.enc().unwrap();
+        for quorum in &[[1, 2, 3], [5, 2, 4], [3, 5, 1]] {
+            assert_eq!(run_parties(&shares, quorum, &ct).unwrap().0, ss.0);
+        }
+        assert_eq!(inverse_mod_q(3) * 3 % HILA5_Q, 1);
+    }
+
+    #[test]
+    fn invalid_quorums() {
+        let (pk, sk) = crypto_kem_keypair().unwrap();
+        let shares = split(&sk, 2, 3).unwrap();
+        let (ct, _) = pk.enc().unwrap();
+        assert!(shares[0].partial_dec(&ct, &[1]).is_err());
+        assert!(shares[0].partial_dec(&ct, &[2, 3]).is_err());
+        assert!(shares[0].partial_dec(&ct, &[1, 1]).is_err());
+        assert!(shares[0].partial_dec(&ct, &[1, 4]).is_err());
+        assert!(shares[0].partial_dec(&ct[1..], &[1, 2]).is_err());
+
+        let partial = shares[0].partial_dec(&ct, &[1, 2]).unwrap();
+        let mut sent = vec![];
+        partial.write_to(&mut sent).unwrap();
+        let twice = [partial, PartialDecapsulation::from_bytes(&sent).unwrap()];
+        assert!(combine(&ct, &twice, &sk.pk_digest).is_err());
+
+        // a partial can't claim index 0, and a quorum can't be oversized
+        let mut zero_index = sent.clone();
+        zero_index[0] = 0;
+        assert!(PartialDecapsulation::from_bytes(&zero_index).is_err());
+        let three = [1, 2, 3].iter()
+            .map(|&index| {
+                let mut bytes = sent.clone();
+                bytes[0] = index;
+                PartialDecapsulation::from_bytes(&bytes).unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert!(combine(&ct, &three, &sk.pk_digest).is_err());
+
+        assert!(split(&sk, 1, 3).is_err());
+        assert!(split(&sk, 4, 3).is_err());
+    }
+}
@@ -0,0 +1,284 @@
+//! HILA5 key exchange groups for TLS 1.3 in `rustls`.
+//!
+//! Two groups are provided, both using codepoints from the private use range
+//! of the TLS supported groups registry:
+//!
+//!  - `HILA5` (`0xFE50`): the client key share is a HILA5 `PublicKey` and the
+//!    server key share is the ciphertext produced by `kem::enc`.
+//!  - `X25519_HILA5` (`0xFE51`): a hybrid of the above with X25519. Both key
+//!    shares are the 32-byte X25519 share followed by the HILA5 share, and the
+//!    shared secret is the X25519 secret followed by the HILA5 secret.
+//!
+//! ```rust,no_run
+//! # extern crate hila5;
+//! # extern crate rustls;
+//! use std::sync::Arc;
+//!
+//! let provider = hila5::tls::provider(hila5::tls::X25519_HILA5);
+//! let config = rustls::ClientConfig::builder_with_provider(Arc::new(provider))
+//!     .with_protocol_versions(&[&rustls::version::TLS13])
+//!     .unwrap();
+//! ```
+
+use rustls::crypto::{ring as ring_provider, ActiveKeyExchange, CompletedKeyExchange,
+                     CryptoProvider, SupportedKxGroup};
+use rustls::{Error, NamedGroup, PeerMisbehaved, ProtocolVersion};
+
+use std::fmt;
+
+use super::*;
+
+/// Private use codepoint for the pure HILA5 group.
+pub const HILA5_GROUP: NamedGroup = NamedGroup::Unknown(0xFE50);
+/// Private use codepoint for the X25519 + HILA5 hybrid group.
+pub const X25519_HILA5_GROUP: NamedGroup = NamedGroup::Unknown(0xFE51);
+
+/// Pure HILA5 key exchange group.
+pub static HILA5: &dyn SupportedKxGroup = &Hila5Group;
+/// Hybrid X25519 + HILA5 key exchange group.
+pub static X25519_HILA5: &dyn SupportedKxGroup = &X25519Hila5Group;
+
+/// The default `rustls` ring provider, with `group` as the only key exchange
+/// group.
+pub fn provider(group: &'static dyn SupportedKxGroup) -> CryptoProvider {
+    CryptoProvider {
+        kx_groups: vec![group],
+        ..ring_provider::default_provider()
+    }
+}
+
+struct Hila5Group;
+
+impl fmt::Debug for Hila5Group {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HILA5")
+    }
+}
+
+impl SupportedKxGroup for Hila5Group {
+    fn start(&self) -> ::std::result::Result<Box<dyn ActiveKeyExchange>, Error> {
+        Ok(Box::new(Hila5KeyExchange::start()?))
+    }
+
+    fn start_and_complete(&self, client_share: &[u8]) -> ::std::result::Result<CompletedKeyExchange, Error> {
+        let (ct, ss) = encapsulate(client_share)?;
+        Ok(CompletedKeyExchange {
+            group: self.name(),
+            pub_key: ct,
+            secret: ss.0.into(),
+        })
+    }
+
+    fn name(&self) -> NamedGroup {
+        HILA5_GROUP
+    }
+
+    fn usable_for_version(&self, version: ProtocolVersion) -> bool {
+        version == ProtocolVersion::TLSv1_3
+    }
+}
+
+struct X25519Hila5Group;
+
+impl fmt::Debug for X25519Hila5Group {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "X25519Hila5")
+    }
+}
+
+impl SupportedKxGroup for X25519Hila5Group {
+    fn start(&self) -> ::std::result::Result<Box<dyn ActiveKeyExchange>, Error> {
+        let x25519 = x25519_secret().map_err(general)?;
+        let hila5 = Hila5KeyExchange::start()?;
+        let mut pub_key = x25519_dalek::PublicKey::from(&x25519).to_bytes().to_vec();
+        pub_key.extend_from_slice(&hila5.pub_key);
+        Ok(Box::new(X25519Hila5KeyExchange { x25519, hila5, pub_key }))
+    }
+
+    fn start_and_complete(&self, client_share: &[u8]) -> ::std::result::Result<CompletedKeyExchange, Error> {
+        if client_share.len() != X25519_LEN + PUBKEY_LEN {
+            return Err(PeerMisbehaved::InvalidKeyShare.into());
+        }
+        let (x25519_share, hila5_share) = client_share.split_at(X25519_LEN);
+        let x25519 = x25519_secret().map_err(general)?;
+        let mut secret = x25519_agree(&x25519, x25519_share)
+            .map_err(|_| Error::from(PeerMisbehaved::InvalidKeyShare))?;
+        let (ct, ss) = encapsulate(hila5_share)?;
+
+        let mut pub_key = x25519_dalek::PublicKey::from(&x25519).to_bytes().to_vec();
+        pub_key.extend_from_slice(&ct);
+        secret.extend_from_slice(&ss.0);
+        Ok(CompletedKeyExchange {
+            group: self.name(),
+            pub_key,
+            secret: secret.into(),
+        })
+    }
+
+    fn name(&self) -> NamedGroup {
+        X25519_HILA5_GROUP
+    }
+
+    fn usable_for_version(&self, version: ProtocolVersion) -> bool {
+        version == ProtocolVersion::TLSv1_3
+    }
+}
+
+/// Client side of the HILA5 key exchange.
+struct Hila5KeyExchange {
+    sk: PrivateKey,
+    pub_key: Vec<u8>,
+}
+
+impl Hila5KeyExchange {
+    fn start() -> ::std::result::Result<Self, Error> {
+        let (pk, sk) = crypto_kem_keypair().map_err(general)?;
+        let mut pub_key = vec![];
+        pk.write_to(&mut pub_key).map_err(general)?;
+        Ok(Hila5KeyExchange { sk, pub_key })
+    }
+
+    fn decapsulate(&self, server_share: &[u8]) -> ::std::result::Result<SharedSecret, Error> {
+        if server_share.len() != CIPHERTEXT_LEN {
+            return Err(PeerMisbehaved::InvalidKeyShare.into());
+        }
+        kem::dec(server_share, &self.sk).map_err(general)
+    }
+}
+
+impl ActiveKeyExchange for Hila5KeyExchange {
+    fn complete(self: Box<Self>, server_share: &[u8]) -> ::std::result::Result<rustls::crypto::SharedSecret, Error> {
+        Ok(self.decapsulate(server_share)?.0.into())
+    }
+
+    fn pub_key(&self) -> &[u8] {
+        &self.pub_key
+    }
+
+    fn group(&self) -> NamedGroup {
+        HILA5_GROUP
+    }
+}
+
+/// Client side of the hybrid key exchange.
+struct X25519Hila5KeyExchange {
+    x25519: x25519_dalek::StaticSecret,
+    hila5: Hila5KeyExchange,
+    pub_key: Vec<u8>,
+}
+
+impl ActiveKeyExchange for X25519Hila5KeyExchange {
+    fn complete(self: Box<Self>, server_share: &[u8]) -> ::std::result::Result<rustls::crypto::SharedSecret, Error> {
+        if server_share.len() != X25519_LEN + CIPHERTEXT_LEN {
+            return Err(PeerMisbehaved::InvalidKeyShare.into());
+        }
+        let (x25519_share, hila5_share) = server_share.split_at(X25519_LEN);
+        let ss = self.hila5.decapsulate(hila5_share)?;
+        let mut secret = x25519_agree(&self.x25519, x25519_share)
+            .map_err(|_| Error::from(PeerMisbehaved::InvalidKeyShare))?;
+        secret.extend_from_slice(&ss.0);
+        Ok(secret.into())
+    }
+
+    fn pub_key(&self) -> &[u8] {
+        &self.pub_key
+    }
+
+    fn group(&self) -> NamedGroup {
+        X25519_HILA5_GROUP
+    }
+}
+
+/// Server side of the HILA5 key exchange.
+fn encapsulate(client_share: &[u8]) -> ::std::result::Result<(Vec<u8>, SharedSecret), Error> {
+    if client_share.len() != PUBKEY_LEN {
+        return Err(PeerMisbehaved::InvalidKeyShare.into());
+    }
+    let pk = PublicKey::from_bytes(client_share);
+    kem::enc(&pk).map_err(general)
+}
+
+fn general(e: errors::Error) -> Error {
+    Error::General(e.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use rcgen;
+    use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+    use rustls::{ClientConfig, ClientConnection, Connection, RootCertStore, ServerConfig,
+                 ServerConnection};
+
+    use rustls::pki_types::ServerName;
+
+    use std::convert::TryFrom;
+    use std::io::{Read, Write};
+    use std::sync::Arc;
+
+    use super::*;
+
+    fn transfer(from: &mut Connection, to: &mut Connection) {
+        let mut buf = vec![];
+        while from.wants_write() {
+            from.write_tls(&mut buf).unwrap();
+        }
+        let mut rd = &buf[..];
+        while !rd.is_empty() {
+            to.read_tls(&mut rd).unwrap();
+            to.process_new_packets().unwrap();
+        }
+    }
+
+    fn handshake(group: &'static dyn SupportedKxGroup) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_der = cert.cert.der().clone();
+        let key_der = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der()));
+
+        let provider = Arc::new(provider(group));
+        let server_config = ServerConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(&[&rustls::version::TLS13]).unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert_der.clone()], key_der).unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add(cert_der).unwrap();
+        let client_config = ClientConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13]).unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let name = ServerName::try_from("localhost").unwrap();
+        let mut client = Connection::from(ClientConnection::new(Arc::new(client_config), name).unwrap());
+        let mut server = Connection::from(ServerConnection::new(Arc::new(server_config)).unwrap());
+
+        client.writer().write_all(b"hello over hila5").unwrap();
+        while client.is_handshaking() || server.is_handshaking() {
+            transfer(&mut client, &mut server);
+            transfer(&mut server, &mut client);
+        }
+        transfer(&mut client, &mut server);
+
+        assert_eq!(client.negotiated_key_exchange_group().unwrap().name(), group.name());
+        assert_eq!(server.negotiated_key_exchange_group().unwrap().name(), group.name());
+
+        let mut received = [0u8; 16];
+        server.reader().read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"hello over hila5");
+    }
+
+    #[test]
+    fn tls13_handshake_hila5() {
+        handshake(HILA5);
+    }
+
+    #[test]
+    fn tls13_handshake_x25519_hila5() {
+        handshake(X25519_HILA5);
+    }
+
+    #[test]
+    fn rejects_bad_key_share() {
+        assert!(HILA5.start_and_complete(&[0u8; 32]).is_err());
+        assert!(X25519_HILA5.start_and_complete(&[0u8; PUBKEY_LEN]).is_err());
+        assert!(HILA5.start().unwrap().complete(&[0u8; 10]).is_err());
+    }
+}
@@ -0,0 +1,1023 @@
+//! TreeKEM-style group key agreement, with a HILA5 keypair at each node.
+//!
+//! Members of a group sit at the leaves of a binary *ratchet tree*, as in
+//! MLS ([RFC 9420](https://www.rfc-editor.org/rfc/rfc9420)). Every non-blank
+//! node holds a HILA5 public key, and each member knows the private keys of
+//! its own leaf and of those nodes on its direct path to the root that it has
+//! been given. A `Commit` changes the group and starts a new epoch:
+//!
+//!  - `Proposal`s add and remove members. Removing a member blanks its leaf
+//!    and direct path; an added member is recorded as *unmerged* at the
+//!    parents above it, since it does not know their private keys yet;
+//!  - the committer picks a fresh leaf secret and derives a chain of path
+//!    secrets up its direct path, `path_secret[n + 1] = Derive(path_secret[n],
+//!    "path")`. The keypair of each node is derived deterministically from
+//!    `Derive(path_secret, "node")`;
+//!  - each path secret is encrypted with `kem::enc` to every node in the
+//!    *resolution* of the corresponding copath node, i.e. its closest
+//!    non-blank descendants together with any unmerged leaves;
+//!  - the commit secret, derived from the root path secret, feeds the key
+//!    schedule:
+//!
+//! ```text
+//! joiner_secret = HMAC(init_secret[n - 1], commit_secret)
+//! epoch_secret  = Derive(joiner_secret, "epoch", GroupContext)
+//! init_secret   = Derive(epoch_secret, "init")
+//! ```
+//!
+//! where `GroupContext` covers the group identifier, the epoch and a hash of
+//! the new tree, so members who disagree on the tree disagree on the secret.
+//! New members receive a `Welcome` with the tree, the joiner secret and the
+//! path secret of their lowest common ancestor with the committer. Updating
+//! one's own keys is a commit without proposals.
+//!
+//! Commits are not signed: the application must authenticate the sender of a
+//! commit, and deliver commits to all members in the same order. As with
+//! `kem`, the encryption of path secrets is only passively secure.
+//!
+//! ```rust
+//! use hila5::treekem::{LeafNode, Member, Proposal};
+//!
+//! let (alice_leaf, alice_sk) = LeafNode::generate(b"alice").unwrap();
+//! let mut alice = Member::create(b"group", alice_leaf, alice_sk).unwrap();
+//!
+//! let (bob_leaf, bob_sk) = LeafNode::generate(b"bob").unwrap();
+//! let (_, welcome) = alice.commit(&[Proposal::Add(Box::new(bob_leaf))]).unwrap();
+//! let bob = Member::join(&welcome.unwrap(), bob_sk).unwrap();
+//! assert_eq!(alice.epoch_secret(), bob.epoch_secret());
+//! ```
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use ring::{aead, digest, hkdf, hmac};
+use ring::rand::SecureRandom;
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+use super::*;
+use errors::*;
+
+/// Length of path secrets and of the secrets of the key schedule.
+pub const SECRET_LEN: usize = 32;
+/// Maximum number of leaves in a ratchet tree.
+pub const MAX_LEAVES: u32 = 1 << 16;
+
+const TAG_LEN: usize = 16;
+const LABEL_PREFIX: &[u8] = b"HILA5v10-treekem ";
+
+const ADD: u8 = 1;
+const REMOVE: u8 = 2;
+
+/// Length of a path secret encrypted to a node.
+const ENCRYPTED_PATH_SECRET_LEN: usize = CIPHERTEXT_LEN + SECRET_LEN + TAG_LEN;
+/// Length of the joiner and path secrets encrypted to a new member.
+const ENCRYPTED_GROUP_SECRETS_LEN: usize = CIPHERTEXT_LEN + 2 * SECRET_LEN + TAG_LEN;
+
+// Array representation of trees with a power of two number of leaves: leaf
+// `i` is node `2i`, and a parent at level `k` has index `2^k - 1` modulo
+// `2^(k + 1)`. See RFC 9420, appendix C.
+
+fn level(x: u32) -> u32 {
+    (!x).trailing_zeros()
+}
+
+fn root(leaves: u32) -> u32 {
+    leaves - 1
+}
+
+fn left(x: u32) -> u32 {
+    x ^ (1 << (level(x) - 1))
+}
+
+fn right(x: u32) -> u32 {
+    x ^ (3 << (level(x) - 1))
+}
+
+fn parent(x: u32) -> u32 {
+    let k = level(x);
+    let b = (x >> (k + 1)) & 1;
+    (x | (1 << k)) ^ (b << (k + 1))
+}
+
+fn sibling(x: u32) -> u32 {
+    let p = parent(x);
+    if x < p { right(p) } else { left(p) }
+}
+
+/// Parents of `x`, from its own parent up to the root.
+fn direct_path(mut x: u32, leaves: u32) -> Vec<u32> {
+    let mut path = vec![];
+    while x != root(leaves) {
+        x = parent(x);
+        path.push(x);
+    }
+    path
+}
+
+/// Siblings of `x` and of each node on its direct path below the root.
+fn copath(x: u32, leaves: u32) -> Vec<u32> {
+    let mut path = vec![x];
+    path.extend(direct_path(x, leaves));
+    path.pop();
+    path.into_iter().map(sibling).collect()
+}
+
+/// Whether `x` is in the subtree rooted at `node`.
+fn in_subtree(node: u32, x: u32) -> bool {
+    let span = (1 << level(node)) - 1;
+    node - span <= x && x <= node + span
+}
+
+/// A member's identity and the HILA5 public key of its leaf.
+///
+/// A prospective member generates one with `LeafNode::generate`, and gives it
+/// to a member to add it to the group.
+#[derive(Clone)]
+pub struct LeafNode {
+    pub identity: Vec<u8>,
+    pub public_key: PublicKey,
+}
+
+impl LeafNode {
+    /// Generate a leaf with a fresh keypair.
+    pub fn generate(identity: &[u8]) -> Result<(Self, PrivateKey)> {
+        if identity.len() > u16::MAX as usize {
+            return Err("identity is too long".into());
+        }
+        let (public_key, sk) = crypto_kem_keypair()?;
+        Ok((LeafNode { identity: identity.to_vec(), public_key }, sk))
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_u16::<BigEndian>(self.identity.len() as u16)?;
+        writer.write_all(&self.identity)?;
+        self.public_key.write_to(writer)
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Self> {
+        let len = reader.read_u16::<BigEndian>()? as usize;
+        let identity = read_vec(reader, len)?;
+        let public_key = read_public_key(reader)?;
+        Ok(LeafNode { identity, public_key })
+    }
+}
+
+#[derive(Clone)]
+struct ParentNode {
+    public_key: PublicKey,
+    /// Leaves added below this node since its key was last set.
+    unmerged_leaves: Vec<u32>,
+}
+
+#[derive(Clone)]
+enum Node {
+    Leaf(LeafNode),
+    Parent(ParentNode),
+}
+
+impl Node {
+    fn public_key(&self) -> &PublicKey {
+        match *self {
+            Node::Leaf(ref leaf) => &leaf.public_key,
+            Node::Parent(ref parent) => &parent.public_key,
+        }
+    }
+}
+
+/// The public state of a group: a left-balanced binary tree whose leaves are
+/// the members, stored as an array of optional (i.e. possibly blank) nodes.
+#[derive(Clone)]
+pub struct RatchetTree {
+    nodes: Vec<Option<Node>>,
+}
+
+impl RatchetTree {
+    fn new(leaf: LeafNode) -> Self {
+        RatchetTree { nodes: vec![Some(Node::Leaf(leaf))] }
+    }
+
+    /// Number of leaves, including blank ones.
+    pub fn leaf_count(&self) -> u32 {
+        (self.nodes.len() as u32 + 1) / 2
+    }
+
+    /// The member at leaf `index`, if any.
+    pub fn leaf(&self, index: u32) -> Option<&LeafNode> {
+        match self.nodes.get(2 * index as usize) {
+            Some(&Some(Node::Leaf(ref leaf))) => Some(leaf),
+            _ => None,
+        }
+    }
+
+    /// Number of members.
+    pub fn member_count(&self) -> usize {
+        (0..self.leaf_count()).filter(|&i| self.leaf(i).is_some()).count()
+    }
+
+    /// SHA3 hash of the serialised tree.
+    pub fn hash(&self) -> Result<Vec<u8>> {
+        let mut bytes = vec![];
+        self.write_to(&mut bytes)?;
+        Ok(sha3(&bytes))
+    }
+
+    fn node(&self, x: u32) -> Option<&Node> {
+        self.nodes[x as usize].as_ref()
+    }
+
+    /// The non-blank nodes covering the subtree of `x`, together with the
+    /// unmerged leaves of those nodes.
+    fn resolution(&self, x: u32) -> Vec<u32> {
+        match self.nodes[x as usize] {
+            Some(Node::Leaf(_)) => vec![x],
+            Some(Node::Parent(ref p)) => {
+                let mut out = vec![x];
+                out.extend(p.unmerged_leaves.iter().map(|&leaf| 2 * leaf));
+                out
+            }
+            None if level(x) == 0 => vec![],
+            None => {
+                let mut out = self.resolution(left(x));
+                out.extend(self.resolution(right(x)));
+                out
+            }
+        }
+    }
+
+    /// Put `leaf` in the leftmost blank leaf, extending the tree if there is
+    /// none, and return its index.
+    fn add_leaf(&mut self, leaf: LeafNode) -> Result<u32> {
+        let index = match (0..self.leaf_count()).find(|&i| self.nodes[2 * i as usize].is_none()) {
+            Some(i) => i,
+            None => {
+                let i = self.leaf_count();
+                if i >= MAX_LEAVES {
+                    return Err("too many members".into());
+                }
+                let len = 2 * self.nodes.len() + 1;
+                self.nodes.resize(len, None);
+                i
+            }
+        };
+        self.nodes[2 * index as usize] = Some(Node::Leaf(leaf));
+        for x in direct_path(2 * index, self.leaf_count()) {
+            if let Some(Node::Parent(ref mut p)) = self.nodes[x as usize] {
+                p.unmerged_leaves.push(index);
+            }
+        }
+        Ok(index)
+    }
+
+    /// Blank leaf `index` and its direct path, and drop the right half of the
+    /// tree while it is empty.
+    fn remove_leaf(&mut self, index: u32) {
+        self.nodes[2 * index as usize] = None;
+        for x in direct_path(2 * index, self.leaf_count()) {
+            self.nodes[x as usize] = None;
+        }
+        while self.leaf_count() > 1 && self.nodes[self.leaf_count() as usize..].iter().all(Option::is_none) {
+            let len = self.leaf_count() as usize - 1;
+            self.nodes.truncate(len);
+        }
+    }
+
+    /// Set the keys of leaf `index` and its direct path from an update path.
+    fn apply_path(&mut self, index: u32, leaf_key: &PublicKey, path: &[UpdatePathNode]) -> Result<()> {
+        match self.nodes[2 * index as usize] {
+            Some(Node::Leaf(ref mut leaf)) => leaf.public_key = leaf_key.clone(),
+            _ => return Err("commit sender is not a member".into()),
+        }
+        let direct = direct_path(2 * index, self.leaf_count());
+        if direct.len() != path.len() {
+            return Err("update path has the wrong length".into());
+        }
+        for (&x, node) in direct.iter().zip(path) {
+            self.nodes[x as usize] = Some(Node::Parent(ParentNode {
+                public_key: node.public_key.clone(),
+                unmerged_leaves: vec![],
+            }));
+        }
+        Ok(())
+    }
+
+    /// Apply removals, then additions, returning the indices of the new
+    /// leaves.
+    fn apply_proposals(&mut self, proposals: &[Proposal], sender: u32) -> Result<Vec<u32>> {
+        for proposal in proposals {
+            if let Proposal::Remove(index) = *proposal {
+                if index == sender {
+                    return Err("a member cannot remove itself".into());
+                }
+                if index >= self.leaf_count() || self.leaf(index).is_none() {
+                    return Err("removed leaf is not a member".into());
+                }
+                self.remove_leaf(index);
+            }
+        }
+        let mut added = vec![];
+        for proposal in proposals {
+            if let Proposal::Add(ref leaf) = *proposal {
+                added.push(self.add_leaf((**leaf).clone())?);
+            }
+        }
+        Ok(added)
+    }
+
+    /// Resolution of `x`, leaving out the leaves in `exclude`.
+    fn filtered_resolution(&self, x: u32, exclude: &[u32]) -> Vec<u32> {
+        self.resolution(x).into_iter()
+            .filter(|&r| level(r) > 0 || !exclude.contains(&(r / 2)))
+            .collect()
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_u32::<BigEndian>(self.leaf_count())?;
+        for node in &self.nodes {
+            match *node {
+                None => writer.write_u8(0)?,
+                Some(Node::Leaf(ref leaf)) => {
+                    writer.write_u8(1)?;
+                    leaf.write_to(writer)?;
+                }
+                Some(Node::Parent(ref p)) => {
+                    writer.write_u8(1)?;
+                    p.public_key.write_to(writer)?;
+                    writer.write_u32::<BigEndian>(p.unmerged_leaves.len() as u32)?;
+                    for &leaf in &p.unmerged_leaves {
+                        writer.write_u32::<BigEndian>(leaf)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Self> {
+        let leaves = reader.read_u32::<BigEndian>()?;
+        if leaves == 0 || leaves > MAX_LEAVES || !leaves.is_power_of_two() {
+            return Err("invalid ratchet tree size".into());
+        }
+        let mut nodes = vec![];
+        for x in 0..2 * leaves - 1 {
+            let node = match reader.read_u8()? {
+                0 => None,
+                1 if level(x) == 0 => Some(Node::Leaf(LeafNode::read_from(reader)?)),
+                1 => {
+                    let public_key = read_public_key(reader)?;
+                    let count = reader.read_u32::<BigEndian>()?;
+                    let mut unmerged_leaves = vec![];
+                    for _ in 0..count {
+                        let leaf = reader.read_u32::<BigEndian>()?;
+                        if !in_subtree(x, 2 * leaf) {
+                            return Err("invalid unmerged leaf".into());
+                        }
+                        unmerged_leaves.push(leaf);
+                    }
+                    Some(Node::Parent(ParentNode { public_key, unmerged_leaves }))
+                }
+                _ => return Err("invalid ratchet tree node".into()),
+            };
+            nodes.push(node);
+        }
+        Ok(RatchetTree { nodes })
+    }
+
+    pub fn from_bytes(mut input: &[u8]) -> Result<Self> {
+        let tree = Self::read_from(&mut input)?;
+        if !input.is_empty() {
+            return Err("trailing data after ratchet tree".into());
+        }
+        Ok(tree)
+    }
+}
+
+/// A change to the membership of the group, made by a `Commit`.
+#[derive(Clone)]
+pub enum Proposal {
+    /// Add a new member.
+    Add(Box<LeafNode>),
+    /// Remove the member at the given leaf index.
+    Remove(u32),
+}
+
+#[derive(Clone)]
+struct UpdatePathNode {
+    public_key: PublicKey,
+    /// The path secret, encrypted to each node of the copath resolution.
+    encrypted_path_secrets: Vec<Vec<u8>>,
+}
+
+/// Message moving the group to the next epoch.
+#[derive(Clone)]
+pub struct Commit {
+    epoch: u64,
+    sender: u32,
+    proposals: Vec<Proposal>,
+    leaf_key: PublicKey,
+    path: Vec<UpdatePathNode>,
+}
+
+impl Commit {
+    /// Epoch in which the commit was made.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Leaf index of the committer.
+    pub fn sender(&self) -> u32 {
+        self.sender
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_u64::<BigEndian>(self.epoch)?;
+        writer.write_u32::<BigEndian>(self.sender)?;
+        writer.write_u32::<BigEndian>(self.proposals.len() as u32)?;
+        for proposal in &self.proposals {
+            match *proposal {
+                Proposal::Add(ref leaf) => {
+                    writer.write_u8(ADD)?;
+                    leaf.write_to(writer)?;
+                }
+                Proposal::Remove(index) => {
+                    writer.write_u8(REMOVE)?;
+                    writer.write_u32::<BigEndian>(index)?;
+                }
+            }
+        }
+        self.leaf_key.write_to(writer)?;
+        writer.write_u32::<BigEndian>(self.path.len() as u32)?;
+        for node in &self.path {
+            node.public_key.write_to(writer)?;
+            writer.write_u32::<BigEndian>(node.encrypted_path_secrets.len() as u32)?;
+            for ct in &node.encrypted_path_secrets {
+                writer.write_all(ct)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn from_bytes(mut input: &[u8]) -> Result<Self> {
+        let reader = &mut input;
+        let epoch = reader.read_u64::<BigEndian>()?;
+        let sender = reader.read_u32::<BigEndian>()?;
+        let mut proposals = vec![];
+        for _ in 0..reader.read_u32::<BigEndian>()? {
+            proposals.push(match reader.read_u8()? {
+                ADD => Proposal::Add(Box::new(LeafNode::read_from(reader)?)),
+                REMOVE => Proposal::Remove(reader.read_u32::<BigEndian>()?),
+                _ => return Err("invalid proposal".into()),
+            });
+        }
+        let leaf_key = read_public_key(reader)?;
+        let mut path = vec![];
+        for _ in 0..reader.read_u32::<BigEndian>()? {
+            let public_key = read_public_key(reader)?;
+            let mut encrypted_path_secrets = vec![];
+            for _ in 0..reader.read_u32::<BigEndian>()? {
+                encrypted_path_secrets.push(read_vec(reader, ENCRYPTED_PATH_SECRET_LEN)?);
+            }
+            path.push(UpdatePathNode { public_key, encrypted_path_secrets });
+        }
+        if !reader.is_empty() {
+            return Err("trailing data after commit".into());
+        }
+        Ok(Commit { epoch, sender, proposals, leaf_key, path })
+    }
+}
+
+/// Message giving new members the state of the group after a `Commit`.
+#[derive(Clone)]
+pub struct Welcome {
+    group_id: Vec<u8>,
+    epoch: u64,
+    sender: u32,
+    tree: RatchetTree,
+    /// Joiner secret and path secret, encrypted to each new leaf.
+    secrets: Vec<(u32, Vec<u8>)>,
+}
+
+impl Welcome {
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_u32::<BigEndian>(self.group_id.len() as u32)?;
+        writer.write_all(&self.group_id)?;
+        writer.write_u64::<BigEndian>(self.epoch)?;
+        writer.write_u32::<BigEndian>(self.sender)?;
+        self.tree.write_to(writer)?;
+        writer.write_u32::<BigEndian>(self.secrets.len() as u32)?;
+        for &(leaf, ref ct) in &self.secrets {
+            writer.write_u32::<BigEndian>(leaf)?;
+            writer.write_all(ct)?;
+        }
+        Ok(())
+    }
+
+    pub fn from_bytes(mut input: &[u8]) -> Result<Self> {
+        let reader = &mut input;
+        let len = reader.read_u32::<BigEndian>()? as usize;
+        let group_id = read_vec(reader, len)?;
+        let epoch = reader.read_u64::<BigEndian>()?;
+        let sender = reader.read_u32::<BigEndian>()?;
+        let tree = RatchetTree::read_from(reader)?;
+        let mut secrets = vec![];
+        for _ in 0..reader.read_u32::<BigEndian>()? {
+            let leaf = reader.read_u32::<BigEndian>()?;
+            secrets.push((leaf, read_vec(reader, ENCRYPTED_GROUP_SECRETS_LEN)?));
+        }
+        if !reader.is_empty() {
+            return Err("trailing data after welcome".into());
+        }
+        Ok(Welcome { group_id, epoch, sender, tree, secrets })
+    }
+}
+
+/// A member's view of the group: the public tree, its private keys and the
+/// key schedule of the current epoch.
+#[derive(Clone)]
+pub struct Member {
+    group_id: Vec<u8>,
+    epoch: u64,
+    leaf: u32,
+    tree: RatchetTree,
+    /// Private keys, by node index.
+    keys: HashMap<u32, PrivateKey>,
+    init_secret: Vec<u8>,
+    epoch_secret: Vec<u8>,
+}
+
+impl Member {
+    /// Create a new group, with `leaf` as its only member.
+    pub fn create(group_id: &[u8], leaf: LeafNode, sk: PrivateKey) -> Result<Self> {
+        let epoch_secret = random_secret()?;
+        let mut keys = HashMap::new();
+        keys.insert(0, sk);
+        Ok(Member {
+            group_id: group_id.to_vec(),
+            epoch: 0,
+            leaf: 0,
+            tree: RatchetTree::new(leaf),
+            keys,
+            init_secret: derive(&epoch_secret, b"init", &[]),
+            epoch_secret,
+        })
+    }
+
+    /// Join a group from a `Welcome`, using the private key of the `LeafNode`
+    /// the committer added.
+    pub fn join(welcome: &Welcome, sk: PrivateKey) -> Result<Self> {
+        let tree = welcome.tree.clone();
+        let mut found = None;
+        for &(leaf, ref ct) in &welcome.secrets {
+            if let Some(node) = tree.leaf(leaf) {
+                if node.public_key.digest()? == sk.pk_digest {
+                    found = Some((leaf, ct));
+                    break;
+                }
+            }
+        }
+        let (leaf, ct) = found.ok_or("welcome is not for this key")?;
+        let sender = welcome.sender;
+        if tree.leaf(sender).is_none() || sender == leaf {
+            return Err("invalid welcome sender".into());
+        }
+
+        let context = group_context(&welcome.group_id, welcome.epoch, &tree)?;
+        let secrets = decrypt_secret(&sk, ct, &context)?;
+        let (joiner_secret, path_secret) = secrets.split_at(SECRET_LEN);
+
+        let path = direct_path(2 * sender, tree.leaf_count());
+        let start = path.iter().position(|&x| in_subtree(x, 2 * leaf))
+            .ok_or("invalid welcome sender")?;
+        let mut keys = derive_path_keys(&tree, &path[start..], path_secret)?.1;
+        keys.insert(2 * leaf, sk);
+
+        let epoch_secret = derive(joiner_secret, b"epoch", &context);
+        Ok(Member {
+            group_id: welcome.group_id.clone(),
+            epoch: welcome.epoch,
+            leaf,
+            tree,
+            keys,
+            init_secret: derive(&epoch_secret, b"init", &[]),
+            epoch_secret,
+        })
+    }
+
+    pub fn group_id(&self) -> &[u8] {
+        &self.group_id
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Secret shared by all members in the current epoch, from which
+    /// applications derive their keys.
+    pub fn epoch_secret(&self) -> &[u8] {
+        &self.epoch_secret
+    }
+
+    /// This member's leaf index.
+    pub fn leaf_index(&self) -> u32 {
+        self.leaf
+    }
+
+    pub fn tree(&self) -> &RatchetTree {
+        &self.tree
+    }
+
+    /// Commit `proposals` together with a fresh update path, moving to the
+    /// next epoch. The commit is sent to the existing members, and the
+    /// welcome, if members were added, to the new members.
+    pub fn commit(&mut self, proposals: &[Proposal]) -> Result<(Commit, Option<Welcome>)> {
+        let mut tree = self.tree.clone();
+        let added = tree.apply_proposals(proposals, self.leaf)?;
+        let leaves = tree.leaf_count();
+        let path = direct_path(2 * self.leaf, leaves);
+        let copath = copath(2 * self.leaf, leaves);
+
+        let leaf_secret = random_secret()?;
+        let (leaf_key, leaf_sk) = node_keypair(&leaf_secret)?;
+        let mut path_secrets = vec![];
+        let mut path_keys = vec![];
+        let mut secret = leaf_secret;
+        for _ in &path {
+            secret = derive(&secret, b"path", &[]);
+            path_keys.push(node_keypair(&secret)?);
+            path_secrets.push(secret.clone());
+        }
+        let commit_secret = derive(&secret, b"path", &[]);
+
+        let mut update_path: Vec<UpdatePathNode> = path_keys.iter()
+            .map(|(pk, _)| UpdatePathNode { public_key: pk.clone(), encrypted_path_secrets: vec![] })
+            .collect();
+        tree.apply_path(self.leaf, &leaf_key, &update_path)?;
+        let context = group_context(&self.group_id, self.epoch + 1, &tree)?;
+
+        for (i, node) in update_path.iter_mut().enumerate() {
+            for x in tree.filtered_resolution(copath[i], &added) {
+                let pk = tree.node(x).ok_or("blank node in resolution")?.public_key();
+                node.encrypted_path_secrets.push(encrypt_secret(pk, &path_secrets[i], &context)?);
+            }
+        }
+
+        let commit = Commit {
+            epoch: self.epoch,
+            sender: self.leaf,
+            proposals: proposals.to_vec(),
+            leaf_key,
+            path: update_path,
+        };
+
+        let mut keys = self.keys.clone();
+        keys.retain(|&x, _| (x as usize) < tree.nodes.len() && tree.node(x).is_some() && !path.contains(&x));
+        keys.insert(2 * self.leaf, leaf_sk);
+        for (&x, (_, sk)) in path.iter().zip(path_keys) {
+            keys.insert(x, sk);
+        }
+        let joiner_secret = extract(&self.init_secret, &commit_secret);
+
+        let welcome = if added.is_empty() {
+            None
+        } else {
+            let mut secrets = vec![];
+            for &leaf in &added {
+                let i = path.iter().position(|&x| in_subtree(x, 2 * leaf))
+                    .ok_or("new member is not below the committer's path")?;
+                let mut plaintext = joiner_secret.clone();
+                plaintext.extend_from_slice(&path_secrets[i]);
+                let pk = &tree.leaf(leaf).ok_or("new member is missing")?.public_key;
+                secrets.push((leaf, encrypt_secret(pk, &plaintext, &context)?));
+            }
+            Some(Welcome {
+                group_id: self.group_id.clone(),
+                epoch: self.epoch + 1,
+                sender: self.leaf,
+                tree: tree.clone(),
+                secrets,
+            })
+        };
+
+        self.next_epoch(tree, keys, &joiner_secret, &context);
+        Ok((commit, welcome))
+    }
+
+    /// Refresh this member's leaf and path keys, without changing the
+    /// membership of the group.
+    pub fn update(&mut self) -> Result<Commit> {
+        Ok(self.commit(&[])?.0)
+    }
+
+    /// Process a commit made by another member. The member is unchanged if
+    /// the commit is rejected.
+    pub fn process(&mut self, commit: &Commit) -> Result<()> {
+        if commit.epoch != self.epoch {
+            return Err("commit is for a different epoch".into());
+        }
+        if commit.sender == self.leaf {
+            return Err("cannot process own commit".into());
+        }
+        if commit.sender >= self.tree.leaf_count() || self.tree.leaf(commit.sender).is_none() {
+            return Err("commit sender is not a member".into());
+        }
+
+        let mut tree = self.tree.clone();
+        let added = tree.apply_proposals(&commit.proposals, commit.sender)?;
+        if self.leaf >= tree.leaf_count() || tree.leaf(self.leaf).is_none() {
+            return Err("this member was removed from the group".into());
+        }
+        let leaves = tree.leaf_count();
+        let path = direct_path(2 * commit.sender, leaves);
+        let copath = copath(2 * commit.sender, leaves);
+        tree.apply_path(commit.sender, &commit.leaf_key, &commit.path)?;
+        let context = group_context(&self.group_id, self.epoch + 1, &tree)?;
+
+        // the lowest node of the committer's path above this member
+        let i = path.iter().position(|&x| in_subtree(x, 2 * self.leaf))
+            .ok_or("member is not below the committer's path")?;
+        let resolution = tree.filtered_resolution(copath[i], &added);
+        if resolution.len() != commit.path[i].encrypted_path_secrets.len() {
+            return Err("update path has the wrong number of ciphertexts".into());
+        }
+        let (ct, sk) = resolution.iter().zip(&commit.path[i].encrypted_path_secrets)
+            .filter_map(|(x, ct)| self.keys.get(x).map(|sk| (ct, sk)))
+            .next()
+            .ok_or("no private key for the update path")?;
+        let path_secret = decrypt_secret(sk, ct, &context)?;
+        let (commit_secret, path_keys) = derive_path_keys(&tree, &path[i..], &path_secret)?;
+
+        let mut keys = self.keys.clone();
+        keys.retain(|&x, _| (x as usize) < tree.nodes.len() && tree.node(x).is_some() && !path.contains(&x));
+        keys.extend(path_keys);
+        let joiner_secret = extract(&self.init_secret, &commit_secret);
+        self.next_epoch(tree, keys, &joiner_secret, &context);
+        Ok(())
+    }
+
+    fn next_epoch(&mut self, tree: RatchetTree, keys: HashMap<u32, PrivateKey>, joiner_secret: &[u8],
+                  context: &[u8]) {
+        self.epoch += 1;
+        self.tree = tree;
+        self.keys = keys;
+        self.epoch_secret = derive(joiner_secret, b"epoch", context);
+        self.init_secret = derive(&self.epoch_secret, b"init", &[]);
+    }
+}
+
+/// Derive the keys of the nodes of `path` from the path secret of its first
+/// node, checking them against the public keys in the tree. Returns the
+/// commit secret and the private keys.
+fn derive_path_keys(tree: &RatchetTree, path: &[u32], path_secret: &[u8])
+    -> Result<(Vec<u8>, HashMap<u32, PrivateKey>)>
+{
+    let mut keys = HashMap::new();
+    let mut secret = path_secret.to_vec();
+    for (i, &x) in path.iter().enumerate() {
+        if i > 0 {
+            secret = derive(&secret, b"path", &[]);
+        }
+        let (pk, sk) = node_keypair(&secret)?;
+        let expected = tree.node(x).ok_or("blank node on update path")?.public_key();
+        if pk.digest()? != expected.digest()? {
+            return Err("path secret does not match the update path".into());
+        }
+        keys.insert(x, sk);
+    }
+    Ok((derive(&secret, b"path", &[]), keys))
+}
+
+fn node_keypair(path_secret: &[u8]) -> Result<(PublicKey, PrivateKey)> {
+    keygen::keypair_from_seed(&derive(path_secret, b"node", &[]))
+}
+
+fn group_context(group_id: &[u8], epoch: u64, tree: &RatchetTree) -> Result<Vec<u8>> {
+    let mut out = vec![];
+    out.write_u32::<BigEndian>(group_id.len() as u32)?;
+    out.extend_from_slice(group_id);
+    out.write_u64::<BigEndian>(epoch)?;
+    out.extend_from_slice(&tree.hash()?);
+    Ok(out)
+}
+
+/// Encrypt `secret` to `pk`, with the group context as associated data.
+fn encrypt_secret(pk: &PublicKey, secret: &[u8], context: &[u8]) -> Result<Vec<u8>> {
+    let (mut out, ss) = kem::enc(pk)?;
+    let ct_len = out.len();
+    out.extend_from_slice(secret);
+    out.extend_from_slice(&[0u8; TAG_LEN]);
+    let key = aead::SealingKey::new(&aead::CHACHA20_POLY1305, &derive(&ss.0, b"encrypt", &[]))?;
+    aead::seal_in_place(&key, &[0u8; 12], context, &mut out[ct_len..], TAG_LEN)?;
+    Ok(out)
+}
+
+fn decrypt_secret(sk: &PrivateKey, input: &[u8], context: &[u8]) -> Result<Vec<u8>> {
+    if input.len() < CIPHERTEXT_LEN + TAG_LEN {
+        return Err("truncated encrypted secret".into());
+    }
+    let (ct, sealed) = input.split_at(CIPHERTEXT_LEN);
+    let ss = kem::dec(ct, sk)?;
+    let key = aead::OpeningKey::new(&aead::CHACHA20_POLY1305, &derive(&ss.0, b"encrypt", &[]))?;
+    let mut body = sealed.to_vec();
+    let len = aead::open_in_place(&key, &[0u8; 12], context, 0, &mut body)
+        .map_err(|_| "could not decrypt secret")?
+        .len();
+    body.truncate(len);
+    Ok(body)
+}
+
+fn extract(salt: &[u8], ikm: &[u8]) -> Vec<u8> {
+    let salt = hmac::SigningKey::new(&digest::SHA256, salt);
+    hmac::sign(&salt, ikm).as_ref().to_vec()
+}
+
+/// HKDF-Expand `secret` with the labelled `context`.
+fn derive(secret: &[u8], label: &[u8], context: &[u8]) -> Vec<u8> {
+    let mut info = LABEL_PREFIX.to_vec();
+    info.extend_from_slice(label);
+    info.push(0);
+    info.extend_from_slice(context);
+
+    let prk = hmac::SigningKey::new(&digest::SHA256, secret);
+    let mut out = vec![0u8; SECRET_LEN];
+    hkdf::expand(&prk, &info, &mut out);
+    out
+}
+
+fn random_secret() -> Result<Vec<u8>> {
+    let mut out = vec![0u8; SECRET_LEN];
+    get_rng().fill(&mut out)?;
+    Ok(out)
+}
+
+fn read_public_key<R: Read>(reader: &mut R) -> Result<PublicKey> {
+    Ok(PublicKey::from_bytes(&read_vec(reader, PUBKEY_LEN)?))
+}
+
+fn read_vec<R: Read>(reader: &mut R, len: usize) -> Result<Vec<u8>> {
+    let mut out = vec![0u8; len];
+    reader.read_exact(&mut out)?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Client {
+        sk: Option<PrivateKey>,
+        member: Option<Member>,
+    }
+
+    /// Deliver `commit` and `welcome` to every client except the committer.
+    fn deliver(clients: &mut [Client], committer: usize, commit: &Commit, welcome: &Option<Welcome>) {
+        let mut bytes = vec![];
+        commit.write_to(&mut bytes).unwrap();
+        let commit = Commit::from_bytes(&bytes).unwrap();
+        for (i, client) in clients.iter_mut().enumerate() {
+            if i == committer {
+                continue;
+            }
+            if let Some(ref mut member) = client.member {
+                if member.process(&commit).is_err() {
+                    // removed members can no longer follow the group
+                    client.member = None;
+                }
+            } else if let (Some(welcome), Some(sk)) = (welcome.as_ref(), client.sk.as_ref()) {
+                let mut bytes = vec![];
+                welcome.write_to(&mut bytes).unwrap();
+                let joined = Member::join(&Welcome::from_bytes(&bytes).unwrap(), sk.clone()).ok();
+                if joined.is_some() {
+                    client.member = joined;
+                    client.sk = None;
+                }
+            }
+        }
+    }
+
+    fn assert_converged(clients: &[Client]) {
+        let members: Vec<&Member> = clients.iter().filter_map(|c| c.member.as_ref()).collect();
+        for member in &members[1..] {
+            assert_eq!(member.epoch(), members[0].epoch());
+            assert_eq!(member.epoch_secret(), members[0].epoch_secret());
+            assert_eq!(member.tree().hash().unwrap(), members[0].tree().hash().unwrap());
+        }
+        assert_eq!(members[0].tree().member_count(), members.len());
+    }
+
+    fn add(leaf: &LeafNode) -> Proposal {
+        Proposal::Add(Box::new(leaf.clone()))
+    }
+
+    #[test]
+    fn tree_math() {
+        // the eight leaf tree of RFC 9420, appendix C
+        assert_eq!(root(8), 7);
+        assert_eq!(level(7), 3);
+        assert_eq!((left(7), right(7)), (3, 11));
+        assert_eq!(parent(4), 5);
+        assert_eq!(parent(11), 7);
+        assert_eq!(sibling(9), 13);
+        assert_eq!(direct_path(4, 8), vec![5, 3, 7]);
+        assert_eq!(copath(4, 8), vec![6, 1, 11]);
+        assert!(in_subtree(11, 8) && in_subtree(11, 14) && !in_subtree(11, 6));
+    }
+
+    #[test]
+    fn group_converges() {
+        let mut leaves = vec![];
+        let mut clients = vec![];
+        for i in 0..10 {
+            let (leaf, sk) = LeafNode::generate(format!("client {}", i).as_bytes()).unwrap();
+            leaves.push(leaf);
+            clients.push(Client { sk: Some(sk), member: None });
+        }
+        let sk = clients[0].sk.take().unwrap();
+        clients[0].member = Some(Member::create(b"group", leaves[0].clone(), sk).unwrap());
+
+        // alice adds three members, then one of them adds the rest
+        let adds: Vec<Proposal> = leaves[1..4].iter().map(add).collect();
+        let (commit, welcome) = clients[0].member.as_mut().unwrap().commit(&adds).unwrap();
+        deliver(&mut clients, 0, &commit, &welcome);
+        assert_converged(&clients);
+
+        let adds: Vec<Proposal> = leaves[4..].iter().map(add).collect();
+        let (commit, welcome) = clients[2].member.as_mut().unwrap().commit(&adds).unwrap();
+        deliver(&mut clients, 2, &commit, &welcome);
+        assert_converged(&clients);
+        assert_eq!(clients[0].member.as_ref().unwrap().tree().member_count(), 10);
+
+        // everybody updates in turn
+        for i in 0..clients.len() {
+            let old = clients[i].member.as_ref().unwrap().epoch_secret().to_vec();
+            let commit = clients[i].member.as_mut().unwrap().update().unwrap();
+            deliver(&mut clients, i, &commit, &None);
+            assert_converged(&clients);
+            assert!(clients[i].member.as_ref().unwrap().epoch_secret() != &old[..]);
+        }
+
+        // remove two members, and add one back with a new key
+        let removed: Vec<u32> = [3, 7].iter()
+            .map(|&i| clients[i].member.as_ref().unwrap().leaf_index())
+            .collect();
+        let (leaf, sk) = LeafNode::generate(b"client 3").unwrap();
+        let proposals = vec![Proposal::Remove(removed[0]), Proposal::Remove(removed[1]), add(&leaf)];
+        let (commit, welcome) = clients[5].member.as_mut().unwrap().commit(&proposals).unwrap();
+        deliver(&mut clients, 5, &commit, &None);
+        assert!(clients[3].member.is_none() && clients[7].member.is_none());
+        clients[3].sk = Some(sk);
+        deliver_welcome(&mut clients[3], &welcome);
+        assert_converged(&clients);
+        assert_eq!(clients[0].member.as_ref().unwrap().tree().member_count(), 9);
+
+        let commit = clients[3].member.as_mut().unwrap().update().unwrap();
+        deliver(&mut clients, 3, &commit, &None);
+        assert_converged(&clients);
+    }
+
+    fn deliver_welcome(client: &mut Client, welcome: &Option<Welcome>) {
+        let sk = client.sk.take().unwrap();
+        client.member = Some(Member::join(welcome.as_ref().unwrap(), sk).unwrap());
+    }
+
+    #[test]
+    fn serialize_tree() {
+        let (leaf, sk) = LeafNode::generate(b"alice").unwrap();
+        let mut alice = Member::create(b"group", leaf, sk).unwrap();
+        let adds: Vec<Proposal> = (0..4).map(|_| add(&LeafNode::generate(b"x").unwrap().0)).collect();
+        alice.commit(&adds).unwrap();
+        alice.commit(&[Proposal::Remove(3)]).unwrap();
+
+        let mut bytes = vec![];
+        alice.tree().write_to(&mut bytes).unwrap();
+        let tree = RatchetTree::from_bytes(&bytes).unwrap();
+        assert_eq!(tree.hash().unwrap(), alice.tree().hash().unwrap());
+        assert_eq!(tree.leaf_count(), 8);
+        assert_eq!(tree.member_count(), 4);
+        assert_eq!(tree.leaf(1).unwrap().identity, b"x");
+        assert!(tree.leaf(3).is_none());
+
+        assert!(RatchetTree::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        bytes[3] = 3;
+        assert!(RatchetTree::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_commits() {
+        let (leaf, sk) = LeafNode::generate(b"alice").unwrap();
+        let mut alice = Member::create(b"group", leaf, sk).unwrap();
+        let (bob_leaf, bob_sk) = LeafNode::generate(b"bob").unwrap();
+        let (_, welcome) = alice.commit(&[Proposal::Add(Box::new(bob_leaf))]).unwrap();
+        let mut bob = Member::join(&welcome.unwrap(), bob_sk).unwrap();
+
+        let commit = alice.update().unwrap();
+        let mut tampered = commit.clone();
+        tampered.leaf_key = LeafNode::generate(b"eve").unwrap().0.public_key;
+        let before = bob.epoch_secret().to_vec();
+        assert!(bob.process(&tampered).is_err());
+        assert_eq!(bob.epoch_secret(), &before[..]);
+
+        bob.process(&commit).unwrap();
+        assert_eq!(bob.epoch_secret(), alice.epoch_secret());
+        assert!(bob.process(&commit).is_err());
+        assert!(alice.commit(&[Proposal::Remove(0)]).is_err());
+    }
+}
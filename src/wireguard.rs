@@ -0,0 +1,377 @@
+//! Post-quantum preshared keys for WireGuard, in the style of Rosenpass.
+//!
+//! WireGuard mixes an optional 32-byte preshared key (PSK) into its handshake.
+//! If the PSK is derived from a post-quantum key exchange and rotated
+//! regularly, then recorded WireGuard traffic stays confidential against an
+//! attacker who later breaks X25519.
+//!
+//! The two peers run the `ake` key exchange over UDP, authenticating with
+//! their static HILA5 keys, once per epoch. Each datagram is
+//!
+//! ```text
+//! type (1 byte) || epoch (4 bytes, big endian) || ake message
+//! ```
+//!
+//! The responder acknowledges Msg3 with a `MSG3_ACK` datagram whose body is
+//! an HMAC of the epoch under the session key, and the initiator retransmits
+//! Msg3 until that acknowledgement arrives, so it never installs a PSK the
+//! responder has not confirmed. If the acknowledgement is lost, the initiator
+//! gives up and runs a fresh exchange for the same epoch, whose PSK replaces
+//! the one the responder already installed.
+//!
+//! The PSK for an epoch is `HKDF-SHA256(salt, session_key, epoch)`. The
+//! PSK is written out as base64, as accepted by `wg set <interface> peer
+//! <key> preshared-key <file>`.
+//!
+//! The `hila5-wg-psk` binary wraps this module as a command line tool.
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use byteorder::{BigEndian, ByteOrder};
+use ring::{constant_time, digest, hkdf, hmac};
+
+use std::fs;
+use std::io::{self, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::*;
+use ake;
+use errors::*;
+
+/// Length of a WireGuard preshared key.
+pub const PSK_LEN: usize = 32;
+/// Length of the datagram header.
+pub const HEADER_LEN: usize = 5;
+
+const PSK_SALT: &[u8] = b"HILA5v10-WireGuard-PSK";
+const MAX_DATAGRAM: usize = 1 << 16;
+
+const MSG1: u8 = 1;
+const MSG2: u8 = 2;
+const MSG3: u8 = 3;
+const MSG3_ACK: u8 = 4;
+
+const ACK_LABEL: &[u8] = b"HILA5v10-WireGuard-ack";
+const RETRANSMIT_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A WireGuard preshared key.
+pub struct Psk(pub [u8; PSK_LEN]);
+
+impl Psk {
+    /// Derive the PSK for `epoch` from an `ake` session key.
+    pub fn derive(session: &SessionKey, epoch: u32) -> Self {
+        let mut info = [0u8; 4];
+        BigEndian::write_u32(&mut info, epoch);
+        let salt = hmac::SigningKey::new(&digest::SHA256, PSK_SALT);
+        let mut psk = [0u8; PSK_LEN];
+        hkdf::extract_and_expand(&salt, &session.0, &info, &mut psk);
+        Psk(psk)
+    }
+
+    /// Base64 encoding, as used by `wg`.
+    pub fn to_base64(&self) -> String {
+        STANDARD.encode(self.0)
+    }
+
+    /// Write the key to `path` in the format read by `wg set ...
+    /// preshared-key <path>`, replacing the file atomically. On Unix the file
+    /// is only readable by its owner.
+    pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let mut tmp_name = path.file_name()
+            .ok_or_else(|| Error::from("PSK path has no file name"))?
+            .to_os_string();
+        tmp_name.push(".tmp");
+        let tmp = path.with_file_name(tmp_name);
+        {
+            let mut file = create_private(&tmp)?;
+            writeln!(file, "{}", self.to_base64())?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+/// The `wg` command installing the PSK in `psk_file` for a peer.
+pub fn wg_set_command(interface: &str, peer: &str, psk_file: &Path) -> String {
+    format!("wg set {} peer {} preshared-key {}", interface, peer, psk_file.display())
+}
+
+/// Static keys of the local peer, and the static public key of the remote
+/// peer.
+pub struct Peers<'a> {
+    pub sk: &'a PrivateKey,
+    pub remote: &'a PublicKey,
+}
+
+/// Run the initiator side of the exchange for `epoch` with the responder at
+/// `addr`, failing if Msg2 or the acknowledgement of Msg3 does not arrive
+/// within `timeout`.
+pub fn initiate(socket: &UdpSocket, addr: SocketAddr, peers: &Peers, epoch: u32,
+                timeout: Duration) -> Result<Psk> {
+    let (initiator, msg1) = ake::Initiator::start(peers.sk, peers.remote)?;
+    let mut body = vec![];
+    msg1.write_to(&mut body)?;
+    socket.send_to(&datagram(MSG1, epoch, &body), addr)?;
+
+    let deadline = Instant::now() + timeout;
+    // Skip replies that fail to confirm, such as a late Msg2 answering the
+    // Msg1 of an earlier attempt.
+    let msg2 = loop {
+        let (from, msg_type, msg_epoch, body) = recv(socket, deadline)?;
+        if from == addr && msg_type == MSG2 && msg_epoch == epoch {
+            match ake::Msg2::from_bytes(&body) {
+                Ok(msg2) if initiator.check(&msg2).is_ok() => break msg2,
+                _ => {}
+            }
+        }
+    };
+    let (session, msg3) = initiator.finish(&msg2)?;
+    body.clear();
+    msg3.write_to(&mut body)?;
+    let msg3 = datagram(MSG3, epoch, &body);
+    // Computing Msg3 can be slow, so the acknowledgement gets its own timeout.
+    let deadline = Instant::now() + timeout;
+    loop {
+        socket.send_to(&msg3, addr)?;
+        let resend = ::std::cmp::min(Instant::now() + RETRANSMIT_INTERVAL, deadline);
+        let acked = loop {
+            match recv(socket, resend) {
+                Ok((from, MSG3_ACK, msg_epoch, body)) if from == addr && msg_epoch == epoch => {
+                    if check_ack(&session, epoch, &body) {
+                        break true;
+                    }
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    if Instant::now() >= deadline {
+                        return Err(err);
+                    }
+                    break false;
+                }
+            }
+        };
+        if acked {
+            return Ok(Psk::derive(&session, epoch));
+        }
+    }
+}
+
+/// Run the responder side of the exchange, answering the first valid
+/// initiator and returning the epoch and PSK once its key is confirmed and
+/// Msg3 has been acknowledged.
+///
+/// Returns an error if the exchange does not complete within `timeout`.
+pub fn respond(socket: &UdpSocket, peers: &Peers, timeout: Duration) -> Result<(u32, Psk)> {
+    let deadline = Instant::now() + timeout;
+    let mut pending: Option<(SocketAddr, u32, ake::Responder)> = None;
+    loop {
+        let (from, msg_type, epoch, body) = recv(socket, deadline)?;
+        match msg_type {
+            MSG1 => {
+                // A new Msg1 replaces any handshake in progress, so that the
+                // initiator can retry after losing our reply.
+                let msg1 = match ake::Msg1::from_bytes(&body) {
+                    Ok(msg1) => msg1,
+                    Err(_) => continue,
+                };
+                let (responder, msg2) = match ake::Responder::respond(peers.sk, peers.remote, &msg1) {
+                    Ok(r) => r,
+                    Err(_) => continue,
+                };
+                let mut reply = vec![];
+                msg2.write_to(&mut reply)?;
+                socket.send_to(&datagram(MSG2, epoch, &reply), from)?;
+                pending = Some((from, epoch, responder));
+            }
+            MSG3 => {
+                // A forged or corrupted Msg3 must not abort the exchange.
+                let msg3 = match pending {
+                    Some((addr, pending_epoch, ref responder)) if addr == from && pending_epoch == epoch => {
+                        ake::Msg3::from_bytes(&body).ok().filter(|msg3| responder.check(msg3).is_ok())
+                    }
+                    _ => None,
+                };
+                let msg3 = match msg3 {
+                    Some(msg3) => msg3,
+                    None => continue,
+                };
+                let (_, _, responder) = pending.take().unwrap();
+                let session = responder.confirm(&msg3)?;
+                socket.send_to(&datagram(MSG3_ACK, epoch, &ack_tag(&session, epoch)), from)?;
+                return Ok((epoch, Psk::derive(&session, epoch)));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Initiate an exchange every `interval`, passing each new PSK to `on_psk`.
+///
+/// Failed exchanges are retried at the next interval. Runs for `rounds`
+/// successful exchanges, or forever if `rounds` is `None`.
+pub fn run_initiator<F>(socket: &UdpSocket, addr: SocketAddr, peers: &Peers, interval: Duration,
+                        rounds: Option<u32>, mut on_psk: F) -> Result<()>
+    where F: FnMut(u32, &Psk) -> Result<()>
+{
+    let timeout = ::std::cmp::min(interval, Duration::from_secs(5));
+    let mut epoch = 0;
+    while rounds.map(|n| epoch < n).unwrap_or(true) {
+        let start = Instant::now();
+        if let Ok(psk) = initiate(socket, addr, peers, epoch, timeout) {
+            on_psk(epoch, &psk)?;
+            epoch += 1;
+        }
+        if let Some(rest) = interval.checked_sub(start.elapsed()) {
+            thread::sleep(rest);
+        }
+    }
+    Ok(())
+}
+
+/// Answer exchanges from the initiator, passing each new PSK to `on_psk`.
+///
+/// Runs for `rounds` successful exchanges, or forever if `rounds` is `None`.
+pub fn run_responder<F>(socket: &UdpSocket, peers: &Peers, timeout: Duration,
+                        rounds: Option<u32>, mut on_psk: F) -> Result<()>
+    where F: FnMut(u32, &Psk) -> Result<()>
+{
+    let mut completed = 0;
+    while rounds.map(|n| completed < n).unwrap_or(true) {
+        // Timeouts and failed exchanges just wait for the next attempt.
+        if let Ok((epoch, psk)) = respond(socket, peers, timeout) {
+            on_psk(epoch, &psk)?;
+            completed += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Create (or truncate) a file which only the owner can read.
+pub fn create_private(path: &Path) -> io::Result<fs::File> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
+
+fn ack_tag(session: &SessionKey, epoch: u32) -> Vec<u8> {
+    let key = hmac::SigningKey::new(&digest::SHA256, &session.0);
+    let mut ctx = hmac::SigningContext::with_key(&key);
+    ctx.update(ACK_LABEL);
+    ctx.update(&epoch.to_be_bytes());
+    ctx.sign().as_ref().to_vec()
+}
+
+fn check_ack(session: &SessionKey, epoch: u32, tag: &[u8]) -> bool {
+    constant_time::verify_slices_are_equal(&ack_tag(session, epoch), tag).is_ok()
+}
+
+fn datagram(msg_type: u8, epoch: u32, body: &[u8]) -> Vec<u8> {
+    let mut out = vec![msg_type, 0, 0, 0, 0];
+    BigEndian::write_u32(&mut out[1..HEADER_LEN], epoch);
+    out.extend_from_slice(body);
+    out
+}
+
+/// Receive the next well-formed datagram before `deadline`.
+fn recv(socket: &UdpSocket, deadline: Instant) -> Result<(SocketAddr, u8, u32, Vec<u8>)> {
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    loop {
+        let remaining = deadline.checked_duration_since(Instant::now())
+            .filter(|d| *d > Duration::from_millis(0))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "key exchange timed out"))?;
+        socket.set_read_timeout(Some(remaining))?;
+        let (len, from) = socket.recv_from(&mut buf)?;
+        if len < HEADER_LEN {
+            continue;
+        }
+        let epoch = BigEndian::read_u32(&buf[1..HEADER_LEN]);
+        return Ok((from, buf[0], epoch, buf[HEADER_LEN..len].to_vec()));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc;
+
+    use super::*;
+
+    #[test]
+    fn psk_rotation_over_udp_loopback() {
+        let (pk_a, sk_a) = crypto_kem_keypair().unwrap();
+        let (pk_b, sk_b) = crypto_kem_keypair().unwrap();
+
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let responder = thread::spawn(move || {
+            let peers = Peers { sk: &sk_b, remote: &pk_a };
+            run_responder(&server, &peers, Duration::from_secs(10), Some(3), |epoch, psk| {
+                tx.send((epoch, psk.0)).unwrap();
+                Ok(())
+            }).unwrap();
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let peers = Peers { sk: &sk_a, remote: &pk_b };
+        let mut psks = vec![];
+        run_initiator(&client, addr, &peers, Duration::from_millis(50), Some(3), |epoch, psk| {
+            psks.push((epoch, psk.0));
+            Ok(())
+        }).unwrap();
+        responder.join().unwrap();
+
+        let responder_psks: Vec<_> = rx.iter().collect();
+        assert_eq!(psks, responder_psks);
+        assert_eq!(psks.iter().map(|p| p.0).collect::<Vec<_>>(), vec![0, 1, 2]);
+        assert!(psks[0].1 != psks[1].1 && psks[1].1 != psks[2].1);
+    }
+
+    #[test]
+    fn wrong_peer_key_times_out() {
+        let (pk_a, sk_a) = crypto_kem_keypair().unwrap();
+        let (_, sk_b) = crypto_kem_keypair().unwrap();
+        let (pk_c, _) = crypto_kem_keypair().unwrap();
+
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let responder = thread::spawn(move || {
+            let peers = Peers { sk: &sk_b, remote: &pk_a };
+            respond(&server, &peers, Duration::from_millis(500)).is_err()
+        });
+
+        // The initiator expects the responder to hold the key for `pk_c`.
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let peers = Peers { sk: &sk_a, remote: &pk_c };
+        assert!(initiate(&client, addr, &peers, 0, Duration::from_millis(500)).is_err());
+        assert!(responder.join().unwrap());
+    }
+
+    #[test]
+    fn psk_file_format() {
+        let psk = Psk([7u8; PSK_LEN]);
+        let dir = ::std::env::temp_dir().join(format!("hila5-wg-psk-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("peer.psk");
+        fs::write(dir.join("peer.tmp"), "unrelated").unwrap();
+        psk.write_to_file(&path).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("peer.tmp")).unwrap(), "unrelated");
+        assert!(!dir.join("peer.psk.tmp").exists());
+
+        let contents = fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.trim_end().len(), 44);
+        assert_eq!(STANDARD.decode(contents.trim_end()).unwrap(), &psk.0[..]);
+        assert_eq!(wg_set_command("wg0", "PEERKEY=", &path),
+                   format!("wg set wg0 peer PEERKEY= preshared-key {}", path.display()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}